@@ -0,0 +1,54 @@
+//! Attribute macros for `tonic_prometheus_layer`, re-exported from it with the
+//! `macros` feature.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, ItemFn, LitStr};
+
+/// Record calls of an async function in the `function_calls_*` metrics, with
+/// `method="fn"` and `path` set to the function's module path and name.
+///
+/// Use `#[instrument_grpc(name = "...")]` to choose the `path` label instead.
+#[proc_macro_attribute]
+pub fn instrument_grpc(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut name: Option<LitStr> = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("unsupported instrument_grpc argument"))
+        }
+    });
+    parse_macro_input!(attr with parser);
+
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = parse_macro_input!(item as ItemFn);
+    if sig.asyncness.is_none() {
+        return syn::Error::new_spanned(
+            sig.fn_token,
+            "instrument_grpc only supports async functions",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let ident = sig.ident.to_string();
+    let path = match name {
+        Some(name) => quote!(#name),
+        None => quote!(::core::concat!(::core::module_path!(), "::", #ident)),
+    };
+
+    quote! {
+        #(#attrs)*
+        #vis #sig {
+            let __function_call = ::tonic_prometheus_layer::__private::FunctionCall::start(#path);
+            #block
+        }
+    }
+    .into()
+}
@@ -0,0 +1,135 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+thread_local! {
+    /// Bytes allocated by the current thread through [`TrackingAllocator`].
+    static ALLOCATED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Whether [`TrackingAllocator`] is the global allocator, so that an
+/// allocation count of zero can be trusted.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Global allocator counting the bytes allocated by each thread, so that the
+/// allocations made while polling a server call's handler are recorded in
+/// `grpc_server_alloc_bytes`.
+///
+/// ```ignore
+/// use tonic_prometheus_layer::TrackingAllocator;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TrackingAllocator = TrackingAllocator::new(std::alloc::System);
+/// ```
+pub struct TrackingAllocator<A = System> {
+    inner: A,
+}
+
+impl<A> TrackingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+impl<A: GlobalAlloc> TrackingAllocator<A> {
+    fn track(&self, bytes: usize) {
+        if !INSTALLED.load(Ordering::Relaxed) {
+            INSTALLED.store(true, Ordering::Relaxed);
+        }
+        // The thread-local is gone while the thread shuts down.
+        let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + bytes as u64));
+    }
+}
+
+// SAFETY: all allocation is delegated to the inner allocator, with the
+// caller's arguments unchanged; tracking only touches a thread-local counter
+// and never allocates.
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.track(layout.size());
+        // SAFETY: the caller upholds `GlobalAlloc::alloc`'s contract for `layout`.
+        unsafe { self.inner.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.track(layout.size());
+        // SAFETY: the caller upholds `GlobalAlloc::alloc_zeroed`'s contract for `layout`.
+        unsafe { self.inner.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: `ptr` was allocated by `self.inner` with `layout`, since every
+        // allocation of this allocator is delegated to it.
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.track(new_size.saturating_sub(layout.size()));
+        // SAFETY: `ptr` was allocated by `self.inner` with `layout`, and the
+        // caller upholds `GlobalAlloc::realloc`'s contract for `new_size`.
+        unsafe { self.inner.realloc(ptr, layout, new_size) }
+    }
+}
+
+/// Bytes allocated by the current thread so far, if [`TrackingAllocator`] is
+/// the global allocator.
+pub(crate) fn thread_allocated() -> Option<u64> {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    ALLOCATED.try_with(Cell::get).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Full};
+    use prometheus::core::Collector;
+    use tonic::codegen::http::{request, response, HeaderValue};
+    use tonic::codegen::Bytes;
+    use tower::{service_fn, Layer, ServiceExt};
+
+    use super::*;
+    use crate::metrics::ALLOC_HISTOGRAM;
+    use crate::MetricsLayer;
+
+    #[global_allocator]
+    static ALLOCATOR: TrackingAllocator = TrackingAllocator::new(System);
+
+    #[tokio::test]
+    async fn attributes_allocations_to_calls() {
+        const SIZE: usize = 1 << 20;
+        let service = service_fn(|_req: request::Request<()>| async {
+            let buffer = std::hint::black_box(vec![0u8; SIZE]);
+            let mut resp = response::Response::new(Full::new(Bytes::from(buffer)));
+            resp.headers_mut()
+                .insert("grpc-status", HeaderValue::from_static("0"));
+            Ok::<_, std::convert::Infallible>(resp)
+        });
+        let req = request::Request::builder()
+            .uri("/test.Alloc/Get")
+            .body(())
+            .unwrap();
+
+        let resp = MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+        resp.into_body().collect().await.unwrap();
+
+        let families = ALLOC_HISTOGRAM.collect();
+        let histogram = families[0]
+            .get_metric()
+            .iter()
+            .find(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|label| label.get_value() == "test.Alloc")
+            })
+            .unwrap()
+            .get_histogram();
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert!(histogram.get_sample_sum() >= SIZE as f64);
+    }
+}
@@ -0,0 +1,303 @@
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::Buf;
+use http_body::{Body, Frame, SizeHint};
+use pin_project::{pin_project, pinned_drop};
+use tonic::Code;
+
+use crate::clock::Timestamp;
+use crate::connect::ErrorScanner;
+use crate::frames::FrameChecker;
+use crate::grpc_web::TrailersScanner;
+use crate::metrics::{feature_enabled, RecordingFeature};
+use crate::ServerCall;
+
+/// Response body wrapper that records the final gRPC status once the
+/// trailers have been sent.
+///
+/// Streaming responses (and unary responses that are not trailers-only)
+/// carry `grpc-status` in the HTTP trailers, so the call can only be
+/// accounted for when the body finishes. gRPC-Web and Connect responses
+/// carry it inside the body instead, which is scanned for.
+#[pin_project(PinnedDrop)]
+pub struct MetricsBody<B> {
+    #[pin]
+    inner: B,
+    call: Option<ServerCall>,
+    scanner: Option<Scanner>,
+    frames: Option<FrameChecker>,
+}
+
+impl<B> MetricsBody<B> {
+    pub(crate) fn new(
+        inner: B,
+        call: Option<ServerCall>,
+        scanner: Option<Scanner>,
+        frames: Option<FrameChecker>,
+    ) -> Self {
+        Self {
+            inner,
+            call,
+            scanner,
+            frames,
+        }
+    }
+}
+
+/// Looks for the final status inside the data frames of the response body.
+pub(crate) enum Scanner {
+    GrpcWeb(TrailersScanner),
+    Connect(ErrorScanner),
+}
+
+impl Scanner {
+    fn feed(&mut self, chunk: &[u8]) -> Option<Code> {
+        match self {
+            Scanner::GrpcWeb(scanner) => scanner.feed(chunk),
+            Scanner::Connect(scanner) => {
+                scanner.feed(chunk);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn finish(&self) -> Code {
+        match self {
+            Scanner::GrpcWeb(_) => Code::Ok,
+            Scanner::Connect(scanner) => scanner.finish(),
+        }
+    }
+}
+
+impl<B> Body for MetricsBody<B>
+where
+    B: Body,
+    B::Data: Clone,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+
+        // The transport only asks for the next frame once it has sent the
+        // previous one, which waits for flow control window on slow consumers.
+        if let Some(call) = this.call.as_mut() {
+            if let Some(sent_at) = call.frame_sent_at.take() {
+                call.send_blocked += sent_at.elapsed();
+            }
+        }
+
+        // Streaming handlers run while the body is polled.
+        let poll_started = this.call.as_ref().and_then(ServerCall::poll_started);
+        let frame = this.inner.as_mut().poll_frame(cx);
+        if let (Some(started), Some(call)) = (poll_started, this.call.as_ref()) {
+            call.polled(started);
+        }
+        let frame = ready!(frame);
+        check_frames(this.frames, &frame);
+        if let (Some(Ok(frame)), Some(call)) = (&frame, this.call.as_mut()) {
+            if let Some(data) = frame.data_ref() {
+                call.response_bytes += data.remaining() as u64;
+                call.message_sent();
+                if feature_enabled(RecordingFeature::SendBlockedTime) {
+                    call.frame_sent_at = Some(Timestamp::now());
+                }
+            } else if let Some(trailers) = frame.trailers_ref() {
+                call.response_metadata_bytes += crate::metadata_size(trailers);
+            }
+        }
+        let code = match &frame {
+            Some(Ok(frame)) => match (frame.data_ref(), this.scanner.as_mut()) {
+                (Some(data), Some(scanner)) if this.call.is_some() => {
+                    find_map_chunks(data, |chunk| scanner.feed(chunk))
+                }
+                _ => frame
+                    .trailers_ref()
+                    .map(|trailers| crate::grpc_status(trailers).unwrap_or(Code::Ok)),
+            },
+            Some(Err(_)) => {
+                if let Some(call) = this.call.take() {
+                    call.fail();
+                }
+                None
+            }
+            None => Some(this.scanner.as_ref().map_or(Code::Ok, Scanner::finish)),
+        };
+        // Transports drop the body without polling it again once it reports
+        // its end, so a body ending with data finishes the call here.
+        let code = code.or_else(|| {
+            let data = matches!(&frame, Some(Ok(frame)) if frame.is_data());
+            (data && this.inner.is_end_stream())
+                .then(|| this.scanner.as_ref().map_or(Code::Ok, Scanner::finish))
+        });
+        if let Some(code) = code {
+            if let Some(call) = this.call.take() {
+                call.finish(code);
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Request body wrapper that checks the message framing of gRPC requests,
+/// see [`FrameCheckLayer`](crate::FrameCheckLayer).
+#[pin_project]
+pub(crate) struct MetricsRequestBody<B> {
+    #[pin]
+    inner: B,
+    frames: Option<FrameChecker>,
+}
+
+impl<B> MetricsRequestBody<B> {
+    pub(crate) fn new(inner: B, frames: Option<FrameChecker>) -> Self {
+        Self { inner, frames }
+    }
+}
+
+impl<B> Body for MetricsRequestBody<B>
+where
+    B: Body,
+    B::Data: Clone,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = ready!(this.inner.poll_frame(cx));
+        check_frames(this.frames, &frame);
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Feed a frame polled from a body to its frame checker, if any.
+fn check_frames<D: Buf + Clone, E>(
+    frames: &mut Option<FrameChecker>,
+    frame: &Option<Result<Frame<D>, E>>,
+) {
+    let Some(checker) = frames.as_mut() else {
+        return;
+    };
+    match frame {
+        Some(Ok(frame)) => match frame.data_ref() {
+            Some(data) => {
+                find_map_chunks(data, |chunk| {
+                    checker.feed(chunk);
+                    None::<()>
+                });
+            }
+            // Trailers end the body.
+            None => checker.finish(),
+        },
+        Some(Err(_)) => *frames = None,
+        None => checker.finish(),
+    }
+}
+
+/// The first `Some` returned by `f` for the contiguous chunks of `data`, in
+/// order. The chunks are walked on a clone, leaving `data` to the transport.
+fn find_map_chunks<D: Buf + Clone, T>(
+    data: &D,
+    mut f: impl FnMut(&[u8]) -> Option<T>,
+) -> Option<T> {
+    let mut rest = data.clone();
+    while rest.has_remaining() {
+        let chunk = rest.chunk();
+        let len = chunk.len();
+        if let Some(found) = f(chunk) {
+            return Some(found);
+        }
+        rest.advance(len);
+    }
+    None
+}
+
+#[pinned_drop]
+impl<B> PinnedDrop for MetricsBody<B> {
+    fn drop(self: Pin<&mut Self>) {
+        // A body dropped before reaching its end means the peer went away.
+        if let Some(call) = self.project().call.take() {
+            call.finish(Code::Cancelled);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+
+    use bytes::Bytes;
+    use tonic::codegen::http::HeaderMap;
+
+    use super::*;
+    use crate::metrics::FRAME_ERRORS;
+
+    /// A buffer of many separate chunks, like a chain of small writes.
+    #[derive(Clone)]
+    struct Chunks(VecDeque<Bytes>);
+
+    impl Buf for Chunks {
+        fn remaining(&self) -> usize {
+            self.0.iter().map(Bytes::len).sum()
+        }
+
+        fn chunk(&self) -> &[u8] {
+            self.0.front().map_or(&[], |chunk| chunk)
+        }
+
+        fn advance(&mut self, mut cnt: usize) {
+            while cnt > 0 {
+                let front = self.0.front_mut().unwrap();
+                let n = cnt.min(front.len());
+                front.advance(n);
+                cnt -= n;
+                if front.is_empty() {
+                    self.0.pop_front();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn checks_every_chunk() {
+        let mut message = vec![0];
+        message.extend_from_slice(&20u32.to_be_bytes());
+        message.extend_from_slice(&[7; 20]);
+        let chunks = message
+            .iter()
+            .map(|byte| Bytes::copy_from_slice(&[*byte]))
+            .collect();
+
+        let mut frames = Some(FrameChecker::new("test-chunks", &HeaderMap::new()));
+        let frame = Some(Ok::<_, Infallible>(Frame::data(Chunks(chunks))));
+        check_frames(&mut frames, &frame);
+        check_frames(&mut frames, &None::<Result<Frame<Chunks>, Infallible>>);
+        assert_eq!(FRAME_ERRORS.with_label_values(&["test-chunks"]).get(), 0);
+    }
+}
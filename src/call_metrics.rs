@@ -0,0 +1,115 @@
+use std::sync::{Arc, Mutex};
+
+use prometheus::{CounterVec, HistogramVec};
+
+use crate::metrics::{get_settings, recording_failed};
+
+/// A metric that handlers can record values into through [`CallMetrics`],
+/// labeled by `grpc_service` and `grpc_method`, in that order.
+///
+/// Register it in a registry yourself and add it to
+/// `GlobalSettings::call_metrics`.
+#[derive(Clone)]
+pub enum CallMetric {
+    /// Incremented by each value; negative values are ignored.
+    Counter(CounterVec),
+    Histogram(HistogramVec),
+}
+
+/// Request extension for recording per-call values, such as rows scanned or
+/// cache hits, into the metrics in `GlobalSettings::call_metrics`.
+///
+/// The values are recorded with the service and method labels of the call
+/// when it finishes.
+///
+/// ```no_run
+/// # fn handler(request: tonic::Request<()>) {
+/// if let Some(metrics) = request.extensions().get::<tonic_prometheus_layer::metrics::CallMetrics>() {
+///     metrics.record("rows_scanned", 42.0);
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CallMetrics(Arc<Mutex<Vec<(String, f64)>>>);
+
+impl CallMetrics {
+    /// Record `value` into the metric configured under `name`, if any.
+    pub fn record(&self, name: impl Into<String>, value: f64) {
+        self.0.lock().unwrap().push((name.into(), value));
+    }
+
+    /// Record the values of a finished call of `service`/`method`.
+    pub(crate) fn flush(&self, service: &str, method: &str) {
+        let call_metrics = &get_settings().call_metrics;
+        for (name, value) in self.0.lock().unwrap().drain(..) {
+            let recorded = match call_metrics.get(&name) {
+                Some(CallMetric::Counter(counter)) if value >= 0.0 => counter
+                    .get_metric_with_label_values(&[service, method])
+                    .map(|counter| counter.inc_by(value)),
+                Some(CallMetric::Histogram(histogram)) => histogram
+                    .get_metric_with_label_values(&[service, method])
+                    .map(|histogram| histogram.observe(value)),
+                _ => Ok(()),
+            };
+            if let Err(error) = recorded {
+                recording_failed(&name, error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{histogram_opts, opts};
+
+    use super::*;
+    use crate::metrics::{self, RECORDING_ERRORS};
+
+    #[test]
+    fn flushes_recorded_values() {
+        let labels = ["grpc_service", "grpc_method"];
+        let hits = CounterVec::new(opts!("cache_hits_total", "Cache hits."), &labels).unwrap();
+        let rows =
+            HistogramVec::new(histogram_opts!("rows_scanned", "Rows scanned."), &labels).unwrap();
+        let _settings = metrics::test_settings(|settings| {
+            settings.call_metrics = [
+                ("cache_hits".to_owned(), CallMetric::Counter(hits.clone())),
+                (
+                    "rows_scanned".to_owned(),
+                    CallMetric::Histogram(rows.clone()),
+                ),
+            ]
+            .into();
+        });
+
+        let metrics = CallMetrics::default();
+        metrics.record("cache_hits", 2.0);
+        metrics.record("cache_hits", -1.0);
+        metrics.record("rows_scanned", 42.0);
+        metrics.record("unknown", 1.0);
+        metrics.flush("test.Custom", "Get");
+
+        let labels = ["test.Custom", "Get"];
+        assert_eq!(hits.with_label_values(&labels).get(), 2.0);
+        let rows = rows.with_label_values(&labels);
+        assert_eq!(rows.get_sample_count(), 1);
+        assert_eq!(rows.get_sample_sum(), 42.0);
+    }
+
+    #[test]
+    fn fails_open_on_recording_errors() {
+        let hits = CounterVec::new(opts!("mislabeled_total", "Mislabeled."), &["tenant"]).unwrap();
+        let _settings = metrics::test_settings(|settings| {
+            settings.fail_open = true;
+            settings.call_metrics = [("mislabeled".to_owned(), CallMetric::Counter(hits))].into();
+        });
+
+        let metrics = CallMetrics::default();
+        metrics.record("mislabeled", 1.0);
+        metrics.record("mislabeled", 1.0);
+        metrics.flush("test.Mislabeled", "Get");
+
+        let errors = RECORDING_ERRORS.with_label_values(&["mislabeled"]);
+        assert_eq!(errors.get(), 2);
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+use prometheus::proto::{MetricFamily, MetricType};
+
+use crate::merge;
+
+/// Number of most frequent values listed per label.
+const TOP_VALUES: usize = 10;
+
+/// Series counts of an exported family, see [`cardinality_report`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct FamilyCardinality {
+    pub name: String,
+    /// Number of label combinations.
+    pub series: usize,
+    /// Number of samples in the exposition, which counts each bucket of a
+    /// histogram, and the count and sum of histograms and summaries.
+    pub samples: usize,
+    /// The labels of the family, by number of distinct values, descending.
+    pub labels: Vec<LabelCardinality>,
+}
+
+/// Values of a label of a family, see [`cardinality_report`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelCardinality {
+    pub name: String,
+    /// Number of distinct values.
+    pub distinct: usize,
+    /// The most frequent values with their number of series, descending.
+    pub top_values: Vec<(String, usize)>,
+}
+
+/// Count the exported series of each family, and of each value of their
+/// labels, to find the label combinations that blow up the registry.
+///
+/// Families are sorted by number of samples, descending, and each label lists
+/// its ten most frequent values. The counts are those of the export, after
+/// `GlobalSettings::relabel` rules and with merged registries.
+///
+/// ```
+/// for family in tonic_prometheus_layer::metrics::cardinality_report() {
+///     println!("{}: {} series", family.name, family.series);
+/// }
+/// ```
+pub fn cardinality_report() -> Vec<FamilyCardinality> {
+    report(&merge::gather())
+}
+
+/// Render [`cardinality_report`] as plain text, one family per line followed
+/// by its labels, e.g. to serve from a debug endpoint.
+pub fn render_cardinality_report() -> String {
+    let mut out = String::new();
+    let _ = write_report(&mut out, &cardinality_report());
+    out
+}
+
+fn report(families: &[MetricFamily]) -> Vec<FamilyCardinality> {
+    let mut report: Vec<_> = families.iter().map(family_cardinality).collect();
+    report.sort_by(|a, b| b.samples.cmp(&a.samples).then_with(|| a.name.cmp(&b.name)));
+    report
+}
+
+fn family_cardinality(family: &MetricFamily) -> FamilyCardinality {
+    let mut values: HashMap<&str, HashMap<&str, usize>> = HashMap::new();
+    let mut samples = 0;
+    for metric in family.get_metric() {
+        samples += match family.get_field_type() {
+            MetricType::HISTOGRAM => metric.get_histogram().get_bucket().len() + 3,
+            MetricType::SUMMARY => metric.get_summary().get_quantile().len() + 2,
+            _ => 1,
+        };
+        for label in metric.get_label() {
+            *values
+                .entry(label.get_name())
+                .or_default()
+                .entry(label.get_value())
+                .or_default() += 1;
+        }
+    }
+
+    let mut labels: Vec<_> = values
+        .into_iter()
+        .map(|(name, values)| {
+            let distinct = values.len();
+            let mut top_values: Vec<_> = values
+                .into_iter()
+                .map(|(value, count)| (value.to_owned(), count))
+                .collect();
+            top_values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            top_values.truncate(TOP_VALUES);
+            LabelCardinality {
+                name: name.to_owned(),
+                distinct,
+                top_values,
+            }
+        })
+        .collect();
+    labels.sort_by(|a, b| {
+        b.distinct
+            .cmp(&a.distinct)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    FamilyCardinality {
+        name: family.get_name().to_owned(),
+        series: family.get_metric().len(),
+        samples,
+        labels,
+    }
+}
+
+fn write_report(out: &mut String, report: &[FamilyCardinality]) -> fmt::Result {
+    for family in report {
+        writeln!(
+            out,
+            "{} series={} samples={}",
+            family.name, family.series, family.samples
+        )?;
+        for label in &family.labels {
+            write!(out, "  {} distinct={}", label.name, label.distinct)?;
+            for (value, count) in &label.top_values {
+                write!(out, " {value:?}={count}")?;
+            }
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_series() {
+        let registry = prometheus::Registry::new();
+        let handled = prometheus::IntCounterVec::new(
+            prometheus::opts!("cardinality_handled_total", "test"),
+            &["grpc_method", "grpc_code"],
+        )
+        .unwrap();
+        registry.register(Box::new(handled.clone())).unwrap();
+        handled.with_label_values(&["A", "OK"]).inc();
+        handled.with_label_values(&["B", "OK"]).inc();
+        handled.with_label_values(&["B", "Internal"]).inc();
+
+        let report = report(&registry.gather());
+        assert_eq!(
+            report,
+            [FamilyCardinality {
+                name: "cardinality_handled_total".to_owned(),
+                series: 3,
+                samples: 3,
+                labels: vec![
+                    LabelCardinality {
+                        name: "grpc_code".to_owned(),
+                        distinct: 2,
+                        top_values: vec![("OK".to_owned(), 2), ("Internal".to_owned(), 1)],
+                    },
+                    LabelCardinality {
+                        name: "grpc_method".to_owned(),
+                        distinct: 2,
+                        top_values: vec![("B".to_owned(), 2), ("A".to_owned(), 1)],
+                    },
+                ],
+            }]
+        );
+
+        let mut out = String::new();
+        write_report(&mut out, &report).unwrap();
+        assert_eq!(
+            out,
+            "cardinality_handled_total series=3 samples=3\n  \
+             grpc_code distinct=2 \"OK\"=2 \"Internal\"=1\n  \
+             grpc_method distinct=2 \"B\"=2 \"A\"=1\n"
+        );
+    }
+}
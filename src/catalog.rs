@@ -0,0 +1,173 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+
+use crate::metrics::{get_settings, METHOD_INFO};
+
+/// Label value for calls to methods missing from the catalog.
+pub(crate) const UNIMPLEMENTED: &str = "<unimplemented>";
+
+/// Methods passed to [`describe_methods`], by service.
+static CATALOG: Lazy<RwLock<HashMap<String, HashSet<String>>>> = Lazy::new(Default::default);
+
+/// A method served by the server, for [`describe_methods`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MethodDescriptor {
+    pub service: String,
+    pub method: String,
+    pub kind: MethodKind,
+}
+
+impl MethodDescriptor {
+    pub fn new(service: impl Into<String>, method: impl Into<String>, kind: MethodKind) -> Self {
+        Self {
+            service: service.into(),
+            method: method.into(),
+            kind,
+        }
+    }
+}
+
+/// Streaming type of a gRPC method, reported as the `grpc_type` label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MethodKind {
+    Unary,
+    ClientStream,
+    ServerStream,
+    BidiStream,
+}
+
+impl MethodKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MethodKind::Unary => "unary",
+            MethodKind::ClientStream => "client_stream",
+            MethodKind::ServerStream => "server_stream",
+            MethodKind::BidiStream => "bidi_stream",
+        }
+    }
+}
+
+/// Export `grpc_server_method_info` for each of `methods`, so that methods
+/// without any traffic yet still show up.
+///
+/// Once any method is described, server calls to methods missing from the
+/// catalog are recorded with `grpc_method="<unimplemented>"`, and with
+/// `grpc_service="<unimplemented>"` too if the service is unknown, so that
+/// requests for bogus paths do not create new series.
+///
+/// The `grpc_service` label is set after `GlobalSettings::service_names`,
+/// like the labels of the calls.
+pub fn describe_methods(methods: impl IntoIterator<Item = MethodDescriptor>) {
+    let mut catalog = CATALOG.write().unwrap();
+    for m in methods {
+        describe(&mut catalog, m);
+    }
+}
+
+fn describe(catalog: &mut HashMap<String, HashSet<String>>, m: MethodDescriptor) {
+    let service = get_settings().service_names.apply(m.service.clone());
+    METHOD_INFO
+        .with_label_values(&[&service, &m.method, m.kind.as_str()])
+        .set(1);
+    // Calls are looked up by their fully-qualified service name.
+    catalog.entry(m.service).or_default().insert(m.method);
+}
+
+/// How much of a called method the catalog knows about.
+pub(crate) enum Known {
+    Method,
+    Service,
+    Neither,
+}
+
+/// Look up a method in the catalog. Without a catalog, every method is known.
+pub(crate) fn known(service: &str, method: &str) -> Known {
+    lookup(&CATALOG.read().unwrap(), service, method)
+}
+
+fn lookup(catalog: &HashMap<String, HashSet<String>>, service: &str, method: &str) -> Known {
+    if catalog.is_empty() {
+        return Known::Method;
+    }
+    match catalog.get(service) {
+        Some(methods) if methods.contains(method) => Known::Method,
+        Some(_) => Known::Service,
+        None => Known::Neither,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{self, ServiceNames};
+
+    #[test]
+    fn looks_up_methods() {
+        let mut catalog = HashMap::new();
+        assert!(matches!(
+            lookup(&catalog, "test.Bogus", "Get"),
+            Known::Method
+        ));
+
+        describe(
+            &mut catalog,
+            MethodDescriptor::new("test.Known", "Get", MethodKind::Unary),
+        );
+        assert!(matches!(
+            lookup(&catalog, "test.Known", "Get"),
+            Known::Method
+        ));
+        assert!(matches!(
+            lookup(&catalog, "test.Known", "Put"),
+            Known::Service
+        ));
+        assert!(matches!(
+            lookup(&catalog, "test.Bogus", "Get"),
+            Known::Neither
+        ));
+    }
+
+    #[test]
+    fn exports_method_info() {
+        let mut catalog = HashMap::new();
+        for m in [
+            MethodDescriptor::new("test.Info", "Get", MethodKind::Unary),
+            MethodDescriptor::new("test.Info", "Watch", MethodKind::ServerStream),
+        ] {
+            describe(&mut catalog, m);
+        }
+
+        let info = |method, kind| {
+            METHOD_INFO
+                .with_label_values(&["test.Info", method, kind])
+                .get()
+        };
+        assert_eq!(info("Get", "unary"), 1);
+        assert_eq!(info("Watch", "server_stream"), 1);
+        assert_eq!(info("Watch", "unary"), 0);
+        assert_eq!(catalog["test.Info"].len(), 2);
+    }
+
+    #[test]
+    fn describes_stripped_service() {
+        let _settings = metrics::test_settings(|settings| {
+            settings.service_names = ServiceNames::StripPackage;
+        });
+        let mut catalog = HashMap::new();
+        describe(
+            &mut catalog,
+            MethodDescriptor::new("test.catalog.Described", "Get", MethodKind::Unary),
+        );
+
+        let info = |service| {
+            METHOD_INFO
+                .with_label_values(&[service, "Get", "unary"])
+                .get()
+        };
+        assert_eq!(info("Described"), 1);
+        assert_eq!(info("test.catalog.Described"), 0);
+        assert!(catalog["test.catalog.Described"].contains("Get"));
+    }
+}
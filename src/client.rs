@@ -1,19 +1,44 @@
 use pin_project::pin_project;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Instant;
 use tonic::codegen::http::{Request, Response};
 use tonic::{Code, GrpcMethod};
+use tower::load::Load;
 use tower::Service;
 
-use crate::metrics::{CLIENT_COUNTER_HANDLED, CLIENT_COUNTER_STARTED, CLIENT_HISTOGRAM};
+use crate::clock::Timestamp;
+use crate::connectivity::Connectivity;
+use crate::metrics::{
+    get_settings, ProxyContext, CLIENT_COUNTER_HANDLED, CLIENT_COUNTER_STARTED, CLIENT_HISTOGRAM,
+};
+
+mod private {
+    /// Responses of the `http` versions supported by [`super::MetricsChannel`].
+    pub trait GrpcResponse {
+        /// The `grpc-status` of a trailers-only response.
+        fn grpc_status(&self) -> Option<tonic::Code>;
+    }
+}
+
+pub(crate) use private::GrpcResponse;
+
+impl<B> GrpcResponse for Response<B> {
+    fn grpc_status(&self) -> Option<Code> {
+        self.headers()
+            .get("grpc-status")
+            .map(|s| Code::from_bytes(s.as_bytes()))
+    }
+}
 
 #[pin_project]
 pub struct MetricsChannelFuture<F> {
     service: String,
     method: String,
-    started_at: Option<Instant>,
+    started_at: Option<Timestamp>,
+    /// Context of the server call making this call, if any.
+    proxy: Option<ProxyContext>,
     #[pin]
     inner: F,
 }
@@ -23,15 +48,23 @@ impl<F> MetricsChannelFuture<F> {
         Self {
             inner,
             started_at: None,
+            proxy: None,
             service,
             method,
         }
     }
+
+    /// Add the duration of the call to `proxy` when it completes.
+    pub(crate) fn with_proxy(mut self, proxy: Option<ProxyContext>) -> Self {
+        self.proxy = proxy;
+        self
+    }
 }
 
-impl<F, O, E> Future for MetricsChannelFuture<F>
+impl<F, R, E> Future for MetricsChannelFuture<F>
 where
-    F: Future<Output = Result<Response<O>, E>>,
+    F: Future<Output = Result<R, E>>,
+    R: GrpcResponse,
 {
     type Output = F::Output;
 
@@ -42,18 +75,19 @@ where
             CLIENT_COUNTER_STARTED
                 .with_label_values(&[this.service, this.method])
                 .inc();
-            Instant::now()
+            Timestamp::now()
         });
 
         if let Poll::Ready(v) = this.inner.poll(cx) {
-            let code = v.as_ref().map_or(Code::Unknown, |resp| {
-                resp.headers()
-                    .get("grpc-status")
-                    .map(|s| Code::from_bytes(s.as_bytes()))
-                    .unwrap_or(Code::Ok)
-            });
+            let code = v
+                .as_ref()
+                .map_or(Code::Unknown, |resp| resp.grpc_status().unwrap_or(Code::Ok));
             let code_str = format!("{:?}", code);
-            let elapsed = Instant::now().duration_since(*started_at).as_secs_f64();
+            let elapsed = started_at.elapsed();
+            if let Some(proxy) = this.proxy {
+                proxy.add(elapsed);
+            }
+            let elapsed = elapsed.as_secs_f64();
             CLIENT_COUNTER_HANDLED
                 .with_label_values(&[this.service, this.method, &code_str])
                 .inc();
@@ -68,8 +102,10 @@ where
 }
 
 /// Wrapper for instrumenting a tonic client channel with gRPC metrics.
+#[derive(Clone, Debug)]
 pub struct MetricsChannel<T> {
     inner: T,
+    connectivity: Option<Arc<Connectivity>>,
 }
 
 impl<T> MetricsChannel<T> {
@@ -88,7 +124,49 @@ impl<T> MetricsChannel<T> {
     /// }
     /// ```
     pub fn new(inner: T) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            connectivity: None,
+        }
+    }
+
+    /// Also track the connectivity state of the channel in
+    /// `grpc_client_channel_state` and `grpc_client_reconnects_total`, with
+    /// `target` as label, e.g. the endpoint URI.
+    ///
+    /// The state is derived from the readiness of the inner channel: pending
+    /// while connecting, ready, or failed. A ready channel that is pending
+    /// because its buffer is full stays ready. Clones share the state.
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.connectivity = Some(Arc::new(Connectivity::new(target.into())));
+        self
+    }
+
+    /// Get a reference to the inner channel.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the inner channel.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consume `self`, returning the inner channel.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Poll the inner channel for readiness, recording the connectivity state.
+    pub(crate) fn poll_inner_ready<R>(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>>
+    where
+        T: Service<R>,
+    {
+        let ready = self.inner.poll_ready(cx);
+        if let Some(connectivity) = &self.connectivity {
+            connectivity.observe(&ready);
+        }
+        ready
     }
 }
 
@@ -102,7 +180,7 @@ where
     type Future = MetricsChannelFuture<T::Future>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+        self.poll_inner_ready::<Request<I>>(cx)
     }
 
     fn call(&mut self, req: Request<I>) -> Self::Future {
@@ -110,7 +188,21 @@ where
             .extensions()
             .get::<GrpcMethod>()
             .map_or(("", ""), |gm| (gm.service(), gm.method()));
-        MetricsChannelFuture::new(service.into(), method.into(), self.inner.call(req))
+        let settings = get_settings();
+        let method = settings.method_label(service, method.into());
+        let service = settings.service_names.apply(service.into());
+        let proxy = req.extensions().get::<ProxyContext>().cloned();
+        MetricsChannelFuture::new(service, method, self.inner.call(req)).with_proxy(proxy)
+    }
+}
+
+/// Forwards the inner channel's load, so instrumented channels can be balanced
+/// with `tower::balance`.
+impl<T: Load> Load for MetricsChannel<T> {
+    type Metric = T::Metric;
+
+    fn load(&self) -> Self::Metric {
+        self.inner.load()
     }
 }
 
@@ -132,7 +224,6 @@ mod tests {
         let resp = client
             .check(HealthCheckRequest {
                 service: String::from("yes"),
-                ..Default::default()
             })
             .await
             .expect("Health.Check()")
@@ -141,16 +232,14 @@ mod tests {
         let resp = client
             .check(HealthCheckRequest {
                 service: String::from("unknown"),
-                ..Default::default()
             })
             .await
             .expect_err("Health.Check()");
         assert_eq!(resp.code(), Code::NotFound);
 
-        let got = crate::metrics::encode_to_string().unwrap();
-        assert!(got.contains(
-            "\ngrpc_client_handled_total{grpc_code=\"NotFound\",grpc_method=\"Check\",grpc_service=\"grpc.health.v1.Health\"} 1\n"));
-        assert!(got.contains(
-            "\ngrpc_client_handled_total{grpc_code=\"Ok\",grpc_method=\"Check\",grpc_service=\"grpc.health.v1.Health\"} 1\n"));
+        let got = crate::metrics::snapshot();
+        let check = got.client("grpc.health.v1.Health", "Check").unwrap();
+        assert_eq!(check.handled(Code::NotFound), 1);
+        assert_eq!(check.handled(Code::Ok), 1);
     }
 }
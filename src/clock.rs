@@ -0,0 +1,100 @@
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "quanta")]
+use once_cell::sync::OnceCell;
+
+use crate::metrics::{get_settings, TimeSource};
+
+/// Keeps `quanta::Instant::recent` up to date for `TimeSource::Coarse`. Without
+/// it, `recent` falls back to reading the clock.
+#[cfg(feature = "quanta")]
+static UPKEEP: OnceCell<Option<quanta::Handle>> = OnceCell::new();
+
+/// Start of a call, read from `GlobalSettings::time_source`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Timestamp {
+    Std(Instant),
+    #[cfg(feature = "quanta")]
+    Quanta(quanta::Instant),
+}
+
+impl Timestamp {
+    pub(crate) fn now() -> Self {
+        match get_settings().time_source {
+            TimeSource::Std => Timestamp::Std(Instant::now()),
+            #[cfg(feature = "quanta")]
+            TimeSource::Quanta => Timestamp::Quanta(quanta::Instant::now()),
+            #[cfg(feature = "quanta")]
+            TimeSource::Coarse(resolution) => {
+                UPKEEP.get_or_init(|| quanta::Upkeep::new(resolution).start().ok());
+                Timestamp::Quanta(quanta::Instant::recent())
+            }
+        }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        match (self, Self::now()) {
+            (Timestamp::Std(start), Timestamp::Std(now)) => now.saturating_duration_since(*start),
+            #[cfg(feature = "quanta")]
+            (Timestamp::Quanta(start), Timestamp::Quanta(now)) => {
+                now.saturating_duration_since(*start)
+            }
+            #[cfg(feature = "quanta")]
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// CPU time consumed by the current thread.
+#[cfg(unix)]
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid, writable timespec.
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } != 0 {
+        return None;
+    }
+    Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sleep_elapsed() -> Duration {
+        let started_at = Timestamp::now();
+        std::thread::sleep(Duration::from_millis(20));
+        started_at.elapsed()
+    }
+
+    #[test]
+    fn std_elapsed() {
+        assert!(sleep_elapsed() >= Duration::from_millis(20));
+    }
+
+    #[cfg(feature = "quanta")]
+    #[test]
+    fn quanta_elapsed() {
+        let _settings = crate::metrics::test_settings(|settings| {
+            settings.time_source = TimeSource::Quanta;
+        });
+        assert!(sleep_elapsed() >= Duration::from_millis(20));
+    }
+
+    #[cfg(feature = "quanta")]
+    #[test]
+    fn coarse_elapsed() {
+        let _settings = crate::metrics::test_settings(|settings| {
+            settings.time_source = TimeSource::Coarse(Duration::from_millis(1));
+        });
+        // Accurate to about the resolution of the upkeep thread.
+        assert!(sleep_elapsed() >= Duration::from_millis(15));
+    }
+}
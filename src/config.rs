@@ -0,0 +1,111 @@
+//! Deserialization of the settings from configuration files, with the `serde`
+//! feature.
+//!
+//! Durations are given in seconds, e.g. `0.25`, status codes by name, e.g.
+//! `DeadlineExceeded`, and maps keyed by method as `service/method`.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use tonic::Code;
+
+/// A duration in seconds.
+pub(crate) fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let seconds = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(seconds).map_err(D::Error::custom)
+}
+
+/// An optional duration in seconds.
+pub(crate) fn option_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<f64>::deserialize(deserializer)?
+        .map(|seconds| Duration::try_from_secs_f64(seconds).map_err(D::Error::custom))
+        .transpose()
+}
+
+/// Status codes by name, e.g. `Cancelled`.
+pub(crate) fn codes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Code>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|name| {
+            (0..=16)
+                .map(Code::from_i32)
+                .find(|code| format!("{code:?}") == *name)
+                .ok_or_else(|| D::Error::custom(format!("unknown status code {name:?}")))
+        })
+        .collect()
+}
+
+/// A map keyed by `service/method`, split into a service and method pair.
+pub(crate) fn method_map<'de, D, V>(
+    deserializer: D,
+) -> Result<HashMap<(String, String), V>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    HashMap::<String, V>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, value)| match key.rsplit_once('/') {
+            Some((service, method)) => Ok(((service.to_owned(), method.to_owned()), value)),
+            None => Err(D::Error::custom(format!(
+                "{key:?} is not of the form service/method"
+            ))),
+        })
+        .collect()
+}
+
+/// A map keyed by `service/method` whose values are durations in seconds,
+/// nested by service then method.
+pub(crate) fn method_durations<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, HashMap<String, Duration>>, D::Error> {
+    let mut durations = HashMap::<String, HashMap<_, _>>::new();
+    for ((service, method), seconds) in method_map::<D, f64>(deserializer)? {
+        let duration = Duration::try_from_secs_f64(seconds).map_err(D::Error::custom)?;
+        durations
+            .entry(service)
+            .or_default()
+            .insert(method, duration);
+    }
+    Ok(durations)
+}
+
+/// Value of header labels for requests without a listed value.
+pub(crate) fn other() -> String {
+    "other".to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::value::Error;
+    use serde::de::IntoDeserializer;
+
+    use super::*;
+
+    #[test]
+    fn deserializes_values() {
+        let seconds = IntoDeserializer::<Error>::into_deserializer(0.25);
+        assert_eq!(duration(seconds).unwrap(), Duration::from_millis(250));
+
+        let names = vec!["Cancelled".to_owned(), "DeadlineExceeded".to_owned()];
+        assert_eq!(
+            codes(IntoDeserializer::<Error>::into_deserializer(names)).unwrap(),
+            [Code::Cancelled, Code::DeadlineExceeded]
+        );
+        let names = vec!["Slow".to_owned()];
+        assert!(codes(IntoDeserializer::<Error>::into_deserializer(names)).is_err());
+
+        let methods = HashMap::from([("helloworld.Greeter/SayHello".to_owned(), 0.5)]);
+        let methods = method_durations(IntoDeserializer::<Error>::into_deserializer(methods));
+        assert_eq!(
+            methods.unwrap()["helloworld.Greeter"]["SayHello"],
+            Duration::from_millis(500)
+        );
+        let methods = HashMap::from([("helloworld.Greeter/SayHello".to_owned(), -1.0)]);
+        assert!(method_durations(IntoDeserializer::<Error>::into_deserializer(methods)).is_err());
+    }
+}
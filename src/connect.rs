@@ -0,0 +1,89 @@
+use tonic::Code;
+
+use crate::protocol::code_from_http_status;
+
+/// Largest error body buffered while looking for the Connect error code.
+const MAX_ERROR_BODY: usize = 4096;
+
+/// Collects a Connect unary error response to find its error code.
+///
+/// Connect reports failures with a non-200 HTTP status and a JSON body like
+/// `{"code": "not_found", "message": "..."}`. The HTTP status alone is
+/// ambiguous, so it is only used when the body has no recognizable code.
+pub(crate) struct ErrorScanner {
+    fallback: Code,
+    body: Vec<u8>,
+}
+
+impl ErrorScanner {
+    pub(crate) fn new(http_status: u16) -> Self {
+        Self {
+            fallback: code_from_http_status(http_status),
+            body: Vec::new(),
+        }
+    }
+
+    pub(crate) fn feed(&mut self, chunk: &[u8]) {
+        let n = (MAX_ERROR_BODY - self.body.len()).min(chunk.len());
+        self.body.extend_from_slice(&chunk[..n]);
+    }
+
+    pub(crate) fn finish(&self) -> Code {
+        std::str::from_utf8(&self.body)
+            .ok()
+            .and_then(find_code)
+            .and_then(code_from_name)
+            .unwrap_or(self.fallback)
+    }
+}
+
+/// Extract the string value of the top-level `"code"` field without pulling
+/// in a full JSON parser.
+fn find_code(json: &str) -> Option<&str> {
+    let rest = &json[json.find("\"code\"")? + "\"code\"".len()..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let rest = rest.strip_prefix('"')?;
+    Some(&rest[..rest.find('"')?])
+}
+
+fn code_from_name(name: &str) -> Option<Code> {
+    Some(match name {
+        "canceled" => Code::Cancelled,
+        "unknown" => Code::Unknown,
+        "invalid_argument" => Code::InvalidArgument,
+        "deadline_exceeded" => Code::DeadlineExceeded,
+        "not_found" => Code::NotFound,
+        "already_exists" => Code::AlreadyExists,
+        "permission_denied" => Code::PermissionDenied,
+        "resource_exhausted" => Code::ResourceExhausted,
+        "failed_precondition" => Code::FailedPrecondition,
+        "aborted" => Code::Aborted,
+        "out_of_range" => Code::OutOfRange,
+        "unimplemented" => Code::Unimplemented,
+        "internal" => Code::Internal,
+        "unavailable" => Code::Unavailable,
+        "data_loss" => Code::DataLoss,
+        "unauthenticated" => Code::Unauthenticated,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_from_body() {
+        let mut scanner = ErrorScanner::new(404);
+        scanner.feed(br#"{"code": "not"#);
+        scanner.feed(br#"_found", "message": "no such thing"}"#);
+        assert_eq!(scanner.finish(), Code::NotFound);
+    }
+
+    #[test]
+    fn code_from_status() {
+        let mut scanner = ErrorScanner::new(503);
+        scanner.feed(b"<html>bad gateway</html>");
+        assert_eq!(scanner.finish(), Code::Unavailable);
+    }
+}
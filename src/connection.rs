@@ -0,0 +1,459 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+
+use futures_core::Stream;
+use once_cell::sync::Lazy;
+use pin_project::{pin_project, pinned_drop};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tonic::codegen::http::Extensions;
+#[cfg(feature = "tls")]
+use tonic::transport::server::TlsConnectInfo;
+#[cfg(unix)]
+use tonic::transport::server::UdsConnectInfo;
+use tonic::transport::server::{Connected, TcpConnectInfo};
+
+use crate::metrics::{
+    CONNECTIONS_HISTOGRAM, CONNECTIONS_MAX_STREAMS, CONNECTIONS_OPEN, CONNECTIONS_OPENED,
+    CONNECTIONS_REQUESTS,
+};
+
+/// Open connections by address, so requests can find the connection they
+/// arrived on through their `TcpConnectInfo` extension, see [`tcp_info`].
+static STREAMS: Lazy<Mutex<HashMap<ConnectionKey, Arc<Streams>>>> = Lazy::new(Default::default);
+
+type ConnectionKey = (Option<SocketAddr>, Option<SocketAddr>);
+
+fn connection_key(info: &TcpConnectInfo) -> Option<ConnectionKey> {
+    info.remote_addr()
+        .map(|remote| (info.local_addr(), Some(remote)))
+}
+
+/// The info of the TCP connection a request arrived on, also under TLS.
+pub(crate) fn tcp_info(extensions: &Extensions) -> Option<&TcpConnectInfo> {
+    let info = extensions.get::<TcpConnectInfo>();
+    #[cfg(feature = "tls")]
+    let info = info.or_else(|| {
+        extensions
+            .get::<TlsConnectInfo<TcpConnectInfo>>()
+            .map(TlsConnectInfo::get_ref)
+    });
+    info
+}
+
+/// The info of the Unix socket connection a request arrived on, also under
+/// TLS.
+#[cfg(unix)]
+pub(crate) fn uds_info(extensions: &Extensions) -> Option<&UdsConnectInfo> {
+    let info = extensions.get::<UdsConnectInfo>();
+    #[cfg(feature = "tls")]
+    let info = info.or_else(|| {
+        extensions
+            .get::<TlsConnectInfo<UdsConnectInfo>>()
+            .map(TlsConnectInfo::get_ref)
+    });
+    info
+}
+
+/// Concurrent streams on a single connection.
+#[derive(Default)]
+struct Streams {
+    active: AtomicUsize,
+    max: AtomicUsize,
+    /// Requests served on the connection so far.
+    total: AtomicU64,
+}
+
+/// Counts a request as an active stream on its connection until dropped.
+pub(crate) struct StreamGuard(Arc<Streams>);
+
+impl StreamGuard {
+    /// Start tracking the request with these extensions, if it arrived on a
+    /// TCP connection accepted through [`MetricsIncoming`].
+    pub(crate) fn track(extensions: &Extensions) -> Option<Self> {
+        let key = connection_key(tcp_info(extensions)?)?;
+        let streams = STREAMS.lock().unwrap().get(&key)?.clone();
+
+        let active = streams.active.fetch_add(1, Ordering::Relaxed) + 1;
+        streams.max.fetch_max(active, Ordering::Relaxed);
+        streams.total.fetch_add(1, Ordering::Relaxed);
+
+        Some(Self(streams))
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Wrapper for instrumenting the incoming connection stream of a tonic server
+/// with connection metrics.
+///
+/// ```no_run
+/// # async fn run(router: tonic::transport::server::Router) {
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:9090").await.unwrap();
+/// let incoming = tonic::transport::server::TcpIncoming::from_listener(listener, true, None).unwrap();
+///
+/// router
+///     .serve_with_incoming(tonic_prometheus_layer::MetricsIncoming::new(incoming))
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+#[pin_project]
+pub struct MetricsIncoming<S> {
+    #[pin]
+    inner: S,
+}
+
+impl<S> MetricsIncoming<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S, IO, E> Stream for MetricsIncoming<S>
+where
+    S: Stream<Item = Result<IO, E>>,
+    IO: Connected,
+{
+    type Item = Result<MetricsConnection<IO>, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let conn = ready!(self.project().inner.poll_next(cx));
+
+        Poll::Ready(conn.map(|conn| conn.map(MetricsConnection::new)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// A connection accepted through [`MetricsIncoming`], recorded as closed when
+/// it is dropped.
+///
+/// For TCP connections, with or without TLS, the highest number of concurrent requests seen on the
+/// connection and the number of requests served on it are recorded too.
+#[pin_project(PinnedDrop)]
+pub struct MetricsConnection<IO> {
+    #[pin]
+    inner: IO,
+    opened_at: Instant,
+    streams: Option<(ConnectionKey, Arc<Streams>)>,
+}
+
+impl<IO: Connected> MetricsConnection<IO> {
+    pub fn new(inner: IO) -> Self {
+        CONNECTIONS_OPENED.inc();
+        CONNECTIONS_OPEN.inc();
+
+        let info = inner.connect_info();
+        let info = &info as &dyn Any;
+        let tcp = info.downcast_ref::<TcpConnectInfo>();
+        #[cfg(feature = "tls")]
+        let tcp = tcp.or_else(|| {
+            info.downcast_ref::<TlsConnectInfo<TcpConnectInfo>>()
+                .map(TlsConnectInfo::get_ref)
+        });
+        let streams = tcp.and_then(connection_key).map(|key| {
+            let streams = Arc::<Streams>::default();
+            STREAMS.lock().unwrap().insert(key, streams.clone());
+            (key, streams)
+        });
+
+        Self {
+            inner,
+            opened_at: Instant::now(),
+            streams,
+        }
+    }
+}
+
+#[pinned_drop]
+impl<IO> PinnedDrop for MetricsConnection<IO> {
+    fn drop(self: Pin<&mut Self>) {
+        CONNECTIONS_OPEN.dec();
+        CONNECTIONS_HISTOGRAM.observe(self.opened_at.elapsed().as_secs_f64());
+
+        if let Some((key, streams)) = self.streams.as_ref() {
+            let mut open = STREAMS.lock().unwrap();
+            // The address may already have been reused by a newer connection.
+            if open.get(key).is_some_and(|s| Arc::ptr_eq(s, streams)) {
+                open.remove(key);
+            }
+            CONNECTIONS_MAX_STREAMS.observe(streams.max.load(Ordering::Relaxed) as f64);
+            CONNECTIONS_REQUESTS.observe(streams.total.load(Ordering::Relaxed) as f64);
+        }
+    }
+}
+
+impl<IO: Connected> Connected for MetricsConnection<IO> {
+    // Keep the inner connection info so `Request::remote_addr` keeps working.
+    type ConnectInfo = IO::ConnectInfo;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.inner.connect_info()
+    }
+}
+
+impl<IO: AsyncRead> AsyncRead for MetricsConnection<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite> AsyncWrite for MetricsConnection<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::transport;
+
+    /// Both ends of a loopback TCP connection, the accepted one first.
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    }
+
+    /// The extensions tonic adds to the requests arriving on `conn`.
+    fn extensions<IO: Connected>(conn: &IO) -> Extensions {
+        let mut extensions = Extensions::new();
+        extensions.insert(conn.connect_info());
+        extensions
+    }
+
+    /// A listener that accepts a single connection.
+    struct Accept(Option<TcpStream>);
+
+    impl Stream for Accept {
+        type Item = io::Result<TcpStream>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.0.take().map(Ok))
+        }
+    }
+
+    #[tokio::test]
+    async fn records_connections() {
+        let opened = CONNECTIONS_OPENED.get();
+        let closed = CONNECTIONS_HISTOGRAM.get_sample_count();
+        let (server, _client) = tcp_pair().await;
+        let mut incoming = std::pin::pin!(MetricsIncoming::new(Accept(Some(server))));
+
+        let next = std::future::poll_fn(|cx| incoming.as_mut().poll_next(cx));
+        let conn = next.await.unwrap().unwrap();
+        // Other tests accept connections concurrently.
+        assert!(CONNECTIONS_OPENED.get() > opened);
+        assert!(CONNECTIONS_OPEN.get() >= 1);
+
+        drop(conn);
+        assert!(CONNECTIONS_HISTOGRAM.get_sample_count() > closed);
+        let next = std::future::poll_fn(|cx| incoming.as_mut().poll_next(cx));
+        assert!(next.await.is_none());
+    }
+
+    #[tokio::test]
+    async fn tcp_connect_info() {
+        let (server, _client) = tcp_pair().await;
+        let conn = MetricsConnection::new(server);
+        let extensions = extensions(&conn);
+
+        assert_eq!(transport(&extensions), "tcp");
+        assert!(StreamGuard::track(&extensions).is_some());
+        assert_eq!(
+            conn.streams
+                .as_ref()
+                .unwrap()
+                .1
+                .total
+                .load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn records_max_concurrent_streams() {
+        let observed = CONNECTIONS_MAX_STREAMS.get_sample_sum();
+        let (server, _client) = tcp_pair().await;
+        let conn = MetricsConnection::new(server);
+        let extensions = extensions(&conn);
+
+        let guards: Vec<_> = (0..3)
+            .map(|_| StreamGuard::track(&extensions).unwrap())
+            .collect();
+        drop(guards);
+        let _guard = StreamGuard::track(&extensions).unwrap();
+        let streams = &conn.streams.as_ref().unwrap().1;
+        assert_eq!(streams.active.load(Ordering::Relaxed), 1);
+        assert_eq!(streams.max.load(Ordering::Relaxed), 3);
+
+        drop(conn);
+        // Other tests close connections concurrently.
+        assert!(CONNECTIONS_MAX_STREAMS.get_sample_sum() >= observed + 3.0);
+    }
+
+    #[tokio::test]
+    async fn records_requests_per_connection() {
+        let observed = CONNECTIONS_REQUESTS.get_sample_sum();
+        let (server, _client) = tcp_pair().await;
+        let conn = MetricsConnection::new(server);
+        let extensions = extensions(&conn);
+
+        for _ in 0..5 {
+            StreamGuard::track(&extensions).unwrap();
+        }
+        drop(conn);
+        assert!(StreamGuard::track(&extensions).is_none());
+        // Other tests close connections concurrently.
+        assert!(CONNECTIONS_REQUESTS.get_sample_sum() >= observed + 5.0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn uds_connect_info() {
+        let (server, _client) = tokio::net::UnixStream::pair().unwrap();
+        let conn = MetricsConnection::new(server);
+        let extensions = extensions(&conn);
+
+        assert_eq!(transport(&extensions), "uds");
+        assert!(uds_info(&extensions).is_some());
+        assert!(conn.streams.is_none());
+    }
+
+    #[cfg(feature = "tls")]
+    mod tls {
+        use std::sync::Arc;
+
+        use tokio::io::{AsyncRead, AsyncWrite};
+        use tokio_rustls::client::TlsStream as ClientTlsStream;
+        use tokio_rustls::rustls::crypto::ring;
+        use tokio_rustls::rustls::pki_types::ServerName;
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+        use tokio_rustls::server::TlsStream;
+        use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+        use super::*;
+
+        const CA: &[u8] = include_bytes!("../testdata/ca.pem");
+        const CERT: &[u8] = include_bytes!("../testdata/localhost.pem");
+        const KEY: &[u8] = include_bytes!("../testdata/localhost.key");
+
+        /// Both ends of a TLS connection over `server` and `client`, the server
+        /// one first. The client end must be kept open while the server end is
+        /// used, or the peer address is gone.
+        async fn handshake<IO>(server: IO, client: IO) -> (TlsStream<IO>, ClientTlsStream<IO>)
+        where
+            IO: AsyncRead + AsyncWrite + Unpin,
+        {
+            let provider = Arc::new(ring::default_provider());
+            let certs = rustls_pemfile::certs(&mut &CERT[..])
+                .collect::<Result<_, _>>()
+                .unwrap();
+            let key = rustls_pemfile::private_key(&mut &KEY[..]).unwrap().unwrap();
+            let server_config = ServerConfig::builder_with_provider(provider.clone())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(certs, key)
+                .unwrap();
+
+            let mut roots = RootCertStore::empty();
+            for ca in rustls_pemfile::certs(&mut &CA[..]) {
+                roots.add(ca.unwrap()).unwrap();
+            }
+            let client_config = ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+
+            let name = ServerName::try_from("localhost").unwrap();
+            let (server, client) = tokio::join!(
+                TlsAcceptor::from(Arc::new(server_config)).accept(server),
+                TlsConnector::from(Arc::new(client_config)).connect(name, client),
+            );
+            (server.unwrap(), client.unwrap())
+        }
+
+        #[tokio::test]
+        async fn tls_tcp_connect_info() {
+            let (server, client) = tcp_pair().await;
+            let (server, _client) = handshake(server, client).await;
+            let conn = MetricsConnection::new(server);
+            let extensions = extensions(&conn);
+
+            assert!(extensions.get::<TlsConnectInfo<TcpConnectInfo>>().is_some());
+            assert_eq!(transport(&extensions), "tcp");
+            assert!(StreamGuard::track(&extensions).is_some());
+            assert_eq!(
+                conn.streams
+                    .as_ref()
+                    .unwrap()
+                    .1
+                    .total
+                    .load(Ordering::Relaxed),
+                1
+            );
+        }
+
+        #[cfg(unix)]
+        #[tokio::test]
+        async fn tls_uds_connect_info() {
+            let (server, client) = tokio::net::UnixStream::pair().unwrap();
+            let (server, _client) = handshake(server, client).await;
+            let conn = MetricsConnection::new(server);
+            let extensions = extensions(&conn);
+
+            assert!(extensions.get::<TlsConnectInfo<UdsConnectInfo>>().is_some());
+            assert_eq!(transport(&extensions), "uds");
+            assert!(conn.streams.is_none());
+        }
+    }
+}
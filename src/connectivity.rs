@@ -0,0 +1,134 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::task::Poll;
+
+use crate::metrics::{CLIENT_CHANNEL_STATE, CLIENT_RECONNECTS};
+
+/// Connectivity states of a channel, with the values of the gRPC
+/// `ConnectivityState` enum they are exported as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum State {
+    Idle = 0,
+    Connecting = 1,
+    Ready = 2,
+    TransientFailure = 3,
+    Shutdown = 4,
+}
+
+impl State {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => State::Idle,
+            1 => State::Connecting,
+            2 => State::Ready,
+            3 => State::TransientFailure,
+            _ => State::Shutdown,
+        }
+    }
+}
+
+/// Connectivity state of a [`MetricsChannel`](crate::MetricsChannel) and its
+/// clones, as observed from the readiness of the inner channel.
+#[derive(Debug)]
+pub(crate) struct Connectivity {
+    target: String,
+    state: AtomicU8,
+}
+
+impl Connectivity {
+    pub(crate) fn new(target: String) -> Self {
+        CLIENT_CHANNEL_STATE
+            .with_label_values(&[&target])
+            .set(State::Idle as i64);
+        Self {
+            target,
+            state: AtomicU8::new(State::Idle as u8),
+        }
+    }
+
+    /// Record the state implied by a `poll_ready` result of the inner channel:
+    /// pending while connecting, ready, or failed.
+    ///
+    /// A ready channel is also pending while its buffer is full, so pending
+    /// only means connecting before the first ready or after a failure.
+    pub(crate) fn observe<E>(&self, ready: &Poll<Result<(), E>>) {
+        let state = match ready {
+            Poll::Pending => match State::from_u8(self.state.load(Ordering::Relaxed)) {
+                State::Idle | State::TransientFailure => State::Connecting,
+                _ => return,
+            },
+            Poll::Ready(Ok(())) => State::Ready,
+            Poll::Ready(Err(_)) => State::TransientFailure,
+        };
+        self.transition(state);
+    }
+
+    fn transition(&self, state: State) {
+        let previous = State::from_u8(self.state.swap(state as u8, Ordering::Relaxed));
+        if previous == state {
+            return;
+        }
+
+        CLIENT_CHANNEL_STATE
+            .with_label_values(&[&self.target])
+            .set(state as i64);
+        // A new connection attempt after a failure.
+        let reconnect = matches!(
+            (previous, state),
+            (State::TransientFailure, State::Connecting | State::Ready)
+        );
+        if reconnect {
+            CLIENT_RECONNECTS.with_label_values(&[&self.target]).inc();
+        }
+    }
+}
+
+impl Drop for Connectivity {
+    fn drop(&mut self) {
+        CLIENT_CHANNEL_STATE
+            .with_label_values(&[&self.target])
+            .set(State::Shutdown as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_reconnects() {
+        let target = "connectivity-test";
+        let connectivity = Connectivity::new(target.to_owned());
+        for ready in [
+            Poll::Pending,
+            Poll::Ready(Ok(())),
+            Poll::Ready(Ok(())),
+            Poll::Pending,
+            Poll::Ready(Err(())),
+            Poll::Pending,
+            Poll::Ready(Ok(())),
+        ] {
+            connectivity.observe(&ready);
+        }
+
+        let state = CLIENT_CHANNEL_STATE.with_label_values(&[target]);
+        assert_eq!(state.get(), State::Ready as i64);
+        assert_eq!(CLIENT_RECONNECTS.with_label_values(&[target]).get(), 1);
+        drop(connectivity);
+        assert_eq!(state.get(), State::Shutdown as i64);
+    }
+
+    #[test]
+    fn ignores_backpressure() {
+        let target = "connectivity-backpressure-test";
+        let connectivity = Connectivity::new(target.to_owned());
+        for ready in [Poll::Ready(Ok::<_, ()>(())), Poll::Pending] {
+            connectivity.observe(&ready);
+        }
+
+        let state = CLIENT_CHANNEL_STATE.with_label_values(&[target]);
+        assert_eq!(state.get(), State::Ready as i64);
+        connectivity.observe(&Poll::Ready(Ok::<_, ()>(())));
+        assert_eq!(CLIENT_RECONNECTS.with_label_values(&[target]).get(), 0);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use prometheus::TextEncoder;
+
+use crate::merge;
+use crate::metrics::Error;
+
+/// Counter and histogram values at the previous delta collection, by family
+/// name and label pairs.
+static PREVIOUS: Lazy<Mutex<HashMap<SeriesKey, Previous>>> = Lazy::new(Default::default);
+
+type SeriesKey = (String, Vec<(String, String)>);
+
+enum Previous {
+    Counter(f64),
+    Histogram {
+        count: u64,
+        sum: f64,
+        buckets: Vec<u64>,
+    },
+}
+
+/// Gather the collected metrics with counters and histograms holding the
+/// change since the previous call instead of their running totals, for
+/// push-based pipelines that expect delta temporality. Gauges are unchanged.
+///
+/// The first call returns the totals. A series that was reset since the
+/// previous call reports its new total.
+pub fn gather_deltas() -> Vec<MetricFamily> {
+    let mut families = merge::gather();
+    let mut previous = PREVIOUS.lock().unwrap();
+
+    for family in &mut families {
+        let name = family.get_name().to_owned();
+        let kind = family.get_field_type();
+        for metric in family.mut_metric().iter_mut() {
+            let key = (name.clone(), label_pairs(metric));
+            match kind {
+                MetricType::COUNTER => {
+                    let total = metric.get_counter().get_value();
+                    let delta = match previous.insert(key, Previous::Counter(total)) {
+                        Some(Previous::Counter(before)) if before <= total => total - before,
+                        _ => total,
+                    };
+                    let mut counter = metric.get_counter().clone();
+                    counter.set_value(delta);
+                    metric.set_counter(counter);
+                }
+                MetricType::HISTOGRAM => {
+                    let mut histogram = metric.get_histogram().clone();
+                    let (count, sum) = (histogram.get_sample_count(), histogram.get_sample_sum());
+                    let mut buckets = histogram.get_bucket().to_vec();
+                    let totals = buckets.iter().map(|b| b.get_cumulative_count()).collect();
+
+                    let current = Previous::Histogram {
+                        count,
+                        sum,
+                        buckets: totals,
+                    };
+                    match previous.insert(key, current) {
+                        Some(Previous::Histogram {
+                            count: count_before,
+                            sum: sum_before,
+                            buckets: buckets_before,
+                        }) if count_before <= count && buckets_before.len() == buckets.len() => {
+                            histogram.set_sample_count(count - count_before);
+                            histogram.set_sample_sum(sum - sum_before);
+                            for (bucket, before) in buckets.iter_mut().zip(buckets_before) {
+                                bucket.set_cumulative_count(
+                                    bucket.get_cumulative_count().saturating_sub(before),
+                                );
+                            }
+                            histogram.set_bucket(buckets.into());
+                            metric.set_histogram(histogram);
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    families
+}
+
+/// Export [`gather_deltas`] to the Prometheus format.
+pub fn encode_deltas_to_string() -> Result<String, Error> {
+    let mut output = String::new();
+
+    TextEncoder::new()
+        .encode_utf8(&gather_deltas(), &mut output)
+        .map_err(Error::PrometheusEncoding)?;
+
+    Ok(output)
+}
+
+fn label_pairs(metric: &Metric) -> Vec<(String, String)> {
+    metric
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name().to_owned(), l.get_value().to_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::get_settings;
+
+    fn counter_value(families: &[MetricFamily], name: &str) -> f64 {
+        families
+            .iter()
+            .find(|f| f.get_name() == name)
+            .map(|f| f.get_metric()[0].get_counter().get_value())
+            .unwrap()
+    }
+
+    #[test]
+    fn counters_report_changes() {
+        let counter = prometheus::Counter::new("delta_test_total", "test").unwrap();
+        get_settings()
+            .registry
+            .register(Box::new(counter.clone()))
+            .unwrap();
+
+        counter.inc_by(2.0);
+        assert_eq!(counter_value(&gather_deltas(), "delta_test_total"), 2.0);
+        counter.inc_by(3.0);
+        assert_eq!(counter_value(&gather_deltas(), "delta_test_total"), 3.0);
+        assert_eq!(counter_value(&gather_deltas(), "delta_test_total"), 0.0);
+    }
+}
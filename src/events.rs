@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+use tonic::Code;
+
+use crate::metrics::get_settings;
+
+static EVENTS: Lazy<broadcast::Sender<CallEvent>> = Lazy::new(|| {
+    let capacity = get_settings()
+        .call_events
+        .as_ref()
+        .map_or(1, |settings| settings.capacity.max(1));
+    broadcast::channel(capacity).0
+});
+
+/// A server call starting or finishing, as sent to [`subscribe`]rs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CallEvent {
+    Started {
+        service: String,
+        method: String,
+    },
+    Finished {
+        service: String,
+        method: String,
+        code: Code,
+        duration: Duration,
+    },
+}
+
+/// Receive an event for every server call starting and finishing from now on.
+///
+/// Events are only sent when `GlobalSettings::call_events` is set. A receiver
+/// that falls more than `capacity` events behind skips the oldest ones, see
+/// [`broadcast::error::RecvError::Lagged`].
+///
+/// Subscribe after [`try_init_settings`](crate::metrics::try_init_settings):
+/// the channel is created with the capacity of the settings in place at the
+/// first subscription, and subscribing before them installs the defaults, so
+/// that `try_init_settings` then fails with `Error::AlreadyInitialized`.
+pub fn subscribe() -> broadcast::Receiver<CallEvent> {
+    EVENTS.subscribe()
+}
+
+/// Send the event built by `event`, if anyone is listening.
+pub(crate) fn send(event: impl FnOnce() -> CallEvent) {
+    if get_settings().call_events.is_none() || EVENTS.receiver_count() == 0 {
+        return;
+    }
+    // Receivers may all have been dropped since the check above.
+    let _ = EVENTS.send(event());
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+    use tower::{Layer, ServiceExt};
+
+    use super::*;
+    use crate::metrics::{test_settings, CallEventsSettings};
+    use crate::tests::{grpc_ok_service, grpc_request};
+    use crate::MetricsLayer;
+
+    #[tokio::test]
+    async fn sends_started_and_finished() {
+        let _settings = test_settings(|settings| {
+            settings.call_events = Some(CallEventsSettings::default());
+        });
+        let mut events = subscribe();
+
+        let resp = MetricsLayer::new()
+            .layer(grpc_ok_service())
+            .oneshot(grpc_request("/test.Events/Get"))
+            .await
+            .unwrap();
+        resp.into_body().collect().await.unwrap();
+
+        // Calls of concurrent tests are sent to the same channel.
+        let mut got = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            match &event {
+                CallEvent::Started { service, .. } | CallEvent::Finished { service, .. }
+                    if service == "test.Events" =>
+                {
+                    got.push(event)
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(got.len(), 2);
+        assert_eq!(
+            got[0],
+            CallEvent::Started {
+                service: "test.Events".to_owned(),
+                method: "Get".to_owned(),
+            }
+        );
+        assert!(matches!(
+            &got[1],
+            CallEvent::Finished { method, code: Code::Ok, .. } if method == "Get"
+        ));
+    }
+}
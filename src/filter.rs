@@ -0,0 +1,235 @@
+use prometheus::proto::{Metric, MetricFamily};
+use prometheus::TextEncoder;
+
+use crate::merge;
+use crate::metrics::Error;
+use crate::relabel::add_up;
+
+/// Selects the series exported by [`encode_filtered`].
+///
+/// A series is selected if its family name starts with one of the prefixes,
+/// or there are none, and it has all the label values.
+///
+/// Labels can also be removed from the selected series, e.g. to slim the
+/// export for a secondary scraper. Series that only differed in the removed
+/// labels are added up into one; summaries lose their quantiles then, as those
+/// cannot be added up.
+///
+/// ```
+/// use tonic_prometheus_layer::metrics::MetricFilter;
+///
+/// let filter = MetricFilter::new()
+///     .prefix("grpc_server_handled")
+///     .label("grpc_service", "grpc.health.v1.Health");
+///
+/// let slim = MetricFilter::new()
+///     .drop_label("grpc_server_handling_seconds", "grpc_code")
+///     .keep_labels("grpc_server_started", ["grpc_service"]);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MetricFilter {
+    prefixes: Vec<String>,
+    labels: Vec<(String, String)>,
+    label_rules: Vec<LabelRule>,
+}
+
+/// Labels removed from the families whose name starts with `prefix`.
+#[derive(Clone, Debug)]
+enum LabelRule {
+    Drop { prefix: String, name: String },
+    Keep { prefix: String, names: Vec<String> },
+}
+
+impl LabelRule {
+    /// Whether the label `name` of the family `family` is exported.
+    fn keeps(&self, family: &str, name: &str) -> bool {
+        match self {
+            LabelRule::Drop {
+                prefix,
+                name: dropped,
+            } => !family.starts_with(prefix.as_str()) || name != dropped,
+            LabelRule::Keep { prefix, names } => {
+                !family.starts_with(prefix.as_str()) || names.iter().any(|n| n == name)
+            }
+        }
+    }
+}
+
+impl MetricFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Select families whose name starts with `prefix`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefixes.push(prefix.into());
+        self
+    }
+
+    /// Select series with the label `name` set to `value`.
+    pub fn label(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push((name.into(), value.into()));
+        self
+    }
+
+    /// Remove the label `name` from families whose name starts with `prefix`.
+    pub fn drop_label(mut self, prefix: impl Into<String>, name: impl Into<String>) -> Self {
+        self.label_rules.push(LabelRule::Drop {
+            prefix: prefix.into(),
+            name: name.into(),
+        });
+        self
+    }
+
+    /// Remove all labels but `names` from families whose name starts with
+    /// `prefix`.
+    pub fn keep_labels(
+        mut self,
+        prefix: impl Into<String>,
+        names: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.label_rules.push(LabelRule::Keep {
+            prefix: prefix.into(),
+            names: names.into_iter().map(Into::into).collect(),
+        });
+        self
+    }
+
+    fn selects_family(&self, family: &MetricFamily) -> bool {
+        self.prefixes.is_empty()
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| family.get_name().starts_with(prefix.as_str()))
+    }
+
+    fn selects_metric(&self, metric: &Metric) -> bool {
+        self.labels.iter().all(|(name, value)| {
+            metric
+                .get_label()
+                .iter()
+                .any(|l| l.get_name() == name && l.get_value() == value)
+        })
+    }
+
+    pub(crate) fn apply(&self, families: Vec<MetricFamily>) -> Vec<MetricFamily> {
+        families
+            .into_iter()
+            .filter(|family| self.selects_family(family))
+            .filter_map(|mut family| {
+                let metrics: Vec<Metric> = family
+                    .take_metric()
+                    .into_iter()
+                    .filter(|metric| self.selects_metric(metric))
+                    .collect();
+                if metrics.is_empty() {
+                    return None;
+                }
+                let metrics = self.relabel(&family, metrics);
+                family.set_metric(metrics.into());
+                Some(family)
+            })
+            .collect()
+    }
+
+    /// Remove the labels dropped by the rules from `metrics`, adding up the
+    /// series that end up with the same labels.
+    fn relabel(&self, family: &MetricFamily, metrics: Vec<Metric>) -> Vec<Metric> {
+        if self.label_rules.is_empty() {
+            return metrics;
+        }
+        let name = family.get_name();
+
+        let metrics = metrics
+            .into_iter()
+            .map(|mut metric| {
+                let labels = metric
+                    .take_label()
+                    .into_iter()
+                    .filter(|l| self.label_rules.iter().all(|r| r.keeps(name, l.get_name())))
+                    .map(|mut l| (l.take_name(), l.take_value()))
+                    .collect();
+                (labels, metric)
+            })
+            .collect();
+        add_up(metrics, family.get_field_type())
+    }
+}
+
+/// Export the series selected by `filter` to the Prometheus format, e.g. for a
+/// health endpoint that only needs a few of them.
+pub fn encode_filtered(filter: &MetricFilter) -> Result<String, Error> {
+    let mut output = String::new();
+
+    TextEncoder::new()
+        .encode_utf8(&filter.apply(merge::gather()), &mut output)
+        .map_err(Error::PrometheusEncoding)?;
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_series() {
+        let registry = prometheus::Registry::new();
+        let handled = prometheus::IntCounterVec::new(
+            prometheus::opts!("filter_handled_total", "test"),
+            &["grpc_service"],
+        )
+        .unwrap();
+        let started = prometheus::IntCounter::new("filter_started_total", "test").unwrap();
+        registry.register(Box::new(handled.clone())).unwrap();
+        registry.register(Box::new(started.clone())).unwrap();
+        handled.with_label_values(&["a"]).inc();
+        handled.with_label_values(&["b"]).inc();
+        started.inc();
+
+        let filter = MetricFilter::new()
+            .prefix("filter_handled")
+            .label("grpc_service", "b");
+        let families = filter.apply(registry.gather());
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].get_metric().len(), 1);
+        assert_eq!(families[0].get_metric()[0].get_label()[0].get_value(), "b");
+
+        let filter = MetricFilter::new().label("grpc_service", "c");
+        assert!(filter.apply(registry.gather()).is_empty());
+    }
+
+    #[test]
+    fn drops_labels() {
+        let registry = prometheus::Registry::new();
+        let handling = prometheus::HistogramVec::new(
+            prometheus::histogram_opts!("filter_handling_seconds", "test", vec![1.0]),
+            &["grpc_method", "grpc_code"],
+        )
+        .unwrap();
+        registry.register(Box::new(handling.clone())).unwrap();
+        handling.with_label_values(&["a", "OK"]).observe(0.5);
+        handling.with_label_values(&["a", "Internal"]).observe(2.0);
+        handling.with_label_values(&["b", "OK"]).observe(0.5);
+
+        let filter = MetricFilter::new().drop_label("filter_handling", "grpc_code");
+        let families = filter.apply(registry.gather());
+        let metrics = families[0].get_metric();
+        assert_eq!(metrics.len(), 2);
+        let a = metrics
+            .iter()
+            .find(|m| m.get_label()[0].get_value() == "a")
+            .unwrap();
+        assert_eq!(a.get_label().len(), 1);
+        assert_eq!(a.get_histogram().get_sample_count(), 2);
+        assert_eq!(a.get_histogram().get_bucket()[0].get_cumulative_count(), 1);
+
+        let filter = MetricFilter::new().keep_labels("filter_handling", Vec::<String>::new());
+        let families = filter.apply(registry.gather());
+        assert_eq!(families[0].get_metric().len(), 1);
+        assert_eq!(
+            families[0].get_metric()[0].get_histogram().get_sample_sum(),
+            3.0
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{request, HeaderMap};
+use tonic::codegen::StdError;
+use tower::{Layer, Service};
+
+use crate::body::MetricsRequestBody;
+use crate::metrics::FRAME_ERRORS;
+use crate::protocol::Protocol;
+
+const FRAME_HEADER_LEN: usize = 5;
+
+/// Incremental check of the length-prefixed message framing of a gRPC body.
+///
+/// Each message is sent as a compressed flag byte, a big-endian four byte
+/// length and the message itself. A flag other than 0 or 1, a compressed
+/// message without a `grpc-encoding`, or a body ending in the middle of a
+/// message counts once in `grpc_server_frame_errors_total`. Checking stops
+/// at the first error, since the message boundaries are lost from there on.
+pub(crate) struct FrameChecker {
+    direction: &'static str,
+    compression: bool,
+    header: [u8; FRAME_HEADER_LEN],
+    header_len: usize,
+    remaining: u64,
+    done: bool,
+}
+
+impl FrameChecker {
+    /// Check a body sent in `direction`, with the headers that came with it.
+    pub(crate) fn new(direction: &'static str, headers: &HeaderMap) -> Self {
+        Self {
+            direction,
+            compression: headers
+                .get("grpc-encoding")
+                .is_some_and(|encoding| encoding != "identity"),
+            header: [0; FRAME_HEADER_LEN],
+            header_len: 0,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// Feed the next chunk of the body.
+    pub(crate) fn feed(&mut self, mut chunk: &[u8]) {
+        while !self.done && !chunk.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(chunk.len() as u64) as usize;
+                self.remaining -= n as u64;
+                chunk = &chunk[n..];
+                continue;
+            }
+
+            let n = (FRAME_HEADER_LEN - self.header_len).min(chunk.len());
+            self.header[self.header_len..self.header_len + n].copy_from_slice(&chunk[..n]);
+            self.header_len += n;
+            chunk = &chunk[n..];
+            if self.header_len < FRAME_HEADER_LEN {
+                return;
+            }
+            self.header_len = 0;
+            match self.header[0] {
+                0 => {}
+                1 if self.compression => {}
+                _ => return self.fail(),
+            }
+            let len = [
+                self.header[1],
+                self.header[2],
+                self.header[3],
+                self.header[4],
+            ];
+            self.remaining = u32::from_be_bytes(len).into();
+        }
+    }
+
+    /// The body ended; a message still in progress was truncated.
+    pub(crate) fn finish(&mut self) {
+        if self.header_len > 0 || self.remaining > 0 {
+            self.fail();
+        }
+        self.done = true;
+    }
+
+    fn fail(&mut self) {
+        if !self.done {
+            self.done = true;
+            FRAME_ERRORS.with_label_values(&[self.direction]).inc();
+        }
+    }
+}
+
+/// Layer checking the message framing of gRPC request bodies, to count
+/// corrupt or misbehaving clients in `grpc_server_frame_errors_total`.
+///
+/// Responses are checked by [`MetricsLayer`](crate::MetricsLayer) itself.
+/// Checking requests requires wrapping their body, which tonic routers only
+/// accept boxed, so this layer passes tonic's [`BoxBody`] to the inner
+/// service. Put it inside of the metrics layer:
+///
+/// ```no_run
+/// # async fn run() {
+/// let (_, health) = tonic_health::server::health_reporter();
+///
+/// tonic::transport::Server::builder()
+///     .layer(tonic_prometheus_layer::MetricsLayer::new())
+///     .layer(tonic_prometheus_layer::FrameCheckLayer)
+///     .add_service(health)
+///     .serve("127.0.0.1:9090".parse().unwrap())
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameCheckLayer;
+
+impl<S> Layer<S> for FrameCheckLayer {
+    type Service = FrameCheckService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        FrameCheckService { service: inner }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FrameCheckService<S> {
+    service: S,
+}
+
+impl<S, B> Service<request::Request<B>> for FrameCheckService<S>
+where
+    S: Service<request::Request<BoxBody>>,
+    B: http_body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<StdError>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: request::Request<B>) -> Self::Future {
+        let frames = (Protocol::detect(req.headers()) == Protocol::Grpc)
+            .then(|| FrameChecker::new("request", req.headers()));
+        self.service
+            .call(req.map(|body| tonic::body::boxed(MetricsRequestBody::new(body, frames))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(flag: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![flag];
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn errors(direction: &'static str, headers: &HeaderMap, body: &[&[u8]]) -> u64 {
+        let mut checker = FrameChecker::new(direction, headers);
+        for chunk in body {
+            checker.feed(chunk);
+        }
+        checker.finish();
+        checker.finish();
+        FRAME_ERRORS.with_label_values(&[direction]).get()
+    }
+
+    #[test]
+    fn counts_frame_errors() {
+        let mut gzip = HeaderMap::new();
+        gzip.insert("grpc-encoding", "gzip".parse().unwrap());
+        let mut body = frame(0, b"first");
+        body.extend(frame(1, b"second"));
+
+        // Chunk boundaries anywhere, including inside the frame headers.
+        let (a, b) = body.split_at(3);
+        let (b, c) = b.split_at(9);
+        assert_eq!(errors("test-valid", &gzip, &[a, b, c]), 0);
+        assert_eq!(errors("test-uncompressed", &HeaderMap::new(), &[&body]), 1);
+        assert_eq!(errors("test-flag", &gzip, &[&frame(2, b"message")]), 1);
+        assert_eq!(
+            errors("test-truncated", &gzip, &[&body[..body.len() - 1]]),
+            1
+        );
+        assert_eq!(errors("test-header", &gzip, &[&body[..2]]), 1);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::clock::Timestamp;
+use crate::in_flight::InFlightGuard;
+use crate::metrics::{COUNTER_MP, GAUGE_MP, HISTOGRAM_MP};
+
+/// Value of the `method` label of functions instrumented with
+/// `#[instrument_grpc]`.
+const METHOD: &str = "fn";
+
+/// A call of a function instrumented with `#[instrument_grpc]`, recorded in
+/// the `function_calls_*` metrics when it is dropped.
+pub struct FunctionCall {
+    path: &'static str,
+    started_at: Timestamp,
+    _in_flight: InFlightGuard,
+}
+
+impl FunctionCall {
+    pub fn start(path: &'static str) -> Self {
+        FunctionCall {
+            path,
+            started_at: Timestamp::now(),
+            _in_flight: GAUGE_MP.start(METHOD, path),
+        }
+    }
+}
+
+impl Drop for FunctionCall {
+    fn drop(&mut self) {
+        let labels = [METHOD, self.path];
+        COUNTER_MP.with_label_values(&labels).inc();
+        HISTOGRAM_MP
+            .with_label_values(&labels)
+            .observe(self.started_at.elapsed().as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::core::Collector;
+
+    use super::*;
+
+    /// Value of `function_calls_concurrent` for `path`.
+    fn concurrent(path: &str) -> f64 {
+        GAUGE_MP.collect()[0]
+            .get_metric()
+            .iter()
+            .find(|m| m.get_label()[1].get_value() == path)
+            .map_or(0.0, |m| m.get_gauge().get_value())
+    }
+
+    #[test]
+    fn records_function_calls() {
+        let path = "test::function::records";
+        let labels = [METHOD, path];
+
+        let call = FunctionCall::start(path);
+        assert_eq!(concurrent(path), 1.0);
+        assert_eq!(COUNTER_MP.with_label_values(&labels).get(), 0);
+
+        drop(call);
+        assert_eq!(concurrent(path), 0.0);
+        assert_eq!(COUNTER_MP.with_label_values(&labels).get(), 1);
+        assert_eq!(
+            HISTOGRAM_MP.with_label_values(&labels).get_sample_count(),
+            1
+        );
+    }
+}
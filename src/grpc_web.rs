@@ -0,0 +1,169 @@
+use base64::Engine;
+use tonic::Code;
+
+const TRAILERS_FLAG: u8 = 0x80;
+const FRAME_HEADER_LEN: usize = 5;
+
+/// Incremental scanner for the trailer frame of a gRPC-Web response body.
+///
+/// gRPC-Web has no HTTP trailers: the final status is sent as a last
+/// length-prefixed frame flagged with `0x80` whose payload is an HTTP/1
+/// style header block. Data frames are skipped without being buffered.
+pub(crate) struct TrailersScanner {
+    text: bool,
+    base64: Vec<u8>,
+    header: [u8; FRAME_HEADER_LEN],
+    header_len: usize,
+    remaining: usize,
+    trailers: Option<Vec<u8>>,
+}
+
+impl TrailersScanner {
+    pub(crate) fn new(text: bool) -> Self {
+        Self {
+            text,
+            base64: Vec::new(),
+            header: [0; FRAME_HEADER_LEN],
+            header_len: 0,
+            remaining: 0,
+            trailers: None,
+        }
+    }
+
+    /// Feed the next chunk of the response body, returning the status once
+    /// the trailer frame is complete.
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> Option<Code> {
+        if !self.text {
+            return self.scan(chunk);
+        }
+
+        // grpc-web-text bodies are base64; decode whole quadruplets only, since a
+        // chunk boundary may fall in the middle of one.
+        self.base64
+            .extend(chunk.iter().filter(|b| !b.is_ascii_whitespace()));
+        let complete = self.base64.len() / 4 * 4;
+        let mut code = None;
+        let mut start = 0;
+        // Padding may occur mid-stream when each frame was encoded on its own.
+        while start < complete {
+            let end = self.base64[start..complete]
+                .chunks(4)
+                .position(|q| q.ends_with(b"="))
+                .map_or(complete, |i| start + (i + 1) * 4);
+            let decoded =
+                match base64::engine::general_purpose::STANDARD.decode(&self.base64[start..end]) {
+                    Ok(decoded) => decoded,
+                    // Not base64; drop it rather than failing on every later chunk.
+                    Err(_) => {
+                        self.base64.clear();
+                        return None;
+                    }
+                };
+            code = code.or(self.scan(&decoded));
+            start = end;
+        }
+        self.base64.drain(..complete);
+
+        code
+    }
+
+    fn scan(&mut self, mut chunk: &[u8]) -> Option<Code> {
+        while !chunk.is_empty() {
+            if self.header_len < FRAME_HEADER_LEN {
+                let n = (FRAME_HEADER_LEN - self.header_len).min(chunk.len());
+                self.header[self.header_len..self.header_len + n].copy_from_slice(&chunk[..n]);
+                self.header_len += n;
+                chunk = &chunk[n..];
+
+                if self.header_len == FRAME_HEADER_LEN {
+                    let len = u32::from_be_bytes(self.header[1..].try_into().unwrap()) as usize;
+                    self.remaining = len;
+                    if self.header[0] & TRAILERS_FLAG != 0 {
+                        self.trailers = Some(Vec::with_capacity(len));
+                    }
+                }
+            }
+
+            let n = self.remaining.min(chunk.len());
+            if let Some(trailers) = &mut self.trailers {
+                trailers.extend_from_slice(&chunk[..n]);
+            }
+            self.remaining -= n;
+            chunk = &chunk[n..];
+
+            if self.header_len == FRAME_HEADER_LEN && self.remaining == 0 {
+                if let Some(trailers) = self.trailers.take() {
+                    return Some(parse_status(&trailers));
+                }
+                self.header_len = 0;
+            }
+        }
+
+        None
+    }
+}
+
+fn parse_status(trailers: &[u8]) -> Code {
+    trailers
+        .split(|b| *b == b'\n')
+        .filter_map(|line| {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let colon = line.iter().position(|b| *b == b':')?;
+            let (name, value) = (&line[..colon], &line[colon + 1..]);
+            name.eq_ignore_ascii_case(b"grpc-status")
+                .then(|| Code::from_bytes(value.trim_ascii()))
+        })
+        .next()
+        .unwrap_or(Code::Unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(flag: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![flag];
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn binary_split_across_chunks() {
+        let mut body = frame(0, b"message");
+        body.extend(frame(
+            TRAILERS_FLAG,
+            b"grpc-status:5\r\ngrpc-message:nope\r\n",
+        ));
+
+        let mut scanner = TrailersScanner::new(false);
+        let (first, second) = body.split_at(9);
+        assert_eq!(scanner.feed(first), None);
+        assert_eq!(scanner.feed(second), Some(Code::NotFound));
+    }
+
+    #[test]
+    fn text_encoded_per_frame() {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let mut body = engine.encode(frame(0, b"m"));
+        body.push_str(&engine.encode(frame(TRAILERS_FLAG, b"grpc-status: 7\r\n")));
+
+        let mut scanner = TrailersScanner::new(true);
+        let (first, second) = body.as_bytes().split_at(3);
+        assert_eq!(scanner.feed(first), None);
+        assert_eq!(scanner.feed(second), Some(Code::PermissionDenied));
+    }
+
+    #[test]
+    fn text_malformed() {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let mut scanner = TrailersScanner::new(true);
+        assert_eq!(scanner.feed(b"!!!!not base64"), None);
+        assert!(scanner.base64.len() < 4);
+
+        let body = engine.encode(frame(TRAILERS_FLAG, b"grpc-status: 3\r\n"));
+        let mut scanner = TrailersScanner::new(true);
+        assert_eq!(scanner.feed(b"!!!!"), None);
+        assert_eq!(scanner.feed(body.as_bytes()), Some(Code::InvalidArgument));
+    }
+}
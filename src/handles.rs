@@ -0,0 +1,124 @@
+//! Handles to the server metric families, for recording calls that do not go
+//! through [`MetricsLayer`](crate::MetricsLayer), e.g. batch jobs or internal
+//! invocations, next to the ones that do.
+
+use std::time::{Duration, SystemTime};
+
+use prometheus::{HistogramVec, IntCounterVec};
+use tonic::Code;
+
+use crate::metrics::{
+    get_settings, LabelValues, COUNTER_SM, COUNTER_SMC, HISTOGRAM_SMC, LAST_HANDLED,
+};
+use crate::rpcz::unix_seconds;
+use crate::{native, self_check, sketch};
+
+/// The `grpc_server_started_total` family, labeled by `grpc_service` and
+/// `grpc_method`, followed by `protocol`, `transport`, `priority`, `authority`,
+/// the baggage labels and `caller` if enabled.
+pub fn server_started() -> &'static IntCounterVec {
+    &COUNTER_SM
+}
+
+/// The `grpc_server_handled_total` family, labeled by `grpc_service`,
+/// `grpc_method` and `grpc_code`, followed by `protocol`, `transport`,
+/// `priority`, `authority`, the baggage labels and `caller` if enabled.
+pub fn server_handled() -> &'static IntCounterVec {
+    &COUNTER_SMC
+}
+
+/// The `grpc_server_handling_seconds` family, labeled like
+/// [`server_handled`] but without `caller`.
+pub fn server_handling() -> &'static HistogramVec {
+    &HISTOGRAM_SMC
+}
+
+/// Server metrics of a single method, filling in the labels the way
+/// [`MetricsLayer`](crate::MetricsLayer) does.
+///
+/// Calls are recorded with `protocol="grpc"`, `transport="other"` and the
+/// default `priority`, `authority`, baggage and `caller` values when those
+/// labels are enabled.
+#[derive(Clone, Debug)]
+pub struct MethodMetrics {
+    service: String,
+    method: String,
+}
+
+impl MethodMetrics {
+    pub fn new(service: impl Into<String>, method: impl Into<String>) -> Self {
+        self_check::init();
+        MethodMetrics {
+            service: get_settings().service_names.apply(service.into()),
+            method: method.into(),
+        }
+    }
+
+    /// Count a started call.
+    pub fn started(&self) {
+        let baggage = default_baggage();
+        let labels = default_labels(&baggage).counter(&self.service, &self.method, None);
+        COUNTER_SM.with_label_values(&labels).inc();
+    }
+
+    /// Count a finished call and observe its duration.
+    pub fn handled(&self, code: Code, duration: Duration) {
+        let code_str = format!("{:?}", code);
+        let baggage = default_baggage();
+        let values = default_labels(&baggage);
+        let labels = values.server(&self.service, &self.method, Some(&code_str));
+        COUNTER_SMC
+            .with_label_values(&values.handled(&self.service, &self.method, code, &code_str))
+            .inc();
+        LAST_HANDLED
+            .with_label_values(&values.server(&self.service, &self.method, None))
+            .set(unix_seconds(SystemTime::now()));
+        if !get_settings().histogram_excluded_codes.contains(&code) {
+            let elapsed = duration.as_secs_f64();
+            HISTOGRAM_SMC.with_label_values(&labels).observe(elapsed);
+            native::observe(&labels, elapsed);
+            sketch::record(&self.service, &self.method, elapsed);
+        }
+    }
+}
+
+/// The defaults of `GlobalSettings::baggage_labels`.
+fn default_baggage() -> Vec<&'static str> {
+    get_settings()
+        .baggage_labels
+        .iter()
+        .map(|baggage| baggage.default.as_str())
+        .collect()
+}
+
+/// The optional label values of calls recorded through [`MethodMetrics`].
+fn default_labels<'b>(baggage: &'b [&'static str]) -> LabelValues<'static, 'b> {
+    let settings = get_settings();
+    LabelValues {
+        protocol: "grpc",
+        transport: "other",
+        priority: settings.priority_label.as_ref().map_or("", |p| &p.default),
+        authority: settings.authority_label.as_ref().map_or("", |a| &a.default),
+        baggage,
+        caller: settings.caller_label.as_ref().map_or("", |c| &c.default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics;
+
+    #[test]
+    fn records_method_metrics() {
+        let method = MethodMetrics::new("test.Handles", "Run");
+        method.started();
+        method.handled(Code::Aborted, Duration::from_millis(20));
+
+        let got = metrics::snapshot();
+        let call = got.server("test.Handles", "Run").unwrap();
+        assert_eq!(call.started, 1);
+        assert_eq!(call.handled(Code::Aborted), 1);
+        assert_eq!(call.duration[&Code::Aborted].count, 1);
+    }
+}
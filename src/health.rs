@@ -0,0 +1,80 @@
+use tonic::server::NamedService;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+use crate::metrics::SERVING_STATUS;
+
+/// Wrapper around a tonic-health [`HealthReporter`] that mirrors every status
+/// it sets into the `grpc_server_serving_status` gauge.
+///
+/// ```ignore
+/// let (reporter, health_service) = tonic_health::server::health_reporter();
+/// let mut reporter = tonic_prometheus_layer::MetricsHealthReporter::new(reporter);
+/// reporter.set_serving::<GreeterServer<MyGreeter>>().await;
+/// ```
+#[derive(Clone, Debug)]
+pub struct MetricsHealthReporter {
+    inner: HealthReporter,
+}
+
+impl MetricsHealthReporter {
+    pub fn new(inner: HealthReporter) -> Self {
+        Self { inner }
+    }
+
+    /// Get a reference to the inner reporter.
+    pub fn get_ref(&self) -> &HealthReporter {
+        &self.inner
+    }
+
+    /// Consume `self`, returning the inner reporter.
+    pub fn into_inner(self) -> HealthReporter {
+        self.inner
+    }
+
+    pub async fn set_serving<S: NamedService>(&mut self) {
+        self.set_service_status(S::NAME, ServingStatus::Serving)
+            .await
+    }
+
+    pub async fn set_not_serving<S: NamedService>(&mut self) {
+        self.set_service_status(S::NAME, ServingStatus::NotServing)
+            .await
+    }
+
+    pub async fn set_service_status(&mut self, service_name: &str, status: ServingStatus) {
+        let value = match status {
+            ServingStatus::Serving => 1,
+            ServingStatus::NotServing | ServingStatus::Unknown => 0,
+        };
+        SERVING_STATUS.with_label_values(&[service_name]).set(value);
+
+        self.inner.set_service_status(service_name, status).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn mirrors_serving_status() {
+        struct Checked;
+        impl NamedService for Checked {
+            const NAME: &'static str = "test.Checked";
+        }
+
+        let (reporter, _service) = tonic_health::server::health_reporter();
+        let mut reporter = MetricsHealthReporter::new(reporter);
+        let status = SERVING_STATUS.with_label_values(&["test.Checked"]);
+
+        reporter.set_serving::<Checked>().await;
+        assert_eq!(status.get(), 1);
+        reporter.set_not_serving::<Checked>().await;
+        assert_eq!(status.get(), 0);
+        reporter
+            .set_service_status("test.Checked", ServingStatus::Serving)
+            .await;
+        assert_eq!(status.get(), 1);
+    }
+}
@@ -0,0 +1,578 @@
+//! Support for stacks still on `http` 0.2 and `http-body` 0.4, such as hyper
+//! 0.14 and tonic before 0.12, e.g. during a migration.
+//!
+//! [`MetricsChannel`](crate::MetricsChannel) accepts `http` 0.2 requests as is. On the server, use
+//! [`Http02MetricsLayer`] in place of [`MetricsLayer`]: it converts requests
+//! and responses to `http` 1 around the metrics layer, so calls are recorded
+//! the same way.
+//!
+//! Request extensions are passed through to the inner service, along with the
+//! [`GrpcCallInfo`], [`CallMetrics`] and [`ProxyContext`] inserted by the
+//! layer, but the layer only sees the extensions of `http` 1, so e.g. the
+//! connect info of an older tonic server is not available to it.
+//! A [`Rejected`] extension of the response is passed to the layer as well.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use pin_project::pin_project;
+use tonic::codegen::http as http1;
+use tower::{Layer, Service};
+
+use crate::metrics::{CallMetrics, ProxyContext};
+use crate::{GrpcCallInfo, MetricsBody, MetricsLayer, MetricsService, Rejected};
+
+#[cfg(feature = "client")]
+mod channel {
+    use std::task::{Context, Poll};
+
+    use tonic::Code;
+    use tower::Service;
+
+    use crate::client::{GrpcResponse, MetricsChannelFuture};
+    use crate::metrics::{get_settings, ProxyContext};
+    use crate::MetricsChannel;
+
+    impl<B> GrpcResponse for http_02::Response<B> {
+        fn grpc_status(&self) -> Option<Code> {
+            self.headers()
+                .get("grpc-status")
+                .map(|s| Code::from_bytes(s.as_bytes()))
+        }
+    }
+
+    impl<I, O, T> Service<http_02::Request<I>> for MetricsChannel<T>
+    where
+        T: Service<http_02::Request<I>, Response = http_02::Response<O>>,
+    {
+        type Response = T::Response;
+        type Error = T::Error;
+        type Future = MetricsChannelFuture<T::Future>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.poll_inner_ready::<http_02::Request<I>>(cx)
+        }
+
+        fn call(&mut self, req: http_02::Request<I>) -> Self::Future {
+            // Older tonic clients have no `GrpcMethod` extension of this version,
+            // so go by the path.
+            let path = req.uri().path();
+            let (service, method) = path
+                .strip_prefix('/')
+                .and_then(|p| p.split_once('/'))
+                .unwrap_or(("", ""));
+            let settings = get_settings();
+            let method = settings.method_label(service, method.to_owned());
+            let service = settings.service_names.apply(service.into());
+            let proxy = req.extensions().get::<ProxyContext>().cloned();
+            MetricsChannelFuture::new(service, method, self.get_mut().call(req)).with_proxy(proxy)
+        }
+    }
+}
+
+/// [`MetricsLayer`] for servers on `http` 0.2.
+#[derive(Clone, Debug, Default)]
+pub struct Http02MetricsLayer {
+    inner: MetricsLayer,
+}
+
+impl Http02MetricsLayer {
+    pub fn new(inner: MetricsLayer) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S> Layer<S> for Http02MetricsLayer {
+    type Service = Http02MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Http02MetricsService {
+            inner: self.inner.layer(Downgrade { inner }),
+        }
+    }
+}
+
+/// [`MetricsService`] for servers on `http` 0.2.
+#[derive(Clone, Debug)]
+pub struct Http02MetricsService<S> {
+    inner: MetricsService<Downgrade<S>>,
+}
+
+impl<S, B, C> Service<http_02::Request<B>> for Http02MetricsService<S>
+where
+    S: Service<http_02::Request<B>, Response = http_02::Response<C>>,
+    C: http_body_04::Body,
+{
+    type Response = http_02::Response<Body1<MetricsBody<Body04<C>>>>;
+    type Error = S::Error;
+    type Future = UpgradeFuture<crate::MetricsFuture<DowngradeFuture<S::Future>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http_02::Request<B>) -> Self::Future {
+        UpgradeFuture {
+            inner: self.inner.call(request_to_1(req)),
+        }
+    }
+}
+
+#[pin_project]
+pub struct UpgradeFuture<F> {
+    #[pin]
+    inner: F,
+}
+
+impl<F, C, E> Future for UpgradeFuture<F>
+where
+    F: Future<Output = Result<http1::Response<C>, E>>,
+{
+    type Output = Result<http_02::Response<Body1<C>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let resp = ready!(self.project().inner.poll(cx))?;
+        Poll::Ready(Ok(response_to_02(resp.map(Body1::new))))
+    }
+}
+
+/// Service on `http` 0.2 called by the metrics layer with `http` 1 requests.
+#[derive(Clone, Debug)]
+pub struct Downgrade<S> {
+    inner: S,
+}
+
+impl<S, B, C> Service<http1::Request<B>> for Downgrade<S>
+where
+    S: Service<http_02::Request<B>, Response = http_02::Response<C>>,
+{
+    type Response = http1::Response<Body04<C>>;
+    type Error = S::Error;
+    type Future = DowngradeFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http1::Request<B>) -> Self::Future {
+        DowngradeFuture {
+            inner: self.inner.call(request_to_02(req)),
+        }
+    }
+}
+
+#[pin_project]
+pub struct DowngradeFuture<F> {
+    #[pin]
+    inner: F,
+}
+
+impl<F, C, E> Future for DowngradeFuture<F>
+where
+    F: Future<Output = Result<http_02::Response<C>, E>>,
+{
+    type Output = Result<http1::Response<Body04<C>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let resp = ready!(self.project().inner.poll(cx))?;
+        Poll::Ready(Ok(response_to_1(resp.map(Body04::new))))
+    }
+}
+
+/// The `http` 0.2 extensions of a request or response, carried through the
+/// `http` 1 one, which requires extensions to be `Clone`.
+#[derive(Clone)]
+struct Extensions02(Arc<Mutex<Option<http_02::Extensions>>>);
+
+impl Extensions02 {
+    fn new(extensions: http_02::Extensions) -> Self {
+        Self(Arc::new(Mutex::new(Some(extensions))))
+    }
+
+    fn take(extensions: &mut http1::Extensions) -> http_02::Extensions {
+        extensions
+            .remove::<Extensions02>()
+            .and_then(|e| e.0.lock().unwrap().take())
+            .unwrap_or_default()
+    }
+}
+
+fn request_to_1<B>(req: http_02::Request<B>) -> http1::Request<B> {
+    let (parts, body) = req.into_parts();
+
+    let mut req = http1::Request::new(body);
+    *req.method_mut() = http1::Method::from_bytes(parts.method.as_str().as_bytes())
+        .expect("methods are the same in both versions");
+    *req.uri_mut() = parts
+        .uri
+        .to_string()
+        .parse()
+        .expect("URIs are the same in both versions");
+    *req.version_mut() = version_to_1(parts.version);
+    *req.headers_mut() = headers_to_1(&parts.headers);
+    req.extensions_mut()
+        .insert(Extensions02::new(parts.extensions));
+    req
+}
+
+fn request_to_02<B>(req: http1::Request<B>) -> http_02::Request<B> {
+    let (mut parts, body) = req.into_parts();
+
+    let mut extensions = Extensions02::take(&mut parts.extensions);
+    if let Some(info) = parts.extensions.remove::<GrpcCallInfo>() {
+        extensions.insert(info);
+    }
+    if let Some(call_metrics) = parts.extensions.remove::<CallMetrics>() {
+        extensions.insert(call_metrics);
+    }
+    if let Some(proxy) = parts.extensions.remove::<ProxyContext>() {
+        extensions.insert(proxy);
+    }
+
+    let mut req = http_02::Request::new(body);
+    *req.method_mut() = http_02::Method::from_bytes(parts.method.as_str().as_bytes())
+        .expect("methods are the same in both versions");
+    *req.uri_mut() = parts
+        .uri
+        .to_string()
+        .parse()
+        .expect("URIs are the same in both versions");
+    *req.version_mut() = version_to_02(parts.version);
+    *req.headers_mut() = headers_to_02(&parts.headers);
+    *req.extensions_mut() = extensions;
+    req
+}
+
+fn response_to_1<B>(resp: http_02::Response<B>) -> http1::Response<B> {
+    let (parts, body) = resp.into_parts();
+
+    let mut resp = http1::Response::new(body);
+    *resp.status_mut() = http1::StatusCode::from_u16(parts.status.as_u16())
+        .expect("status codes are the same in both versions");
+    *resp.version_mut() = version_to_1(parts.version);
+    *resp.headers_mut() = headers_to_1(&parts.headers);
+    if let Some(rejected) = parts.extensions.get::<Rejected>() {
+        resp.extensions_mut().insert(*rejected);
+    }
+    resp.extensions_mut()
+        .insert(Extensions02::new(parts.extensions));
+    resp
+}
+
+fn response_to_02<B>(resp: http1::Response<B>) -> http_02::Response<B> {
+    let (mut parts, body) = resp.into_parts();
+
+    let mut resp = http_02::Response::new(body);
+    *resp.status_mut() = http_02::StatusCode::from_u16(parts.status.as_u16())
+        .expect("status codes are the same in both versions");
+    *resp.version_mut() = version_to_02(parts.version);
+    *resp.headers_mut() = headers_to_02(&parts.headers);
+    *resp.extensions_mut() = Extensions02::take(&mut parts.extensions);
+    resp
+}
+
+fn version_to_1(version: http_02::Version) -> http1::Version {
+    match version {
+        http_02::Version::HTTP_09 => http1::Version::HTTP_09,
+        http_02::Version::HTTP_10 => http1::Version::HTTP_10,
+        http_02::Version::HTTP_2 => http1::Version::HTTP_2,
+        http_02::Version::HTTP_3 => http1::Version::HTTP_3,
+        _ => http1::Version::HTTP_11,
+    }
+}
+
+fn version_to_02(version: http1::Version) -> http_02::Version {
+    match version {
+        http1::Version::HTTP_09 => http_02::Version::HTTP_09,
+        http1::Version::HTTP_10 => http_02::Version::HTTP_10,
+        http1::Version::HTTP_2 => http_02::Version::HTTP_2,
+        http1::Version::HTTP_3 => http_02::Version::HTTP_3,
+        _ => http_02::Version::HTTP_11,
+    }
+}
+
+fn headers_to_1(headers: &http_02::HeaderMap) -> http1::HeaderMap {
+    let mut converted = http1::HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            http1::HeaderName::from_bytes(name.as_str().as_bytes()),
+            http1::HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            converted.append(name, value);
+        }
+    }
+    converted
+}
+
+fn headers_to_02(headers: &http1::HeaderMap) -> http_02::HeaderMap {
+    let mut converted = http_02::HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (
+            http_02::HeaderName::from_bytes(name.as_str().as_bytes()),
+            http_02::HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            converted.append(name, value);
+        }
+    }
+    converted
+}
+
+/// An `http-body` 0.4 body as an `http-body` 1 one.
+#[pin_project]
+pub struct Body04<B> {
+    #[pin]
+    inner: B,
+    data_done: bool,
+    done: bool,
+}
+
+impl<B> Body04<B> {
+    fn new(inner: B) -> Self {
+        Self {
+            inner,
+            data_done: false,
+            done: false,
+        }
+    }
+}
+
+impl<B: http_body_04::Body> http_body::Body for Body04<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        if *this.done {
+            return Poll::Ready(None);
+        }
+
+        if !*this.data_done {
+            match ready!(this.inner.as_mut().poll_data(cx)) {
+                Some(Ok(data)) => return Poll::Ready(Some(Ok(http_body::Frame::data(data)))),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => *this.data_done = true,
+            }
+        }
+
+        let trailers = ready!(this.inner.poll_trailers(cx));
+        *this.done = true;
+        Poll::Ready(match trailers {
+            Ok(Some(trailers)) => Some(Ok(http_body::Frame::trailers(headers_to_1(&trailers)))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        let hint = self.inner.size_hint();
+        let mut converted = http_body::SizeHint::new();
+        converted.set_lower(hint.lower());
+        if let Some(upper) = hint.upper() {
+            converted.set_upper(upper);
+        }
+        converted
+    }
+}
+
+/// An `http-body` 1 body as an `http-body` 0.4 one.
+#[pin_project]
+pub struct Body1<B> {
+    #[pin]
+    inner: B,
+    trailers: Option<http1::HeaderMap>,
+    done: bool,
+}
+
+impl<B> Body1<B> {
+    fn new(inner: B) -> Self {
+        Self {
+            inner,
+            trailers: None,
+            done: false,
+        }
+    }
+}
+
+impl<B: http_body::Body> http_body_04::Body for Body1<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let mut this = self.project();
+        while !*this.done {
+            match ready!(this.inner.as_mut().poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => return Poll::Ready(Some(Ok(data))),
+                    Err(frame) => {
+                        if let Ok(trailers) = frame.into_trailers() {
+                            *this.trailers = Some(trailers);
+                            *this.done = true;
+                        }
+                    }
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => *this.done = true,
+            }
+        }
+        Poll::Ready(None)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http_02::HeaderMap>, Self::Error>> {
+        let mut this = self.project();
+        // Data left unread by the caller is skipped.
+        while !*this.done {
+            match ready!(this.inner.as_mut().poll_frame(cx)) {
+                Some(Ok(frame)) => {
+                    if let Ok(trailers) = frame.into_trailers() {
+                        *this.trailers = Some(trailers);
+                        *this.done = true;
+                    }
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => *this.done = true,
+            }
+        }
+        Poll::Ready(Ok(this.trailers.take().map(|t| headers_to_02(&t))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.trailers.is_none() && (self.done || self.inner.is_end_stream())
+    }
+
+    fn size_hint(&self) -> http_body_04::SizeHint {
+        let hint = self.inner.size_hint();
+        let mut converted = http_body_04::SizeHint::new();
+        converted.set_lower(hint.lower());
+        if let Some(upper) = hint.upper() {
+            converted.set_upper(upper);
+        }
+        converted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::poll_fn;
+
+    use bytes::Bytes;
+    use http_body_04::Body as _;
+    use tonic::Code;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::metrics::{self, COUNTER_REJECTED};
+
+    /// An `http-body` 0.4 body of a single chunk of data and trailers.
+    struct TestBody {
+        data: Option<Bytes>,
+        trailers: Option<http_02::HeaderMap>,
+    }
+
+    impl http_body_04::Body for TestBody {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+            Poll::Ready(self.data.take().map(Ok))
+        }
+
+        fn poll_trailers(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<Option<http_02::HeaderMap>, Self::Error>> {
+            Poll::Ready(Ok(self.trailers.take()))
+        }
+    }
+
+    fn request(path: &str) -> http_02::Request<()> {
+        http_02::Request::builder()
+            .uri(path)
+            .header("content-type", "application/grpc")
+            .body(())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn status_from_trailers() {
+        let service = service_fn(|req: http_02::Request<()>| async move {
+            let info = req.extensions().get::<GrpcCallInfo>().unwrap();
+            assert_eq!((info.service(), info.method()), ("test.Http02", "Get"));
+
+            let mut trailers = http_02::HeaderMap::new();
+            trailers.insert("grpc-status", http_02::HeaderValue::from_static("5"));
+            let body = TestBody {
+                data: Some(Bytes::from_static(b"data")),
+                trailers: Some(trailers),
+            };
+            Ok::<_, Infallible>(http_02::Response::new(body))
+        });
+
+        let resp = Http02MetricsLayer::default()
+            .layer(service)
+            .oneshot(request("/test.Http02/Get"))
+            .await
+            .unwrap();
+        let got = metrics::snapshot();
+        let call = got.server("test.Http02", "Get").unwrap();
+        assert_eq!(call.started, 1);
+        assert_eq!(call.handled_total(), 0);
+
+        let mut body = Box::pin(resp.into_body());
+        let data = poll_fn(|cx| body.as_mut().poll_data(cx)).await;
+        assert_eq!(&data.unwrap().unwrap()[..], b"data");
+        let trailers = poll_fn(|cx| body.as_mut().poll_trailers(cx)).await;
+        assert_eq!(trailers.unwrap().unwrap()["grpc-status"], "5");
+
+        let got = metrics::snapshot();
+        let call = got.server("test.Http02", "Get").unwrap();
+        assert_eq!(call.handled(Code::NotFound), 1);
+    }
+
+    #[tokio::test]
+    async fn status_from_headers() {
+        let service = service_fn(|_req: http_02::Request<()>| async {
+            let body = TestBody {
+                data: None,
+                trailers: None,
+            };
+            let mut resp = http_02::Response::new(body);
+            resp.headers_mut()
+                .insert("grpc-status", http_02::HeaderValue::from_static("16"));
+            resp.extensions_mut().insert(Rejected::new("auth"));
+            Ok::<_, Infallible>(resp)
+        });
+
+        let resp = Http02MetricsLayer::default()
+            .layer(service)
+            .oneshot(request("/test.Http02/Rejected"))
+            .await
+            .unwrap();
+        let mut body = Box::pin(resp.into_body());
+        assert!(poll_fn(|cx| body.as_mut().poll_data(cx)).await.is_none());
+
+        let got = metrics::snapshot();
+        let call = got.server("test.Http02", "Rejected").unwrap();
+        assert_eq!(call.handled(Code::Unauthenticated), 1);
+        let rejected = COUNTER_REJECTED.with_label_values(&["test.Http02", "Rejected", "auth"]);
+        assert_eq!(rejected.get(), 1);
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Gauge, Metric, MetricFamily, MetricType};
+
+use crate::metrics::label_pair;
+
+/// Gauge of the requests in flight by two labels, e.g. HTTP method and path.
+///
+/// Requests only touch atomic counters, shared with their [`InFlightGuard`];
+/// the gauge is built from the counters when the registry is gathered.
+///
+/// Alternatively, the gauge is the highest number of requests in flight since
+/// the previous scrape, which catches bursts too short to show up in the
+/// number sampled at scrape time. Gathering does not reset it, so snapshots
+/// and other gathers in between scrapes do not lose the bursts.
+#[derive(Clone)]
+pub(crate) struct InFlight {
+    desc: Desc,
+    high_water: bool,
+    counters: Arc<RwLock<HashMap<CounterKey, Arc<Level>>>>,
+}
+
+/// Values of the two labels.
+type CounterKey = (String, String);
+
+#[derive(Default)]
+struct Level {
+    current: AtomicI64,
+    /// Highest value of `current` since the previous [`InFlight::reset`].
+    max: AtomicI64,
+}
+
+impl InFlight {
+    /// Gauge of the requests currently in flight.
+    pub(crate) fn new(name: &str, help: &str, labels: [&str; 2]) -> prometheus::Result<Self> {
+        Self::with_kind(name, help, labels, false)
+    }
+
+    /// Gauge of the highest number of requests in flight since the previous
+    /// [`InFlight::reset`].
+    pub(crate) fn high_water(
+        name: &str,
+        help: &str,
+        labels: [&str; 2],
+    ) -> prometheus::Result<Self> {
+        Self::with_kind(name, help, labels, true)
+    }
+
+    fn with_kind(
+        name: &str,
+        help: &str,
+        labels: [&str; 2],
+        high_water: bool,
+    ) -> prometheus::Result<Self> {
+        let desc = Desc::new(
+            name.to_owned(),
+            help.to_owned(),
+            labels.map(str::to_owned).to_vec(),
+            HashMap::new(),
+        )?;
+        Ok(Self {
+            desc,
+            high_water,
+            counters: Default::default(),
+        })
+    }
+
+    /// Count a request as in flight until the guard is dropped.
+    pub(crate) fn start(&self, first: &str, second: &str) -> InFlightGuard {
+        let key = (first.to_owned(), second.to_owned());
+        let existing = self.counters.read().unwrap().get(&key).cloned();
+        let level = existing.unwrap_or_else(|| {
+            self.counters
+                .write()
+                .unwrap()
+                .entry(key)
+                .or_default()
+                .clone()
+        });
+
+        let current = level.current.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.high_water {
+            level.max.fetch_max(current, Ordering::Relaxed);
+        }
+        InFlightGuard(level)
+    }
+
+    /// Start the next interval of the high-water mark from the requests still
+    /// in flight, once the gauge has been scraped.
+    pub(crate) fn reset(&self) {
+        for level in self.counters.read().unwrap().values() {
+            let current = level.current.load(Ordering::Relaxed);
+            level.max.store(current, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Counts a request as in flight until dropped.
+pub(crate) struct InFlightGuard(Arc<Level>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.current.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Collector for InFlight {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let counters = self.counters.read().unwrap();
+        let metrics: Vec<Metric> = counters
+            .iter()
+            .map(|((first, second), level)| {
+                let current = level.current.load(Ordering::Relaxed);
+                let value = if self.high_water {
+                    level.max.load(Ordering::Relaxed).max(current)
+                } else {
+                    current
+                };
+                let mut gauge = Gauge::default();
+                gauge.set_value(value as f64);
+
+                let mut metric = Metric::default();
+                metric.set_label(
+                    vec![
+                        label_pair(&self.desc.variable_labels[0], first),
+                        label_pair(&self.desc.variable_labels[1], second),
+                    ]
+                    .into(),
+                );
+                metric.set_gauge(gauge);
+                metric
+            })
+            .collect();
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::GAUGE);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn high_water_mark() {
+        let in_flight = InFlight::high_water("test_in_flight_max", "test", ["a", "b"]).unwrap();
+        let value = || {
+            in_flight.collect()[0].get_metric()[0]
+                .get_gauge()
+                .get_value()
+        };
+
+        let first = in_flight.start("x", "y");
+        let second = in_flight.start("x", "y");
+        drop(first);
+        assert_eq!(value(), 2.0);
+        in_flight.reset();
+        assert_eq!(value(), 1.0);
+        drop(second);
+        assert_eq!(value(), 1.0);
+        in_flight.reset();
+        assert_eq!(value(), 0.0);
+    }
+
+    #[test]
+    fn gathers_between_scrapes_keep_the_max() {
+        let in_flight = InFlight::high_water("test_in_flight_max", "test", ["a", "b"]).unwrap();
+        let registry = prometheus::Registry::new();
+        registry.register(Box::new(in_flight.clone())).unwrap();
+        let value = || registry.gather()[0].get_metric()[0].get_gauge().get_value();
+
+        drop((in_flight.start("x", "y"), in_flight.start("x", "y")));
+        crate::metrics::Snapshot::from_registry(&registry);
+        assert_eq!(value(), 2.0);
+        in_flight.reset();
+        assert_eq!(value(), 0.0);
+    }
+}
@@ -0,0 +1,136 @@
+use std::fmt::Write;
+use std::time::SystemTime;
+
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+
+use crate::merge;
+use crate::metrics::Error;
+
+/// Export the collected metrics in the InfluxDB line protocol, for backends
+/// that ingest it directly.
+///
+/// Each family is a measurement and its labels are tags, as in Telegraf's
+/// Prometheus input. Counters, gauges and untyped metrics have a single
+/// `counter`, `gauge` or `value` field. Histograms and summaries have `count`
+/// and `sum` fields, plus a field per bucket upper bound or quantile. All
+/// lines carry the current time as timestamp, in nanoseconds.
+pub fn encode_influx_line_protocol() -> Result<String, Error> {
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    Ok(encode(&merge::gather(), timestamp))
+}
+
+fn encode(families: &[MetricFamily], timestamp: u128) -> String {
+    let mut out = String::new();
+    for family in families {
+        for metric in family.get_metric() {
+            let fields = fields(metric, family.get_field_type());
+            if fields.is_empty() {
+                continue;
+            }
+
+            escape(&mut out, family.get_name(), &[',', ' ']);
+            for label in metric.get_label() {
+                // Empty tag values are invalid, and mean the same as no tag.
+                if label.get_value().is_empty() {
+                    continue;
+                }
+                out.push(',');
+                escape(&mut out, label.get_name(), &[',', '=', ' ']);
+                out.push('=');
+                escape(&mut out, label.get_value(), &[',', '=', ' ']);
+            }
+            for (i, (name, value)) in fields.iter().enumerate() {
+                out.push(if i == 0 { ' ' } else { ',' });
+                escape(&mut out, name, &[',', '=', ' ']);
+                let _ = write!(out, "={value}");
+            }
+            let _ = writeln!(out, " {timestamp}");
+        }
+    }
+    out
+}
+
+/// Field names and values of a series. Non-finite values are left out, as
+/// line protocol has no representation for them.
+fn fields(metric: &Metric, field_type: MetricType) -> Vec<(String, f64)> {
+    let fields = match field_type {
+        MetricType::COUNTER => vec![("counter".to_owned(), metric.get_counter().get_value())],
+        MetricType::GAUGE => vec![("gauge".to_owned(), metric.get_gauge().get_value())],
+        MetricType::UNTYPED => vec![("value".to_owned(), metric.get_untyped().get_value())],
+        MetricType::HISTOGRAM => {
+            let histogram = metric.get_histogram();
+            let mut fields = vec![
+                ("count".to_owned(), histogram.get_sample_count() as f64),
+                ("sum".to_owned(), histogram.get_sample_sum()),
+            ];
+            fields.extend(histogram.get_bucket().iter().map(|b| {
+                (
+                    b.get_upper_bound().to_string(),
+                    b.get_cumulative_count() as f64,
+                )
+            }));
+            fields.push(("+Inf".to_owned(), histogram.get_sample_count() as f64));
+            fields
+        }
+        MetricType::SUMMARY => {
+            let summary = metric.get_summary();
+            let mut fields = vec![
+                ("count".to_owned(), summary.get_sample_count() as f64),
+                ("sum".to_owned(), summary.get_sample_sum()),
+            ];
+            fields.extend(
+                summary
+                    .get_quantile()
+                    .iter()
+                    .map(|q| (q.get_quantile().to_string(), q.get_value())),
+            );
+            fields
+        }
+    };
+
+    fields.into_iter().filter(|(_, v)| v.is_finite()).collect()
+}
+
+fn escape(out: &mut String, s: &str, special: &[char]) {
+    for c in s.chars() {
+        if special.contains(&c) || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_protocol() {
+        let registry = prometheus::Registry::new();
+        let handled = prometheus::IntCounterVec::new(
+            prometheus::opts!("influx_handled_total", "test"),
+            &["grpc_service", "grpc_code"],
+        )
+        .unwrap();
+        let handling = prometheus::Histogram::with_opts(prometheus::histogram_opts!(
+            "influx_handling_seconds",
+            "test",
+            vec![0.5]
+        ))
+        .unwrap();
+        registry.register(Box::new(handled.clone())).unwrap();
+        registry.register(Box::new(handling.clone())).unwrap();
+        handled.with_label_values(&["a b,c", ""]).inc_by(2);
+        handling.observe(0.25);
+
+        assert_eq!(
+            encode(&registry.gather(), 1),
+            "influx_handled_total,grpc_service=a\\ b\\,c counter=2 1\n\
+             influx_handling_seconds count=1,sum=0.25,0.5=1,+Inf=1 1\n"
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::clock::Timestamp;
+use crate::metrics::{feature_enabled, RecordingFeature, INTERARRIVAL_HISTOGRAM};
+
+/// Start of the most recent call of each method, by service and method.
+static LAST_STARTED: Lazy<Mutex<HashMap<(String, String), Timestamp>>> =
+    Lazy::new(Default::default);
+
+/// Observe the time since the previous call of the method started, if
+/// the `interarrival_time` recording feature is on.
+pub(crate) fn record(service: &str, method: &str) {
+    if !feature_enabled(RecordingFeature::InterarrivalTime) {
+        return;
+    }
+    observe(service, method);
+}
+
+fn observe(service: &str, method: &str) {
+    let previous = LAST_STARTED
+        .lock()
+        .unwrap()
+        .insert((service.to_owned(), method.to_owned()), Timestamp::now());
+    if let Some(previous) = previous {
+        INTERARRIVAL_HISTOGRAM
+            .with_label_values(&[service, method])
+            .observe(previous.elapsed().as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn observes_time_between_starts() {
+        let histogram = INTERARRIVAL_HISTOGRAM.with_label_values(&["test.Arrivals", "Get"]);
+
+        observe("test.Arrivals", "Get");
+        assert_eq!(histogram.get_sample_count(), 0);
+        std::thread::sleep(Duration::from_millis(20));
+        observe("test.Arrivals", "Get");
+        assert_eq!(histogram.get_sample_count(), 1);
+        assert!(histogram.get_sample_sum() >= 0.02);
+    }
+}
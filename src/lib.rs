@@ -1,17 +1,79 @@
 //!
-//! 
+//!
 //! # Tonic Prometheus Layer
 //! A lightweight Prometheus metrics layer for Tonic gRPC client and server
 //!
 //! It provides the following metrics:
 //! * `grpc_server_handled_total`: a **Counter** for tracking the total number of completed gRPC server calls.
 //! * `grpc_server_started_total`: a **Counter** for tracking the total number of gRPC server calls started.
-//!    The difference between this and `grpc_server_handled_total` equals the number of ongoing server requests.
+//!   The difference between this and `grpc_server_handled_total` equals the number of ongoing server requests.
 //! * `grpc_server_handling_seconds`: a **Histogram** for tracking gRPC server call duration.
+//! * `grpc_server_transport_errors_total`: a **Counter** for tracking gRPC server calls that failed with a transport
+//!   error (e.g. a stream reset) rather than returning a gRPC status.
+//! * `grpc_server_retried_requests_total`: a **Counter** for tracking gRPC server calls that clients sent as retries,
+//!   according to the `grpc-previous-rpc-attempts` header.
+//! * `grpc_server_request_metadata_bytes` and `grpc_server_response_metadata_bytes`: **Histograms** of the size
+//!   of request headers and of response headers plus trailers, if `GlobalSettings::metadata_size` is set.
+//! * `grpc_server_request_age_seconds`: a **Histogram** of the time between the client sending a call and
+//!   the server receiving it, from the timestamp header named by `GlobalSettings::request_age_header`.
+//! * `grpc_server_cpu_seconds`: a **Histogram** of the thread CPU time spent polling each server call's
+//!   handler, if `GlobalSettings::cpu_time` is set, to tell busy calls from ones waiting on others.
+//! * `grpc_server_interarrival_seconds`: a **Histogram** of the time between the starts of consecutive calls of each
+//!   method, if `GlobalSettings::interarrival_time` is set, for capacity modeling and spotting retry storms.
+//! * `grpc_server_send_blocked_seconds`: a **Histogram** of the time each server call's response waited for the
+//!   transport to take its next message, mostly HTTP/2 flow control, if `GlobalSettings::send_blocked_time` is set,
+//!   to tell slow-consuming clients from slow handlers.
+//! * `grpc_proxy_overhead_seconds`: a **Histogram** of the duration of each server call minus that of the client calls
+//!   it made through `MetricsChannel`, if `GlobalSettings::proxy_overhead` is set, see `metrics::ProxyContext`.
+//! * `grpc_server_msg_interval_seconds`: a **Histogram** of the time between consecutive messages sent in each server
+//!   call's response, if `GlobalSettings::message_interval` is set, to detect stalls inside long-lived streams.
+//! * `grpc_server_open_stream_age_seconds`: a **Gauge** of the number of open server calls of each method at most
+//!   `le` seconds old, sampled in the background if `GlobalSettings::open_stream_age` is set, for streams that run
+//!   for hours or days and only show up in the duration histogram once they end.
+//! * `grpc_server_alloc_bytes`: a **Histogram** of the bytes allocated while polling each server call's handler,
+//!   if `TrackingAllocator` (`alloc-tracking` feature) is the global allocator.
+//! * `grpc_server_in_flight_max`: a **Gauge** of the highest number of concurrent calls of each method since the
+//!   previous scrape, which catches short saturation bursts that the number of ongoing calls at scrape time misses.
+//! * `grpc_server_last_handled_timestamp_seconds`: a **Gauge** of the Unix time each method last completed a call,
+//!   e.g. to find methods that have not been called in a long time.
+//! * `grpc_server_slow_requests_total`: a **Counter** for tracking gRPC server calls slower than
+//!   `GlobalSettings::slow_requests`, if set.
+//! * `grpc_server_long_polls_total`: a **Counter** of polls of server call handlers longer than
+//!   `GlobalSettings::long_poll_threshold`, and `grpc_server_max_poll_seconds`: a **Gauge** of the longest poll of
+//!   each method since the previous scrape, if the threshold is set.
+//! * `grpc_server_slo_burn_rate`: a **Gauge** of the rate at which calls of each method with an objective in
+//!   `GlobalSettings::slos` consume its error budget, by `window` (`5m`, `1h` or `6h`), and
+//!   `grpc_server_slo_events_total`: a **Counter** of these calls by `result` (`good` or `bad`).
+//! * `grpc_server_rejected_total`: a **Counter** for tracking gRPC server calls rejected before reaching the handler,
+//!   by `reason`, from the `Rejected` extension of their response.
+//! * `grpc_server_frame_errors_total`: a **Counter** of malformed gRPC message frames, by `direction` (`request`
+//!   or `response`): invalid compressed flags, compressed messages without a `grpc-encoding`, and truncated
+//!   messages. Requests are only checked under a `FrameCheckLayer`, placed inside of the metrics layer.
+//! * `grpc_server_method_info`: an info-style **Gauge**, always 1, for every method passed to
+//!   `metrics::describe_methods`, so methods show up on dashboards before they receive traffic.
+//! * `grpc_metrics_inconsistencies_total`: a **Counter** of invariants between the server metrics found violated
+//!   on gather, if `GlobalSettings::self_check` is set.
+//! * `grpc_metrics_scrape_duration_seconds` and `grpc_metrics_scrape_size_bytes`: **Gauges** of the time taken by
+//!   and size of the previous `metrics::encode_to_string()` export, and `grpc_metrics_recording_errors_total`:
+//!   a **Counter** of errors registering or recording metrics, see `GlobalSettings::fail_open`.
+//! * `grpc_server_connections_opened_total`, `grpc_server_connections_open` and `grpc_server_connection_duration_seconds`:
+//!   a **Counter**, **Gauge** and **Histogram** tracking server connections accepted through `MetricsIncoming`.
+//! * `grpc_server_connection_max_concurrent_streams`: a **Histogram** of the highest number of concurrent requests
+//!   on each TCP connection accepted through `MetricsIncoming`, observed when it closes.
+//! * `grpc_server_connection_requests`: a **Histogram** of the number of requests served on each TCP connection
+//!   accepted through `MetricsIncoming`, observed when it closes, to find clients that open a connection per call.
+//! * `grpc_server_tls_handshake_seconds` and `grpc_server_tls_handshake_failures_total`: a **Histogram** and
+//!   **Counter** tracking TLS handshakes wrapped in `MetricsHandshake`.
+//! * `grpc_server_serving_status`: a **Gauge** mirroring the health status of each service set through a
+//!   `MetricsHealthReporter` (`health` feature), 1 if serving and 0 otherwise.
 //! * `grpc_client_handled_total`: a **Counter** for tracking the total number of completed gRPC client calls.
 //! * `grpc_client_started_total`: a **Counter** for tracking the total number of gRPC client calls started.
-//!    The difference between this and `grpc_client_handled_total` equals the number of ongoing client requests.
+//!   The difference between this and `grpc_client_handled_total` equals the number of ongoing client requests.
 //! * `grpc_client_handling_seconds`: a **Histogram** for tracking gRPC client call duration.
+//! * `grpc_client_channel_state`: a **Gauge** with the connectivity state of each channel wrapped with
+//!   `MetricsChannel::with_target()`, by `target`: 0 idle, 1 connecting, 2 ready, 3 transient failure, 4 shutdown.
+//! * `grpc_client_reconnects_total`: a **Counter** for tracking the number of times such channels started connecting
+//!   again after failing, by `target`.
 //!
 //! ## Usage
 //!
@@ -27,37 +89,37 @@
 //! ```rust,no_run
 //! use std::net::SocketAddr;
 //! use std::str::FromStr;
-//! 
+//!
 //! use rocket::{get, routes};
 //! use rocket::http::Status;
 //! use rocket::response::content::RawText;
 //! use rocket::config::Shutdown;
 //! use rocket::response::status::Custom;
 //! use tonic_prometheus_layer::metrics::GlobalSettings;
-//! 
+//!
 //! use crate::api::server;
 //! use crate::proto::service_server::ServiceServer;
-//! 
+//!
 //! mod api;
 //! mod proto;
-//! 
+//!
 //! #[tokio::main]
 //! async fn main() {
 //!     let addr: SocketAddr = "127.0.0.1:9090".parse().unwrap();
-//! 
+//!
 //!     let service = server::Server {};
-//! 
+//!
 //!     tonic_prometheus_layer::metrics::try_init_settings(GlobalSettings {
 //!         histogram_buckets: vec![0.01, 0.05, 0.1, 0.5, 1.0, 2.5, 5.0, 10.0],
 //!         ..Default::default()
 //!     }).unwrap();
-//! 
+//!
 //!     let metrics_layer = tonic_prometheus_layer::MetricsLayer::new();
-//! 
+//!
 //!     tokio::spawn(async {
 //!         run_http_server("127.0.0.1:8090").await
 //!     });
-//! 
+//!
 //!     tonic::transport::Server::builder()
 //!         .layer(metrics_layer)
 //!         .add_service(ServiceServer::new(service))
@@ -65,17 +127,17 @@
 //!         .await
 //!         .unwrap();
 //! }
-//! 
+//!
 //! #[get("/metrics")]
 //! async fn metrics() -> Custom<RawText<String>> {
 //!     let body = tonic_prometheus_layer::metrics::encode_to_string().unwrap();
-//! 
+//!
 //!     Custom(Status::Ok, RawText(body))
 //! }
-//! 
+//!
 //! pub async fn run_http_server(addr: &str) {
 //!     let addr = SocketAddr::from_str(addr).unwrap();
-//! 
+//!
 //!     let config = rocket::config::Config {
 //!         address: addr.ip(),
 //!         port: addr.port(),
@@ -85,7 +147,7 @@
 //!         },
 //!         ..rocket::config::Config::release_default()
 //!     };
-//! 
+//!
 //!     rocket::custom(config)
 //!         .mount("/", routes![metrics])
 //!         .launch()
@@ -94,6 +156,127 @@
 //! }
 //! ```
 //!
+//! ### Service and Method Names
+//!
+//! The `grpc_service` label holds fully qualified service names. Set `GlobalSettings::service_names` to
+//! `ServiceNames::StripPackage` to drop the package, or to `ServiceNames::Custom` to map them yourself.
+//! When wrapping a single generated server rather than the whole router, build the layer with
+//! `MetricsLayer::for_named_service::<ServiceServer<T>>()` to take the service name from its `NamedService`
+//! implementation instead of splitting each request path.
+//! Add services with many method names, such as generic proxies, to `GlobalSettings::collapsed_methods` to
+//! record all their calls under `grpc_method="*"` while other services keep per-method series.
+//!
+//! Layers that transcode HTTP/JSON requests into gRPC calls can insert a `RpcMethod` extension into
+//! the request before it reaches the metrics layer, so the call is attributed to that gRPC service
+//! and method instead of the REST path.
+//!
+//! Once methods are passed to `metrics::describe_methods`, calls to other methods are all recorded as
+//! `<unimplemented>`.
+//! Set `GlobalSettings::series_threshold` to record methods under `__other__` until they have been
+//! called a few times, which keeps one-off probes from adding series.
+//!
+//! ### Layer Order
+//!
+//! The order of the layers matters: inside of compression, around tonic-web and around authentication, the metrics
+//! layer records the right status, protocol and duration. `stack()` returns a `MetricsStack` that takes these
+//! layers and puts them in that order, usable as a layer or as a `ServiceBuilder`. Metrics layers nested
+//! inside of another, e.g. around a single service, pass its calls through instead of recording them twice.
+//!
+//! ### gRPC-Web and Connect
+//!
+//! Requests made with `application/grpc-web*` content types (e.g. when serving through `tonic-web`)
+//! are detected automatically, and their status is read from the trailer frame at the end of the
+//! response body. Connect unary requests (`application/proto`, or any request with a
+//! `connect-protocol-version` header) are detected too, with the code taken from the JSON error body.
+//! Set `GlobalSettings::protocol_label` to tell them apart from native gRPC calls, and
+//! `GlobalSettings::protocol_label_transport` to further split native calls into `h2` and cleartext `h2c`.
+//!
+//! ### Optional Labels
+//!
+//! Set `GlobalSettings::transport_label` to tell calls over TCP and Unix sockets apart.
+//! With the `tls` feature, connections over TLS (tonic's `TlsConnectInfo`) count as the socket underneath,
+//! for this label as well as the per-connection metrics of `MetricsIncoming`.
+//! Set `GlobalSettings::priority_label` to a `HeaderLabel` to add a `priority` label from a request header
+//! such as `x-request-priority`, e.g. to alert only on degradation of interactive traffic. Values outside the
+//! configured ones are recorded as `other`.
+//! Likewise, set `GlobalSettings::caller_label` to attribute calls to the calling service named in a header
+//! such as `x-client-name`, in a `caller` label on `grpc_server_started_total` and `grpc_server_handled_total`.
+//! For alerts that only need the error ratio, set `GlobalSettings::result_label` to add a `grpc_result` label, `ok`
+//! or `error`, to `grpc_server_handled_total`; list codes such as `Cancelled` in its `ok_codes` to count them as `ok`.
+//! When one server fronts several DNS names, set `GlobalSettings::authority_label` to the hosts to tell apart
+//! in an `authority` label; requests to other hosts are recorded as `other`.
+//! To dimension the metrics by context propagated from upstream services, add `metrics::BaggageLabel`s to
+//! `GlobalSettings::baggage_labels`, e.g. `BaggageLabel::new("tenant", ["acme", "globex"])`, which label calls by
+//! the entries of the W3C `baggage` header with those keys. As with header labels, unlisted values are recorded
+//! as `other`.
+//!
+//! ### Request Extensions
+//!
+//! The layer inserts a `GrpcCallInfo` extension with the service and method of each gRPC call into the
+//! request, for inner layers and handlers that need them.
+//! Layers between the metrics layer and the handler that reject calls, such as authentication or rate limiting,
+//! can insert a `Rejected` extension naming the reason into their response, to count the call in
+//! `grpc_server_rejected_total` in addition to its status.
+//! To record per-call values of your own, such as rows scanned or cache hits, register `CounterVec`s or
+//! `HistogramVec`s labeled by `grpc_service` and `grpc_method` and add them to `GlobalSettings::call_metrics`.
+//! Handlers then find a `metrics::CallMetrics` extension in the request to record values into them by name.
+//! In services that proxy calls to others through `MetricsChannel`, set `GlobalSettings::proxy_overhead` and copy
+//! the `metrics::ProxyContext` extension of each inbound request into its outbound ones, to export the time spent
+//! besides waiting for them in `grpc_proxy_overhead_seconds`.
+//!
+//! ### Non-gRPC Traffic
+//!
+//! When plain HTTP routes (e.g. axum) share a server with tonic services, requests without a gRPC
+//! content type are recorded like gRPC calls by default. Set `GlobalSettings::non_grpc_requests` to
+//! `NonGrpcRequests::Skip` to ignore them, or to `NonGrpcRequests::Http` to record them in the
+//! `http_server_requests_total` and `http_server_request_duration_seconds` metrics instead.
+//! Requests whose path is not of the form `/service/method` are recorded with the whole path as method;
+//! set `GlobalSettings::unparseable_paths` to label them all as `unknown` or to skip them.
+//!
+//! ### Recording Outside the Layer
+//!
+//! To record work that does not go through the layer, such as batch jobs, in the same metrics and with
+//! the same labels, use `metrics::MethodMetrics`, or the `metrics::server_started()`,
+//! `metrics::server_handled()` and `metrics::server_handling()` families directly.
+//! With the `macros` feature, annotate async functions that are not RPCs, such as internal helpers, with
+//! `#[instrument_grpc]` to count their calls, duration and concurrency in the `function_calls_total`,
+//! `function_calls_duration_seconds` and `function_calls_concurrent` metrics, with `method="fn"` and
+//! the function's path as `path`.
+//!
+//! ### Slow and Failed Calls
+//!
+//! Set `GlobalSettings::rpcz` to retain the most recent slow or failed server calls, and serve
+//! `rpcz::render_html()` or `rpcz::render_json()` from a debug endpoint such as `/debug/rpcz`.
+//! Set `GlobalSettings::slowest_calls` to keep the slowest calls of each method over a recent window,
+//! available through `slowest::slowest_calls()` or as JSON from `slowest::render_json()`.
+//! Set `GlobalSettings::rates` to compute per-method request and error rates in-process with
+//! `metrics::rates(window)`, e.g. for admission control or health endpoints.
+//! Set `GlobalSettings::quantile_sketches` to estimate accurate tail latencies in-process with
+//! `metrics::quantile(service, method, 0.999)`, optionally exported as a summary. The sketches also
+//! back `metrics::bucket_report()`, which suggests histogram buckets per method from observed latencies.
+//! Set their `window` to a `metrics::SketchWindow` to estimate the quantiles over rotating windows of recent calls,
+//! e.g. the last 5 minutes, instead of all calls since the start.
+//! For health checks and autoscaling hooks in the same binary, `metrics::percentile(service, method, 99.0)` returns
+//! a method's p99 duration without scraping the process, from its sketch if set and otherwise interpolated from the
+//! `grpc_server_handling_seconds` buckets since the start.
+//! To find which label combinations blow up the registry, `metrics::cardinality_report()` counts the exported
+//! series of each family and the most frequent values of each label. `ScrapeLayer::with_cardinality_path()` serves
+//! it as text, e.g. on `/debug/cardinality`.
+//! Set `GlobalSettings::slow_request_log` to a `SlowThreshold` to log calls slower than it with
+//! `tracing::warn!`, with optional per-method thresholds.
+//! Async handlers that block, e.g. on file I/O or a synchronous mutex, stall every call on their executor thread
+//! without showing up as slow themselves. Set `GlobalSettings::long_poll_threshold`, e.g. to 10ms, to time each poll
+//! of the handler and of streaming response bodies, and count the longer ones by method.
+//! Add `metrics::Slo` objectives of methods to `GlobalSettings::slos`, e.g. `Slo::availability(0.999)` or
+//! `Slo::availability(0.99).latency(Duration::from_millis(300))`, to export `grpc_server_slo_burn_rate` over 5m, 1h
+//! and 6h windows for multi-window burn-rate alerts, and the good and bad calls in `grpc_server_slo_events_total`.
+//!
+//! For other side effects on completed calls, such as audit logs or billing, register a callback with
+//! `MetricsLayer::with_observer`. It receives a `CallInfo` with the service, method, code, duration and
+//! body sizes of each call.
+//! Set `GlobalSettings::call_events` to also broadcast start and finish events to receivers obtained
+//! from `metrics::subscribe()`, e.g. for adaptive concurrency controllers running as separate tasks.
+//!
 //! ## Client Instrumentation
 //!
 //! Wrap each individual tonic client Channel object:
@@ -109,52 +292,390 @@
 //!     let mut client = tonic_health::pb::health_client::HealthClient::new(channel);
 //! }
 //! ```
+//!
+//! Add `.with_target("http://localhost")` to also export the channel's connectivity state and reconnects.
+//!
+//! Client instrumentation is part of the default `client` feature. Servers that make no gRPC calls can
+//! leave it out with `default-features = false`.
+//!
+//! ## Export
+//!
+//! `metrics::encode_to_string()` exports all metrics in the Prometheus text format, as in the example above.
+//! To serve `/metrics` on the gRPC server's own port instead of a second listener, add `ScrapeLayer` outside of
+//! `MetricsLayer` and enable `accept_http1` on the server builder, as Prometheus scrapes over HTTP/1.1.
+//! It answers `HEAD` requests, and tags responses with an `ETag` so that scrapers sending `If-None-Match` get
+//! `304 Not Modified` without a body while the metrics are unchanged.
+//! Where only gRPC traffic is allowed, enable the `scrape-service` feature and add
+//! `scrape_service::MetricsServer` to the server; its `GetMetrics` call streams the exposition in chunks.
+//! Clients can be generated from `proto/metrics.proto`.
+//! On hosts where no listening port may be opened, `metrics::start_textfile_writer(path, interval)` writes the
+//! exposition to a `.prom` file for node_exporter's textfile collector.
+//!
+//! For push-based pipelines that expect delta temporality, `metrics::gather_deltas()` and
+//! `metrics::encode_deltas_to_string()` report the change of counters and histograms since their last call.
+//! Set `GlobalSettings::native_histograms` to keep high-resolution native histogram buckets for
+//! `grpc_server_handling_seconds`, and serve `metrics::encode_protobuf()` with the
+//! `metrics::PROTOBUF_FORMAT` content type to a Prometheus server with native histograms enabled.
+//! For backends that ingest InfluxDB line protocol, `metrics::encode_influx_line_protocol()` exports each
+//! family as a measurement with its labels as tags.
+//! To serve only some series, e.g. on a lightweight health endpoint, select them by name prefix and
+//! label values with a `metrics::MetricFilter` and export them with `metrics::encode_filtered()`.
+//! The filter can also drop labels at export time, e.g. `grpc_code` from histograms for a cheap secondary
+//! scraper, with `drop_label` and `keep_labels`; series that only differed in them are added up.
+//!
+//! To adapt all exports to consumers you don't control, add `metrics::Relabel` rules to `GlobalSettings::relabel`
+//! to rename or merge label values, add static labels, or rewrite labels with a function of your own.
+//! Set `GlobalSettings::namespace` to prefix all exported metric names.
+//! To follow your own conventions for help text, override it by metric name in `GlobalSettings::help`.
+//! For consumers that forward scraped series with a delay, such as aggregating proxies, set
+//! `GlobalSettings::sample_timestamps` to attach the time of the gather to every exported sample.
+//! To label every series with the service's OpenTelemetry identity (`service.name`, `service.version`,
+//! `deployment.environment`, ...), use `metrics::resource_registry(&resource)` (`opentelemetry` feature) as
+//! `GlobalSettings::registry`, or build one with const labels from `metrics::resource_labels()`.
+//!
+//! ### Multiple Registries
+//!
+//! Pass the `prometheus::Registry` of another library to `metrics::merge_registry()` to export its metrics
+//! along with the layer's, with families of the same name merged into one.
+//! Alternatively, set `GlobalSettings::use_default_registry` to register the layer's metrics in
+//! `prometheus::default_registry()`, where many crates register theirs.
+//!
+//! Set `GlobalSettings::tenants` to additionally record the started, handled and handling time metrics of
+//! each call in a registry of its tenant, chosen by a `Tenant` request extension or a request header.
+//! Only the configured tenants get a registry; serve each of them with
+//! `metrics::encode_tenant_to_string(tenant)`, e.g. for per-tenant chargeback.
+//!
+//! To keep some services out of the main registry altogether, e.g. health and reflection, set
+//! `GlobalSettings::registry_selector` to pick the registry for the calls of each service. It is passed the
+//! `grpc_service` label value, so with `ServiceNames::StripPackage` the service name without its package.
+//!
+//! ## Configuration
+//!
+//! Settings are passed to `metrics::try_init_settings()` once, before the first call is recorded.
+//! `GlobalSettings::from_env()` reads the namespace and histogram buckets from the `GRPC_METRICS_NAMESPACE` and
+//! `GRPC_METRICS_BUCKETS` environment variables, and clears `GlobalSettings::legacy_metrics` if
+//! `GRPC_METRICS_DISABLE_LEGACY` is `true`, to tune the metrics per deployment without code changes. Without
+//! `legacy_metrics`, server calls are no longer recorded in the `function_calls_*` metrics by HTTP method and path.
+//! With the `serde` feature, `GlobalSettings` implements `Deserialize`, so it can live in the service's YAML or TOML
+//! configuration, with unset fields at their defaults. Durations are given in seconds, status codes by name and
+//! per-method maps such as `slos` keyed by `service/method`; the registry, functions and `call_metrics` can only
+//! be set in code.
+//!
+//! Metrics that conflict with ones already in the registry panic when first recorded. Set
+//! `GlobalSettings::fail_open` to log such errors and count them in `grpc_metrics_recording_errors_total`
+//! instead, leaving the conflicting metrics out of the export.
+//!
+//! The opt-in recording features (`metadata_size`, `cpu_time`, `interarrival_time`, `send_blocked_time`,
+//! `message_interval` and `legacy_metrics`) can be switched while the server runs with
+//! `metrics::set_feature_enabled()`, e.g. to record more detail during an incident and stop again after it, or over
+//! HTTP with `ScrapeLayer::with_features_path()`. Labels can't be switched this way, as every series of a family
+//! has the same labels.
+//!
+//! Call durations are timed with `std::time::Instant`. With the `quanta` feature, set
+//! `GlobalSettings::time_source` to `TimeSource::Quanta` for a cheaper TSC-based clock, or to
+//! `TimeSource::Coarse` to read a timestamp cached by a background thread, trading resolution for speed.
+//!
+//! Servers and clients still on `http` 0.2 (e.g. hyper 0.14 or tonic before 0.12) are supported with the `http02`
+//! feature: wrap servers in `http02::Http02MetricsLayer` instead of `MetricsLayer`, while `MetricsChannel`
+//! accepts `http` 0.2 requests as is.
+//!
+//! ## Testing
+//!
+//! `metrics::snapshot()` returns the recorded gRPC metrics as typed values per service and method.
+//! With the `test-util` feature, the `assert_handled!` and `assert_started!` macros and `MetricsDiff`
+//! make it possible to assert on the effect of a test body without parsing the exposition format.
+//! `test_util::TestHarness` serves instrumented services in-process over an in-memory transport and
+//! hands out an instrumented client channel for end-to-end tests.
 use std::future::Future;
+use std::net::SocketAddr;
 use std::num::NonZeroUsize;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Instant;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use pin_project::pin_project;
-use tonic::codegen::http::{request, response};
+use tonic::codegen::http::uri::Scheme;
+use tonic::codegen::http::{header, request, response, Extensions, HeaderMap, HeaderValue};
+use tonic::server::NamedService;
 use tonic::Code;
+use tower::load::Load;
 use tower::{Layer, Service};
 
-use crate::metrics::{COUNTER_MP, GAUGE_MP, HISTOGRAM_MP};
-use crate::metrics::{COUNTER_SM, COUNTER_SMC, HISTOGRAM_SMC};
+use crate::body::Scanner;
+use crate::call_metrics::CallMetrics;
+use crate::catalog::{Known, UNIMPLEMENTED};
+use crate::clock::{thread_cpu_time, Timestamp};
+use crate::connect::ErrorScanner;
+use crate::connection::StreamGuard;
+use crate::events::CallEvent;
+use crate::frames::FrameChecker;
+use crate::grpc_web::TrailersScanner;
+use crate::in_flight::InFlightGuard;
+use crate::long_poll::PollMax;
+use crate::metrics::{
+    feature_enabled, LabelValues, RecordingFeature, HTTP_COUNTER, HTTP_HISTOGRAM,
+};
+use crate::metrics::{
+    get_settings, COUNTER_REJECTED, COUNTER_RETRIED, COUNTER_SLOW, COUNTER_SM, COUNTER_SMC,
+    COUNTER_TRANSPORT_ERRORS, HISTOGRAM_SMC,
+};
+use crate::metrics::{
+    NonGrpcRequests, ServerMetrics, UnparseablePaths, COUNTER_MP, GAUGE_MP, HISTOGRAM_MP,
+    IN_FLIGHT_MAX,
+};
+use crate::metrics::{
+    COUNTER_LONG_POLLS, CPU_HISTOGRAM, LAST_HANDLED, MAX_POLL, MSG_INTERVAL_HISTOGRAM,
+    PROXY_OVERHEAD_HISTOGRAM, REQUEST_AGE_HISTOGRAM, REQUEST_METADATA_HISTOGRAM,
+    RESPONSE_METADATA_HISTOGRAM, SEND_BLOCKED_HISTOGRAM,
+};
+use crate::observer::Observer;
+use crate::protocol::{code_from_http_status, Protocol};
+use crate::proxy::ProxyContext;
+use crate::stream_age::OpenCallGuard;
+use crate::tenants::TenantMetrics;
 
+#[cfg(feature = "alloc-tracking")]
+mod alloc;
+mod body;
+mod call_metrics;
+mod cardinality;
+mod catalog;
+#[cfg(feature = "client")]
 mod client;
+mod clock;
+#[cfg(feature = "serde")]
+mod config;
+mod connect;
+mod connection;
+#[cfg(feature = "client")]
+mod connectivity;
+mod delta;
+mod events;
+mod filter;
+mod frames;
+#[cfg(feature = "macros")]
+mod function;
+mod grpc_web;
+mod handles;
+#[cfg(feature = "health")]
+mod health;
+#[cfg(feature = "http02")]
+pub mod http02;
+mod in_flight;
+mod influx;
+mod interarrival;
+mod long_poll;
+mod merge;
 pub mod metrics;
+mod native;
+mod observer;
+mod percentile;
+mod protocol;
+mod proxy;
+mod rates;
+mod relabel;
+mod resource;
+pub mod rpcz;
+mod scrape;
+#[cfg(feature = "scrape-service")]
+pub mod scrape_service;
+mod self_check;
+mod series;
+mod sketch;
+mod slo;
+pub mod slowest;
+mod snapshot;
+mod stack;
+mod stream_age;
+mod tenants;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+mod textfile;
+mod tls;
+mod toggles;
 
+#[cfg(feature = "alloc-tracking")]
+pub use alloc::TrackingAllocator;
+pub use body::MetricsBody;
+#[cfg(feature = "client")]
 pub use client::MetricsChannel;
+pub use connection::{MetricsConnection, MetricsIncoming};
+pub use frames::{FrameCheckLayer, FrameCheckService};
+#[cfg(feature = "health")]
+pub use health::MetricsHealthReporter;
+pub use observer::{CallInfo, CallObserver};
+pub use scrape::{ScrapeBody, ScrapeFuture, ScrapeLayer, ScrapeService};
+pub use stack::{stack, MetricsStack, StackLayers};
+pub use tls::{HandshakeError, MetricsHandshake};
+#[cfg(feature = "macros")]
+pub use tonic_prometheus_layer_macros::instrument_grpc;
 
-#[derive(Clone, Default)]
-pub struct MetricsLayer {}
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::function::FunctionCall;
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MetricsLayer {
+    observer: Option<Observer>,
+    named_service: Option<&'static str>,
+}
 
 impl MetricsLayer {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Layer for a single generated server, e.g. `ServiceServer<T>`, that
+    /// labels its calls with the service name `S::NAME` fixed at construction
+    /// instead of splitting each request path.
+    ///
+    /// ```
+    /// use tonic_health::pb::health_server::HealthServer;
+    /// use tonic_health::server::HealthService;
+    /// use tower::Layer;
+    ///
+    /// let (_, health) = tonic_health::server::health_reporter();
+    /// let layer =
+    ///     tonic_prometheus_layer::MetricsLayer::for_named_service::<HealthServer<HealthService>>();
+    /// let health = layer.layer(health);
+    /// ```
+    ///
+    /// Paths outside of the service, which the server does not serve, are
+    /// labeled as usual.
+    pub fn for_named_service<S: NamedService>() -> Self {
+        Self {
+            named_service: Some(S::NAME),
+            ..Default::default()
+        }
+    }
+
+    /// Invoke `observer` for every call completed by the server.
+    pub fn with_observer(mut self, observer: impl CallObserver) -> Self {
+        self.observer = Some(Observer(Arc::new(observer)));
+        self
+    }
 }
 
 impl<S> Layer<S> for MetricsLayer {
     type Service = MetricsService<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        MetricsService { service: inner }
+        MetricsService {
+            service: inner,
+            observer: self.observer.clone(),
+            named_service: self.named_service,
+        }
+    }
+}
+
+/// Request extension naming the gRPC method that serves a request.
+///
+/// Layers that transcode other traffic into gRPC calls (e.g. an HTTP/JSON
+/// gateway) can insert it before the request reaches [`MetricsService`], so
+/// that the call is attributed to this service and method rather than to the
+/// request path.
+#[derive(Clone, Debug)]
+pub struct RpcMethod {
+    service: String,
+    method: String,
+}
+
+impl RpcMethod {
+    pub fn new(service: impl Into<String>, method: impl Into<String>) -> Self {
+        Self {
+            service: service.into(),
+            method: method.into(),
+        }
+    }
+}
+
+/// Response extension marking a call as rejected before reaching the
+/// handler, e.g. by an authentication or rate limiting layer between
+/// [`MetricsService`] and the service.
+///
+/// Such calls are recorded with their status as usual, and also counted in
+/// `grpc_server_rejected_total` by `reason`, so that they can be told apart
+/// from statuses returned by handlers.
+///
+/// ```
+/// use tonic_prometheus_layer::Rejected;
+///
+/// let mut resp = tonic::Status::resource_exhausted("rate limited").into_http();
+/// resp.extensions_mut().insert(Rejected::new("ratelimit"));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rejected {
+    reason: &'static str,
+}
+
+impl Rejected {
+    /// Mark the response as a rejection for `reason`, the value of the
+    /// `reason` label, e.g. `auth` or `ratelimit`.
+    pub fn new(reason: &'static str) -> Self {
+        Self { reason }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        self.reason
+    }
+}
+
+/// Request extension inserted by [`MetricsService`] with the gRPC service and
+/// method of the call, so that inner layers and handlers need not parse the
+/// request path again.
+///
+/// It is not inserted for requests that are not gRPC calls, see
+/// [`metrics::NonGrpcRequests`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GrpcCallInfo {
+    service: String,
+    method: String,
+}
+
+impl GrpcCallInfo {
+    /// The fully qualified service name, e.g. `grpc.health.v1.Health`.
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct MetricsService<S> {
     service: S,
+    observer: Option<Observer>,
+    named_service: Option<&'static str>,
+}
+
+impl<S> MetricsService<S> {
+    /// Get a reference to the inner service.
+    pub fn get_ref(&self) -> &S {
+        &self.service
+    }
+
+    /// Get a mutable reference to the inner service.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.service
+    }
+
+    /// Consume `self`, returning the inner service.
+    pub fn into_inner(self) -> S {
+        self.service
+    }
 }
 
 impl<S, B, C> Service<request::Request<B>> for MetricsService<S>
 where
     S: Service<request::Request<B>, Response = response::Response<C>>,
+    C: http_body::Body,
 {
-    type Response = S::Response;
+    type Response = response::Response<MetricsBody<C>>;
     type Error = S::Error;
     type Future = MetricsFuture<S::Future>;
 
@@ -162,18 +683,85 @@ where
         self.service.poll_ready(cx)
     }
 
-    fn call(&mut self, req: request::Request<B>) -> Self::Future {
+    fn call(&mut self, mut req: request::Request<B>) -> Self::Future {
         let method = req.method().to_string();
         let path = req.uri().path().to_owned();
-        let service_method_separator: Option<NonZeroUsize> = match path.chars().next() {
-            Some(first_char) if first_char == '/' => path[1..]
+        if req.extensions().get::<Recorded>().is_some() {
+            let mut future = MetricsFuture::new(method, path, None, self.service.call(req));
+            future.skip = true;
+            return future;
+        }
+        req.extensions_mut().insert(Recorded);
+
+        let named = self.named_service.filter(|name| {
+            path.strip_prefix('/')
+                .and_then(|p| p.strip_prefix(name))
+                .is_some_and(|p| p.starts_with('/'))
+        });
+        let service_method_separator: Option<NonZeroUsize> = match (named, path.chars().next()) {
+            (Some(name), _) => NonZeroUsize::new(name.len() + 1),
+            (None, Some('/')) => path[1..]
                 .find('/')
                 .map(|p| NonZeroUsize::new(p + 1).unwrap()),
             _ => None,
         };
+        let mut info = RequestInfo::new(&req);
+        info.observer = self.observer.clone();
+        let call_info = match (&info.rpc, service_method_separator) {
+            _ if info.protocol == Protocol::Http => None,
+            (Some(rpc), _) => Some(GrpcCallInfo {
+                service: rpc.service.clone(),
+                method: rpc.method.clone(),
+            }),
+            (None, Some(sep)) => Some(GrpcCallInfo {
+                service: path[1..sep.get()].to_owned(),
+                method: path[sep.get() + 1..].to_owned(),
+            }),
+            (None, None) => None,
+        };
+        if let Some(call_info) = call_info {
+            req.extensions_mut().insert(call_info);
+        }
+        if !get_settings().call_metrics.is_empty() && info.protocol != Protocol::Http {
+            let call_metrics = CallMetrics::default();
+            req.extensions_mut().insert(call_metrics.clone());
+            info.call_metrics = Some(call_metrics);
+        }
+        if get_settings().proxy_overhead && info.protocol != Protocol::Http {
+            let context = ProxyContext::default();
+            req.extensions_mut().insert(context.clone());
+            info.proxy = Some(context);
+        }
+        // Decided before the first poll takes the transcoded method out of `info`.
+        let settings = get_settings();
+        let skip = (info.protocol == Protocol::Http
+            && settings.non_grpc_requests == NonGrpcRequests::Skip)
+            || (info.rpc.is_none()
+                && service_method_separator.is_none()
+                && settings.unparseable_paths == UnparseablePaths::Skip);
         let f = self.service.call(req);
 
-        MetricsFuture::new(method, path, service_method_separator, f)
+        let mut future = MetricsFuture::new(method, path, service_method_separator, f);
+        future.info = info;
+        future.skip = skip;
+        future
+    }
+}
+
+/// Request extension marking a request as recorded by a [`MetricsService`],
+/// so that a second one nested inside it, e.g. in a preset [`stack`] and
+/// around a single service, passes the request through instead of recording
+/// the call twice.
+#[derive(Clone, Copy, Debug)]
+struct Recorded;
+
+/// Forwards the inner service's load, so the layer can sit under a
+/// `tower::balance` load balancer.
+impl<S: Load> Load for MetricsService<S> {
+    type Metric = S::Metric;
+
+    fn load(&self) -> Self::Metric {
+        self.service.load()
     }
 }
 
@@ -182,7 +770,11 @@ pub struct MetricsFuture<F> {
     method: String,
     path: String,
     service_method_separator: Option<NonZeroUsize>,
-    started_at: Option<Instant>,
+    info: RequestInfo,
+    call: Option<ServerCall>,
+    /// Whether the call is not recorded, because an outer [`MetricsService`]
+    /// records it or the settings skip requests like it.
+    skip: bool,
     #[pin]
     inner: F,
 }
@@ -195,7 +787,9 @@ impl<F> MetricsFuture<F> {
         inner: F,
     ) -> Self {
         Self {
-            started_at: None,
+            info: RequestInfo::default(),
+            call: None,
+            skip: false,
             inner,
             method,
             path,
@@ -207,56 +801,1288 @@ impl<F> MetricsFuture<F> {
 impl<F, B, E> Future for MetricsFuture<F>
 where
     F: Future<Output = Result<response::Response<B>, E>>,
+    B: http_body::Body,
 {
-    type Output = F::Output;
+    type Output = Result<response::Response<MetricsBody<B>>, E>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let this = self.project();
 
-        let (rpc_service, rpc_method) = match this.service_method_separator {
-            Some(sep) => (
-                &this.path[1..(*sep).into()],
-                &this.path[usize::from(*sep) + 1..],
-            ),
-            // If unparseable, say service is empty and method is the entire path
-            None => ("", this.path as &str),
+        let settings = get_settings();
+        if *this.skip {
+            return this
+                .inner
+                .poll(cx)
+                .map_ok(|resp| resp.map(|body| MetricsBody::new(body, None, None, None)));
+        }
+
+        if this.call.is_none() {
+            let unparseable = this.info.rpc.is_none() && this.service_method_separator.is_none();
+            let rpc = this.info.rpc.take();
+            let (rpc_service, rpc_method) = match (rpc, this.service_method_separator) {
+                (Some(rpc), _) => (rpc.service, rpc.method),
+                (None, Some(sep)) => (
+                    this.path[1..(*sep).into()].to_owned(),
+                    this.path[usize::from(*sep) + 1..].to_owned(),
+                ),
+                (None, None) => match settings.unparseable_paths {
+                    UnparseablePaths::Unknown => ("unknown".to_owned(), "unknown".to_owned()),
+                    // Say service is empty and method is the entire path
+                    _ => (String::new(), this.path.clone()),
+                },
+            };
+            let (rpc_service, rpc_method) = match catalog::known(&rpc_service, &rpc_method) {
+                Known::Service if !unparseable => (rpc_service, UNIMPLEMENTED.to_owned()),
+                Known::Neither if !unparseable => {
+                    (UNIMPLEMENTED.to_owned(), UNIMPLEMENTED.to_owned())
+                }
+                _ => (rpc_service, rpc_method),
+            };
+            let rpc_method = settings.method_label(&rpc_service, rpc_method);
+            let (rpc_service, rpc_method) = if series::admit(&rpc_service, &rpc_method) {
+                (rpc_service, rpc_method)
+            } else {
+                (series::OTHER.to_owned(), series::OTHER.to_owned())
+            };
+            let rpc_service = settings.service_names.apply(rpc_service);
+
+            *this.call = Some(ServerCall::start(
+                std::mem::take(this.method),
+                std::mem::take(this.path),
+                rpc_service,
+                rpc_method,
+                std::mem::take(this.info),
+            ));
+        }
+
+        // Handlers run on the polling thread, so the thread's CPU time spent in
+        // the poll is theirs.
+        let cpu_started = feature_enabled(RecordingFeature::CpuTime)
+            .then(thread_cpu_time)
+            .flatten();
+        #[cfg(feature = "alloc-tracking")]
+        let allocated_before = alloc::thread_allocated();
+        let poll_started = this.call.as_ref().and_then(ServerCall::poll_started);
+        let poll = this.inner.poll(cx);
+        if let (Some(started), Some(call)) = (poll_started, this.call.as_mut()) {
+            call.polled(started);
+        }
+        if let (Some(started), Some(call)) = (cpu_started, this.call.as_mut()) {
+            if let Some(now) = thread_cpu_time() {
+                call.cpu_time += now.saturating_sub(started);
+            }
+        }
+        #[cfg(feature = "alloc-tracking")]
+        if let (Some(before), Some(call)) = (allocated_before, this.call.as_mut()) {
+            let after = alloc::thread_allocated().unwrap_or(before);
+            *call.allocated.get_or_insert(0) += after - before;
+        }
+        let Poll::Ready(v) = poll else {
+            return Poll::Pending;
         };
+        let mut call = this
+            .call
+            .take()
+            .expect("MetricsFuture polled after completion");
+        let protocol = call.info.protocol;
 
-        let started_at = this.started_at.get_or_insert_with(|| {
-            GAUGE_MP.with_label_values(&[this.method, this.path]).inc();
-            COUNTER_SM
-                .with_label_values(&[rpc_service, rpc_method])
-                .inc();
+        Poll::Ready(match v {
+            Ok(resp) => {
+                call.http_status = Some(resp.status().as_u16());
+                call.rejected = resp.extensions().get::<Rejected>().map(Rejected::reason);
+                call.response_metadata_bytes = metadata_size(resp.headers());
 
-            Instant::now()
-        });
+                // Trailers-only responses carry the status in the headers; otherwise the
+                // status arrives in the trailers at the end of the body.
+                let mut status = grpc_status(resp.headers());
+                let scanner = match protocol {
+                    Protocol::Grpc | Protocol::Http => None,
+                    // Gateways usually translate the status to HTTP, so fall back to that.
+                    Protocol::Transcoded => {
+                        status = status.or(Some(code_from_http_status(resp.status().as_u16())));
+                        None
+                    }
+                    Protocol::GrpcWeb => Some(Scanner::GrpcWeb(TrailersScanner::new(false))),
+                    Protocol::GrpcWebText => Some(Scanner::GrpcWeb(TrailersScanner::new(true))),
+                    // Connect unary calls only put an error in the body when they fail.
+                    Protocol::Connect if resp.status().is_success() => {
+                        status = Some(Code::Ok);
+                        None
+                    }
+                    Protocol::Connect => {
+                        Some(Scanner::Connect(ErrorScanner::new(resp.status().as_u16())))
+                    }
+                };
+                let call = match status {
+                    Some(code) => {
+                        call.finish(code);
+                        None
+                    }
+                    None if resp.body().is_end_stream() => {
+                        call.finish(scanner.as_ref().map_or(Code::Ok, Scanner::finish));
+                        None
+                    }
+                    None => Some(call),
+                };
+                let frames = (protocol == Protocol::Grpc)
+                    .then(|| FrameChecker::new("response", resp.headers()));
+                Ok(resp.map(|body| MetricsBody::new(body, call, scanner, frames)))
+            }
+            Err(e) => {
+                call.fail();
+                Err(e)
+            }
+        })
+    }
+}
+
+/// Details taken from the request before it is handed to the inner service.
+#[derive(Default)]
+pub(crate) struct RequestInfo {
+    protocol: Protocol,
+    rpc: Option<RpcMethod>,
+    peer: Option<SocketAddr>,
+    request_bytes: Option<u64>,
+    /// Whether the request arrived over a cleartext connection, from its URI scheme.
+    cleartext: bool,
+    /// Value of the `transport` label.
+    transport: &'static str,
+    /// Value of the `priority` label.
+    priority: &'static str,
+    /// Value of the `caller` label.
+    caller: &'static str,
+    /// Value of the `authority` label.
+    authority: &'static str,
+    /// Values of `GlobalSettings::baggage_labels`.
+    baggage: Vec<&'static str>,
+    request_metadata_bytes: usize,
+    request_age: Option<Duration>,
+    /// The `grpc-previous-rpc-attempts` of a retried call.
+    previous_attempts: Option<u32>,
+    tenant: Option<&'static TenantMetrics>,
+    observer: Option<Observer>,
+    call_metrics: Option<CallMetrics>,
+    proxy: Option<ProxyContext>,
+    // Held until the call is dropped, to count it as active on its connection.
+    _stream: Option<StreamGuard>,
+}
+
+impl RequestInfo {
+    fn new<B>(req: &request::Request<B>) -> Self {
+        let rpc = req.extensions().get::<RpcMethod>().cloned();
+        let protocol = match rpc {
+            Some(_) => Protocol::Transcoded,
+            None => Protocol::detect(req.headers()),
+        };
 
-        if let Poll::Ready(v) = this.inner.poll(cx) {
-            let code = v.as_ref().map_or(Code::Unknown, |resp| {
-                resp.headers()
-                    .get("grpc-status")
-                    .map(|s| Code::from_bytes(s.as_bytes()))
-                    .unwrap_or(Code::Ok)
+        Self {
+            protocol,
+            rpc,
+            peer: connection::tcp_info(req.extensions()).and_then(|info| info.remote_addr()),
+            request_bytes: req
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse().ok()),
+            cleartext: req.uri().scheme() == Some(&Scheme::HTTP),
+            transport: transport(req.extensions()),
+            priority: get_settings()
+                .priority_label
+                .as_ref()
+                .map_or("", |label| label.value(req.headers())),
+            caller: get_settings()
+                .caller_label
+                .as_ref()
+                .map_or("", |label| label.value(req.headers())),
+            authority: get_settings().authority_label.as_ref().map_or("", |label| {
+                let authority = req.uri().authority().map(|a| a.as_str()).or_else(|| {
+                    req.headers()
+                        .get(header::HOST)
+                        .and_then(|v| v.to_str().ok())
+                });
+                label.value(authority)
+            }),
+            baggage: get_settings()
+                .baggage_labels
+                .iter()
+                .map(|label| label.value(req.headers()))
+                .collect(),
+            previous_attempts: req
+                .headers()
+                .get("grpc-previous-rpc-attempts")
+                .and_then(|v| v.to_str().ok()?.parse().ok())
+                .filter(|&n| n > 0),
+            request_metadata_bytes: metadata_size(req.headers()),
+            request_age: get_settings()
+                .request_age_header
+                .as_ref()
+                .and_then(|name| request_age(req.headers().get(name)?)),
+            tenant: tenants::tenant_of(req),
+            observer: None,
+            call_metrics: None,
+            proxy: None,
+            _stream: StreamGuard::track(req.extensions()),
+        }
+    }
+
+    /// Value of the `protocol` label.
+    fn protocol_label(&self) -> &'static str {
+        match self.protocol {
+            Protocol::Grpc if get_settings().protocol_label_transport => {
+                if self.cleartext {
+                    "h2c"
+                } else {
+                    "h2"
+                }
+            }
+            protocol => protocol.as_str(),
+        }
+    }
+}
+
+/// An in-flight server call, recorded when it is finished or dropped.
+pub(crate) struct ServerCall {
+    method: String,
+    path: String,
+    rpc_service: String,
+    rpc_method: String,
+    info: RequestInfo,
+    http_status: Option<u16>,
+    /// Reason of a [`Rejected`] response.
+    rejected: Option<&'static str>,
+    response_bytes: u64,
+    response_metadata_bytes: usize,
+    /// Thread CPU time spent polling the handler, if `GlobalSettings::cpu_time` is set.
+    cpu_time: Duration,
+    /// Time the response body waited for the transport to take the next frame,
+    /// if `GlobalSettings::send_blocked_time` is set.
+    send_blocked: Duration,
+    /// When the response body last yielded a data frame.
+    frame_sent_at: Option<Timestamp>,
+    /// When the response body yielded its previous message, if
+    /// `GlobalSettings::message_interval` is set.
+    message_sent_at: Option<Timestamp>,
+    /// Bytes allocated while polling the handler, if `TrackingAllocator` is installed.
+    #[cfg(feature = "alloc-tracking")]
+    allocated: Option<u64>,
+    /// Metrics in the registry chosen by `GlobalSettings::registry_selector`.
+    routed: Option<Arc<ServerMetrics>>,
+    /// Counts the call as in flight until it is recorded.
+    in_flight: Option<InFlightGuard>,
+    /// Counts the call in `grpc_server_in_flight_max` until it is recorded.
+    in_flight_max: Option<InFlightGuard>,
+    /// Longest poll of the method, if `GlobalSettings::long_poll_threshold` is set.
+    max_poll: Option<PollMax>,
+    /// Tracks the call for `grpc_server_open_stream_age_seconds` until it is recorded.
+    open: Option<OpenCallGuard>,
+    started_at: Timestamp,
+    done: bool,
+}
+
+impl ServerCall {
+    fn start(
+        method: String,
+        path: String,
+        rpc_service: String,
+        rpc_method: String,
+        info: RequestInfo,
+    ) -> Self {
+        let mut call = Self {
+            in_flight: feature_enabled(RecordingFeature::LegacyMetrics)
+                .then(|| GAUGE_MP.start(&method, &path)),
+            in_flight_max: None,
+            max_poll: None,
+            open: None,
+            method,
+            path,
+            rpc_service,
+            rpc_method,
+            info,
+            http_status: None,
+            rejected: None,
+            response_bytes: 0,
+            response_metadata_bytes: 0,
+            cpu_time: Duration::ZERO,
+            send_blocked: Duration::ZERO,
+            frame_sent_at: None,
+            message_sent_at: None,
+            #[cfg(feature = "alloc-tracking")]
+            allocated: None,
+            routed: None,
+            started_at: Timestamp::now(),
+            done: false,
+        };
+
+        self_check::init();
+        if !call.is_http() {
+            call.routed = metrics::routed_metrics(&call.rpc_service);
+            call.in_flight_max = Some(IN_FLIGHT_MAX.start(&call.rpc_service, &call.rpc_method));
+            if get_settings().long_poll_threshold.is_some() {
+                call.max_poll = Some(MAX_POLL.method(&call.rpc_service, &call.rpc_method));
+            }
+            call.open = stream_age::open(&call.rpc_service, &call.rpc_method);
+            rates::start();
+            interarrival::record(&call.rpc_service, &call.rpc_method);
+            match &call.routed {
+                Some(routed) => routed
+                    .started
+                    .with_label_values(&call.counter_labels(None))
+                    .inc(),
+                None => COUNTER_SM
+                    .with_label_values(&call.counter_labels(None))
+                    .inc(),
+            }
+            if let Some(tenant) = call.info.tenant {
+                tenant
+                    .metrics
+                    .started
+                    .with_label_values(&call.counter_labels(None))
+                    .inc();
+            }
+            if let Some(attempts) = call.info.previous_attempts {
+                let mut labels = call.labels(None);
+                let attempt = (attempts + 1).to_string();
+                if get_settings().retry_attempt_label {
+                    labels.push(&attempt);
+                }
+                COUNTER_RETRIED.with_label_values(&labels).inc();
+            }
+            if let Some(age) = call.info.request_age {
+                REQUEST_AGE_HISTOGRAM
+                    .with_label_values(&call.labels(None))
+                    .observe(age.as_secs_f64());
+            }
+            events::send(|| CallEvent::Started {
+                service: call.rpc_service.clone(),
+                method: call.rpc_method.clone(),
             });
-            let code_str = format!("{:?}", code);
-            let elapsed = Instant::now().duration_since(*started_at).as_secs_f64();
-            COUNTER_MP
-                .with_label_values(&[this.method, this.path])
+        }
+
+        call
+    }
+
+    /// Whether the call goes to the plain HTTP metrics instead of the gRPC ones.
+    fn is_http(&self) -> bool {
+        self.info.protocol == Protocol::Http
+            && get_settings().non_grpc_requests == NonGrpcRequests::Http
+    }
+
+    /// Values of the optional labels of the call.
+    fn label_values(&self) -> LabelValues<'_, '_> {
+        LabelValues {
+            protocol: self.info.protocol_label(),
+            transport: self.info.transport,
+            priority: self.info.priority,
+            authority: self.info.authority,
+            baggage: &self.info.baggage,
+            caller: self.info.caller,
+        }
+    }
+
+    /// Label values for the gRPC metrics, in registration order.
+    fn labels<'a>(&'a self, code: Option<&'a str>) -> Vec<&'a str> {
+        self.label_values()
+            .server(&self.rpc_service, &self.rpc_method, code)
+    }
+
+    /// Label values for the started and handled counters, which also carry `caller`.
+    fn counter_labels<'a>(&'a self, code: Option<&'a str>) -> Vec<&'a str> {
+        self.label_values()
+            .counter(&self.rpc_service, &self.rpc_method, code)
+    }
+
+    /// Label values for the handled counter, which also carries `grpc_result`.
+    fn handled_labels<'a>(&'a self, code: Code, code_str: &'a str) -> Vec<&'a str> {
+        self.label_values()
+            .handled(&self.rpc_service, &self.rpc_method, code, code_str)
+    }
+
+    /// Start of a poll of the handler, if `GlobalSettings::long_poll_threshold`
+    /// is set.
+    pub(crate) fn poll_started(&self) -> Option<Timestamp> {
+        self.max_poll.as_ref().map(|_| Timestamp::now())
+    }
+
+    /// Record a poll of the handler that started at `started`.
+    pub(crate) fn polled(&self, started: Timestamp) {
+        let (Some(max_poll), Some(threshold)) =
+            (&self.max_poll, get_settings().long_poll_threshold)
+        else {
+            return;
+        };
+        let elapsed = started.elapsed();
+        max_poll.observe(elapsed);
+        if elapsed > threshold {
+            COUNTER_LONG_POLLS
+                .with_label_values(&self.labels(None))
                 .inc();
-            COUNTER_SMC
-                .with_label_values(&[rpc_service, rpc_method, &code_str])
+        }
+    }
+
+    /// Record the time since the previous message of the response, if the
+    /// `message_interval` recording feature is on.
+    pub(crate) fn message_sent(&mut self) {
+        if !feature_enabled(RecordingFeature::MessageInterval) || self.is_http() {
+            return;
+        }
+        if let Some(previous) = self.message_sent_at.replace(Timestamp::now()) {
+            MSG_INTERVAL_HISTOGRAM
+                .with_label_values(&self.labels(None))
+                .observe(previous.elapsed().as_secs_f64());
+        }
+    }
+
+    pub(crate) fn finish(mut self, code: Code) {
+        self.record(code);
+    }
+
+    /// Finish the call after the inner service or body returned an error.
+    ///
+    /// Such calls are still reported as `Unknown`, but are also counted
+    /// separately so they can be told apart from application statuses.
+    pub(crate) fn fail(mut self) {
+        if !self.is_http() {
+            COUNTER_TRANSPORT_ERRORS
+                .with_label_values(&self.labels(None))
                 .inc();
+        }
+        self.record(Code::Unknown);
+    }
+
+    fn record(&mut self, code: Code) {
+        if std::mem::replace(&mut self.done, true) {
+            return;
+        }
+
+        let code_str = format!("{:?}", code);
+        let duration = self.started_at.elapsed();
+        let elapsed = duration.as_secs_f64();
+        let (method, path) = (&self.method, &self.path);
+        if feature_enabled(RecordingFeature::LegacyMetrics) {
+            COUNTER_MP.with_label_values(&[method, path]).inc();
             HISTOGRAM_MP
-                .with_label_values(&[this.method, this.path])
+                .with_label_values(&[method, path])
                 .observe(elapsed);
+        }
+        if self.is_http() {
+            // No status is left empty: the request was dropped before responding.
+            let status = self.http_status.map(|s| s.to_string()).unwrap_or_default();
+            let labels = [method.as_str(), path.as_str(), status.as_str()];
+            HTTP_COUNTER.with_label_values(&labels).inc();
+            HTTP_HISTOGRAM.with_label_values(&labels).observe(elapsed);
+        } else {
+            let labels = self.labels(Some(&code_str));
+            let observe = !get_settings().histogram_excluded_codes.contains(&code);
+            let (handled, handling) = match &self.routed {
+                Some(routed) => (&routed.handled, &routed.handling),
+                None => (&*COUNTER_SMC, &*HISTOGRAM_SMC),
+            };
+            handled
+                .with_label_values(&self.handled_labels(code, &code_str))
+                .inc();
+            if observe {
+                handling.with_label_values(&labels).observe(elapsed);
+                if self.routed.is_none() {
+                    native::observe(&labels, elapsed);
+                }
+                sketch::record(&self.rpc_service, &self.rpc_method, elapsed);
+            }
+            if let Some(tenant) = self.info.tenant {
+                tenant
+                    .metrics
+                    .handled
+                    .with_label_values(&self.handled_labels(code, &code_str))
+                    .inc();
+                if observe {
+                    tenant
+                        .metrics
+                        .handling
+                        .with_label_values(&labels)
+                        .observe(elapsed);
+                }
+            }
+            if feature_enabled(RecordingFeature::MetadataSize) {
+                let labels = self.labels(None);
+                REQUEST_METADATA_HISTOGRAM
+                    .with_label_values(&labels)
+                    .observe(self.info.request_metadata_bytes as f64);
+                RESPONSE_METADATA_HISTOGRAM
+                    .with_label_values(&labels)
+                    .observe(self.response_metadata_bytes as f64);
+            }
+            if feature_enabled(RecordingFeature::CpuTime) {
+                CPU_HISTOGRAM
+                    .with_label_values(&self.labels(None))
+                    .observe(self.cpu_time.as_secs_f64());
+            }
+            if feature_enabled(RecordingFeature::SendBlockedTime) {
+                SEND_BLOCKED_HISTOGRAM
+                    .with_label_values(&self.labels(None))
+                    .observe(self.send_blocked.as_secs_f64());
+            }
+            LAST_HANDLED
+                .with_label_values(&self.labels(None))
+                .set(rpcz::unix_seconds(SystemTime::now()));
+            if let Some(call_metrics) = &self.info.call_metrics {
+                call_metrics.flush(&self.rpc_service, &self.rpc_method);
+            }
+            if let Some(overhead) = self.info.proxy.as_ref().and_then(|p| p.overhead(duration)) {
+                PROXY_OVERHEAD_HISTOGRAM
+                    .with_label_values(&self.labels(None))
+                    .observe(overhead.as_secs_f64());
+            }
+            #[cfg(feature = "alloc-tracking")]
+            if let Some(allocated) = self.allocated {
+                metrics::ALLOC_HISTOGRAM
+                    .with_label_values(&self.labels(None))
+                    .observe(allocated as f64);
+            }
+
+            let call = CallInfo {
+                service: &self.rpc_service,
+                method: &self.rpc_method,
+                code,
+                duration,
+                request_bytes: self.info.request_bytes,
+                response_bytes: self.response_bytes,
+                peer: self.info.peer,
+            };
+            if let Some(reason) = self.rejected {
+                COUNTER_REJECTED
+                    .with_label_values(&self.labels(Some(reason)))
+                    .inc();
+            }
+            if let Some(threshold) = &get_settings().slow_requests {
+                if threshold.is_slow(call.service, call.method, duration) {
+                    COUNTER_SLOW.with_label_values(&self.labels(None)).inc();
+                }
+            }
+            if let Some(threshold) = &get_settings().slow_request_log {
+                if threshold.is_slow(call.service, call.method, duration) {
+                    tracing::warn!(
+                        grpc_service = call.service,
+                        grpc_method = call.method,
+                        grpc_code = ?code,
+                        ?duration,
+                        "slow gRPC call"
+                    );
+                }
+            }
+            events::send(|| CallEvent::Finished {
+                service: call.service.to_owned(),
+                method: call.method.to_owned(),
+                code,
+                duration,
+            });
+            rpcz::record(&call);
+            slo::record(call.service, call.method, code, duration);
+            slowest::record(&call);
+            if let Some(observer) = &self.info.observer {
+                observer.0.on_complete(&call);
+            }
+        }
+        self.in_flight.take();
+        self.in_flight_max.take();
+        self.open.take();
+    }
+}
+
+impl Drop for ServerCall {
+    fn drop(&mut self) {
+        // Dropped before completion: the caller cancelled the request.
+        self.record(Code::Cancelled);
+    }
+}
+
+/// Read the `grpc-status` out of a header or trailer map.
+pub(crate) fn grpc_status(headers: &HeaderMap) -> Option<Code> {
+    headers
+        .get("grpc-status")
+        .map(|s| Code::from_bytes(s.as_bytes()))
+}
+
+/// The kind of connection a request arrived on, from its connect info. TLS
+/// is not a transport of its own: it is reported as the socket underneath.
+fn transport(extensions: &Extensions) -> &'static str {
+    if connection::tcp_info(extensions).is_some() {
+        return "tcp";
+    }
+    #[cfg(unix)]
+    if connection::uds_info(extensions).is_some() {
+        return "uds";
+    }
+    "other"
+}
+
+/// Time since the Unix timestamp in `sent_at`, unless it is in the future
+/// (e.g. because of clock skew between client and server).
+fn request_age(sent_at: &HeaderValue) -> Option<Duration> {
+    let sent_at = sent_at.to_str().ok()?.parse::<f64>().ok()?;
+    let sent_at = UNIX_EPOCH + Duration::try_from_secs_f64(sent_at).ok()?;
+    SystemTime::now().duration_since(sent_at).ok()
+}
+
+/// Size of the metadata in `headers`, counting names and values.
+pub(crate) fn metadata_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http_body_util::{BodyExt, Full};
+    use tonic::codegen::http::HeaderValue;
+    use tonic::codegen::Bytes;
+    use tower::{service_fn, ServiceExt};
+
+    /// A service answering every call with `grpc-status` 0 in its headers.
+    pub(crate) fn grpc_ok_service() -> impl Service<
+        request::Request<()>,
+        Response = response::Response<Full<Bytes>>,
+        Error = std::convert::Infallible,
+    > {
+        grpc_service("0")
+    }
+
+    /// A service answering every call with the given `grpc-status` in its headers.
+    pub(crate) fn grpc_service(
+        status: &'static str,
+    ) -> impl Service<
+        request::Request<()>,
+        Response = response::Response<Full<Bytes>>,
+        Error = std::convert::Infallible,
+    > {
+        service_fn(move |_req: request::Request<()>| async move {
+            let mut resp = response::Response::new(Full::new(Bytes::from_static(b"data")));
+            resp.headers_mut()
+                .insert("grpc-status", HeaderValue::from_static(status));
+            Ok(resp)
+        })
+    }
+
+    /// A gRPC call to `path`.
+    pub(crate) fn grpc_request(path: &str) -> request::Request<()> {
+        request::Request::builder()
+            .uri(path)
+            .header("content-type", "application/grpc")
+            .body(())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn attributes_transcoded_calls() {
+        let service = service_fn(|_req: request::Request<()>| async {
+            let mut resp = response::Response::new(Full::new(Bytes::from_static(b"{}")));
+            *resp.status_mut() = tonic::codegen::http::StatusCode::NOT_FOUND;
+            Ok::<_, std::convert::Infallible>(resp)
+        });
+        let mut req = request::Request::builder()
+            .uri("/v1/things/1")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(RpcMethod::new("test.Gateway", "GetThing"));
+
+        let resp = MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+        resp.into_body().collect().await.unwrap();
+        let got = metrics::snapshot();
+        let call = got.server("test.Gateway", "GetThing").unwrap();
+        assert_eq!(call.handled(Code::Unimplemented), 1);
+        assert!(got.server("v1", "things/1").is_none());
+    }
+
+    #[tokio::test]
+    async fn transcoded_calls_without_separator() {
+        let _settings = metrics::test_settings(|settings| {
+            settings.unparseable_paths = UnparseablePaths::Skip;
+        });
+        let service = service_fn(|_req: request::Request<()>| async {
+            // Pending for a few polls, each of which sees the call again.
+            for _ in 0..3 {
+                tokio::task::yield_now().await;
+            }
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            let body = Full::new(Bytes::from_static(b"data"))
+                .with_trailers(async move { Some(Ok(trailers)) });
+
+            Ok::<_, std::convert::Infallible>(response::Response::new(body))
+        });
+        let mut req = request::Request::builder()
+            .uri("/things")
+            .header(header::CONTENT_TYPE, "application/grpc")
+            .body(())
+            .unwrap();
+        req.extensions_mut()
+            .insert(RpcMethod::new("test.Transcoded", "Get"));
+
+        let resp = MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+        resp.into_body().collect().await.unwrap();
+        let got = metrics::snapshot();
+        let call = got.server("test.Transcoded", "Get").unwrap();
+        assert_eq!(call.handled(Code::Ok), 1);
+        assert_eq!(call.handled(Code::Cancelled), 0);
+    }
+
+    #[tokio::test]
+    async fn status_from_trailers() {
+        let service = service_fn(|_req: request::Request<()>| async {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("5"));
+            let body = Full::new(Bytes::from_static(b"data"))
+                .with_trailers(async move { Some(Ok(trailers)) });
+
+            Ok::<_, std::convert::Infallible>(response::Response::new(body))
+        });
+        let req = request::Request::builder()
+            .uri("/test.Trailers/Check")
+            .body(())
+            .unwrap();
+
+        let resp = MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+        let got = metrics::snapshot();
+        assert_eq!(
+            got.server("test.Trailers", "Check")
+                .unwrap()
+                .handled_total(),
+            0
+        );
+
+        resp.into_body().collect().await.unwrap();
+        let got = metrics::snapshot();
+        assert_eq!(
+            got.server("test.Trailers", "Check")
+                .unwrap()
+                .handled(Code::NotFound),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_transport_errors() {
+        let service = service_fn(|_req: request::Request<()>| async {
+            Err::<response::Response<Full<Bytes>>, _>(std::io::Error::other("stream reset"))
+        });
+        let req = grpc_request("/test.TransportError/Get");
+
+        let res = MetricsLayer::new().layer(service).oneshot(req).await;
+        assert!(res.is_err());
+        let errors = COUNTER_TRANSPORT_ERRORS.with_label_values(&["test.TransportError", "Get"]);
+        assert_eq!(errors.get(), 1);
+        let got = metrics::snapshot();
+        assert_eq!(
+            got.server("test.TransportError", "Get")
+                .unwrap()
+                .handled(Code::Unknown),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn logs_slow_calls() {
+        /// Collects the methods of the slow call warnings logged on this thread.
+        #[derive(Clone, Default)]
+        struct Warnings(Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl tracing::field::Visit for Warnings {
+            fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                if field.name() == "grpc_method" {
+                    self.0.lock().unwrap().push(value.to_owned());
+                }
+            }
+
+            fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn std::fmt::Debug) {}
+        }
+
+        impl tracing::Subscriber for Warnings {
+            fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+                tracing::span::Id::from_u64(1)
+            }
+            fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+            fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+            fn event(&self, event: &tracing::Event<'_>) {
+                if *event.metadata().level() == tracing::Level::WARN {
+                    event.record(&mut self.clone());
+                }
+            }
+            fn enter(&self, _: &tracing::span::Id) {}
+            fn exit(&self, _: &tracing::span::Id) {}
+        }
+
+        let _settings = metrics::test_settings(|settings| {
+            settings.slow_request_log = Some(metrics::SlowThreshold::new(Duration::ZERO).method(
+                "test.SlowLog",
+                "Fast",
+                Duration::MAX,
+            ));
+        });
+        let warnings = Warnings::default();
+        let _subscriber = tracing::subscriber::set_default(warnings.clone());
+        for method in ["Fast", "Slow"] {
+            let service = grpc_ok_service();
+            let req = grpc_request(&format!("/test.SlowLog/{method}"));
+
+            MetricsLayer::new()
+                .layer(service)
+                .oneshot(req)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(*warnings.0.lock().unwrap(), ["Slow"]);
+    }
+
+    #[tokio::test]
+    async fn counts_slow_calls() {
+        let _settings = metrics::test_settings(|settings| {
+            settings.slow_requests = Some(metrics::SlowThreshold::new(Duration::ZERO).method(
+                "test.SlowCount",
+                "Fast",
+                Duration::MAX,
+            ));
+        });
+        for method in ["Fast", "Slow"] {
+            let service = grpc_ok_service();
+            let req = grpc_request(&format!("/test.SlowCount/{method}"));
+
+            MetricsLayer::new()
+                .layer(service)
+                .oneshot(req)
+                .await
+                .unwrap();
+        }
+
+        let slow = |method| COUNTER_SLOW.with_label_values(&["test.SlowCount", method]);
+        assert_eq!(slow("Fast").get(), 0);
+        assert_eq!(slow("Slow").get(), 1);
+    }
+
+    #[tokio::test]
+    async fn records_metadata_size() {
+        let _settings = metrics::test_settings(|settings| {
+            settings.metadata_size = true;
+        });
+        let service = service_fn(|_req: request::Request<()>| async {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            let body = Full::new(Bytes::from_static(b"data"))
+                .with_trailers(async move { Some(Ok(trailers)) });
+
+            Ok::<_, std::convert::Infallible>(response::Response::new(body))
+        });
+        let req = request::Request::builder()
+            .uri("/test.Metadata/Get")
+            .header("content-type", "application/grpc")
+            .header("authorization", "x".repeat(100))
+            .body(())
+            .unwrap();
+
+        let resp = MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+        resp.into_body().collect().await.unwrap();
+
+        let labels = ["test.Metadata", "Get"];
+        let request = REQUEST_METADATA_HISTOGRAM.with_label_values(&labels);
+        assert_eq!(request.get_sample_sum(), (12 + 16 + 13 + 100) as f64);
+        let response = RESPONSE_METADATA_HISTOGRAM.with_label_values(&labels);
+        assert_eq!(response.get_sample_sum(), (11 + 1) as f64);
+    }
+
+    #[tokio::test]
+    async fn records_request_age() {
+        let _settings = metrics::test_settings(|settings| {
+            settings.request_age_header = Some("x-sent-at".to_owned());
+        });
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        for (method, sent_at) in [("Past", now - Duration::from_secs(2)), ("Future", now * 2)] {
+            let service = grpc_ok_service();
+            let mut req = grpc_request(&format!("/test.Age/{method}"));
+            req.headers_mut().insert(
+                "x-sent-at",
+                HeaderValue::from_str(&sent_at.as_secs_f64().to_string()).unwrap(),
+            );
+
+            MetricsLayer::new()
+                .layer(service)
+                .oneshot(req)
+                .await
+                .unwrap();
+        }
+
+        let past = REQUEST_AGE_HISTOGRAM.with_label_values(&["test.Age", "Past"]);
+        assert_eq!(past.get_sample_count(), 1);
+        assert!((2.0..3.0).contains(&past.get_sample_sum()));
+        // Clock skew must not be recorded as a negative or zero age.
+        let future = REQUEST_AGE_HISTOGRAM.with_label_values(&["test.Age", "Future"]);
+        assert_eq!(future.get_sample_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn excludes_codes_from_histogram() {
+        let _settings = metrics::test_settings(|settings| {
+            settings.histogram_excluded_codes = vec![Code::Cancelled];
+        });
+        for status in ["0", "1"] {
+            let service = grpc_service(status);
+            let req = grpc_request("/test.Excluded/Get");
+
+            MetricsLayer::new()
+                .layer(service)
+                .oneshot(req)
+                .await
+                .unwrap();
+        }
+
+        let observed = |code| {
             HISTOGRAM_SMC
-                .with_label_values(&[rpc_service, rpc_method, &code_str])
-                .observe(elapsed);
-            GAUGE_MP.with_label_values(&[this.method, this.path]).dec();
+                .with_label_values(&["test.Excluded", "Get", code])
+                .get_sample_count()
+        };
+        assert_eq!(observed("Ok"), 1);
+        assert_eq!(observed("Cancelled"), 0);
+        let got = metrics::snapshot();
+        let call = got.server("test.Excluded", "Get").unwrap();
+        assert_eq!(call.handled(Code::Cancelled), 1);
+    }
 
-            Poll::Ready(v)
-        } else {
-            Poll::Pending
+    #[tokio::test]
+    async fn collapses_methods() {
+        let _settings = metrics::test_settings(|settings| {
+            settings.collapsed_methods = ["test.Proxy".to_owned()].into();
+        });
+        for uri in [
+            "/test.Proxy/First",
+            "/test.Proxy/Second",
+            "/test.Direct/Get",
+        ] {
+            let service = grpc_ok_service();
+            let req = grpc_request(uri);
+
+            MetricsLayer::new()
+                .layer(service)
+                .oneshot(req)
+                .await
+                .unwrap();
+        }
+
+        let got = metrics::snapshot();
+        assert_eq!(got.server("test.Proxy", "*").unwrap().handled(Code::Ok), 2);
+        assert!(got.server("test.Proxy", "First").is_none());
+        assert_eq!(
+            got.server("test.Direct", "Get").unwrap().handled(Code::Ok),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn records_message_interval() {
+        /// A streaming response body yielding its frames one by one.
+        struct Frames(std::collections::VecDeque<http_body::Frame<Bytes>>);
+
+        impl http_body::Body for Frames {
+            type Data = Bytes;
+            type Error = std::convert::Infallible;
+
+            fn poll_frame(
+                mut self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+            ) -> Poll<Option<Result<http_body::Frame<Bytes>, Self::Error>>> {
+                Poll::Ready(self.0.pop_front().map(Ok))
+            }
+        }
+
+        let _settings = metrics::test_settings(|settings| {
+            settings.message_interval = true;
+        });
+        let service = service_fn(|_req: request::Request<()>| async {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            let mut frames: std::collections::VecDeque<_> = (0..3)
+                .map(|_| http_body::Frame::data(Bytes::from_static(b"message")))
+                .collect();
+            frames.push_back(http_body::Frame::trailers(trailers));
+            Ok::<_, std::convert::Infallible>(response::Response::new(Frames(frames)))
+        });
+        let req = grpc_request("/test.Interval/Watch");
+
+        let mut body = MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap()
+            .into_body();
+        while body.frame().await.is_some() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let interval = MSG_INTERVAL_HISTOGRAM.with_label_values(&["test.Interval", "Watch"]);
+        assert_eq!(interval.get_sample_count(), 2);
+        assert!(interval.get_sample_sum() >= 0.02);
+    }
+
+    #[cfg(not(feature = "client"))]
+    #[tokio::test]
+    async fn records_without_client() {
+        let service = grpc_ok_service();
+        let req = grpc_request("/test.ServerOnly/Get");
+
+        MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+        let got = metrics::snapshot();
+        assert_eq!(
+            got.server("test.ServerOnly", "Get")
+                .unwrap()
+                .handled(Code::Ok),
+            1
+        );
+        assert!(got.client_methods().next().is_none());
+        assert!(!metrics::encode_to_string()
+            .unwrap()
+            .contains("grpc_client_"));
+    }
+
+    #[tokio::test]
+    async fn finishes_at_end_of_stream() {
+        let service = service_fn(|_req: request::Request<()>| async {
+            let body = Full::new(Bytes::from_static(b"data"));
+            Ok::<_, std::convert::Infallible>(response::Response::new(body))
+        });
+        let req = request::Request::builder()
+            .uri("/test.EndOfStream/Get")
+            .body(())
+            .unwrap();
+
+        let mut body = MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap()
+            .into_body();
+        // Like hyper, stop polling once the body reports its end.
+        while !http_body::Body::is_end_stream(&body) {
+            body.frame().await.unwrap().unwrap();
+        }
+        drop(body);
+
+        let got = metrics::snapshot();
+        let call = got.server("test.EndOfStream", "Get").unwrap();
+        assert_eq!(call.handled(Code::Ok), 1);
+        assert_eq!(call.handled(Code::Cancelled), 0);
+    }
+
+    #[tokio::test]
+    async fn records_connect_errors() {
+        let service = service_fn(|_req: request::Request<()>| async {
+            let body = Full::new(Bytes::from_static(
+                br#"{"code":"not_found","message":"no"}"#,
+            ));
+            let mut resp = response::Response::new(body);
+            *resp.status_mut() = tonic::codegen::http::StatusCode::NOT_FOUND;
+            resp.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            Ok::<_, std::convert::Infallible>(resp)
+        });
+        let req = request::Request::builder()
+            .uri("/test.Connect/Get")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("connect-protocol-version", "1")
+            .body(())
+            .unwrap();
+
+        let mut body = MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap()
+            .into_body();
+        while !http_body::Body::is_end_stream(&body) {
+            body.frame().await.unwrap().unwrap();
+        }
+        drop(body);
+
+        let got = metrics::snapshot();
+        let call = got.server("test.Connect", "Get").unwrap();
+        assert_eq!(call.handled(Code::NotFound), 1);
+        assert_eq!(call.handled_total(), 1);
+    }
+
+    #[tokio::test]
+    async fn routes_to_selected_registry() {
+        static SELECTED: once_cell::sync::Lazy<prometheus::Registry> =
+            once_cell::sync::Lazy::new(prometheus::Registry::new);
+        let _settings = metrics::test_settings(|settings| {
+            settings.service_names = metrics::ServiceNames::StripPackage;
+            settings.registry_selector = Some(|service| {
+                assert_eq!(service, "Routed");
+                &SELECTED
+            });
+        });
+        let service = grpc_ok_service();
+        let req = grpc_request("/test.routing.Routed/Get");
+
+        let resp = MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+        resp.into_body().collect().await.unwrap();
+
+        let routed = metrics::Snapshot::from_registry(&SELECTED);
+        let call = routed.server("Routed", "Get").unwrap();
+        assert_eq!(call.started, 1);
+        assert_eq!(call.handled(Code::Ok), 1);
+        assert!(metrics::snapshot().server("Routed", "Get").is_none());
+    }
+
+    #[tokio::test]
+    async fn routes_clones_of_global_registry_to_it() {
+        static CLONE: once_cell::sync::Lazy<prometheus::Registry> =
+            once_cell::sync::Lazy::new(|| metrics::get_settings().registry.clone());
+        let _settings = metrics::test_settings(|settings| {
+            settings.registry_selector = Some(|_| &CLONE);
+        });
+        // The global families are registered before the selector picks the clone.
+        once_cell::sync::Lazy::force(&COUNTER_SM);
+        once_cell::sync::Lazy::force(&COUNTER_SMC);
+        once_cell::sync::Lazy::force(&HISTOGRAM_SMC);
+        let service = grpc_ok_service();
+        let req = grpc_request("/test.routing.Cloned/Get");
+
+        let resp = MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+        resp.into_body().collect().await.unwrap();
+
+        let call = metrics::snapshot();
+        let call = call.server("test.routing.Cloned", "Get").unwrap();
+        assert_eq!(call.started, 1);
+        assert_eq!(call.handled(Code::Ok), 1);
+    }
+
+    #[tokio::test]
+    async fn observer_sees_completed_calls() {
+        let service = grpc_ok_service();
+        let req = grpc_request("/test.Observer/Check");
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let layer = MetricsLayer::new().with_observer(move |call: &CallInfo<'_>| {
+            let call = (call.service.to_owned(), call.method.to_owned(), call.code);
+            tx.lock().unwrap().send(call).unwrap();
+        });
+        layer.layer(service).oneshot(req).await.unwrap();
+
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            ("test.Observer".to_owned(), "Check".to_owned(), Code::Ok)
+        );
+    }
+
+    #[tokio::test]
+    async fn inserts_call_info() {
+        let service = service_fn(|req: request::Request<()>| async move {
+            let info = req.extensions().get::<GrpcCallInfo>().unwrap();
+            assert_eq!((info.service(), info.method()), ("test.Info", "Get"));
+            Ok::<_, std::convert::Infallible>(response::Response::new(Full::<Bytes>::default()))
+        });
+        let req = grpc_request("/test.Info/Get");
+
+        MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn counts_rejected() {
+        let service = service_fn(|_req: request::Request<()>| async {
+            let mut resp = tonic::Status::unauthenticated("no token").into_http();
+            resp.extensions_mut().insert(Rejected::new("auth"));
+            Ok::<_, std::convert::Infallible>(resp)
+        });
+        let req = grpc_request("/test.Rejected/Get");
+
+        MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+        let rejected = COUNTER_REJECTED.with_label_values(&["test.Rejected", "Get", "auth"]);
+        assert_eq!(rejected.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn named_service() {
+        /// A service mounted under a prefix, which path splitting alone cuts
+        /// at the wrong slash.
+        struct Named;
+        impl NamedService for Named {
+            const NAME: &'static str = "mount/test.Named";
         }
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let service = service_fn(move |req: request::Request<()>| {
+            let info = req.extensions().get::<GrpcCallInfo>().unwrap();
+            let call = (info.service().to_owned(), info.method().to_owned());
+            tx.send(call).unwrap();
+            grpc_ok_service().oneshot(req)
+        });
+        let layer = MetricsLayer::for_named_service::<Named>();
+        for path in ["/mount/test.Named/Get/Sub", "/other.Named/Get"] {
+            let resp = layer
+                .layer(service.clone())
+                .oneshot(grpc_request(path))
+                .await
+                .unwrap();
+            resp.into_body().collect().await.unwrap();
+        }
+
+        let calls: Vec<_> = rx.try_iter().collect();
+        assert_eq!(
+            calls,
+            [
+                ("mount/test.Named".to_owned(), "Get/Sub".to_owned()),
+                // Paths outside of the service are split as usual.
+                ("other.Named".to_owned(), "Get".to_owned()),
+            ]
+        );
+        let got = metrics::snapshot();
+        let named = got.server("mount/test.Named", "Get/Sub").unwrap();
+        assert_eq!(named.handled(Code::Ok), 1);
+        assert!(got.server("mount", "test.Named/Get/Sub").is_none());
+        assert_eq!(
+            got.server("other.Named", "Get").unwrap().handled(Code::Ok),
+            1
+        );
     }
 }
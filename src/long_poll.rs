@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Gauge, Metric, MetricFamily, MetricType};
+
+use crate::metrics::label_pair;
+
+/// Gauge of the longest single poll of each method's handler since the
+/// previous scrape, by gRPC service and method. Gathering does not reset it.
+///
+/// Calls keep a [`PollMax`] handle, so polls only touch an atomic; the gauge
+/// is built from the handles when the registry is gathered.
+#[derive(Clone)]
+pub(crate) struct MaxPoll {
+    desc: Desc,
+    maxima: Arc<RwLock<HashMap<(String, String), PollMax>>>,
+}
+
+/// Longest poll of a method since the previous [`MaxPoll::reset`], in
+/// nanoseconds.
+#[derive(Clone, Default)]
+pub(crate) struct PollMax(Arc<AtomicU64>);
+
+impl PollMax {
+    pub(crate) fn observe(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.0.fetch_max(nanos, Ordering::Relaxed);
+    }
+}
+
+impl MaxPoll {
+    pub(crate) fn new(name: &str, help: &str) -> prometheus::Result<Self> {
+        let desc = Desc::new(
+            name.to_owned(),
+            help.to_owned(),
+            vec!["grpc_service".to_owned(), "grpc_method".to_owned()],
+            HashMap::new(),
+        )?;
+        Ok(Self {
+            desc,
+            maxima: Default::default(),
+        })
+    }
+
+    /// The handle recording the polls of a method.
+    pub(crate) fn method(&self, service: &str, method: &str) -> PollMax {
+        let key = (service.to_owned(), method.to_owned());
+        let existing = self.maxima.read().unwrap().get(&key).cloned();
+        existing.unwrap_or_else(|| self.maxima.write().unwrap().entry(key).or_default().clone())
+    }
+
+    /// Start the next interval of the longest polls, once the gauge has been
+    /// scraped.
+    pub(crate) fn reset(&self) {
+        for max in self.maxima.read().unwrap().values() {
+            max.0.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Collector for MaxPoll {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let maxima = self.maxima.read().unwrap();
+        let metrics: Vec<Metric> = maxima
+            .iter()
+            .map(|((service, method), max)| {
+                let nanos = max.0.load(Ordering::Relaxed);
+                let mut gauge = Gauge::default();
+                gauge.set_value(Duration::from_nanos(nanos).as_secs_f64());
+
+                let mut metric = Metric::default();
+                metric.set_label(
+                    vec![
+                        label_pair(&self.desc.variable_labels[0], service),
+                        label_pair(&self.desc.variable_labels[1], method),
+                    ]
+                    .into(),
+                );
+                metric.set_gauge(gauge);
+                metric
+            })
+            .collect();
+
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::GAUGE);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resets_on_scrape() {
+        let max_poll = MaxPoll::new("test_max_poll_seconds", "test").unwrap();
+        let handle = max_poll.method("a", "b");
+        handle.observe(Duration::from_millis(30));
+        max_poll.method("a", "b").observe(Duration::from_millis(10));
+
+        let value = |max_poll: &MaxPoll| {
+            max_poll.collect()[0].get_metric()[0]
+                .get_gauge()
+                .get_value()
+        };
+        assert_eq!(value(&max_poll), 0.03);
+        max_poll.reset();
+        assert_eq!(value(&max_poll), 0.0);
+    }
+
+    #[test]
+    fn gathers_between_scrapes_keep_the_max() {
+        let max_poll = MaxPoll::new("test_max_poll_seconds", "test").unwrap();
+        let registry = prometheus::Registry::new();
+        registry.register(Box::new(max_poll.clone())).unwrap();
+        let value = || registry.gather()[0].get_metric()[0].get_gauge().get_value();
+
+        max_poll.method("a", "b").observe(Duration::from_millis(30));
+        crate::metrics::Snapshot::from_registry(&registry);
+        assert_eq!(value(), 0.03);
+        max_poll.reset();
+        assert_eq!(value(), 0.0);
+    }
+}
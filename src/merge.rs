@@ -0,0 +1,158 @@
+use std::borrow::Cow;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::collections::HashSet;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use prometheus::proto::MetricFamily;
+use prometheus::Registry;
+
+use crate::metrics::{get_settings, override_help, scraped};
+use crate::relabel::relabel;
+
+/// Registries of other libraries exported along with the layer's metrics.
+static MERGED: RwLock<Vec<Registry>> = RwLock::new(Vec::new());
+
+/// Export the metrics of `registry`, e.g. one of another library, along with
+/// the layer's metrics from [`encode_to_string`](crate::metrics::encode_to_string)
+/// and the other encoders.
+///
+/// Families registered under the same name in several registries are merged
+/// into one. If their types differ, or a series with the same labels is in
+/// several of them, the one registered first wins, with the layer's own
+/// registry first.
+pub fn merge_registry(registry: Registry) {
+    MERGED.write().unwrap().push(registry);
+}
+
+/// Gather the layer's registry and the merged ones.
+pub(crate) fn gather() -> Vec<MetricFamily> {
+    let own = get_settings().registry.gather();
+    let merged = MERGED.read().unwrap();
+    let mut families = if merged.is_empty() {
+        own
+    } else {
+        merge(std::iter::once(own).chain(merged.iter().map(Registry::gather)))
+    };
+
+    override_help(&mut families);
+    relabel(&mut families);
+    timestamp(&mut families);
+    namespace(&mut families);
+    families
+}
+
+/// Gather the metrics for a scrape, see [`gather`]. Unlike other gathers, e.g.
+/// snapshots, it starts the next interval of the gauges of the highest values
+/// since the previous scrape.
+pub(crate) fn scrape() -> Vec<MetricFamily> {
+    let families = gather();
+    scraped();
+    families
+}
+
+/// The name the family `name` is exported under, with the
+/// `GlobalSettings::namespace` prefix.
+pub(crate) fn exported_name(name: &str) -> Cow<'_, str> {
+    match &get_settings().namespace {
+        Some(namespace) => Cow::Owned(format!("{namespace}_{name}")),
+        None => Cow::Borrowed(name),
+    }
+}
+
+/// Prefix the names of `families` with `GlobalSettings::namespace`, if set.
+pub(crate) fn namespace(families: &mut [MetricFamily]) {
+    if get_settings().namespace.is_none() {
+        return;
+    }
+
+    for family in families {
+        let name = exported_name(family.get_name()).into_owned();
+        family.set_name(name);
+    }
+}
+
+/// Attach the current time to the samples of `families`, if
+/// `GlobalSettings::sample_timestamps` is set.
+pub(crate) fn timestamp(families: &mut [MetricFamily]) {
+    if !get_settings().sample_timestamps {
+        return;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+    for family in families {
+        for metric in family.mut_metric().iter_mut() {
+            metric.set_timestamp_ms(now);
+        }
+    }
+}
+
+fn merge(gathered: impl IntoIterator<Item = Vec<MetricFamily>>) -> Vec<MetricFamily> {
+    let mut families: BTreeMap<String, MetricFamily> = BTreeMap::new();
+    for family in gathered.into_iter().flatten() {
+        match families.entry(family.get_name().to_owned()) {
+            Entry::Vacant(entry) => {
+                entry.insert(family);
+            }
+            Entry::Occupied(mut entry) => {
+                let existing = entry.get_mut();
+                if existing.get_field_type() != family.get_field_type() {
+                    continue;
+                }
+                let seen: HashSet<Vec<(String, String)>> =
+                    existing.get_metric().iter().map(label_pairs).collect();
+                for metric in family.get_metric() {
+                    if !seen.contains(&label_pairs(metric)) {
+                        existing.mut_metric().push(metric.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    families.into_values().collect()
+}
+
+fn label_pairs(metric: &prometheus::proto::Metric) -> Vec<(String, String)> {
+    let mut pairs: Vec<_> = metric
+        .get_label()
+        .iter()
+        .map(|l| (l.get_name().to_owned(), l.get_value().to_owned()))
+        .collect();
+    pairs.sort();
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{opts, IntCounterVec, IntGauge};
+
+    #[test]
+    fn merges_families() {
+        let (first, second) = (Registry::new(), Registry::new());
+        let counter = |registry: &Registry, value| {
+            let counter = IntCounterVec::new(opts!("merge_total", "test"), &["lib"]).unwrap();
+            registry.register(Box::new(counter.clone())).unwrap();
+            counter.with_label_values(&[value]).inc();
+            counter.with_label_values(&["shared"]).inc_by(2);
+        };
+        counter(&first, "first");
+        counter(&second, "second");
+        let gauge = IntGauge::new("merge_gauge", "test").unwrap();
+        second.register(Box::new(gauge)).unwrap();
+
+        let families = merge([first.gather(), second.gather()]);
+        assert_eq!(families.len(), 2);
+        assert_eq!(families[0].get_name(), "merge_gauge");
+        let values: Vec<_> = families[1]
+            .get_metric()
+            .iter()
+            .map(|m| m.get_label()[0].get_value())
+            .collect();
+        assert_eq!(values, ["first", "shared", "second"]);
+    }
+}
@@ -1,8 +1,47 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use once_cell::sync::{Lazy, OnceCell};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{LabelPair, MetricFamily};
 use prometheus::{
-    histogram_opts, opts, register_counter_vec_with_registry, register_gauge_vec_with_registry,
-    register_histogram_vec_with_registry, CounterVec, GaugeVec, HistogramVec, TextEncoder,
+    histogram_opts, opts, Gauge, GaugeVec, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Registry, TextEncoder,
+};
+use tonic::codegen::http::HeaderMap;
+use tonic::Code;
+
+use crate::in_flight::InFlight;
+use crate::long_poll::MaxPoll;
+use crate::merge;
+
+pub use crate::call_metrics::{CallMetric, CallMetrics};
+pub use crate::cardinality::{
+    cardinality_report, render_cardinality_report, FamilyCardinality, LabelCardinality,
 };
+pub use crate::catalog::{describe_methods, MethodDescriptor, MethodKind};
+pub use crate::delta::{encode_deltas_to_string, gather_deltas};
+pub use crate::events::{subscribe, CallEvent};
+pub use crate::filter::{encode_filtered, MetricFilter};
+pub use crate::handles::{server_handled, server_handling, server_started, MethodMetrics};
+pub use crate::influx::encode_influx_line_protocol;
+pub use crate::merge::merge_registry;
+pub use crate::native::{encode_protobuf, PROTOBUF_FORMAT};
+pub use crate::percentile::percentile;
+pub use crate::proxy::ProxyContext;
+pub use crate::rates::{rates, Rate};
+pub use crate::relabel::{Labels, Relabel};
+#[cfg(feature = "opentelemetry")]
+pub use crate::resource::resource_registry;
+pub use crate::resource::{resource_labels, RESOURCE_ATTRIBUTES};
+pub use crate::sketch::{bucket_report, quantile, BucketReport};
+pub use crate::slo::Slo;
+pub use crate::snapshot::{snapshot, HistogramSnapshot, MethodSnapshot, Snapshot};
+pub use crate::tenants::{encode_tenant_to_string, tenant_registry, Tenant};
+pub use crate::textfile::{start_textfile_writer, TextfileWriter};
+pub use crate::toggles::{feature_enabled, set_feature_enabled, RecordingFeature};
 
 static GLOBAL_SETTINGS: OnceCell<GlobalSettings> = OnceCell::new();
 
@@ -13,30 +52,28 @@ static GLOBAL_SETTINGS: OnceCell<GlobalSettings> = OnceCell::new();
 // *_SM: Broken out by gRPC service name and method name.
 // *_SMC: Broken out by gRPC service name, method name, and result status code.
 
-pub(crate) static COUNTER_MP: Lazy<CounterVec> = Lazy::new(|| {
+pub(crate) static COUNTER_MP: Lazy<IntCounterVec> = Lazy::new(|| {
     let opts = opts!(COUNTER_MP_NAME, COUNTER_DESCRIPTION);
-    register_counter_vec_with_registry!(opts, &["method", "path"], get_settings().registry.clone())
-        .expect("failed to init counter_mp")
+    register(IntCounterVec::new(opts, &["method", "path"]).expect("failed to init counter_mp"))
 });
 
-pub(crate) static COUNTER_SM: Lazy<CounterVec> = Lazy::new(|| {
+pub(crate) static COUNTER_SM: Lazy<IntCounterVec> = Lazy::new(|| {
     let opts = opts!(COUNTER_SM_NAME, COUNTER_STARTED_DESCRIPTION);
-    register_counter_vec_with_registry!(
-        opts,
-        &["grpc_service", "grpc_method"],
-        get_settings().registry.clone()
+    register(
+        IntCounterVec::new(opts, &counter_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init counter_sm"),
     )
-    .expect("failed to init counter_sm")
 });
 
-pub(crate) static COUNTER_SMC: Lazy<CounterVec> = Lazy::new(|| {
+pub(crate) static COUNTER_SMC: Lazy<IntCounterVec> = Lazy::new(|| {
     let opts = opts!(COUNTER_SMC_NAME, COUNTER_DESCRIPTION);
-    register_counter_vec_with_registry!(
-        opts,
-        &["grpc_service", "grpc_method", "grpc_code"],
-        get_settings().registry.clone()
+    register(
+        IntCounterVec::new(
+            opts,
+            &handled_labels(&["grpc_service", "grpc_method", "grpc_code"]),
+        )
+        .expect("failed to init counter_smc"),
     )
-    .expect("failed to init counter_smc")
 });
 
 pub(crate) static HISTOGRAM_MP: Lazy<HistogramVec> = Lazy::new(|| {
@@ -45,12 +82,7 @@ pub(crate) static HISTOGRAM_MP: Lazy<HistogramVec> = Lazy::new(|| {
         HISTOGRAM_DESCRIPTION,
         get_settings().histogram_buckets.clone()
     );
-    register_histogram_vec_with_registry!(
-        opts,
-        &["method", "path"],
-        get_settings().registry.clone()
-    )
-    .expect("failed to init histogram_mp")
+    register(HistogramVec::new(opts, &["method", "path"]).expect("failed to init histogram_mp"))
 });
 
 pub(crate) static HISTOGRAM_SMC: Lazy<HistogramVec> = Lazy::new(|| {
@@ -59,20 +91,348 @@ pub(crate) static HISTOGRAM_SMC: Lazy<HistogramVec> = Lazy::new(|| {
         HISTOGRAM_DESCRIPTION,
         get_settings().histogram_buckets.clone()
     );
-    register_histogram_vec_with_registry!(
-        opts,
-        &["grpc_service", "grpc_method", "grpc_code"],
-        get_settings().registry.clone()
+    register(
+        HistogramVec::new(
+            opts,
+            &server_labels(&["grpc_service", "grpc_method", "grpc_code"]),
+        )
+        .expect("failed to init histogram_smc"),
+    )
+});
+
+pub(crate) static REQUEST_METADATA_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        REQUEST_METADATA_HISTOGRAM_NAME,
+        REQUEST_METADATA_HISTOGRAM_DESCRIPTION,
+        DEFAULT_METADATA_SIZE_BUCKETS.to_vec()
+    );
+    register(
+        HistogramVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init request_metadata_histogram"),
+    )
+});
+
+pub(crate) static RESPONSE_METADATA_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        RESPONSE_METADATA_HISTOGRAM_NAME,
+        RESPONSE_METADATA_HISTOGRAM_DESCRIPTION,
+        DEFAULT_METADATA_SIZE_BUCKETS.to_vec()
+    );
+    register(
+        HistogramVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init response_metadata_histogram"),
+    )
+});
+
+pub(crate) static REQUEST_AGE_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        REQUEST_AGE_HISTOGRAM_NAME,
+        REQUEST_AGE_HISTOGRAM_DESCRIPTION,
+        get_settings().histogram_buckets.clone()
+    );
+    register(
+        HistogramVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init request_age_histogram"),
     )
-    .expect("failed to init histogram_smc")
 });
 
-pub(crate) static GAUGE_MP: Lazy<GaugeVec> = Lazy::new(|| {
-    let opts = opts!(GAUGE_MP_NAME, GAUGE_DESCRIPTION);
-    register_gauge_vec_with_registry!(opts, &["method", "path"], get_settings().registry.clone())
-        .expect("failed to init gauge")
+pub(crate) static CPU_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        CPU_HISTOGRAM_NAME,
+        CPU_HISTOGRAM_DESCRIPTION,
+        get_settings().histogram_buckets.clone()
+    );
+    register(
+        HistogramVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init cpu_histogram"),
+    )
+});
+
+pub(crate) static INTERARRIVAL_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        INTERARRIVAL_HISTOGRAM_NAME,
+        INTERARRIVAL_HISTOGRAM_DESCRIPTION,
+        DEFAULT_INTERARRIVAL_BUCKETS.to_vec()
+    );
+    register(
+        HistogramVec::new(opts, &["grpc_service", "grpc_method"])
+            .expect("failed to init interarrival_histogram"),
+    )
+});
+
+pub(crate) static SEND_BLOCKED_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        SEND_BLOCKED_HISTOGRAM_NAME,
+        SEND_BLOCKED_HISTOGRAM_DESCRIPTION,
+        get_settings().histogram_buckets.clone()
+    );
+    register(
+        HistogramVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init send_blocked_histogram"),
+    )
+});
+
+pub(crate) static MSG_INTERVAL_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        MSG_INTERVAL_HISTOGRAM_NAME,
+        MSG_INTERVAL_HISTOGRAM_DESCRIPTION,
+        DEFAULT_MSG_INTERVAL_BUCKETS.to_vec()
+    );
+    register(
+        HistogramVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init msg_interval_histogram"),
+    )
+});
+
+pub(crate) static PROXY_OVERHEAD_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        PROXY_OVERHEAD_HISTOGRAM_NAME,
+        PROXY_OVERHEAD_HISTOGRAM_DESCRIPTION,
+        get_settings().histogram_buckets.clone()
+    );
+    register(
+        HistogramVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init proxy_overhead_histogram"),
+    )
+});
+
+pub(crate) static LAST_HANDLED: Lazy<GaugeVec> = Lazy::new(|| {
+    let opts = opts!(LAST_HANDLED_NAME, LAST_HANDLED_DESCRIPTION);
+    register(
+        GaugeVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init last_handled"),
+    )
+});
+
+pub(crate) static COUNTER_TRANSPORT_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = opts!(
+        COUNTER_TRANSPORT_ERRORS_NAME,
+        COUNTER_TRANSPORT_ERRORS_DESCRIPTION
+    );
+    register(
+        IntCounterVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init counter_transport_errors"),
+    )
 });
 
+pub(crate) static COUNTER_SLOW: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = opts!(COUNTER_SLOW_NAME, COUNTER_SLOW_DESCRIPTION);
+    register(
+        IntCounterVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init counter_slow"),
+    )
+});
+
+pub(crate) static COUNTER_LONG_POLLS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = opts!(COUNTER_LONG_POLLS_NAME, COUNTER_LONG_POLLS_DESCRIPTION);
+    register(
+        IntCounterVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init counter_long_polls"),
+    )
+});
+
+pub(crate) static MAX_POLL: Lazy<MaxPoll> = Lazy::new(|| {
+    register(MaxPoll::new(MAX_POLL_NAME, MAX_POLL_DESCRIPTION).expect("failed to init max_poll"))
+});
+
+pub(crate) static COUNTER_REJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = opts!(COUNTER_REJECTED_NAME, COUNTER_REJECTED_DESCRIPTION);
+    register(
+        IntCounterVec::new(
+            opts,
+            &server_labels(&["grpc_service", "grpc_method", "reason"]),
+        )
+        .expect("failed to init counter_rejected"),
+    )
+});
+
+pub(crate) static FRAME_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = opts!(FRAME_ERRORS_NAME, FRAME_ERRORS_DESCRIPTION);
+    register(IntCounterVec::new(opts, &["direction"]).expect("failed to init frame_errors"))
+});
+
+pub(crate) static COUNTER_RETRIED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = opts!(COUNTER_RETRIED_NAME, COUNTER_RETRIED_DESCRIPTION);
+    let mut labels = server_labels(&["grpc_service", "grpc_method"]);
+    if get_settings().retry_attempt_label {
+        labels.push("attempt");
+    }
+    register(IntCounterVec::new(opts, &labels).expect("failed to init counter_retried"))
+});
+
+pub(crate) static METHOD_INFO: Lazy<IntGaugeVec> = Lazy::new(|| {
+    let opts = opts!(METHOD_INFO_NAME, METHOD_INFO_DESCRIPTION);
+    register(
+        IntGaugeVec::new(opts, &["grpc_service", "grpc_method", "grpc_type"])
+            .expect("failed to init method_info"),
+    )
+});
+
+pub(crate) static GAUGE_MP: Lazy<InFlight> = Lazy::new(|| {
+    register(
+        InFlight::new(GAUGE_MP_NAME, GAUGE_DESCRIPTION, ["method", "path"])
+            .expect("failed to init gauge"),
+    )
+});
+
+pub(crate) static IN_FLIGHT_MAX: Lazy<InFlight> = Lazy::new(|| {
+    register(
+        InFlight::high_water(
+            IN_FLIGHT_MAX_NAME,
+            IN_FLIGHT_MAX_DESCRIPTION,
+            ["grpc_service", "grpc_method"],
+        )
+        .expect("failed to init in_flight_max"),
+    )
+});
+
+/// Start the next interval of the gauges of the highest values since the
+/// previous scrape, once the metrics have been gathered for one.
+pub(crate) fn scraped() {
+    if let Some(in_flight_max) = Lazy::get(&IN_FLIGHT_MAX) {
+        in_flight_max.reset();
+    }
+    if let Some(max_poll) = Lazy::get(&MAX_POLL) {
+        max_poll.reset();
+    }
+}
+
+/// The started, handled and handling time gRPC server metrics, registered in a
+/// registry other than the global one.
+pub(crate) struct ServerMetrics {
+    pub(crate) started: IntCounterVec,
+    pub(crate) handled: IntCounterVec,
+    pub(crate) handling: HistogramVec,
+}
+
+impl ServerMetrics {
+    pub(crate) fn register(registry: &Registry) -> Self {
+        let started = IntCounterVec::new(
+            opts!(COUNTER_SM_NAME, COUNTER_STARTED_DESCRIPTION),
+            &counter_labels(&["grpc_service", "grpc_method"]),
+        )
+        .expect("failed to init started");
+        let handled = IntCounterVec::new(
+            opts!(COUNTER_SMC_NAME, COUNTER_DESCRIPTION),
+            &handled_labels(&["grpc_service", "grpc_method", "grpc_code"]),
+        )
+        .expect("failed to init handled");
+        let handling = HistogramVec::new(
+            histogram_opts!(
+                HISTOGRAM_SMC_NAME,
+                HISTOGRAM_DESCRIPTION,
+                get_settings().histogram_buckets.clone()
+            ),
+            &server_labels(&["grpc_service", "grpc_method", "grpc_code"]),
+        )
+        .expect("failed to init handling");
+
+        ServerMetrics {
+            started: register_in(registry, started),
+            handled: register_in(registry, handled),
+            handling: register_in(registry, handling),
+        }
+    }
+}
+
+/// Server metrics of the registries returned by `GlobalSettings::registry_selector`,
+/// by registry address, or `None` for those sharing the global registry.
+static ROUTED: Lazy<Mutex<HashMap<usize, Option<Arc<ServerMetrics>>>>> =
+    Lazy::new(Default::default);
+
+/// The server metrics to record calls of `service` in, if the registry
+/// selector picks a registry other than the global one.
+pub(crate) fn routed_metrics(service: &str) -> Option<Arc<ServerMetrics>> {
+    let settings = get_settings();
+    let registry = (settings.registry_selector?)(service);
+    if std::ptr::eq(registry, &settings.registry) {
+        return None;
+    }
+
+    // Probing is done under the lock, so that probes do not collide.
+    let mut routed = ROUTED.lock().unwrap();
+    routed
+        .entry(registry as *const Registry as usize)
+        .or_insert_with(|| {
+            (!same_registry(registry, &settings.registry))
+                .then(|| Arc::new(ServerMetrics::register(registry)))
+        })
+        .clone()
+}
+
+/// Whether `registry` and `other` share their state, e.g. because one is a
+/// clone of the other, or both are `prometheus::default_registry()`.
+///
+/// Registries do not expose their identity, so a probe is registered in one
+/// and unregistered from the other.
+fn same_registry(registry: &Registry, other: &Registry) -> bool {
+    let probe = RegistryProbe::new();
+    if registry.register(Box::new(probe.clone())).is_err() {
+        return false;
+    }
+    let shared = other.unregister(Box::new(probe.clone())).is_ok();
+    if !shared {
+        let _ = registry.unregister(Box::new(probe));
+    }
+    shared
+}
+
+/// Collector without series, see [`same_registry`].
+#[derive(Clone)]
+struct RegistryProbe(Desc);
+
+impl RegistryProbe {
+    fn new() -> Self {
+        let desc = Desc::new(
+            "tonic_prometheus_layer_registry_probe".to_owned(),
+            "Probe for registry identity.".to_owned(),
+            Vec::new(),
+            HashMap::new(),
+        )
+        .expect("failed to init registry probe");
+        Self(desc)
+    }
+}
+
+impl Collector for RegistryProbe {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.0]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        Vec::new()
+    }
+}
+
+// Plain HTTP server metrics, see NonGrpcRequests::Http.
+
+pub(crate) static HTTP_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = opts!(HTTP_COUNTER_NAME, HTTP_COUNTER_DESCRIPTION);
+    register(
+        IntCounterVec::new(opts, &["method", "path", "status"])
+            .expect("failed to init http_counter"),
+    )
+});
+
+pub(crate) static HTTP_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        HTTP_HISTOGRAM_NAME,
+        HTTP_HISTOGRAM_DESCRIPTION,
+        get_settings().histogram_buckets.clone()
+    );
+    register(
+        HistogramVec::new(opts, &["method", "path", "status"])
+            .expect("failed to init http_histogram"),
+    )
+});
+
+const HTTP_COUNTER_NAME: &str = "http_server_requests_total";
+const HTTP_HISTOGRAM_NAME: &str = "http_server_request_duration_seconds";
+
+const HTTP_COUNTER_DESCRIPTION: &str =
+    "Total number of non-gRPC HTTP requests completed on the server.";
+const HTTP_HISTOGRAM_DESCRIPTION: &str = "Histogram for tracking server HTTP request duration";
+
 // Backward compatibility metrics
 const COUNTER_MP_NAME: &str = "function_calls_total";
 const HISTOGRAM_MP_NAME: &str = "function_calls_duration_seconds";
@@ -80,81 +440,547 @@ const GAUGE_MP_NAME: &str = "function_calls_concurrent";
 
 // Metrics that mirror the ones commonly used in Go:
 // https://github.com/grpc-ecosystem/go-grpc-middleware/blob/main/providers/prometheus/server_metrics.go
-const COUNTER_SM_NAME: &str = "grpc_server_started_total";
-const COUNTER_SMC_NAME: &str = "grpc_server_handled_total";
-const HISTOGRAM_SMC_NAME: &str = "grpc_server_handling_seconds";
+pub(crate) const COUNTER_SM_NAME: &str = "grpc_server_started_total";
+pub(crate) const COUNTER_SMC_NAME: &str = "grpc_server_handled_total";
+pub(crate) const HISTOGRAM_SMC_NAME: &str = "grpc_server_handling_seconds";
+const COUNTER_TRANSPORT_ERRORS_NAME: &str = "grpc_server_transport_errors_total";
+const COUNTER_SLOW_NAME: &str = "grpc_server_slow_requests_total";
+const COUNTER_LONG_POLLS_NAME: &str = "grpc_server_long_polls_total";
+const MAX_POLL_NAME: &str = "grpc_server_max_poll_seconds";
+const COUNTER_REJECTED_NAME: &str = "grpc_server_rejected_total";
+const FRAME_ERRORS_NAME: &str = "grpc_server_frame_errors_total";
+const METHOD_INFO_NAME: &str = "grpc_server_method_info";
+const COUNTER_RETRIED_NAME: &str = "grpc_server_retried_requests_total";
+const REQUEST_AGE_HISTOGRAM_NAME: &str = "grpc_server_request_age_seconds";
+const REQUEST_METADATA_HISTOGRAM_NAME: &str = "grpc_server_request_metadata_bytes";
+const RESPONSE_METADATA_HISTOGRAM_NAME: &str = "grpc_server_response_metadata_bytes";
+const CPU_HISTOGRAM_NAME: &str = "grpc_server_cpu_seconds";
+const LAST_HANDLED_NAME: &str = "grpc_server_last_handled_timestamp_seconds";
+const SEND_BLOCKED_HISTOGRAM_NAME: &str = "grpc_server_send_blocked_seconds";
+const MSG_INTERVAL_HISTOGRAM_NAME: &str = "grpc_server_msg_interval_seconds";
+const PROXY_OVERHEAD_HISTOGRAM_NAME: &str = "grpc_proxy_overhead_seconds";
+const INTERARRIVAL_HISTOGRAM_NAME: &str = "grpc_server_interarrival_seconds";
+const IN_FLIGHT_MAX_NAME: &str = "grpc_server_in_flight_max";
 
 const COUNTER_STARTED_DESCRIPTION: &str = "Total number of RPCs started on the server.";
 const COUNTER_DESCRIPTION: &str =
     "Total number of RPCs completed on the server, regardless of success or failure.";
 const HISTOGRAM_DESCRIPTION: &str = "Histogram for tracking server RPC duration";
+const COUNTER_TRANSPORT_ERRORS_DESCRIPTION: &str =
+    "Total number of server RPCs that failed with a transport error instead of a gRPC status.";
+const COUNTER_SLOW_DESCRIPTION: &str =
+    "Total number of server RPCs that took longer than the configured slow threshold.";
+const COUNTER_REJECTED_DESCRIPTION: &str =
+    "Total number of server RPCs rejected by a layer before reaching the handler, by reason.";
+const FRAME_ERRORS_DESCRIPTION: &str =
+    "Total number of malformed gRPC message frames in server RPC requests and responses.";
+const REQUEST_METADATA_HISTOGRAM_DESCRIPTION: &str =
+    "Histogram of the size of server RPC request headers, in bytes";
+const RESPONSE_METADATA_HISTOGRAM_DESCRIPTION: &str =
+    "Histogram of the size of server RPC response headers and trailers, in bytes";
+const REQUEST_AGE_HISTOGRAM_DESCRIPTION: &str =
+    "Histogram of the time between the client sending a request and the server receiving it";
+const LAST_HANDLED_DESCRIPTION: &str =
+    "Unix time at which the server last completed an RPC of each method.";
+const SEND_BLOCKED_HISTOGRAM_DESCRIPTION: &str =
+    "Histogram of the time server RPC responses waited for the transport to send their messages";
+const PROXY_OVERHEAD_HISTOGRAM_DESCRIPTION: &str =
+    "Histogram of the duration of server RPCs minus that of the client RPCs they made";
+const MSG_INTERVAL_HISTOGRAM_DESCRIPTION: &str =
+    "Histogram of the time between consecutive messages sent in server RPC responses";
+const INTERARRIVAL_HISTOGRAM_DESCRIPTION: &str =
+    "Histogram of the time between the starts of consecutive server RPCs of each method";
+const COUNTER_LONG_POLLS_DESCRIPTION: &str =
+    "Total number of polls of server RPC handlers that took longer than the configured threshold.";
+const MAX_POLL_DESCRIPTION: &str =
+    "Longest single poll of a server RPC handler of each method since the previous scrape.";
+const IN_FLIGHT_MAX_DESCRIPTION: &str =
+    "Highest number of RPCs in flight on the server at once since the previous scrape.";
+const CPU_HISTOGRAM_DESCRIPTION: &str =
+    "Histogram of the thread CPU time spent polling server RPC handlers";
+const COUNTER_RETRIED_DESCRIPTION: &str =
+    "Total number of RPCs started on the server that are retries of earlier attempts.";
+const METHOD_INFO_DESCRIPTION: &str = "Methods served by the server, always 1.";
 const GAUGE_DESCRIPTION: &str = "Gauge for tracking concurrent function calls";
 
+pub(crate) static RECORDING_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = opts!(RECORDING_ERRORS_NAME, RECORDING_ERRORS_DESCRIPTION);
+    let errors = IntCounterVec::new(opts, &["metric"]).expect("failed to init recording_errors");
+    // Not through `register`, which reports its failures here.
+    let _ = get_settings().registry.register(Box::new(errors.clone()));
+    errors
+});
+
+const RECORDING_ERRORS_NAME: &str = "grpc_metrics_recording_errors_total";
+const RECORDING_ERRORS_DESCRIPTION: &str =
+    "Total number of errors registering or recording the gRPC metrics, by metric.";
+
+// Exporter metrics, set by encode_to_string.
+
+static SCRAPE_DURATION: Lazy<Gauge> = Lazy::new(|| {
+    let opts = opts!(SCRAPE_DURATION_NAME, SCRAPE_DURATION_DESCRIPTION);
+    register(Gauge::with_opts(opts).expect("failed to init scrape_duration"))
+});
+
+static SCRAPE_SIZE: Lazy<IntGauge> = Lazy::new(|| {
+    let opts = opts!(SCRAPE_SIZE_NAME, SCRAPE_SIZE_DESCRIPTION);
+    register(IntGauge::with_opts(opts).expect("failed to init scrape_size"))
+});
+
+const SCRAPE_DURATION_NAME: &str = "grpc_metrics_scrape_duration_seconds";
+const SCRAPE_DURATION_DESCRIPTION: &str =
+    "Time taken to gather and encode the metrics in the previous export.";
+const SCRAPE_SIZE_NAME: &str = "grpc_metrics_scrape_size_bytes";
+const SCRAPE_SIZE_DESCRIPTION: &str = "Size of the previous export of the metrics, in bytes.";
+
+// gRPC server connection metrics, see MetricsIncoming.
+
+pub(crate) static CONNECTIONS_OPENED: Lazy<IntCounter> = Lazy::new(|| {
+    let opts = opts!(CONNECTIONS_OPENED_NAME, CONNECTIONS_OPENED_DESCRIPTION);
+    register(IntCounter::with_opts(opts).expect("failed to init connections_opened"))
+});
+
+pub(crate) static CONNECTIONS_OPEN: Lazy<IntGauge> = Lazy::new(|| {
+    let opts = opts!(CONNECTIONS_OPEN_NAME, CONNECTIONS_OPEN_DESCRIPTION);
+    register(IntGauge::with_opts(opts).expect("failed to init connections_open"))
+});
+
+pub(crate) static CONNECTIONS_HISTOGRAM: Lazy<Histogram> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        CONNECTIONS_HISTOGRAM_NAME,
+        CONNECTIONS_HISTOGRAM_DESCRIPTION,
+        get_settings().connection_duration_buckets.clone()
+    );
+    register(Histogram::with_opts(opts).expect("failed to init connections_histogram"))
+});
+
+pub(crate) static CONNECTIONS_MAX_STREAMS: Lazy<Histogram> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        CONNECTIONS_MAX_STREAMS_NAME,
+        CONNECTIONS_MAX_STREAMS_DESCRIPTION,
+        DEFAULT_STREAM_BUCKETS.to_vec()
+    );
+    register(Histogram::with_opts(opts).expect("failed to init connections_max_streams"))
+});
+
+pub(crate) static CONNECTIONS_REQUESTS: Lazy<Histogram> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        CONNECTIONS_REQUESTS_NAME,
+        CONNECTIONS_REQUESTS_DESCRIPTION,
+        DEFAULT_CONNECTION_REQUESTS_BUCKETS.to_vec()
+    );
+    register(Histogram::with_opts(opts).expect("failed to init connections_requests"))
+});
+
+const CONNECTIONS_OPENED_NAME: &str = "grpc_server_connections_opened_total";
+const CONNECTIONS_OPEN_NAME: &str = "grpc_server_connections_open";
+const CONNECTIONS_HISTOGRAM_NAME: &str = "grpc_server_connection_duration_seconds";
+const CONNECTIONS_MAX_STREAMS_NAME: &str = "grpc_server_connection_max_concurrent_streams";
+const CONNECTIONS_REQUESTS_NAME: &str = "grpc_server_connection_requests";
+
+const CONNECTIONS_OPENED_DESCRIPTION: &str = "Total number of connections accepted by the server.";
+const CONNECTIONS_OPEN_DESCRIPTION: &str = "Number of currently open server connections.";
+const CONNECTIONS_HISTOGRAM_DESCRIPTION: &str = "Histogram for tracking server connection lifetime";
+const CONNECTIONS_MAX_STREAMS_DESCRIPTION: &str =
+    "Histogram of the highest number of concurrent requests per connection, observed when it closes";
+const CONNECTIONS_REQUESTS_DESCRIPTION: &str =
+    "Histogram of the number of requests served per connection, observed when it closes";
+
+// TLS handshake metrics, see MetricsHandshake.
+
+pub(crate) static TLS_HANDSHAKE_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        TLS_HANDSHAKE_HISTOGRAM_NAME,
+        TLS_HANDSHAKE_HISTOGRAM_DESCRIPTION,
+        get_settings().histogram_buckets.clone()
+    );
+    register(HistogramVec::new(opts, &["result"]).expect("failed to init tls_handshake_histogram"))
+});
+
+pub(crate) static TLS_HANDSHAKE_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = opts!(
+        TLS_HANDSHAKE_FAILURES_NAME,
+        TLS_HANDSHAKE_FAILURES_DESCRIPTION
+    );
+    register(IntCounterVec::new(opts, &["class"]).expect("failed to init tls_handshake_failures"))
+});
+
+const TLS_HANDSHAKE_HISTOGRAM_NAME: &str = "grpc_server_tls_handshake_seconds";
+const TLS_HANDSHAKE_FAILURES_NAME: &str = "grpc_server_tls_handshake_failures_total";
+
+const TLS_HANDSHAKE_HISTOGRAM_DESCRIPTION: &str =
+    "Histogram for tracking server TLS handshake duration";
+const TLS_HANDSHAKE_FAILURES_DESCRIPTION: &str = "Total number of failed server TLS handshakes.";
+
+// Allocation metrics, see TrackingAllocator.
+
+#[cfg(feature = "alloc-tracking")]
+pub(crate) static ALLOC_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = histogram_opts!(
+        ALLOC_HISTOGRAM_NAME,
+        ALLOC_HISTOGRAM_DESCRIPTION,
+        DEFAULT_ALLOC_BUCKETS.to_vec()
+    );
+    register(
+        HistogramVec::new(opts, &server_labels(&["grpc_service", "grpc_method"]))
+            .expect("failed to init alloc_histogram"),
+    )
+});
+
+#[cfg(feature = "alloc-tracking")]
+const ALLOC_HISTOGRAM_NAME: &str = "grpc_server_alloc_bytes";
+#[cfg(feature = "alloc-tracking")]
+const ALLOC_HISTOGRAM_DESCRIPTION: &str =
+    "Histogram of the bytes allocated while polling server RPC handlers";
+
+// gRPC health metrics, see MetricsHealthReporter.
+
+#[cfg(feature = "health")]
+pub(crate) static SERVING_STATUS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    let opts = opts!(SERVING_STATUS_NAME, SERVING_STATUS_DESCRIPTION);
+    register(IntGaugeVec::new(opts, &["grpc_service"]).expect("failed to init serving_status"))
+});
+
+#[cfg(feature = "health")]
+const SERVING_STATUS_NAME: &str = "grpc_server_serving_status";
+#[cfg(feature = "health")]
+const SERVING_STATUS_DESCRIPTION: &str =
+    "Health serving status of each gRPC service: 1 if serving, 0 otherwise.";
+
 // gRPC client metrics
 
-pub(crate) static CLIENT_COUNTER_STARTED: Lazy<CounterVec> = Lazy::new(|| {
+#[cfg(feature = "client")]
+pub(crate) static CLIENT_COUNTER_STARTED: Lazy<IntCounterVec> = Lazy::new(|| {
     let opts = opts!(
         CLIENT_COUNTER_STARTED_NAME,
         CLIENT_COUNTER_STARTED_DESCRIPTION
     );
-    register_counter_vec_with_registry!(
-        opts,
-        &["grpc_service", "grpc_method"],
-        get_settings().registry.clone()
+    register(
+        IntCounterVec::new(opts, &["grpc_service", "grpc_method"])
+            .expect("failed to init client_counter_started"),
     )
-    .expect("failed to init client_counter_started")
 });
 
-pub(crate) static CLIENT_COUNTER_HANDLED: Lazy<CounterVec> = Lazy::new(|| {
+#[cfg(feature = "client")]
+pub(crate) static CLIENT_COUNTER_HANDLED: Lazy<IntCounterVec> = Lazy::new(|| {
     let opts = opts!(
         CLIENT_COUNTER_HANDLED_NAME,
         CLIENT_COUNTER_HANDLED_DESCRIPTION
     );
-    register_counter_vec_with_registry!(
-        opts,
-        &["grpc_service", "grpc_method", "grpc_code"],
-        get_settings().registry.clone()
+    register(
+        IntCounterVec::new(opts, &["grpc_service", "grpc_method", "grpc_code"])
+            .expect("failed to init client_counter_handled"),
     )
-    .expect("failed to init client_counter_handled")
 });
 
+#[cfg(feature = "client")]
 pub(crate) static CLIENT_HISTOGRAM: Lazy<HistogramVec> = Lazy::new(|| {
     let opts = histogram_opts!(
         CLIENT_HISTOGRAM_NAME,
         CLIENT_HISTOGRAM_DESCRIPTION,
         get_settings().histogram_buckets.clone()
     );
-    register_histogram_vec_with_registry!(
-        opts,
-        &["grpc_service", "grpc_method", "grpc_code"],
-        get_settings().registry.clone()
+    register(
+        HistogramVec::new(opts, &["grpc_service", "grpc_method", "grpc_code"])
+            .expect("failed to init client_histogram"),
     )
-    .expect("failed to init client_histogram")
 });
 
+#[cfg(feature = "client")]
+pub(crate) static CLIENT_CHANNEL_STATE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    let opts = opts!(CLIENT_CHANNEL_STATE_NAME, CLIENT_CHANNEL_STATE_DESCRIPTION);
+    register(IntGaugeVec::new(opts, &["target"]).expect("failed to init client_channel_state"))
+});
+
+#[cfg(feature = "client")]
+pub(crate) static CLIENT_RECONNECTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = opts!(CLIENT_RECONNECTS_NAME, CLIENT_RECONNECTS_DESCRIPTION);
+    register(IntCounterVec::new(opts, &["target"]).expect("failed to init client_reconnects"))
+});
+
+#[cfg(feature = "client")]
+const CLIENT_CHANNEL_STATE_NAME: &str = "grpc_client_channel_state";
+#[cfg(feature = "client")]
+const CLIENT_CHANNEL_STATE_DESCRIPTION: &str = "Connectivity state of each client channel: \
+     0 idle, 1 connecting, 2 ready, 3 transient failure, 4 shutdown.";
+#[cfg(feature = "client")]
+const CLIENT_RECONNECTS_NAME: &str = "grpc_client_reconnects_total";
+#[cfg(feature = "client")]
+const CLIENT_RECONNECTS_DESCRIPTION: &str =
+    "Total number of times client channels started connecting again after being ready or failing.";
+
 // Metrics that mirror the ones commonly used in Go:
 // https://github.com/grpc-ecosystem/go-grpc-middleware/blob/main/providers/prometheus/client_metrics.go
-const CLIENT_COUNTER_STARTED_NAME: &str = "grpc_client_started_total";
-const CLIENT_COUNTER_HANDLED_NAME: &str = "grpc_client_handled_total";
-const CLIENT_HISTOGRAM_NAME: &str = "grpc_client_handling_seconds";
+pub(crate) const CLIENT_COUNTER_STARTED_NAME: &str = "grpc_client_started_total";
+pub(crate) const CLIENT_COUNTER_HANDLED_NAME: &str = "grpc_client_handled_total";
+pub(crate) const CLIENT_HISTOGRAM_NAME: &str = "grpc_client_handling_seconds";
 
+#[cfg(feature = "client")]
 const CLIENT_COUNTER_STARTED_DESCRIPTION: &str = "Total number of client RPCs started.";
+#[cfg(feature = "client")]
 const CLIENT_COUNTER_HANDLED_DESCRIPTION: &str =
     "Total number of client RPCs completed, regardless of success or failure.";
+#[cfg(feature = "client")]
 const CLIENT_HISTOGRAM_DESCRIPTION: &str = "Histogram for tracking client RPC duration";
 
 const DEFAULT_HISTOGRAM_BUCKETS: [f64; 14] = [
     0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
 ];
 
+/// A label of a metric built by a custom collector.
+pub(crate) fn label_pair(name: &str, value: &str) -> LabelPair {
+    let mut pair = LabelPair::default();
+    pair.set_name(name.to_owned());
+    pair.set_value(value.to_owned());
+    pair
+}
+
+/// Register a metric of the layer in the global registry, see [`register_in`].
+pub(crate) fn register<C: Collector + Clone + 'static>(collector: C) -> C {
+    register_in(&get_settings().registry, collector)
+}
+
+/// Register a metric of the layer in `registry`.
+///
+/// On conflicts, e.g. with a metric of the same name registered by the
+/// application, see [`recording_failed`]. In fail-open mode the metric is still
+/// recorded into, but not exported.
+pub(crate) fn register_in<C: Collector + Clone + 'static>(registry: &Registry, collector: C) -> C {
+    if let Err(error) = registry.register(Box::new(collector.clone())) {
+        let name = collector
+            .desc()
+            .first()
+            .map_or_else(String::new, |desc| desc.fq_name.clone());
+        recording_failed(&name, error);
+    }
+    collector
+}
+
+/// Metrics whose errors have been logged.
+static LOGGED_ERRORS: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// Handle an error registering or recording into the metric `name`: panic,
+/// unless `GlobalSettings::fail_open` is set. Then the first error of each
+/// metric is logged, and all are counted in `grpc_metrics_recording_errors_total`.
+pub(crate) fn recording_failed(name: &str, error: prometheus::Error) {
+    if !get_settings().fail_open {
+        panic!("failed to record {name}: {error}");
+    }
+    if LOGGED_ERRORS.lock().unwrap().insert(name.to_owned()) {
+        tracing::error!(metric = name, %error, "failed to record metric");
+    }
+    RECORDING_ERRORS.with_label_values(&[name]).inc();
+}
+
+/// Replace the help text of families overridden in `GlobalSettings::help`.
+pub(crate) fn override_help(families: &mut [MetricFamily]) {
+    let help = &get_settings().help;
+    if help.is_empty() {
+        return;
+    }
+    for family in families {
+        if let Some(text) = help.get(family.get_name()) {
+            family.set_help(text.clone());
+        }
+    }
+}
+
+/// Label names for the gRPC server metrics, including the optional ones
+/// enabled in [`GlobalSettings`].
+pub(crate) fn server_labels(labels: &[&'static str]) -> Vec<&'static str> {
+    let mut labels = labels.to_vec();
+    if get_settings().protocol_label {
+        labels.push("protocol");
+    }
+    if get_settings().transport_label {
+        labels.push("transport");
+    }
+    if get_settings().priority_label.is_some() {
+        labels.push("priority");
+    }
+    if get_settings().authority_label.is_some() {
+        labels.push("authority");
+    }
+    labels.extend(get_settings().baggage_labels.iter().map(BaggageLabel::name));
+    labels
+}
+
+/// Label names of the started and handled counters, which also carry the
+/// `caller` label.
+pub(crate) fn counter_labels(labels: &[&'static str]) -> Vec<&'static str> {
+    let mut labels = server_labels(labels);
+    if get_settings().caller_label.is_some() {
+        labels.push("caller");
+    }
+    labels
+}
+
+/// Label names of the handled counter, which also carries the `grpc_result`
+/// label.
+pub(crate) fn handled_labels(labels: &[&'static str]) -> Vec<&'static str> {
+    let mut labels = counter_labels(labels);
+    if get_settings().result_label.is_some() {
+        labels.push("grpc_result");
+    }
+    labels
+}
+
+/// Values of the optional server labels of a call, filled in by
+/// [`LabelValues::server`] where [`server_labels`] enables them.
+pub(crate) struct LabelValues<'a, 'b> {
+    pub(crate) protocol: &'a str,
+    pub(crate) transport: &'a str,
+    pub(crate) priority: &'a str,
+    pub(crate) authority: &'a str,
+    pub(crate) baggage: &'b [&'a str],
+    pub(crate) caller: &'a str,
+}
+
+impl<'a> LabelValues<'a, '_> {
+    /// Label values for [`server_labels`]: the service, method and code if
+    /// any, followed by the optional labels.
+    pub(crate) fn server(
+        &self,
+        service: &'a str,
+        method: &'a str,
+        code: Option<&'a str>,
+    ) -> Vec<&'a str> {
+        let settings = get_settings();
+        let mut labels = vec![service, method];
+        labels.extend(code);
+        if settings.protocol_label {
+            labels.push(self.protocol);
+        }
+        if settings.transport_label {
+            labels.push(self.transport);
+        }
+        if settings.priority_label.is_some() {
+            labels.push(self.priority);
+        }
+        if settings.authority_label.is_some() {
+            labels.push(self.authority);
+        }
+        labels.extend(self.baggage.iter().copied());
+        labels
+    }
+
+    /// Label values for [`counter_labels`].
+    pub(crate) fn counter(
+        &self,
+        service: &'a str,
+        method: &'a str,
+        code: Option<&'a str>,
+    ) -> Vec<&'a str> {
+        let mut labels = self.server(service, method, code);
+        if get_settings().caller_label.is_some() {
+            labels.push(self.caller);
+        }
+        labels
+    }
+
+    /// Label values for [`handled_labels`].
+    pub(crate) fn handled(
+        &self,
+        service: &'a str,
+        method: &'a str,
+        code: Code,
+        code_str: &'a str,
+    ) -> Vec<&'a str> {
+        let mut labels = self.counter(service, method, Some(code_str));
+        if let Some(result) = &get_settings().result_label {
+            labels.push(result.value(code));
+        }
+        labels
+    }
+}
+
+const DEFAULT_CONNECTION_DURATION_BUCKETS: [f64; 11] = [
+    1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 21600.0, 86400.0,
+];
+
+#[cfg(feature = "alloc-tracking")]
+const DEFAULT_ALLOC_BUCKETS: [f64; 10] = [
+    256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 67108864.0,
+];
+
+const DEFAULT_METADATA_SIZE_BUCKETS: [f64; 10] = [
+    64.0, 256.0, 1024.0, 2048.0, 4096.0, 8192.0, 16384.0, 32768.0, 65536.0, 262144.0,
+];
+
+const DEFAULT_INTERARRIVAL_BUCKETS: [f64; 12] = [
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0,
+];
+
+const DEFAULT_OPEN_STREAM_AGE_BUCKETS: [f64; 10] = [
+    60.0, 300.0, 900.0, 1800.0, 3600.0, 10800.0, 21600.0, 43200.0, 86400.0, 604800.0,
+];
+
+const DEFAULT_MSG_INTERVAL_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+const DEFAULT_CONNECTION_REQUESTS_BUCKETS: [f64; 11] = [
+    1.0, 2.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0, 10000.0, 100000.0,
+];
+
+const DEFAULT_STREAM_BUCKETS: [f64; 11] = [
+    1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0, 512.0, 1024.0,
+];
+
 pub(crate) fn get_settings() -> &'static GlobalSettings {
+    #[cfg(test)]
+    if let Some(settings) = TEST_SETTINGS.with(std::cell::Cell::get) {
+        return settings;
+    }
     GLOBAL_SETTINGS.get_or_init(Default::default)
 }
 
+#[cfg(test)]
+thread_local! {
+    static TEST_SETTINGS: std::cell::Cell<Option<&'static GlobalSettings>> =
+        const { std::cell::Cell::new(None) };
+}
+
+/// Use the default settings changed by `configure` on this thread until the
+/// guard is dropped, for tests of settings other than the defaults.
+///
+/// The settings share the global registry, so metrics first registered under
+/// them are exported as usual. Settings that change label names, e.g.
+/// `protocol_label`, must not be changed this way, as the label names of the
+/// global metrics are fixed when they are first used.
+#[cfg(test)]
+pub(crate) fn test_settings(configure: impl FnOnce(&mut GlobalSettings)) -> TestSettings {
+    let mut settings = GlobalSettings {
+        registry: GLOBAL_SETTINGS
+            .get_or_init(Default::default)
+            .registry
+            .clone(),
+        ..Default::default()
+    };
+    configure(&mut settings);
+    let settings: &'static GlobalSettings = Box::leak(Box::new(settings));
+    TestSettings(TEST_SETTINGS.with(|cell| cell.replace(Some(settings))))
+}
+
+/// Restores the previous settings of the thread when dropped, see
+/// [`test_settings`].
+#[cfg(test)]
+pub(crate) struct TestSettings(Option<&'static GlobalSettings>);
+
+#[cfg(test)]
+impl Drop for TestSettings {
+    fn drop(&mut self) {
+        TEST_SETTINGS.with(|cell| cell.set(self.0));
+    }
+}
+
 /// Initialize the global Prometheus settings.
 ///
 /// You should not call this function if you want to use default settings.
-pub fn try_init_settings(settings: GlobalSettings) -> Result<(), Error> {
+pub fn try_init_settings(mut settings: GlobalSettings) -> Result<(), Error> {
+    if settings.use_default_registry {
+        settings.registry = prometheus::default_registry().clone();
+    }
     GLOBAL_SETTINGS
         .try_insert(settings)
         .map_err(|_| Error::AlreadyInitialized)?;
@@ -166,37 +992,1086 @@ pub fn try_init_settings(settings: GlobalSettings) -> Result<(), Error> {
 pub enum Error {
     #[error("Settings have already been initialized")]
     AlreadyInitialized,
+    #[error("Unknown tenant {0:?}")]
+    UnknownTenant(String),
+    #[error("Invalid value {value:?} of environment variable {name}")]
+    InvalidEnvVar { name: &'static str, value: String },
     #[error(transparent)]
     PrometheusEncoding(#[from] prometheus::Error),
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
 pub struct GlobalSettings {
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub registry: prometheus::Registry,
+    /// Register the metrics in `prometheus::default_registry()` instead of
+    /// `registry`, next to those of other crates using it.
+    pub use_default_registry: bool,
+    /// Help text exported for metrics instead of their default description, by
+    /// metric name.
+    pub help: HashMap<String, String>,
+    /// Attach the time of the gather, in milliseconds, to every exported
+    /// sample, for consumers that forward scraped series with a delay.
+    pub sample_timestamps: bool,
+    /// Prefix of the exported metric names, e.g. `myapp` to export
+    /// `myapp_grpc_server_handled_total`. Applied by all the encoders, after
+    /// `relabel` and `help`, which use the names without it. Disabled by default.
+    pub namespace: Option<String>,
+    /// Also record server calls in the `function_calls_*` metrics by HTTP method
+    /// and path, as earlier versions did. Enabled by default.
+    pub legacy_metrics: bool,
+    /// Rules rewriting the labels of the exported series, applied by all the
+    /// encoders. Empty by default.
+    pub relabel: Vec<Relabel>,
     pub histogram_buckets: Vec<f64>,
+    /// Status codes of server calls whose duration is not observed in
+    /// `grpc_server_handling_seconds`, e.g. `Cancelled` and `DeadlineExceeded`
+    /// which mostly reflect client behavior. They are still counted.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::config::codes"))]
+    pub histogram_excluded_codes: Vec<Code>,
+    /// Also keep native histogram buckets for `grpc_server_handling_seconds`,
+    /// exported by [`encode_protobuf`]. Disabled by default.
+    pub native_histograms: Option<NativeHistogramSettings>,
+    /// Keep a quantile sketch of the server call durations of each method for
+    /// [`quantile`]. Disabled by default.
+    pub quantile_sketches: Option<QuantileSketchSettings>,
+    /// Buckets for the connection lifetime histogram recorded by [`crate::MetricsIncoming`].
+    pub connection_duration_buckets: Vec<f64>,
+    /// Clock used to time server and client calls.
+    pub time_source: TimeSource,
+    /// Add a `protocol` label (`grpc`, `grpc-web`, `connect`, `transcoded` or `http`) to the
+    /// gRPC server metrics.
+    pub protocol_label: bool,
+    /// Report native gRPC calls in the `protocol` label as `h2` or, over cleartext
+    /// connections, `h2c` rather than `grpc`.
+    pub protocol_label_transport: bool,
+    /// Add an `attempt` label to `grpc_server_retried_requests_total` with the
+    /// attempt number, one more than the `grpc-previous-rpc-attempts` header.
+    pub retry_attempt_label: bool,
+    /// Add a `transport` label (`tcp`, `uds` or `other`) to the gRPC server
+    /// metrics, from the connection the request arrived on. Connections
+    /// over TLS are labeled by the socket underneath with the `tls`
+    /// feature, and `other` without it.
+    pub transport_label: bool,
+    /// Add a `priority` label to the gRPC server metrics from a request header,
+    /// e.g. `x-request-priority`. Disabled by default.
+    pub priority_label: Option<HeaderLabel>,
+    /// Add a `caller` label to `grpc_server_started_total` and
+    /// `grpc_server_handled_total` from a request header naming the calling
+    /// service, e.g. `x-client-name`. Disabled by default.
+    pub caller_label: Option<HeaderLabel>,
+    /// Add a `grpc_result` label, `ok` or `error`, to `grpc_server_handled_total`,
+    /// for alerts that only need the error ratio. Disabled by default.
+    pub result_label: Option<ResultLabel>,
+    /// Add an `authority` label to the gRPC server metrics with the host the
+    /// request was sent to. Disabled by default.
+    pub authority_label: Option<AuthorityLabel>,
+    /// Labels of the gRPC server metrics taken from entries of the W3C
+    /// `baggage` request header, e.g. `tenant`, to dimension the metrics by
+    /// context propagated from upstream services. Empty by default.
+    pub baggage_labels: Vec<BaggageLabel>,
+    /// How to record server requests that are not gRPC calls.
+    pub non_grpc_requests: NonGrpcRequests,
+    /// How to record server requests whose path is not `/service/method`.
+    pub unparseable_paths: UnparseablePaths,
+    /// How gRPC service names are written in the `grpc_service` label of server
+    /// and client metrics.
+    pub service_names: ServiceNames,
+    /// Fully qualified names of services whose calls are all recorded under
+    /// `grpc_method="*"`, e.g. generic proxies with many method names. Other
+    /// services keep per-method series. Empty by default.
+    pub collapsed_methods: HashSet<String>,
+    /// Record calls of a method under `grpc_service="__other__"` and
+    /// `grpc_method="__other__"` until it has been called often enough. Disabled
+    /// by default.
+    pub series_threshold: Option<SeriesThreshold>,
+    /// Retain recent slow and failed server calls for [`crate::rpcz`]. Disabled by default.
+    pub rpcz: Option<RpczSettings>,
+    /// Keep the slowest recent calls of each method for [`crate::slowest`]. Disabled by default.
+    pub slowest_calls: Option<SlowestCallsSettings>,
+    /// Sample the server counters for [`rates`]. Disabled by default.
+    pub rates: Option<RatesSettings>,
+    /// Send call events to [`subscribe`]rs. Disabled by default.
+    pub call_events: Option<CallEventsSettings>,
+    /// Log server calls slower than this with `tracing::warn!`. Disabled by default.
+    pub slow_request_log: Option<SlowThreshold>,
+    /// Count server calls slower than this in `grpc_server_slow_requests_total`. Disabled by
+    /// default.
+    pub slow_requests: Option<SlowThreshold>,
+    /// Count polls of server call handlers longer than this in
+    /// `grpc_server_long_polls_total`, and record the longest poll of each
+    /// method in `grpc_server_max_poll_seconds`. A poll blocks its executor
+    /// thread, so long ones reveal blocking code in async handlers. Disabled by
+    /// default.
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "crate::config::option_duration")
+    )]
+    pub long_poll_threshold: Option<Duration>,
+    /// Service level objectives of server methods, by gRPC service and method
+    /// label values, whose error budget burn rates are exported in
+    /// `grpc_server_slo_burn_rate`. Empty by default.
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "crate::config::method_map")
+    )]
+    pub slos: HashMap<(String, String), Slo>,
+    /// Record the size of request and response metadata of server calls in
+    /// `grpc_server_request_metadata_bytes` and `grpc_server_response_metadata_bytes`.
+    pub metadata_size: bool,
+    /// Request header holding the time the client sent the call, as fractional
+    /// Unix seconds, used to record `grpc_server_request_age_seconds`. Disabled
+    /// by default.
+    pub request_age_header: Option<String>,
+    /// Record the thread CPU time spent polling each server call's handler in
+    /// `grpc_server_cpu_seconds`. Only supported on Unix.
+    pub cpu_time: bool,
+    /// Record the time between the starts of consecutive calls of each method
+    /// in `grpc_server_interarrival_seconds`, e.g. to spot bursts of retries
+    /// shorter than the scrape interval.
+    pub interarrival_time: bool,
+    /// Record the time each server call's response body waited for the
+    /// transport to take its next message in `grpc_server_send_blocked_seconds`,
+    /// mostly HTTP/2 flow control holding back responses to slow consumers.
+    pub send_blocked_time: bool,
+    /// Record the time between consecutive messages of each server call's
+    /// response in `grpc_server_msg_interval_seconds`, to detect stalls inside
+    /// long-lived streams.
+    pub message_interval: bool,
+    /// Periodically sample the age of open server calls into
+    /// `grpc_server_open_stream_age_seconds`, for streams that run for hours.
+    /// Disabled by default.
+    pub open_stream_age: Option<OpenStreamAgeSettings>,
+    /// Also record server calls in a separate registry per tenant. Disabled by default.
+    pub tenants: Option<TenantSettings>,
+    /// Picks the registry for the started, handled and handling time metrics of
+    /// calls to each gRPC service, e.g. to keep infrastructure services apart from
+    /// business ones. Calls routed to a registry other than `registry` are not
+    /// seen by [`snapshot`] and [`rates`]. Returning a clone of `registry`, or
+    /// `prometheus::default_registry()` with `use_default_registry`, keeps the
+    /// calls in the global registry.
+    ///
+    /// The selector is passed the `grpc_service` label value, after
+    /// `service_names`: with [`ServiceNames::StripPackage`], `Health` rather
+    /// than `grpc.health.v1.Health`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub registry_selector: Option<fn(&str) -> &'static Registry>,
+    /// Metrics that handlers record values into through the [`CallMetrics`]
+    /// request extension, by name. The extension is only added if this is not
+    /// empty.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub call_metrics: HashMap<String, CallMetric>,
+    /// Insert a [`ProxyContext`] extension into server requests, through which
+    /// the client calls they make are subtracted from their duration in
+    /// `grpc_proxy_overhead_seconds`.
+    pub proxy_overhead: bool,
+    /// Log and count errors registering or recording the metrics, e.g.
+    /// conflicts with metrics of the same name in `registry`, instead of
+    /// panicking. Metrics that failed to register are not exported.
+    pub fail_open: bool,
+    /// Check the server metrics for violated invariants on every gather, e.g.
+    /// more calls handled than started or a negative in-flight gauge, logging
+    /// and counting them in `grpc_metrics_inconsistencies_total`.
+    pub self_check: bool,
+}
+
+/// The codes recorded as `grpc_result="ok"`, see `GlobalSettings::result_label`.
+///
+/// The label does not add series, as its value follows from `grpc_code`, but
+/// saves matching the codes in every alert.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+#[derive(Clone, Debug, Default)]
+pub struct ResultLabel {
+    /// Codes besides `Ok` recorded as `ok`, e.g. `Cancelled` and
+    /// `DeadlineExceeded` which mostly reflect client behavior. Empty by default.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::config::codes"))]
+    pub ok_codes: Vec<Code>,
+}
+
+impl ResultLabel {
+    pub(crate) fn value(&self, code: Code) -> &'static str {
+        if code == Code::Ok || self.ok_codes.contains(&code) {
+            "ok"
+        } else {
+            "error"
+        }
+    }
+}
+
+/// A label taken from a request header, limited to a known set of values to
+/// bound the number of series.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub struct HeaderLabel {
+    pub header: String,
+    /// Header values used as they are, compared case-insensitively.
+    pub values: Vec<String>,
+    /// Value for calls without the header or with a value not in `values`.
+    #[cfg_attr(feature = "serde", serde(default = "crate::config::other"))]
+    pub default: String,
+}
+
+impl HeaderLabel {
+    pub fn new(
+        header: impl Into<String>,
+        values: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        HeaderLabel {
+            header: header.into(),
+            values: values.into_iter().map(Into::into).collect(),
+            default: "other".to_owned(),
+        }
+    }
+
+    /// The label value for a request with these headers.
+    pub(crate) fn value(&self, headers: &HeaderMap) -> &str {
+        headers
+            .get(&self.header)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                self.values
+                    .iter()
+                    .find(|known| known.eq_ignore_ascii_case(v))
+            })
+            .unwrap_or(&self.default)
+    }
+}
+
+/// The hosts recorded in the `authority` label, for servers behind several DNS
+/// names.
+///
+/// The host is taken from the `:authority` pseudo-header, or the `Host` header
+/// for HTTP/1 requests, lowercased and without port.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub struct AuthorityLabel {
+    pub hosts: Vec<String>,
+    /// Value for requests to hosts not in `hosts`.
+    #[cfg_attr(feature = "serde", serde(default = "crate::config::other"))]
+    pub default: String,
+}
+
+impl AuthorityLabel {
+    pub fn new(hosts: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        AuthorityLabel {
+            hosts: hosts.into_iter().map(Into::into).collect(),
+            default: "other".to_owned(),
+        }
+    }
+
+    /// The label value for a request to `authority`, e.g. `api.example.com:443`.
+    pub(crate) fn value(&self, authority: Option<&str>) -> &str {
+        authority
+            .map(normalize_host)
+            .and_then(|host| {
+                self.hosts
+                    .iter()
+                    .find(|known| known.eq_ignore_ascii_case(host))
+            })
+            .unwrap_or(&self.default)
+    }
+}
+
+/// A label taken from an entry of the W3C `baggage` request header, limited to
+/// a known set of values to bound the number of series.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub struct BaggageLabel {
+    /// Baggage key, e.g. `tenant`.
+    pub key: String,
+    /// Name of the label, the key if empty. Set it for keys that are not
+    /// valid label names, e.g. `user.tier`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub label: String,
+    /// Percent-decoded baggage values used as they are, compared
+    /// case-sensitively.
+    pub values: Vec<String>,
+    /// Value for calls without the key or with a value not in `values`.
+    #[cfg_attr(feature = "serde", serde(default = "crate::config::other"))]
+    pub default: String,
+}
+
+impl BaggageLabel {
+    pub fn new(
+        key: impl Into<String>,
+        values: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        BaggageLabel {
+            key: key.into(),
+            label: String::new(),
+            values: values.into_iter().map(Into::into).collect(),
+            default: "other".to_owned(),
+        }
+    }
+
+    /// Use `label` as the name of the label instead of the key.
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        if self.label.is_empty() {
+            &self.key
+        } else {
+            &self.label
+        }
+    }
+
+    /// The label value for a request with these headers. The first entry with
+    /// the key counts; its properties, after `;`, are ignored.
+    pub(crate) fn value(&self, headers: &HeaderMap) -> &str {
+        headers
+            .get_all("baggage")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .find_map(|member| {
+                let (key, value) = member.split(';').next()?.split_once('=')?;
+                (key.trim() == self.key).then(|| percent_decode(value.trim()))
+            })
+            .and_then(|value| self.values.iter().find(|known| **known == value))
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Decode the `%XX` escapes of a baggage value.
+fn percent_decode(value: &str) -> Cow<'_, str> {
+    if !value.contains('%') {
+        return Cow::Borrowed(value);
+    }
+
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let escape = match tail.get(..2) {
+            Some(hex) if b == b'%' && hex.iter().all(u8::is_ascii_hexdigit) => {
+                std::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            }
+            _ => None,
+        };
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                rest = &tail[2..];
+            }
+            None => {
+                decoded.push(b);
+                rest = tail;
+            }
+        }
+    }
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// The host of an authority, without userinfo, port and trailing dot.
+fn normalize_host(authority: &str) -> &str {
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let host = match host.strip_prefix('[') {
+        // IPv6 literal
+        Some(rest) => rest.split_once(']').map_or(rest, |(ip, _)| ip),
+        None => host.split_once(':').map_or(host, |(host, _)| host),
+    };
+    host.strip_suffix('.').unwrap_or(host)
+}
+
+/// Duration above which a server call is considered slow, with optional
+/// overrides for individual methods.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[derive(Clone, Debug)]
+pub struct SlowThreshold {
+    /// Threshold for methods without an override.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::config::duration"))]
+    pub default: Duration,
+    /// Overrides by gRPC service, then method name.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, deserialize_with = "crate::config::method_durations")
+    )]
+    pub methods: HashMap<String, HashMap<String, Duration>>,
+}
+
+impl SlowThreshold {
+    pub fn new(default: Duration) -> Self {
+        SlowThreshold {
+            default,
+            methods: HashMap::new(),
+        }
+    }
+
+    /// Use `threshold` for calls of `service`/`method` instead of the default.
+    pub fn method(
+        mut self,
+        service: impl Into<String>,
+        method: impl Into<String>,
+        threshold: Duration,
+    ) -> Self {
+        self.methods
+            .entry(service.into())
+            .or_default()
+            .insert(method.into(), threshold);
+        self
+    }
+
+    pub(crate) fn is_slow(&self, service: &str, method: &str, duration: Duration) -> bool {
+        let threshold = self
+            .methods
+            .get(service)
+            .and_then(|methods| methods.get(method))
+            .unwrap_or(&self.default);
+        duration > *threshold
+    }
+}
+
+/// The tenants whose server calls are recorded in their own registry, see
+/// [`tenant_registry`].
+///
+/// A call belongs to the tenant named by its [`Tenant`] request extension or,
+/// failing that, by the `header` request header. Calls of other tenants are
+/// only recorded in the global registry, which bounds the number of
+/// registries.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+#[derive(Clone, Debug, Default)]
+pub struct TenantSettings {
+    pub header: Option<String>,
+    pub tenants: Vec<String>,
+}
+
+/// How often a method must be called before it gets its own server series,
+/// which keeps one-off probes and mistyped paths out of the registry.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+#[derive(Clone, Debug)]
+pub struct SeriesThreshold {
+    /// Calls needed within one window.
+    pub min_calls: u32,
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::config::duration"))]
+    pub window: Duration,
+}
+
+impl Default for SeriesThreshold {
+    fn default() -> Self {
+        SeriesThreshold {
+            min_calls: 3,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Resolution of native histograms.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+#[derive(Clone, Debug)]
+pub struct NativeHistogramSettings {
+    /// Buckets grow by a factor of `2^(2^-schema)`, from -4 (coarsest) to 8
+    /// (finest).
+    pub schema: i32,
+    /// Observations at or below this go to the zero bucket.
+    pub zero_threshold: f64,
+}
+
+impl NativeHistogramSettings {
+    pub(crate) fn schema(&self) -> i32 {
+        self.schema.clamp(-4, 8)
+    }
+}
+
+impl Default for NativeHistogramSettings {
+    fn default() -> Self {
+        NativeHistogramSettings {
+            schema: 3,
+            zero_threshold: 2.938735877055719e-39,
+        }
+    }
+}
+
+/// Accuracy and export of the quantile sketches behind [`quantile`].
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+#[derive(Clone, Debug)]
+pub struct QuantileSketchSettings {
+    /// Bound on the relative error of quantile estimates, e.g. 0.01 for 1%.
+    pub relative_accuracy: f64,
+    /// Quantiles exported in the `grpc_server_handling_quantile_seconds`
+    /// summary. Nothing is exported if empty.
+    pub export_quantiles: Vec<f64>,
+    /// Estimate quantiles over the calls of a recent window only, instead of
+    /// all calls since the start. The summary's count and sum still cover all
+    /// calls.
+    pub window: Option<SketchWindow>,
+}
+
+impl Default for QuantileSketchSettings {
+    fn default() -> Self {
+        QuantileSketchSettings {
+            relative_accuracy: 0.01,
+            export_quantiles: Vec::new(),
+            window: None,
+        }
+    }
+}
+
+/// Rotating windows of the quantile sketches, see
+/// [`QuantileSketchSettings::window`].
+///
+/// The calls are kept in `windows` sketches, each covering `duration / windows`,
+/// and the oldest is dropped as a new one starts. Quantiles are estimated over
+/// the sketches added up, so over the last `duration` minus at most one window.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+#[derive(Clone, Debug)]
+pub struct SketchWindow {
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::config::duration"))]
+    pub duration: Duration,
+    pub windows: usize,
+}
+
+impl Default for SketchWindow {
+    fn default() -> Self {
+        SketchWindow {
+            duration: Duration::from_secs(300),
+            windows: 5,
+        }
+    }
+}
+
+/// How often the ages of open server calls are sampled, and the buckets
+/// they are counted in.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+#[derive(Clone, Debug)]
+pub struct OpenStreamAgeSettings {
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::config::duration"))]
+    pub interval: Duration,
+    /// Upper bounds of the age buckets, in seconds.
+    pub buckets: Vec<f64>,
+}
+
+impl Default for OpenStreamAgeSettings {
+    fn default() -> Self {
+        OpenStreamAgeSettings {
+            interval: Duration::from_secs(15),
+            buckets: DEFAULT_OPEN_STREAM_AGE_BUCKETS.to_vec(),
+        }
+    }
+}
+
+/// How many call events [`subscribe`] buffers for each receiver.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+#[derive(Clone, Debug)]
+pub struct CallEventsSettings {
+    pub capacity: usize,
+}
+
+impl Default for CallEventsSettings {
+    fn default() -> Self {
+        CallEventsSettings { capacity: 1024 }
+    }
+}
+
+/// How often [`rates`] samples the server counters, and for how long samples
+/// are kept.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+#[derive(Clone, Debug)]
+pub struct RatesSettings {
+    /// Time between two samples, taken in a background thread.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::config::duration"))]
+    pub resolution: Duration,
+    /// Samples older than this are dropped; it bounds the longest window.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::config::duration"))]
+    pub retention: Duration,
+}
+
+impl Default for RatesSettings {
+    fn default() -> Self {
+        RatesSettings {
+            resolution: Duration::from_secs(1),
+            retention: Duration::from_secs(300),
+        }
+    }
+}
+
+/// How many of the slowest calls [`crate::slowest`] keeps, and for how long.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+#[derive(Clone, Debug)]
+pub struct SlowestCallsSettings {
+    /// Number of calls to keep per method.
+    pub k: usize,
+    /// Calls older than one to two windows are forgotten.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::config::duration"))]
+    pub window: Duration,
+}
+
+impl Default for SlowestCallsSettings {
+    fn default() -> Self {
+        SlowestCallsSettings {
+            k: 10,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Which server calls [`crate::rpcz`] retains.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default))]
+#[derive(Clone, Debug)]
+pub struct RpczSettings {
+    /// Number of calls to retain.
+    pub capacity: usize,
+    /// Successful calls taking at least this long are retained; failed calls
+    /// always are.
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::config::duration"))]
+    pub slow_threshold: Duration,
+}
+
+impl Default for RpczSettings {
+    fn default() -> Self {
+        RpczSettings {
+            capacity: 100,
+            slow_threshold: Duration::from_secs(1),
+        }
+    }
+}
+
+/// How the server layer records requests that are not gRPC calls, e.g. plain
+/// HTTP routes served next to tonic services.
+///
+/// A request is considered a gRPC call when its `content-type` is one of the
+/// gRPC, gRPC-Web or Connect content types.
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonGrpcRequests {
+    /// Record them in the `grpc_server_*` metrics like any other request.
+    #[default]
+    Record,
+    /// Do not record them at all.
+    Skip,
+    /// Record them in the separate `http_server_*` metrics, labeled by HTTP
+    /// method, path and response status.
+    Http,
+}
+
+/// How the server layer labels requests whose path has no `/service/method`
+/// form, e.g. probes by scanners.
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnparseablePaths {
+    /// Record them with an empty `grpc_service` and the whole path as
+    /// `grpc_method`.
+    #[default]
+    FullPath,
+    /// Record them all as `grpc_service="unknown"` and `grpc_method="unknown"`.
+    Unknown,
+    /// Do not record them at all.
+    Skip,
+}
+
+/// Transformation of fully qualified gRPC service names, e.g.
+/// `com.acme.identity.v3.UserService`, before they are used as labels.
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[derive(Clone, Copy, Debug, Default)]
+pub enum ServiceNames {
+    /// Keep the fully qualified name.
+    #[default]
+    FullName,
+    /// Strip the package, keeping `UserService`.
+    StripPackage,
+    /// Map the name with a custom function.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Custom(fn(&str) -> String),
+}
+
+impl ServiceNames {
+    pub(crate) fn apply(&self, service: String) -> String {
+        match self {
+            ServiceNames::FullName => service,
+            ServiceNames::StripPackage => match service.rsplit_once('.') {
+                Some((_, name)) => name.to_owned(),
+                None => service,
+            },
+            ServiceNames::Custom(f) => f(&service),
+        }
+    }
+}
+
+/// Clock used to time calls.
+///
+/// Reading `std::time::Instant` twice per call is noticeable on some
+/// virtualized hosts at high request rates. The `quanta` feature adds cheaper
+/// clocks.
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[derive(Clone, Copy, Debug, Default)]
+pub enum TimeSource {
+    /// `std::time::Instant`.
+    #[default]
+    Std,
+    /// `quanta::Instant::now`, read from the TSC where available.
+    #[cfg(feature = "quanta")]
+    Quanta,
+    /// `quanta::Instant::recent`, a timestamp cached by a background thread
+    /// every `resolution`. Call durations are only accurate to `resolution`,
+    /// so short calls may be recorded as taking no time.
+    #[cfg(feature = "quanta")]
+    Coarse(
+        #[cfg_attr(feature = "serde", serde(deserialize_with = "crate::config::duration"))]
+        Duration,
+    ),
 }
 
 impl Default for GlobalSettings {
     fn default() -> Self {
         GlobalSettings {
             histogram_buckets: DEFAULT_HISTOGRAM_BUCKETS.to_vec(),
+            histogram_excluded_codes: Vec::new(),
+            native_histograms: None,
+            quantile_sketches: None,
+            connection_duration_buckets: DEFAULT_CONNECTION_DURATION_BUCKETS.to_vec(),
+            time_source: TimeSource::default(),
             registry: prometheus::Registry::new(),
+            use_default_registry: false,
+            sample_timestamps: false,
+            namespace: None,
+            legacy_metrics: true,
+            help: HashMap::new(),
+            relabel: Vec::new(),
+            protocol_label: false,
+            protocol_label_transport: false,
+            retry_attempt_label: false,
+            transport_label: false,
+            priority_label: None,
+            caller_label: None,
+            result_label: None,
+            authority_label: None,
+            baggage_labels: Vec::new(),
+            non_grpc_requests: NonGrpcRequests::default(),
+            unparseable_paths: UnparseablePaths::default(),
+            service_names: ServiceNames::default(),
+            collapsed_methods: HashSet::new(),
+            series_threshold: None,
+            rpcz: None,
+            slowest_calls: None,
+            rates: None,
+            call_events: None,
+            slow_request_log: None,
+            slow_requests: None,
+            long_poll_threshold: None,
+            slos: HashMap::new(),
+            metadata_size: false,
+            request_age_header: None,
+            cpu_time: false,
+            interarrival_time: false,
+            send_blocked_time: false,
+            message_interval: false,
+            open_stream_age: None,
+            tenants: None,
+            registry_selector: None,
+            call_metrics: HashMap::new(),
+            proxy_overhead: false,
+            fail_open: false,
+            self_check: false,
         }
     }
 }
 
+/// Label value for the methods of services in `GlobalSettings::collapsed_methods`.
+const COLLAPSED_METHOD: &str = "*";
+
 impl GlobalSettings {
-    fn encode_metrics(&self) -> Result<String, Error> {
+    /// The default settings, with those set by environment variables replaced,
+    /// to tune the metrics per deployment without code changes:
+    ///
+    /// * `GRPC_METRICS_BUCKETS`: comma-separated, increasing `histogram_buckets`,
+    ///   e.g. `0.01,0.1,1,10`.
+    /// * `GRPC_METRICS_NAMESPACE`: the `namespace` of the exported metric names.
+    /// * `GRPC_METRICS_DISABLE_LEGACY`: `true` or `1` to clear `legacy_metrics`.
+    ///
+    /// ```no_run
+    /// use tonic_prometheus_layer::metrics::{try_init_settings, GlobalSettings};
+    ///
+    /// let settings = GlobalSettings::from_env().expect("invalid metrics settings");
+    /// try_init_settings(settings).unwrap();
+    /// ```
+    pub fn from_env() -> Result<Self, Error> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, Error> {
+        let mut settings = Self::default();
+        let invalid = |name, value: &str| Error::InvalidEnvVar {
+            name,
+            value: value.to_owned(),
+        };
+
+        const BUCKETS: &str = "GRPC_METRICS_BUCKETS";
+        if let Some(value) = var(BUCKETS) {
+            let buckets = value
+                .split(',')
+                .map(|bound| bound.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .ok()
+                .filter(|buckets| {
+                    !buckets.is_empty()
+                        && buckets.iter().all(|b| b.is_finite())
+                        && buckets.windows(2).all(|w| w[0] < w[1])
+                })
+                .ok_or_else(|| invalid(BUCKETS, &value))?;
+            settings.histogram_buckets = buckets;
+        }
+
+        const NAMESPACE: &str = "GRPC_METRICS_NAMESPACE";
+        if let Some(value) = var(NAMESPACE) {
+            let valid = value.chars().enumerate().all(|(i, c)| {
+                c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+            });
+            if value.is_empty() || !valid {
+                return Err(invalid(NAMESPACE, &value));
+            }
+            settings.namespace = Some(value);
+        }
+
+        const DISABLE_LEGACY: &str = "GRPC_METRICS_DISABLE_LEGACY";
+        if let Some(value) = var(DISABLE_LEGACY) {
+            settings.legacy_metrics = match value.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" => false,
+                "" | "0" | "false" | "no" => true,
+                _ => return Err(invalid(DISABLE_LEGACY, &value)),
+            };
+        }
+
+        Ok(settings)
+    }
+
+    /// The `grpc_method` label of calls to `service`/`method`.
+    pub(crate) fn method_label(&self, service: &str, method: String) -> String {
+        if self.collapsed_methods.contains(service) {
+            COLLAPSED_METHOD.to_owned()
+        } else {
+            method
+        }
+    }
+
+    fn encode_metrics(&self, gather: fn() -> Vec<MetricFamily>) -> Result<String, Error> {
+        // Register them before gathering, to export them from the first scrape.
+        let (duration, size) = (&*SCRAPE_DURATION, &*SCRAPE_SIZE);
+        let started_at = Instant::now();
         let mut output = String::new();
 
         TextEncoder::new()
-            .encode_utf8(&self.registry.gather(), &mut output)
+            .encode_utf8(&gather(), &mut output)
             .map_err(Error::PrometheusEncoding)?;
 
+        duration.set(started_at.elapsed().as_secs_f64());
+        size.set(output.len() as i64);
         Ok(output)
     }
 }
 
 /// Export the collected metrics to the Prometheus format.
 pub fn encode_to_string() -> Result<String, Error> {
-    get_settings().encode_metrics()
+    get_settings().encode_metrics(merge::scrape)
+}
+
+/// Export the metrics like [`encode_to_string`], without counting it as a
+/// scrape, see [`merge::scrape`].
+pub(crate) fn peek_to_string() -> Result<String, Error> {
+    get_settings().encode_metrics(merge::gather)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn result_values() {
+        let label = ResultLabel {
+            ok_codes: vec![Code::Cancelled],
+        };
+        assert_eq!(label.value(Code::Ok), "ok");
+        assert_eq!(label.value(Code::Cancelled), "ok");
+        assert_eq!(label.value(Code::DeadlineExceeded), "error");
+    }
+
+    #[test]
+    fn encodes_integer_counters() {
+        COUNTER_SM
+            .with_label_values(&["test.Integer", "Get"])
+            .inc_by(3);
+
+        let text = encode_to_string().unwrap();
+        assert!(text.contains("# TYPE grpc_server_started_total counter\n"));
+        assert!(text.contains(
+            "grpc_server_started_total{grpc_method=\"Get\",grpc_service=\"test.Integer\"} 3\n"
+        ));
+    }
+
+    #[test]
+    fn settings_from_env() {
+        let vars = |vars: &[(&'static str, &'static str)]| {
+            let vars = vars.to_vec();
+            move |name: &str| {
+                vars.iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+
+        let settings = GlobalSettings::from_vars(vars(&[
+            ("GRPC_METRICS_BUCKETS", "0.01, 0.1,1"),
+            ("GRPC_METRICS_NAMESPACE", "myapp"),
+            ("GRPC_METRICS_DISABLE_LEGACY", "TRUE"),
+        ]))
+        .unwrap();
+        assert_eq!(settings.histogram_buckets, [0.01, 0.1, 1.0]);
+        assert_eq!(settings.namespace.as_deref(), Some("myapp"));
+        assert!(!settings.legacy_metrics);
+
+        let settings = GlobalSettings::from_vars(vars(&[])).unwrap();
+        assert_eq!(settings.histogram_buckets, DEFAULT_HISTOGRAM_BUCKETS);
+        assert!(settings.legacy_metrics);
+
+        for invalid in [
+            ("GRPC_METRICS_BUCKETS", "1,0.1"),
+            ("GRPC_METRICS_BUCKETS", "fast"),
+            ("GRPC_METRICS_NAMESPACE", "1app"),
+            ("GRPC_METRICS_DISABLE_LEGACY", "maybe"),
+        ] {
+            assert!(matches!(
+                GlobalSettings::from_vars(vars(&[invalid])),
+                Err(Error::InvalidEnvVar { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn priority_label() {
+        let label = HeaderLabel::new("x-request-priority", ["batch", "interactive"]);
+        let headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-request-priority", value.parse().unwrap());
+            headers
+        };
+        assert_eq!(label.value(&headers("Interactive")), "interactive");
+        assert_eq!(label.value(&headers("urgent")), "other");
+        assert_eq!(label.value(&HeaderMap::new()), "other");
+
+        let _settings = test_settings(|settings| settings.priority_label = Some(label));
+        let values = LabelValues {
+            protocol: "",
+            transport: "",
+            priority: "batch",
+            authority: "",
+            baggage: &[],
+            caller: "",
+        };
+        assert_eq!(
+            server_labels(&["grpc_service", "grpc_method"]),
+            ["grpc_service", "grpc_method", "priority"]
+        );
+        assert_eq!(
+            values.server("test.Priority", "Get", None),
+            ["test.Priority", "Get", "batch"]
+        );
+    }
+
+    #[test]
+    fn caller_label() {
+        let label = HeaderLabel::new("x-client-name", ["billing"]);
+        let _settings = test_settings(|settings| settings.caller_label = Some(label));
+        let values = LabelValues {
+            protocol: "",
+            transport: "",
+            priority: "",
+            authority: "",
+            baggage: &[],
+            caller: "billing",
+        };
+
+        // Only the started and handled counters carry the caller.
+        let labels = ["grpc_service", "grpc_method"];
+        assert_eq!(server_labels(&labels), labels);
+        assert_eq!(
+            values.server("test.Caller", "Get", None),
+            ["test.Caller", "Get"]
+        );
+        assert_eq!(
+            counter_labels(&labels),
+            ["grpc_service", "grpc_method", "caller"]
+        );
+        assert_eq!(
+            values.counter("test.Caller", "Get", None),
+            ["test.Caller", "Get", "billing"]
+        );
+    }
+
+    #[test]
+    fn authority_hosts() {
+        let label = AuthorityLabel::new(["api.example.com", "::1"]);
+        assert_eq!(label.value(Some("API.example.com:443")), "api.example.com");
+        assert_eq!(
+            label.value(Some("user@api.example.com.")),
+            "api.example.com"
+        );
+        assert_eq!(label.value(Some("[::1]:8080")), "::1");
+        assert_eq!(label.value(Some("other.example.com")), "other");
+        assert_eq!(label.value(None), "other");
+    }
+
+    #[test]
+    fn baggage_values() {
+        let label = BaggageLabel::new("experiment", ["blue green"]);
+        let mut headers = HeaderMap::new();
+        assert_eq!(label.value(&headers), "other");
+
+        headers.append("baggage", "tenant=acme".parse().unwrap());
+        headers.append(
+            "baggage",
+            "experiment = blue%20green;ttl=60, experiment=red"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(label.value(&headers), "blue green");
+        assert_eq!(
+            BaggageLabel::new("tenant", ["Acme"]).value(&headers),
+            "other"
+        );
+        assert_eq!(percent_decode("100%25%2"), "100%%2");
+    }
+
+    #[test]
+    fn fail_open_registration() {
+        let _settings = test_settings(|settings| settings.fail_open = true);
+        let registry = Registry::new();
+        let counter = || IntCounter::new("test_fail_open_total", "test").unwrap();
+        register_in(&registry, counter());
+        let duplicate = register_in(&registry, counter());
+
+        duplicate.inc();
+        assert_eq!(registry.gather().len(), 1);
+        let errors = RECORDING_ERRORS.with_label_values(&["test_fail_open_total"]);
+        assert_eq!(errors.get(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "failed to record test_fail_closed_total")]
+    fn fail_closed_registration() {
+        let _settings = test_settings(|settings| settings.fail_open = false);
+        let registry = Registry::new();
+        let counter = || IntCounter::new("test_fail_closed_total", "test").unwrap();
+        register_in(&registry, counter());
+        register_in(&registry, counter());
+    }
 }
@@ -0,0 +1,282 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use prometheus::proto::{Metric, MetricFamily};
+use prometheus::{Encoder, ProtobufEncoder};
+
+use crate::merge;
+use crate::metrics::{get_settings, server_labels, Error, HISTOGRAM_SMC_NAME};
+use crate::relabel::relabel_pairs;
+
+/// Native histogram state of `grpc_server_handling_seconds`, by sorted label
+/// pairs.
+static NATIVE: Lazy<Mutex<HashMap<LabelPairs, NativeHistogram>>> = Lazy::new(Default::default);
+
+type LabelPairs = Vec<(String, String)>;
+
+/// Content type of [`encode_protobuf`] output.
+pub const PROTOBUF_FORMAT: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+#[derive(Clone, Default)]
+struct NativeHistogram {
+    zero_count: u64,
+    /// Observation counts by bucket index.
+    positive: BTreeMap<i32, u64>,
+}
+
+/// Observe `value` into the native histogram of the server call with these
+/// label values, if native histograms are enabled.
+pub(crate) fn observe(values: &[&str], value: f64) {
+    let Some(settings) = get_settings().native_histograms.as_ref() else {
+        return;
+    };
+
+    let names = server_labels(&["grpc_service", "grpc_method", "grpc_code"]);
+    let mut labels: LabelPairs = names
+        .iter()
+        .zip(values)
+        .map(|(n, v)| (n.to_string(), v.to_string()))
+        .collect();
+    labels.sort();
+
+    let mut native = NATIVE.lock().unwrap();
+    let histogram = native.entry(labels).or_default();
+    if value <= settings.zero_threshold {
+        histogram.zero_count += 1;
+    } else {
+        *histogram
+            .positive
+            .entry(bucket_index(value, settings.schema()))
+            .or_default() += 1;
+    }
+}
+
+/// Index of the bucket holding `value`: bucket `i` covers `(base^(i-1), base^i]`
+/// with `base = 2^(2^-schema)`.
+fn bucket_index(value: f64, schema: i32) -> i32 {
+    (value.log2() * 2f64.powi(schema)).ceil() as i32
+}
+
+/// Export the collected metrics in the delimited protobuf format, with
+/// `grpc_server_handling_seconds` carrying native histogram buckets next to
+/// the classic ones when `GlobalSettings::native_histograms` is set.
+///
+/// Serve it with the [`PROTOBUF_FORMAT`] content type to a Prometheus server
+/// that has native histograms enabled.
+pub fn encode_protobuf() -> Result<Vec<u8>, Error> {
+    encode_families(merge::scrape())
+}
+
+/// Export the metrics like [`encode_protobuf`], without counting it as a
+/// scrape, see [`merge::scrape`].
+pub(crate) fn peek_protobuf() -> Result<Vec<u8>, Error> {
+    encode_families(merge::gather())
+}
+
+fn encode_families(families: Vec<MetricFamily>) -> Result<Vec<u8>, Error> {
+    let mut output = Vec::new();
+    let native = NATIVE.lock().unwrap();
+    let native = relabel(&native);
+
+    let histogram_name = merge::exported_name(HISTOGRAM_SMC_NAME);
+    for family in families {
+        if family.get_name() == histogram_name && !native.is_empty() {
+            let mut message = Vec::new();
+            write_family(&mut message, &family, &native);
+            write_varint(&mut output, message.len() as u64);
+            output.extend(message);
+        } else {
+            ProtobufEncoder::new()
+                .encode(&[family], &mut output)
+                .map_err(Error::PrometheusEncoding)?;
+        }
+    }
+
+    Ok(output)
+}
+
+/// Native histograms keyed by the labels their series are exported with, see
+/// `GlobalSettings::relabel`.
+fn relabel(
+    native: &HashMap<LabelPairs, NativeHistogram>,
+) -> Cow<'_, HashMap<LabelPairs, NativeHistogram>> {
+    if get_settings().relabel.is_empty() {
+        return Cow::Borrowed(native);
+    }
+
+    let mut relabeled: HashMap<LabelPairs, NativeHistogram> = HashMap::new();
+    for (labels, histogram) in native {
+        let mut labels = labels.clone();
+        relabel_pairs(HISTOGRAM_SMC_NAME, &mut labels);
+        let added = relabeled.entry(labels).or_default();
+        added.zero_count += histogram.zero_count;
+        for (&index, &count) in &histogram.positive {
+            *added.positive.entry(index).or_default() += count;
+        }
+    }
+    Cow::Owned(relabeled)
+}
+
+// io.prometheus.client protobuf messages, written by hand as the prometheus
+// crate's model predates native histograms.
+
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const DELIMITED: u8 = 2;
+
+fn write_family(
+    out: &mut Vec<u8>,
+    family: &MetricFamily,
+    native: &HashMap<LabelPairs, NativeHistogram>,
+) {
+    let settings = get_settings().native_histograms.as_ref();
+    let schema = settings.map_or(0, |s| s.schema());
+    let zero_threshold = settings.map_or(0.0, |s| s.zero_threshold);
+
+    write_bytes(out, 1, family.get_name().as_bytes());
+    write_bytes(out, 2, family.get_help().as_bytes());
+    // MetricType::HISTOGRAM
+    write_key(out, 3, VARINT);
+    write_varint(out, 4);
+
+    for metric in family.get_metric() {
+        let mut labels: LabelPairs = metric
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name().to_owned(), l.get_value().to_owned()))
+            .collect();
+        labels.sort();
+
+        let mut message = Vec::new();
+        for (name, value) in &labels {
+            let mut pair = Vec::new();
+            write_bytes(&mut pair, 1, name.as_bytes());
+            write_bytes(&mut pair, 2, value.as_bytes());
+            write_bytes(&mut message, 1, &pair);
+        }
+        let histogram = write_histogram(metric, native.get(&labels), schema, zero_threshold);
+        write_bytes(&mut message, 7, &histogram);
+        if metric.get_timestamp_ms() != 0 {
+            write_key(&mut message, 6, VARINT);
+            write_varint(&mut message, metric.get_timestamp_ms() as u64);
+        }
+        write_bytes(out, 4, &message);
+    }
+}
+
+fn write_histogram(
+    metric: &Metric,
+    native: Option<&NativeHistogram>,
+    schema: i32,
+    zero_threshold: f64,
+) -> Vec<u8> {
+    let classic = metric.get_histogram();
+    let mut out = Vec::new();
+
+    write_key(&mut out, 1, VARINT);
+    write_varint(&mut out, classic.get_sample_count());
+    write_key(&mut out, 2, FIXED64);
+    out.extend(classic.get_sample_sum().to_le_bytes());
+    for bucket in classic.get_bucket() {
+        let mut message = Vec::new();
+        write_key(&mut message, 1, VARINT);
+        write_varint(&mut message, bucket.get_cumulative_count());
+        write_key(&mut message, 2, FIXED64);
+        message.extend(bucket.get_upper_bound().to_le_bytes());
+        write_bytes(&mut out, 3, &message);
+    }
+
+    let Some(native) = native else {
+        return out;
+    };
+    write_key(&mut out, 5, VARINT);
+    write_varint(&mut out, zigzag(schema.into()));
+    write_key(&mut out, 6, FIXED64);
+    out.extend(zero_threshold.to_le_bytes());
+    write_key(&mut out, 7, VARINT);
+    write_varint(&mut out, native.zero_count);
+
+    let (spans, deltas) = spans_and_deltas(&native.positive);
+    for (offset, length) in spans {
+        let mut message = Vec::new();
+        write_key(&mut message, 1, VARINT);
+        write_varint(&mut message, zigzag(offset.into()));
+        write_key(&mut message, 2, VARINT);
+        write_varint(&mut message, length.into());
+        write_bytes(&mut out, 12, &message);
+    }
+    let mut packed = Vec::new();
+    for delta in deltas {
+        write_varint(&mut packed, zigzag(delta));
+    }
+    if !packed.is_empty() {
+        write_bytes(&mut out, 13, &packed);
+    }
+
+    out
+}
+
+/// Spans of consecutive populated buckets, as (offset from the end of the
+/// previous span, length), and the bucket counts, each as the difference to
+/// the previous bucket.
+fn spans_and_deltas(buckets: &BTreeMap<i32, u64>) -> (Vec<(i32, u32)>, Vec<i64>) {
+    let mut spans: Vec<(i32, u32)> = Vec::new();
+    let mut deltas = Vec::new();
+    let mut previous: Option<(i32, u64)> = None;
+
+    for (&index, &count) in buckets {
+        match previous {
+            Some((last, _)) if index == last + 1 => spans.last_mut().unwrap().1 += 1,
+            Some((last, _)) => spans.push((index - last - 1, 1)),
+            None => spans.push((index, 1)),
+        }
+        deltas.push(count as i64 - previous.map_or(0, |(_, c)| c as i64));
+        previous = Some((index, count));
+    }
+
+    (spans, deltas)
+}
+
+fn write_key(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+    write_varint(out, u64::from(field << 3 | u32::from(wire_type)));
+}
+
+fn write_bytes(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(out, field, DELIMITED);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_and_spans() {
+        assert_eq!(bucket_index(1.0, 0), 0);
+        assert_eq!(bucket_index(2.0, 0), 1);
+        assert_eq!(bucket_index(3.0, 0), 2);
+        assert_eq!(bucket_index(0.25, 0), -2);
+
+        let buckets = BTreeMap::from([(-2, 4), (-1, 1), (3, 2)]);
+        assert_eq!(
+            spans_and_deltas(&buckets),
+            (vec![(-2, 2), (3, 1)], vec![4, -3, 1])
+        );
+    }
+}
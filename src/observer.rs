@@ -0,0 +1,48 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::Code;
+
+/// Callback invoked for every completed server call, registered with
+/// [`MetricsLayer::with_observer`](crate::MetricsLayer::with_observer).
+///
+/// It runs inline when the call completes, so it should be cheap and must not
+/// block.
+pub trait CallObserver: Send + Sync + 'static {
+    fn on_complete(&self, call: &CallInfo<'_>);
+}
+
+impl<F> CallObserver for F
+where
+    F: Fn(&CallInfo<'_>) + Send + Sync + 'static,
+{
+    fn on_complete(&self, call: &CallInfo<'_>) {
+        self(call)
+    }
+}
+
+/// A completed server call.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CallInfo<'a> {
+    pub service: &'a str,
+    pub method: &'a str,
+    pub code: Code,
+    pub duration: Duration,
+    /// Request body size, when the request declared a `content-length`.
+    pub request_bytes: Option<u64>,
+    /// Response body bytes sent before the call completed.
+    pub response_bytes: u64,
+    pub peer: Option<SocketAddr>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Observer(pub(crate) Arc<dyn CallObserver>);
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CallObserver")
+    }
+}
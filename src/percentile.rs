@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use prometheus::core::Collector;
+use prometheus::proto::{Metric, MetricFamily};
+
+use crate::metrics::{routed_metrics, HISTOGRAM_SMC};
+use crate::sketch;
+
+/// Estimate the `p` percentile, e.g. 99.0 for p99, of the durations of server
+/// calls to `service`/`method`, if the method has been called.
+///
+/// The estimate comes from the quantile sketch if
+/// `GlobalSettings::quantile_sketches` is set, and otherwise from the buckets
+/// of `grpc_server_handling_seconds` like PromQL's `histogram_quantile`, over
+/// all calls since the start and all codes recorded in the histogram. It is
+/// meant for health checks and autoscaling hooks in the same process, which
+/// would otherwise have to scrape it.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// let p99 = tonic_prometheus_layer::metrics::percentile("helloworld.Greeter", "SayHello", 99.0);
+/// let overloaded = p99.is_some_and(|p99| p99 > Duration::from_millis(500));
+/// ```
+///
+/// `service` is the value of the `grpc_service` label, after
+/// `GlobalSettings::service_names`.
+pub fn percentile(service: &str, method: &str, p: f64) -> Option<Duration> {
+    if !(0.0..=100.0).contains(&p) {
+        return None;
+    }
+    let q = p / 100.0;
+
+    let seconds = match sketch::quantile(service, method, q) {
+        Some(seconds) => seconds,
+        None => {
+            let families = match routed_metrics(service) {
+                Some(routed) => routed.handling.collect(),
+                None => HISTOGRAM_SMC.collect(),
+            };
+            histogram_quantile(&families, service, method, q)?
+        }
+    };
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// The `q` quantile of the method's observations in a duration histogram,
+/// interpolated linearly within the bucket it falls in.
+fn histogram_quantile(
+    families: &[MetricFamily],
+    service: &str,
+    method: &str,
+    q: f64,
+) -> Option<f64> {
+    // Cumulative counts by upper bound, summed over the method's series.
+    let mut buckets: Vec<(f64, u64)> = Vec::new();
+    let mut count = 0;
+    let of_method = |metric: &&Metric| {
+        metric
+            .get_label()
+            .iter()
+            .all(|label| match label.get_name() {
+                "grpc_service" => label.get_value() == service,
+                "grpc_method" => label.get_value() == method,
+                _ => true,
+            })
+    };
+    let metrics = families.iter().flat_map(|family| family.get_metric());
+    for metric in metrics.filter(of_method) {
+        let histogram = metric.get_histogram();
+        count += histogram.get_sample_count();
+        for (i, bucket) in histogram.get_bucket().iter().enumerate() {
+            match buckets.get_mut(i) {
+                Some((_, cumulative)) => *cumulative += bucket.get_cumulative_count(),
+                None => buckets.push((bucket.get_upper_bound(), bucket.get_cumulative_count())),
+            }
+        }
+    }
+    if count == 0 {
+        return None;
+    }
+
+    let rank = q * count as f64;
+    let mut lower = (0.0, 0);
+    for &(upper_bound, cumulative) in &buckets {
+        if cumulative as f64 >= rank && cumulative > lower.1 {
+            let share = (rank - lower.1 as f64) / (cumulative - lower.1) as f64;
+            return Some(lower.0 + (upper_bound - lower.0) * share);
+        }
+        lower = (upper_bound, cumulative);
+    }
+    // Beyond the highest bucket, of which only the lower bound is known.
+    Some(lower.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_buckets() {
+        let opts = prometheus::histogram_opts!("percentile_seconds", "test", vec![0.1, 0.2, 0.4]);
+        let histogram =
+            prometheus::HistogramVec::new(opts, &["grpc_service", "grpc_method", "grpc_code"])
+                .unwrap();
+        for seconds in [0.05, 0.15, 0.15, 0.3] {
+            histogram
+                .with_label_values(&["test.Percentile", "Get", "OK"])
+                .observe(seconds);
+        }
+        histogram
+            .with_label_values(&["test.Percentile", "Get", "Internal"])
+            .observe(1.0);
+        histogram
+            .with_label_values(&["test.Percentile", "Other", "OK"])
+            .observe(1.0);
+
+        let families = histogram.collect();
+        let quantile = |q| histogram_quantile(&families, "test.Percentile", "Get", q).unwrap();
+        assert!((quantile(0.2) - 0.1).abs() < 1e-9);
+        assert!((quantile(0.5) - 0.175).abs() < 1e-9);
+        assert_eq!(quantile(1.0), 0.4);
+        assert_eq!(
+            histogram_quantile(&families, "test.Percentile", "Put", 0.5),
+            None
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use tonic::codegen::http::{header, HeaderMap};
+use tonic::Code;
+
+/// Wire protocol a call was made with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    #[default]
+    Grpc,
+    /// gRPC-Web with binary message framing.
+    GrpcWeb,
+    /// gRPC-Web with base64-encoded message framing.
+    GrpcWebText,
+    /// Connect protocol unary call.
+    Connect,
+    /// HTTP request transcoded into a gRPC call, see [`crate::RpcMethod`].
+    Transcoded,
+    /// Plain HTTP request that is not a gRPC call at all.
+    Http,
+}
+
+impl Protocol {
+    /// Detect the protocol from the request's `content-type`.
+    pub(crate) fn detect(headers: &HeaderMap) -> Self {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+
+        if content_type.starts_with("application/grpc-web-text") {
+            Protocol::GrpcWebText
+        } else if content_type.starts_with("application/grpc-web") {
+            Protocol::GrpcWeb
+        } else if content_type.starts_with("application/grpc") {
+            Protocol::Grpc
+        } else if content_type.starts_with("application/proto")
+            // JSON is common in plain HTTP routes too, so it needs the Connect header.
+            || headers.contains_key("connect-protocol-version")
+        {
+            Protocol::Connect
+        } else {
+            Protocol::Http
+        }
+    }
+
+    /// Value of the `protocol` label.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Grpc => "grpc",
+            Protocol::GrpcWeb | Protocol::GrpcWebText => "grpc-web",
+            Protocol::Connect => "connect",
+            Protocol::Transcoded => "transcoded",
+            Protocol::Http => "http",
+        }
+    }
+}
+
+/// Map an HTTP status to a gRPC code, as specified for responses that carry
+/// no gRPC status of their own.
+pub(crate) fn code_from_http_status(status: u16) -> Code {
+    match status {
+        200 => Code::Ok,
+        400 => Code::Internal,
+        401 => Code::Unauthenticated,
+        403 => Code::PermissionDenied,
+        404 => Code::Unimplemented,
+        429 | 502 | 503 | 504 => Code::Unavailable,
+        _ => Code::Unknown,
+    }
+}
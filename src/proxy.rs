@@ -0,0 +1,76 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Request extension correlating a server call with the client calls it makes,
+/// to export the time a proxy spends on a call besides waiting for its
+/// upstreams in `grpc_proxy_overhead_seconds`.
+///
+/// The layer inserts it into server requests if
+/// `GlobalSettings::proxy_overhead` is set. Copy it into the requests of the
+/// outbound calls made through [`MetricsChannel`](crate::MetricsChannel) to
+/// count their duration against the inbound call.
+///
+/// ```no_run
+/// use tonic_prometheus_layer::metrics::ProxyContext;
+///
+/// # fn handler(request: tonic::Request<()>) {
+/// let mut outbound = tonic::Request::new(());
+/// if let Some(context) = request.extensions().get::<ProxyContext>() {
+///     outbound.extensions_mut().insert(context.clone());
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ProxyContext(Arc<Outbound>);
+
+#[derive(Debug, Default)]
+struct Outbound {
+    calls: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl ProxyContext {
+    /// Add an outbound call that took `duration`.
+    #[cfg(feature = "client")]
+    pub(crate) fn add(&self, duration: Duration) {
+        self.0.calls.fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.0.nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// The time of a server call that took `duration` not spent in its
+    /// outbound calls, if it made any.
+    ///
+    /// Outbound calls made concurrently add up to more than the time waited
+    /// for them, in which case the overhead is understated, down to zero.
+    pub(crate) fn overhead(&self, duration: Duration) -> Option<Duration> {
+        if self.0.calls.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let outbound = Duration::from_nanos(self.0.nanos.load(Ordering::Relaxed));
+        Some(duration.saturating_sub(outbound))
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overhead() {
+        let context = ProxyContext::default();
+        assert_eq!(context.overhead(Duration::from_millis(10)), None);
+
+        context.clone().add(Duration::from_millis(3));
+        context.add(Duration::from_millis(4));
+        assert_eq!(
+            context.overhead(Duration::from_millis(10)),
+            Some(Duration::from_millis(3))
+        );
+        assert_eq!(
+            context.overhead(Duration::from_millis(5)),
+            Some(Duration::ZERO)
+        );
+    }
+}
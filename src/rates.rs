@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use tonic::Code;
+
+use crate::metrics::get_settings;
+use crate::snapshot::Snapshot;
+
+static SAMPLES: Lazy<Mutex<VecDeque<Sample>>> = Lazy::new(Default::default);
+
+/// Request and error counts of every server method at one point in time.
+struct Sample {
+    at: Instant,
+    counts: HashMap<(String, String), Counts>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counts {
+    requests: u64,
+    errors: u64,
+}
+
+impl Sample {
+    fn take() -> Self {
+        let snapshot = Snapshot::from_registry(&get_settings().registry);
+        let counts = snapshot
+            .server_methods()
+            .map(|(service, method, m)| {
+                let requests = m.handled_total();
+                let counts = Counts {
+                    requests,
+                    errors: requests - m.handled(Code::Ok),
+                };
+                ((service.to_owned(), method.to_owned()), counts)
+            })
+            .collect();
+
+        Sample {
+            at: Instant::now(),
+            counts,
+        }
+    }
+}
+
+/// Request and error rates of a method, per second.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rate {
+    pub requests: f64,
+    /// Calls that completed with any code other than `Ok`.
+    pub errors: f64,
+}
+
+impl Rate {
+    /// Fraction of requests that failed, or zero without requests.
+    pub fn error_ratio(&self) -> f64 {
+        if self.requests > 0.0 {
+            self.errors / self.requests
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Starts the sampler thread on first use.
+static SAMPLER: Lazy<()> = Lazy::new(|| {
+    let result = thread::Builder::new()
+        .name("metrics-rates".to_owned())
+        .spawn(|| {
+            let settings = get_settings()
+                .rates
+                .as_ref()
+                .expect("sampler started without settings");
+            loop {
+                // Sampling gathers the registry, so it is done outside the lock.
+                let sample = Sample::take();
+                push(&mut SAMPLES.lock().unwrap(), sample, settings.retention);
+                thread::sleep(settings.resolution);
+            }
+        });
+    if let Err(e) = result {
+        tracing::warn!("failed to start the rates sampler: {e}");
+    }
+});
+
+/// Start sampling the counters in the background, if
+/// `GlobalSettings::rates` is set.
+pub(crate) fn start() {
+    if get_settings().rates.is_some() {
+        Lazy::force(&SAMPLER);
+    }
+}
+
+/// Append `sample`, dropping the samples older than `retention`.
+fn push(samples: &mut VecDeque<Sample>, sample: Sample, retention: Duration) {
+    while samples
+        .front()
+        .is_some_and(|s| sample.at.duration_since(s.at) > retention)
+    {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+/// Compute request and error rates of every server method over the last
+/// `window`, keyed by `(service, method)`.
+///
+/// Requires [`GlobalSettings::rates`](crate::metrics::GlobalSettings::rates).
+/// Counters are sampled in the background once per `resolution`, so the
+/// actual window is the one between the oldest sample inside `window` and
+/// now. Without such a sample the result is empty.
+pub fn rates(window: Duration) -> BTreeMap<(String, String), Rate> {
+    if get_settings().rates.is_none() {
+        return BTreeMap::new();
+    }
+    start();
+
+    let now = Sample::take();
+    rates_since(&SAMPLES.lock().unwrap(), &now, window)
+}
+
+fn rates_since(
+    samples: &VecDeque<Sample>,
+    now: &Sample,
+    window: Duration,
+) -> BTreeMap<(String, String), Rate> {
+    let Some(start) = samples
+        .iter()
+        .find(|s| now.at.duration_since(s.at) <= window)
+    else {
+        return BTreeMap::new();
+    };
+    let elapsed = now.at.duration_since(start.at).as_secs_f64();
+    if elapsed <= 0.0 {
+        return BTreeMap::new();
+    }
+
+    now.counts
+        .iter()
+        .map(|(key, counts)| {
+            let before = start.counts.get(key).copied().unwrap_or_default();
+            let rate = Rate {
+                requests: (counts.requests - before.requests) as f64 / elapsed,
+                errors: (counts.errors - before.errors) as f64 / elapsed,
+            };
+            (key.clone(), rate)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(at: Instant, requests: u64, errors: u64) -> Sample {
+        let counts = Counts { requests, errors };
+        Sample {
+            at,
+            counts: HashMap::from([(("test.Rates".to_owned(), "Get".to_owned()), counts)]),
+        }
+    }
+
+    #[test]
+    fn rates_over_window() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let retention = Duration::from_secs(60);
+        let mut samples = VecDeque::new();
+        push(&mut samples, sample(at(0), 0, 0), retention);
+        push(&mut samples, sample(at(30), 10, 0), retention);
+        push(&mut samples, sample(at(50), 30, 2), retention);
+
+        let now = sample(at(70), 70, 6);
+        let key = ("test.Rates".to_owned(), "Get".to_owned());
+        // The oldest sample inside the window starts it.
+        assert_eq!(
+            rates_since(&samples, &now, Duration::from_secs(45))[&key],
+            Rate {
+                requests: 1.5,
+                errors: 0.15,
+            }
+        );
+        assert!(rates_since(&samples, &now, Duration::from_secs(5)).is_empty());
+
+        push(&mut samples, now, retention);
+        assert_eq!(samples.len(), 3);
+        assert_eq!(
+            Rate {
+                requests: 4.0,
+                errors: 1.0
+            }
+            .error_ratio(),
+            0.25
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+
+use crate::metrics::get_settings;
+
+/// Label pairs of a series, sorted by name.
+pub type Labels = Vec<(String, String)>;
+
+/// A rule rewriting the labels of exported series, see
+/// `GlobalSettings::relabel`.
+///
+/// Rules only apply to the export: the metrics are recorded as usual, and
+/// series that end up with the same labels are added up into one.
+///
+/// ```
+/// use tonic_prometheus_layer::metrics::Relabel;
+///
+/// let rules = vec![
+///     Relabel::rename_value("grpc_service", "helloworld.Greeter", "greeter"),
+///     Relabel::merge_values("grpc_code", ["Cancelled", "DeadlineExceeded"], "ClientGone"),
+///     Relabel::add_label("cluster", "eu-1"),
+/// ];
+/// ```
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+#[derive(Clone, Debug)]
+pub enum Relabel {
+    /// Replace any of the values `from` of the label `label` with `to`.
+    ReplaceValues {
+        label: String,
+        from: Vec<String>,
+        to: String,
+    },
+    /// Add the label `label` with the value `value` to series that lack it.
+    AddLabel { label: String, value: String },
+    /// Rewrite the labels of the series of the family named by the first
+    /// argument.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Custom(fn(&str, &mut Labels)),
+}
+
+impl Relabel {
+    /// Replace the value `from` of the label `label` with `to`.
+    pub fn rename_value(
+        label: impl Into<String>,
+        from: impl Into<String>,
+        to: impl Into<String>,
+    ) -> Self {
+        Self::merge_values(label, [from], to)
+    }
+
+    /// Replace all of the values `from` of the label `label` with `to`.
+    pub fn merge_values(
+        label: impl Into<String>,
+        from: impl IntoIterator<Item = impl Into<String>>,
+        to: impl Into<String>,
+    ) -> Self {
+        Self::ReplaceValues {
+            label: label.into(),
+            from: from.into_iter().map(Into::into).collect(),
+            to: to.into(),
+        }
+    }
+
+    /// Add the label `label` with the value `value` to every series.
+    pub fn add_label(label: impl Into<String>, value: impl Into<String>) -> Self {
+        Self::AddLabel {
+            label: label.into(),
+            value: value.into(),
+        }
+    }
+
+    fn apply(&self, family: &str, labels: &mut Labels) {
+        match self {
+            Relabel::ReplaceValues { label, from, to } => {
+                for (name, value) in labels.iter_mut() {
+                    if name == label && from.contains(value) {
+                        value.clone_from(to);
+                    }
+                }
+            }
+            Relabel::AddLabel { label, value } => {
+                if !labels.iter().any(|(name, _)| name == label) {
+                    labels.push((label.clone(), value.clone()));
+                }
+            }
+            Relabel::Custom(f) => f(family, labels),
+        }
+    }
+}
+
+/// Rewrite `labels` of a series of the family `family` with the rules of
+/// `GlobalSettings::relabel`.
+pub(crate) fn relabel_pairs(family: &str, labels: &mut Labels) {
+    for rule in &get_settings().relabel {
+        rule.apply(family, labels);
+    }
+    labels.sort();
+}
+
+/// Apply the rules of `GlobalSettings::relabel` to `families`.
+pub(crate) fn relabel(families: &mut [MetricFamily]) {
+    if get_settings().relabel.is_empty() {
+        return;
+    }
+
+    for family in families {
+        let metrics = family
+            .take_metric()
+            .into_iter()
+            .map(|mut metric| {
+                let mut labels = metric
+                    .take_label()
+                    .into_iter()
+                    .map(|mut l| (l.take_name(), l.take_value()))
+                    .collect();
+                relabel_pairs(family.get_name(), &mut labels);
+                (labels, metric)
+            })
+            .collect();
+        let metrics = add_up(metrics, family.get_field_type());
+        family.set_metric(metrics.into());
+    }
+}
+
+/// Set the labels of the series and add up those with the same labels.
+pub(crate) fn add_up(metrics: Vec<(Labels, Metric)>, field_type: MetricType) -> Vec<Metric> {
+    let mut added: Vec<Metric> = Vec::with_capacity(metrics.len());
+    let mut index: HashMap<Labels, usize> = HashMap::new();
+
+    for (labels, mut metric) in metrics {
+        if let Some(&i) = index.get(&labels) {
+            add(&mut added[i], &metric, field_type);
+            continue;
+        }
+        let pairs: Vec<LabelPair> = labels
+            .iter()
+            .map(|(name, value)| {
+                let mut pair = LabelPair::new();
+                pair.set_name(name.clone());
+                pair.set_value(value.clone());
+                pair
+            })
+            .collect();
+        metric.set_label(pairs.into());
+        index.insert(labels, added.len());
+        added.push(metric);
+    }
+    added
+}
+
+/// Add the value of `other` to `metric`.
+fn add(metric: &mut Metric, other: &Metric, field_type: MetricType) {
+    match field_type {
+        MetricType::COUNTER => {
+            let value = metric.get_counter().get_value() + other.get_counter().get_value();
+            metric.mut_counter().set_value(value);
+        }
+        MetricType::GAUGE => {
+            let value = metric.get_gauge().get_value() + other.get_gauge().get_value();
+            metric.mut_gauge().set_value(value);
+        }
+        MetricType::UNTYPED => {
+            let value = metric.get_untyped().get_value() + other.get_untyped().get_value();
+            metric.mut_untyped().set_value(value);
+        }
+        MetricType::HISTOGRAM => {
+            let other = other.get_histogram();
+            let histogram = metric.mut_histogram();
+            histogram.set_sample_count(histogram.get_sample_count() + other.get_sample_count());
+            histogram.set_sample_sum(histogram.get_sample_sum() + other.get_sample_sum());
+            // Series of a family share their buckets.
+            for (bucket, other) in histogram.mut_bucket().iter_mut().zip(other.get_bucket()) {
+                bucket.set_cumulative_count(
+                    bucket.get_cumulative_count() + other.get_cumulative_count(),
+                );
+            }
+        }
+        MetricType::SUMMARY => {
+            let other = other.get_summary();
+            let summary = metric.mut_summary();
+            summary.set_sample_count(summary.get_sample_count() + other.get_sample_count());
+            summary.set_sample_sum(summary.get_sample_sum() + other.get_sample_sum());
+            // Quantiles cannot be added up.
+            summary.clear_quantile();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_labels() {
+        let rules = [
+            Relabel::merge_values("code", ["Cancelled", "DeadlineExceeded"], "ClientGone"),
+            Relabel::add_label("cluster", "eu-1"),
+            Relabel::add_label("code", "unused"),
+        ];
+        let mut labels = vec![("code".to_owned(), "Cancelled".to_owned())];
+        for rule in &rules {
+            rule.apply("family", &mut labels);
+        }
+        labels.sort();
+
+        assert_eq!(
+            labels,
+            [
+                ("cluster".to_owned(), "eu-1".to_owned()),
+                ("code".to_owned(), "ClientGone".to_owned()),
+            ]
+        );
+    }
+}
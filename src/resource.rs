@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+/// OpenTelemetry resource attributes kept as const labels by
+/// [`resource_labels`].
+pub const RESOURCE_ATTRIBUTES: &[&str] = &[
+    "service.name",
+    "service.version",
+    "service.namespace",
+    "service.instance.id",
+    "deployment.environment",
+];
+
+/// Const labels for the [`RESOURCE_ATTRIBUTES`] among `attributes`, so the
+/// exported series carry the same identity as the OTLP traces of the service.
+///
+/// Label names are the attribute keys with every character that is not valid
+/// in a Prometheus label name replaced by `_`, e.g. `service_name`.
+///
+/// ```
+/// let labels = tonic_prometheus_layer::metrics::resource_labels([
+///     ("service.name", "checkout"),
+///     ("deployment.environment", "prod"),
+///     ("host.arch", "amd64"),
+/// ]);
+///
+/// let registry = prometheus::Registry::new_custom(None, Some(labels)).unwrap();
+/// ```
+pub fn resource_labels<K, V>(
+    attributes: impl IntoIterator<Item = (K, V)>,
+) -> HashMap<String, String>
+where
+    K: AsRef<str>,
+    V: ToString,
+{
+    attributes
+        .into_iter()
+        .filter(|(key, _)| RESOURCE_ATTRIBUTES.contains(&key.as_ref()))
+        .map(|(key, value)| (label_name(key.as_ref()), value.to_string()))
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+}
+
+/// A registry labeling every series with the attributes of `resource`, see
+/// [`resource_labels`]. Use it as `GlobalSettings::registry`.
+#[cfg(feature = "opentelemetry")]
+pub fn resource_registry(
+    resource: &opentelemetry_sdk::Resource,
+) -> prometheus::Result<prometheus::Registry> {
+    let labels = resource_labels(resource.iter().map(|(key, value)| (key.as_str(), value)));
+
+    prometheus::Registry::new_custom(None, Some(labels))
+}
+
+fn label_name(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resource_attributes() {
+        let labels = resource_labels([
+            ("service.name", "checkout"),
+            ("service.version", ""),
+            ("deployment.environment", "prod"),
+            ("host.arch", "amd64"),
+        ]);
+
+        assert_eq!(
+            labels,
+            HashMap::from([
+                ("service_name".to_owned(), "checkout".to_owned()),
+                ("deployment_environment".to_owned(), "prod".to_owned()),
+            ])
+        );
+        prometheus::Registry::new_custom(None, Some(labels)).unwrap();
+    }
+}
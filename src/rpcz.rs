@@ -0,0 +1,158 @@
+//! Recent slow and failed server calls, in the spirit of gRPC's rpcz pages.
+//!
+//! Enable it with [`GlobalSettings::rpcz`](crate::metrics::GlobalSettings::rpcz), then
+//! serve [`render_html`] or [`render_json`] from a debug endpoint such as
+//! `/debug/rpcz` next to `/metrics`.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::Lazy;
+use tonic::Code;
+
+use crate::metrics::get_settings;
+use crate::observer::CallInfo;
+
+static CALLS: Lazy<Mutex<VecDeque<CallRecord>>> = Lazy::new(Default::default);
+
+/// A slow or failed server call.
+#[derive(Clone, Debug)]
+pub struct CallRecord {
+    pub service: String,
+    pub method: String,
+    pub code: Code,
+    pub duration: Duration,
+    pub peer: Option<SocketAddr>,
+    pub started_at: SystemTime,
+}
+
+/// Keep the call if it is slow or failed, evicting the oldest retained call
+/// when full.
+pub(crate) fn record(call: &CallInfo<'_>) {
+    let Some(settings) = get_settings().rpcz.as_ref() else {
+        return;
+    };
+    if call.code == Code::Ok && call.duration < settings.slow_threshold || settings.capacity == 0 {
+        return;
+    }
+
+    let mut calls = CALLS.lock().unwrap();
+    if calls.len() >= settings.capacity {
+        calls.pop_front();
+    }
+    calls.push_back(CallRecord::from(call));
+}
+
+impl From<&CallInfo<'_>> for CallRecord {
+    fn from(call: &CallInfo<'_>) -> Self {
+        Self {
+            service: call.service.to_owned(),
+            method: call.method.to_owned(),
+            code: call.code,
+            duration: call.duration,
+            peer: call.peer,
+            started_at: SystemTime::now() - call.duration,
+        }
+    }
+}
+
+/// Retained calls, most recent first.
+pub fn recent_calls() -> Vec<CallRecord> {
+    CALLS.lock().unwrap().iter().rev().cloned().collect()
+}
+
+/// Render the retained calls as a JSON array.
+pub fn render_json() -> String {
+    let mut out = String::from("[");
+    for (i, call) in recent_calls().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let peer = call
+            .peer
+            .map_or_else(|| "null".to_owned(), |p| json_string(&p.to_string()));
+        let _ = write!(
+            out,
+            r#"{{"service":{},"method":{},"code":"{:?}","duration_seconds":{},"peer":{},"timestamp":{}}}"#,
+            json_string(&call.service),
+            json_string(&call.method),
+            call.code,
+            call.duration.as_secs_f64(),
+            peer,
+            unix_seconds(call.started_at),
+        );
+    }
+    out.push(']');
+    out
+}
+
+/// Render the retained calls as an HTML page.
+pub fn render_html() -> String {
+    let mut out = String::from(
+        "<!DOCTYPE html><html><head><title>rpcz</title></head><body>\
+         <h1>Recent slow and failed calls</h1><table border=\"1\">\
+         <tr><th>Started (unix)</th><th>Service</th><th>Method</th><th>Code</th>\
+         <th>Duration (s)</th><th>Peer</th></tr>",
+    );
+    for call in recent_calls() {
+        let _ = write!(
+            out,
+            "<tr><td>{:.3}</td><td>{}</td><td>{}</td><td>{:?}</td><td>{:.6}</td><td>{}</td></tr>",
+            unix_seconds(call.started_at),
+            html_escape(&call.service),
+            html_escape(&call.method),
+            call.code,
+            call.duration.as_secs_f64(),
+            call.peer.map(|p| p.to_string()).unwrap_or_default(),
+        );
+    }
+    out.push_str("</table></body></html>");
+    out
+}
+
+pub(crate) fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        assert_eq!(json_string("a\"b\\c\n"), r#""a\"b\\c\u000a""#);
+        assert_eq!(
+            html_escape("<a href=\"x\">&</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use http_body::{Body, Frame, SizeHint};
+use pin_project::pin_project;
+use tonic::codegen::http::{header, request, response, HeaderValue, Method, StatusCode};
+use tower::{Layer, Service};
+
+use crate::merge;
+use crate::metrics::{peek_to_string, render_cardinality_report, scraped, PROTOBUF_FORMAT};
+use crate::native::peek_protobuf;
+use crate::protocol::Protocol;
+use crate::toggles;
+
+/// Content type of [`encode_to_string`](crate::metrics::encode_to_string) output.
+pub(crate) const TEXT_FORMAT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Samples describing the previous export, which change with every text
+/// export and are left out of its `ETag`.
+const SCRAPE_SAMPLES_PREFIX: &str = "grpc_metrics_scrape_";
+
+/// Layer answering scrapes of the collected metrics on the gRPC server's own
+/// port, so no second listener is needed for `/metrics`.
+///
+/// `GET` requests to the path without a gRPC content type get the text
+/// exposition, or [`encode_protobuf`](crate::metrics::encode_protobuf) output
+/// when the scraper accepts
+/// [`PROTOBUF_FORMAT`]. All other requests go to the inner service.
+///
+/// Responses carry an `ETag` hashed from their content, and requests whose
+/// `If-None-Match` lists it get `304 Not Modified` without a body, which
+/// saves the transfer, though not the encoding, for frequent scrapers.
+/// `HEAD` requests get the headers only. Only responses with the metrics in
+/// their body count as scrapes, after which the gauges of the highest values
+/// since the previous scrape, e.g. `grpc_server_in_flight_max`, start over.
+///
+/// With [`ScrapeLayer::with_features_path`], the recording features of
+/// [`RecordingFeature`](crate::metrics::RecordingFeature) can also be listed
+/// and switched over HTTP.
+///
+/// Prometheus scrapes over HTTP/1.1, so the server must accept it:
+///
+/// ```no_run
+/// # async fn run() {
+/// let (_, health) = tonic_health::server::health_reporter();
+///
+/// tonic::transport::Server::builder()
+///     .accept_http1(true)
+///     .layer(tonic_prometheus_layer::ScrapeLayer::new())
+///     .layer(tonic_prometheus_layer::MetricsLayer::new())
+///     .add_service(health)
+///     .serve("127.0.0.1:9090".parse().unwrap())
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+///
+/// Put it outside of [`MetricsLayer`](crate::MetricsLayer), as above, to keep
+/// scrapes out of the recorded requests.
+#[derive(Clone, Debug)]
+pub struct ScrapeLayer {
+    path: String,
+    cardinality_path: Option<String>,
+    features_path: Option<String>,
+}
+
+impl ScrapeLayer {
+    /// Serve the metrics on `/metrics`.
+    pub fn new() -> Self {
+        Self {
+            path: "/metrics".to_owned(),
+            cardinality_path: None,
+            features_path: None,
+        }
+    }
+
+    /// Serve the metrics on `path` instead.
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Also serve [`render_cardinality_report`] on `path`, e.g.
+    /// `/debug/cardinality`.
+    pub fn with_cardinality_path(mut self, path: impl Into<String>) -> Self {
+        self.cardinality_path = Some(path.into());
+        self
+    }
+
+    /// Also list the state of each recording feature on `path`, e.g.
+    /// `/debug/features`, and switch them with `POST` requests whose query
+    /// names the features, e.g. `?cpu_time=on&metadata_size=off`, see
+    /// [`set_feature_enabled`](crate::metrics::set_feature_enabled).
+    ///
+    /// Anyone reaching the port can switch them, so only serve this where the
+    /// port is not exposed to untrusted clients.
+    pub fn with_features_path(mut self, path: impl Into<String>) -> Self {
+        self.features_path = Some(path.into());
+        self
+    }
+}
+
+impl Default for ScrapeLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Layer<S> for ScrapeLayer {
+    type Service = ScrapeService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ScrapeService {
+            service: inner,
+            path: self.path.clone(),
+            cardinality_path: self.cardinality_path.clone(),
+            features_path: self.features_path.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScrapeService<S> {
+    service: S,
+    path: String,
+    cardinality_path: Option<String>,
+    features_path: Option<String>,
+}
+
+impl<S, B, C> Service<request::Request<B>> for ScrapeService<S>
+where
+    S: Service<request::Request<B>, Response = response::Response<C>>,
+{
+    type Response = response::Response<ScrapeBody<C>>;
+    type Error = S::Error;
+    type Future = ScrapeFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: request::Request<B>) -> Self::Future {
+        if self.features_path.as_deref() == Some(req.uri().path())
+            && Protocol::detect(req.headers()) == Protocol::Http
+        {
+            return ScrapeFuture::Scrape(Some(features(&req)));
+        }
+
+        let get = req.method() == Method::GET || req.method() == Method::HEAD;
+        if get && Protocol::detect(req.headers()) == Protocol::Http {
+            let path = req.uri().path();
+            let resp = if path == self.path {
+                Some(scrape(req.headers()))
+            } else if self.cardinality_path.as_deref() == Some(path) {
+                let mut resp = response::Response::new(Bytes::from(render_cardinality_report()));
+                resp.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; charset=utf-8"),
+                );
+                Some(resp)
+            } else {
+                None
+            };
+            if let Some(resp) = resp {
+                let resp = conditional(&req, resp);
+                if path == self.path
+                    && req.method() == Method::GET
+                    && resp.status() == StatusCode::OK
+                {
+                    scraped();
+                }
+                return ScrapeFuture::Scrape(Some(resp));
+            }
+        }
+
+        ScrapeFuture::Inner(self.service.call(req))
+    }
+}
+
+/// Encode the metrics in the format preferred by the scraper, without counting
+/// it as a scrape yet: `HEAD` and `304` responses drop the body.
+fn scrape(headers: &tonic::codegen::http::HeaderMap) -> response::Response<Bytes> {
+    let protobuf = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains("application/vnd.google.protobuf"));
+
+    let encoded = if protobuf {
+        peek_protobuf().map(|body| (PROTOBUF_FORMAT, Bytes::from(body)))
+    } else {
+        peek_to_string().map(|body| (TEXT_FORMAT, Bytes::from(body)))
+    };
+    let (status, content_type, body) = match encoded {
+        Ok((content_type, body)) => (StatusCode::OK, content_type, body),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "text/plain; charset=utf-8",
+            Bytes::from(e.to_string()),
+        ),
+    };
+
+    let mut resp = response::Response::new(body);
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    resp
+}
+
+/// List the recording features, after switching those named by the query of
+/// `POST` requests.
+fn features<B>(req: &request::Request<B>) -> response::Response<Bytes> {
+    let (status, body) = match *req.method() {
+        Method::GET | Method::HEAD => (StatusCode::OK, toggles::render()),
+        Method::POST => match toggles::apply_query(req.uri().query().unwrap_or_default()) {
+            Ok(()) => (StatusCode::OK, toggles::render()),
+            Err(e) => (StatusCode::BAD_REQUEST, e + "\n"),
+        },
+        _ => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+    };
+
+    let mut resp = response::Response::new(Bytes::from(body));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    if status == StatusCode::METHOD_NOT_ALLOWED {
+        resp.headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static("GET, HEAD, POST"));
+    } else if req.method() == Method::HEAD {
+        *resp.body_mut() = Bytes::new();
+    }
+    resp
+}
+
+/// Tag a successful response with its `ETag`, and strip the body for `HEAD`
+/// requests and requests that already have the content.
+fn conditional<B>(
+    req: &request::Request<B>,
+    mut resp: response::Response<Bytes>,
+) -> response::Response<Bytes> {
+    if resp.status() != StatusCode::OK {
+        return resp;
+    }
+
+    let mut hasher = DefaultHasher::new();
+    let content_type = resp.headers().get(header::CONTENT_TYPE);
+    content_type.hash(&mut hasher);
+    if content_type.is_some_and(|v| v == TEXT_FORMAT) {
+        let prefix = merge::exported_name(SCRAPE_SAMPLES_PREFIX);
+        resp.body()
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.starts_with(prefix.as_bytes()))
+            .for_each(|line| line.hash(&mut hasher));
+    } else {
+        resp.body().hash(&mut hasher);
+    }
+    let etag = format!("\"{:016x}\"", hasher.finish());
+
+    let not_modified = req
+        .headers()
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    resp.headers_mut().insert(
+        header::ETAG,
+        HeaderValue::from_str(&etag).expect("hex digits are a valid header value"),
+    );
+    if not_modified {
+        *resp.status_mut() = StatusCode::NOT_MODIFIED;
+        *resp.body_mut() = Bytes::new();
+    } else if req.method() == Method::HEAD {
+        let len = resp.body().len();
+        resp.headers_mut()
+            .insert(header::CONTENT_LENGTH, len.into());
+        *resp.body_mut() = Bytes::new();
+    }
+    resp
+}
+
+#[pin_project(project = ScrapeFutureProj)]
+pub enum ScrapeFuture<F> {
+    Scrape(Option<response::Response<Bytes>>),
+    Inner(#[pin] F),
+}
+
+impl<F, C, E> std::future::Future for ScrapeFuture<F>
+where
+    F: std::future::Future<Output = Result<response::Response<C>, E>>,
+{
+    type Output = Result<response::Response<ScrapeBody<C>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ScrapeFutureProj::Scrape(resp) => Poll::Ready(Ok(resp
+                .take()
+                .expect("ScrapeFuture polled after completion")
+                .map(|body| ScrapeBody::Metrics(Some(body))))),
+            ScrapeFutureProj::Inner(inner) => {
+                inner.poll(cx).map_ok(|resp| resp.map(ScrapeBody::Inner))
+            }
+        }
+    }
+}
+
+/// Response body of a [`ScrapeService`]: the encoded metrics, or the body of
+/// the inner service.
+#[pin_project(project = ScrapeBodyProj)]
+pub enum ScrapeBody<B> {
+    Metrics(Option<Bytes>),
+    Inner(#[pin] B),
+}
+
+impl<B> Body for ScrapeBody<B>
+where
+    B: Body<Data = Bytes>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.project() {
+            ScrapeBodyProj::Metrics(body) => Poll::Ready(body.take().map(|b| Ok(Frame::data(b)))),
+            ScrapeBodyProj::Inner(inner) => inner.poll_frame(cx),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match self {
+            ScrapeBody::Metrics(body) => body.is_none(),
+            ScrapeBody::Inner(inner) => inner.is_end_stream(),
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self {
+            ScrapeBody::Metrics(body) => {
+                SizeHint::with_exact(body.as_ref().map_or(0, |b| b.len() as u64))
+            }
+            ScrapeBody::Inner(inner) => inner.size_hint(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn routes_scrapes() {
+        let inner = tower::service_fn(|_: request::Request<()>| async {
+            Ok::<_, Infallible>(response::Response::new(
+                http_body_util::Empty::<Bytes>::new(),
+            ))
+        });
+        let service = ScrapeLayer::new()
+            .with_cardinality_path("/debug/cardinality")
+            .with_features_path("/debug/features")
+            .layer(inner);
+
+        let scrape = request::Request::get("/metrics").body(()).unwrap();
+        let resp = service.clone().oneshot(scrape).await.unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], TEXT_FORMAT);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(!body.is_empty());
+
+        let cardinality = request::Request::get("/debug/cardinality")
+            .body(())
+            .unwrap();
+        let resp = service.clone().oneshot(cardinality).await.unwrap();
+        assert!(matches!(resp.body(), ScrapeBody::Metrics(_)));
+
+        let features = request::Request::get("/debug/features").body(()).unwrap();
+        let resp = service.clone().oneshot(features).await.unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.starts_with(b"metadata_size "));
+        let invalid = request::Request::post("/debug/features?cpu_time=maybe")
+            .body(())
+            .unwrap();
+        let resp = service.clone().oneshot(invalid).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let grpc = request::Request::get("/metrics")
+            .header(header::CONTENT_TYPE, "application/grpc")
+            .body(())
+            .unwrap();
+        let resp = service.oneshot(grpc).await.unwrap();
+        assert!(matches!(resp.body(), ScrapeBody::Inner(_)));
+    }
+
+    #[test]
+    fn conditional_scrapes() {
+        // The exporter's own samples differ between otherwise equal exports.
+        let exports = std::cell::Cell::new(0);
+        let scrape = |method, etag: Option<&str>| {
+            let mut req = request::Request::builder().method(method).uri("/metrics");
+            if let Some(etag) = etag {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+            exports.set(exports.get() + 1);
+            let body = format!("up 1\ngrpc_metrics_scrape_size_bytes {}\n", exports.get());
+            let mut resp = response::Response::new(Bytes::from(body));
+            resp.headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(TEXT_FORMAT));
+            conditional(&req.body(()).unwrap(), resp)
+        };
+
+        let resp = scrape(Method::GET, None);
+        let etag = resp.headers()[header::ETAG].to_str().unwrap().to_owned();
+        assert_eq!(resp.body(), "up 1\ngrpc_metrics_scrape_size_bytes 1\n");
+
+        let resp = scrape(Method::HEAD, None);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::ETAG], *etag);
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "38");
+        assert!(resp.body().is_empty());
+
+        let resp = scrape(Method::GET, Some(&format!("\"other\", W/{etag}")));
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert!(resp.body().is_empty());
+
+        let resp = scrape(Method::GET, Some("\"other\""));
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,214 @@
+//! A gRPC service for scraping the collected metrics, for environments that
+//! only allow gRPC traffic.
+//!
+//! Add [`MetricsServer`] to the tonic server next to your own services; clients
+//! can be generated from `proto/metrics.proto` in this crate's sources.
+//!
+//! ```no_run
+//! # async fn run() {
+//! use tonic_prometheus_layer::scrape_service::MetricsServer;
+//!
+//! tonic::transport::Server::builder()
+//!     .add_service(MetricsServer::new())
+//!     .serve("127.0.0.1:9090".parse().unwrap())
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures_core::Stream;
+use tonic::body::{empty_body, BoxBody};
+use tonic::codegen::{http, Body, BoxFuture, Service, StdError};
+use tonic::server::{Grpc, NamedService, ServerStreamingService};
+use tonic::{Code, Status};
+
+use crate::metrics::{encode_protobuf, encode_to_string, PROTOBUF_FORMAT};
+use crate::scrape::TEXT_FORMAT;
+
+/// Largest `data` of a [`MetricsChunk`].
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Messages of `proto/metrics.proto`, written by hand to spare users a build
+// script.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetMetricsRequest {
+    #[prost(enumeration = "Format", tag = "1")]
+    pub format: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Format {
+    /// Prometheus text exposition format.
+    Text = 0,
+    /// Delimited `io.prometheus.client.MetricFamily` messages, see
+    /// [`PROTOBUF_FORMAT`].
+    Protobuf = 1,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MetricsChunk {
+    #[prost(bytes = "bytes", tag = "1")]
+    pub data: Bytes,
+    /// Content type of the concatenated data, set on the first chunk only.
+    #[prost(string, tag = "2")]
+    pub content_type: String,
+}
+
+/// Server of the `tonic_prometheus_layer.v1.Metrics` service.
+#[derive(Clone, Debug, Default)]
+pub struct MetricsServer {
+    _private: (),
+}
+
+impl MetricsServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl NamedService for MetricsServer {
+    const NAME: &'static str = "tonic_prometheus_layer.v1.Metrics";
+}
+
+impl<B> Service<http::Request<B>> for MetricsServer
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        match req.uri().path() {
+            "/tonic_prometheus_layer.v1.Metrics/GetMetrics" => Box::pin(async move {
+                let mut grpc = Grpc::new(tonic::codec::ProstCodec::default());
+                Ok(grpc.server_streaming(GetMetrics, req).await)
+            }),
+            _ => Box::pin(async move {
+                Ok(http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", (Code::Unimplemented as i32).to_string())
+                    .header(http::header::CONTENT_TYPE, "application/grpc")
+                    .body(empty_body())
+                    .unwrap())
+            }),
+        }
+    }
+}
+
+struct GetMetrics;
+
+impl ServerStreamingService<GetMetricsRequest> for GetMetrics {
+    type Response = MetricsChunk;
+    type ResponseStream = Chunks;
+    type Future = BoxFuture<tonic::Response<Chunks>, Status>;
+
+    fn call(&mut self, request: tonic::Request<GetMetricsRequest>) -> Self::Future {
+        let format = request.get_ref().format();
+
+        Box::pin(async move {
+            let encoded = match format {
+                Format::Text => encode_to_string().map(|s| (TEXT_FORMAT, Bytes::from(s))),
+                Format::Protobuf => encode_protobuf().map(|b| (PROTOBUF_FORMAT, Bytes::from(b))),
+            };
+            let (content_type, data) = encoded.map_err(|e| Status::internal(e.to_string()))?;
+            Ok(tonic::Response::new(Chunks::new(content_type, data)))
+        })
+    }
+}
+
+/// The encoded metrics, split into [`MetricsChunk`]s.
+struct Chunks {
+    content_type: Option<&'static str>,
+    data: Bytes,
+}
+
+impl Chunks {
+    fn new(content_type: &'static str, data: Bytes) -> Self {
+        Self {
+            content_type: Some(content_type),
+            data,
+        }
+    }
+}
+
+impl Stream for Chunks {
+    type Item = Result<MetricsChunk, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Always send the first chunk, even if empty, for its content type.
+        let Some(content_type) = self
+            .content_type
+            .take()
+            .or_else(|| (!self.data.is_empty()).then_some(""))
+        else {
+            return Poll::Ready(None);
+        };
+
+        let len = self.data.len().min(CHUNK_SIZE);
+        let data = self.data.split_to(len);
+        Poll::Ready(Some(Ok(MetricsChunk {
+            data,
+            content_type: content_type.to_owned(),
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn chunking() {
+        let data = Bytes::from(vec![b'#'; CHUNK_SIZE + 1]);
+        let mut chunks = Chunks::new("text/plain", data);
+
+        let mut received = Vec::new();
+        while let Some(chunk) = std::future::poll_fn(|cx| Pin::new(&mut chunks).poll_next(cx)).await
+        {
+            received.push(chunk.unwrap());
+        }
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].content_type, "text/plain");
+        assert_eq!(received[0].data.len(), CHUNK_SIZE);
+        assert_eq!(received[1].content_type, "");
+        assert_eq!(received[1].data.len(), 1);
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn serves_metrics() {
+        use tonic::codegen::http::uri::PathAndQuery;
+        use tonic::service::Routes;
+
+        use crate::test_util::TestHarness;
+
+        let harness = TestHarness::spawn(Routes::new(MetricsServer::new())).await;
+        let mut grpc = tonic::client::Grpc::new(harness.channel());
+        grpc.ready().await.unwrap();
+        let mut stream = grpc
+            .server_streaming(
+                tonic::Request::new(GetMetricsRequest::default()),
+                PathAndQuery::from_static("/tonic_prometheus_layer.v1.Metrics/GetMetrics"),
+                tonic::codec::ProstCodec::<GetMetricsRequest, MetricsChunk>::default(),
+            )
+            .await
+            .unwrap()
+            .into_inner();
+
+        let first = stream.message().await.unwrap().unwrap();
+        assert_eq!(first.content_type, TEXT_FORMAT);
+        assert!(!first.data.is_empty());
+    }
+}
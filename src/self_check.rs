@@ -0,0 +1,122 @@
+use once_cell::sync::Lazy;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{opts, IntCounterVec};
+
+use crate::metrics::{get_settings, register, COUNTER_SM, COUNTER_SMC, GAUGE_MP, HISTOGRAM_SMC};
+use crate::snapshot::Snapshot;
+
+static SELF_CHECK: Lazy<()> = Lazy::new(|| {
+    // Register the checked metrics now: registering them from `collect` would
+    // deadlock on the registry.
+    Lazy::force(&COUNTER_SM);
+    Lazy::force(&COUNTER_SMC);
+    Lazy::force(&HISTOGRAM_SMC);
+    Lazy::force(&GAUGE_MP);
+
+    let inconsistencies = IntCounterVec::new(
+        opts!(INCONSISTENCIES_NAME, INCONSISTENCIES_DESCRIPTION),
+        &["check"],
+    )
+    .expect("failed to init inconsistencies");
+    register(SelfCheck { inconsistencies });
+});
+
+const INCONSISTENCIES_NAME: &str = "grpc_metrics_inconsistencies_total";
+const INCONSISTENCIES_DESCRIPTION: &str =
+    "Total number of violated invariants between the gRPC server metrics, found on gather.";
+
+/// Start checking the server metrics on every gather, if enabled.
+pub(crate) fn init() {
+    if get_settings().self_check {
+        Lazy::force(&SELF_CHECK);
+    }
+}
+
+/// Collector that validates the server metrics against each other whenever
+/// the registry is gathered.
+#[derive(Clone)]
+struct SelfCheck {
+    inconsistencies: IntCounterVec,
+}
+
+impl SelfCheck {
+    fn check(&self) {
+        // Collect in the reverse order of recording, so that calls completing
+        // concurrently cannot look like a violation.
+        let families = [
+            HISTOGRAM_SMC.collect(),
+            COUNTER_SMC.collect(),
+            COUNTER_SM.collect(),
+        ]
+        .concat();
+        for (service, method, m) in Snapshot::from_families(&families).server_methods() {
+            if m.handled_total() > m.started {
+                self.report("handled_exceeds_started", service, method);
+            }
+            if m.duration
+                .iter()
+                .any(|(code, histogram)| histogram.count > m.handled(*code))
+            {
+                self.report("histogram_exceeds_handled", service, method);
+            }
+        }
+
+        for family in GAUGE_MP.collect() {
+            for metric in family.get_metric() {
+                if metric.get_gauge().get_value() < 0.0 {
+                    let path = metric
+                        .get_label()
+                        .iter()
+                        .find(|l| l.get_name() == "path")
+                        .map_or("", |l| l.get_value());
+                    self.report("negative_in_flight", "", path);
+                }
+            }
+        }
+    }
+
+    fn report(&self, check: &str, service: &str, method: &str) {
+        tracing::warn!(
+            check,
+            grpc_service = service,
+            grpc_method = method,
+            "inconsistent gRPC server metrics"
+        );
+        self.inconsistencies.with_label_values(&[check]).inc();
+    }
+}
+
+impl Collector for SelfCheck {
+    fn desc(&self) -> Vec<&Desc> {
+        self.inconsistencies.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.check();
+        self.inconsistencies.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_handled_without_started() {
+        let check = SelfCheck {
+            inconsistencies: IntCounterVec::new(
+                opts!("test_inconsistencies_total", "test"),
+                &["check"],
+            )
+            .unwrap(),
+        };
+        COUNTER_SMC
+            .with_label_values(&["test.SelfCheck", "Get", "Ok"])
+            .inc();
+
+        check.collect();
+        let reported = |check_name| check.inconsistencies.with_label_values(&[check_name]).get();
+        assert!(reported("handled_exceeds_started") >= 1);
+    }
+}
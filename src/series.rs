@@ -0,0 +1,132 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+
+use crate::metrics::{get_settings, SeriesThreshold};
+
+/// Label value for calls to methods that did not reach the series threshold.
+pub(crate) const OTHER: &str = "__other__";
+
+/// Most methods counted towards the threshold at once. Calls of other methods
+/// are recorded under [`OTHER`] while there are this many, which bounds the
+/// memory a flood of probes to distinct paths can take.
+const MAX_CANDIDATES: usize = 10_000;
+
+static SERIES: Lazy<Mutex<Series>> = Lazy::new(Default::default);
+
+#[derive(Default)]
+struct Series {
+    admitted: HashSet<(String, String)>,
+    candidates: HashMap<(String, String), Candidate>,
+    /// When expired candidates were last pruned, at most once per window.
+    pruned_at: Option<Instant>,
+}
+
+struct Candidate {
+    window_started: Instant,
+    calls: u32,
+}
+
+/// Whether calls of `service`/`method` get their own series, see
+/// `GlobalSettings::series_threshold`. Once admitted, a method stays admitted.
+pub(crate) fn admit(service: &str, method: &str) -> bool {
+    let Some(threshold) = get_settings().series_threshold.as_ref() else {
+        return true;
+    };
+
+    let key = (service.to_owned(), method.to_owned());
+    SERIES.lock().unwrap().admit(key, threshold, Instant::now())
+}
+
+impl Series {
+    fn admit(&mut self, key: (String, String), threshold: &SeriesThreshold, now: Instant) -> bool {
+        if self.admitted.contains(&key) {
+            return true;
+        }
+
+        if !self.candidates.contains_key(&key) && self.candidates.len() >= MAX_CANDIDATES {
+            let prune = self
+                .pruned_at
+                .is_none_or(|at| now.duration_since(at) >= threshold.window);
+            if prune {
+                self.candidates
+                    .retain(|_, c| now.duration_since(c.window_started) < threshold.window);
+                self.pruned_at = Some(now);
+            }
+            if self.candidates.len() >= MAX_CANDIDATES {
+                return false;
+            }
+        }
+
+        let candidate = self.candidates.entry(key.clone()).or_insert(Candidate {
+            window_started: now,
+            calls: 0,
+        });
+        if now.duration_since(candidate.window_started) >= threshold.window {
+            candidate.window_started = now;
+            candidate.calls = 0;
+        }
+        candidate.calls += 1;
+        if candidate.calls < threshold.min_calls {
+            return false;
+        }
+
+        self.candidates.remove(&key);
+        self.admitted.insert(key);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::metrics::test_settings;
+
+    #[test]
+    fn admits_after_threshold() {
+        let _settings = test_settings(|settings| {
+            settings.series_threshold = Some(SeriesThreshold {
+                min_calls: 3,
+                window: Duration::from_secs(60),
+            });
+        });
+
+        assert!(!admit("test.Series", "Get"));
+        assert!(!admit("test.Series", "Get"));
+        assert!(!admit("test.Series", "Put"));
+        assert!(admit("test.Series", "Get"));
+        assert!(admit("test.Series", "Get"));
+        assert!(!admit("test.Series", "Put"));
+    }
+
+    #[test]
+    fn bounds_candidates() {
+        let threshold = SeriesThreshold {
+            min_calls: 2,
+            window: Duration::from_secs(60),
+        };
+        let key = |i: usize| ("test.Flood".to_owned(), format!("Probe{i}"));
+        let mut series = Series::default();
+        let now = Instant::now();
+
+        for i in 0..MAX_CANDIDATES + 100 {
+            assert!(!series.admit(key(i), &threshold, now));
+        }
+        assert_eq!(series.candidates.len(), MAX_CANDIDATES);
+        // Known candidates are still counted, new ones only fill freed room.
+        assert!(series.admit(key(0), &threshold, now));
+        assert!(!series.admit(key(MAX_CANDIDATES), &threshold, now));
+        assert!(!series.admit(key(MAX_CANDIDATES + 1), &threshold, now));
+        assert!(!series.admit(key(MAX_CANDIDATES + 1), &threshold, now));
+        assert_eq!(series.candidates.len(), MAX_CANDIDATES);
+
+        // Expired candidates make room once the window has passed.
+        let later = now + threshold.window;
+        assert!(!series.admit(key(MAX_CANDIDATES + 1), &threshold, later));
+        assert_eq!(series.candidates.len(), 1);
+    }
+}
@@ -0,0 +1,375 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Metric, MetricFamily, MetricType, Quantile, Summary};
+
+use crate::metrics::{get_settings, label_pair, register, SketchWindow};
+
+/// Sketches of the server call durations of each method.
+static SKETCHES: Lazy<Mutex<HashMap<(String, String), MethodSketch>>> = Lazy::new(Default::default);
+
+static EXPORT: Lazy<()> = Lazy::new(|| {
+    let desc = Desc::new(
+        SUMMARY_NAME.to_owned(),
+        SUMMARY_DESCRIPTION.to_owned(),
+        vec!["grpc_service".to_owned(), "grpc_method".to_owned()],
+        HashMap::new(),
+    )
+    .expect("failed to init quantile summary");
+    register(SketchSummary { desc });
+});
+
+const SUMMARY_NAME: &str = "grpc_server_handling_quantile_seconds";
+const SUMMARY_DESCRIPTION: &str =
+    "Quantiles of server RPC duration, estimated from an in-process sketch";
+
+/// Values below this are counted as zero.
+const MIN_VALUE: f64 = 1e-9;
+
+/// A DDSketch: quantile estimates are within the configured relative accuracy
+/// of the true value.
+#[derive(Clone)]
+struct Sketch {
+    gamma_ln: f64,
+    zero: u64,
+    buckets: BTreeMap<i32, u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Sketch {
+    fn new(relative_accuracy: f64) -> Self {
+        let alpha = relative_accuracy.clamp(1e-4, 0.5);
+        Self {
+            gamma_ln: ((1.0 + alpha) / (1.0 - alpha)).ln(),
+            zero: 0,
+            buckets: BTreeMap::new(),
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        if value < MIN_VALUE {
+            self.zero += 1;
+        } else {
+            let index = (value.ln() / self.gamma_ln).ceil() as i32;
+            *self.buckets.entry(index).or_default() += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// An empty sketch with the same accuracy.
+    fn empty(&self) -> Self {
+        Self {
+            gamma_ln: self.gamma_ln,
+            zero: 0,
+            buckets: BTreeMap::new(),
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn merge(&mut self, other: &Sketch) {
+        for (&index, &count) in &other.buckets {
+            *self.buckets.entry(index).or_default() += count;
+        }
+        self.zero += other.zero;
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = q.clamp(0.0, 1.0) * (self.count - 1) as f64;
+        let mut seen = self.zero;
+        if seen as f64 > rank {
+            return Some(0.0);
+        }
+        for (&index, &count) in &self.buckets {
+            seen += count;
+            if seen as f64 > rank {
+                let gamma = self.gamma_ln.exp();
+                return Some(2.0 * (index as f64 * self.gamma_ln).exp() / (gamma + 1.0));
+            }
+        }
+        None
+    }
+}
+
+/// The durations of the calls of one method: a single sketch, or with
+/// `QuantileSketchSettings::window`, a sketch per sub-window, oldest first.
+///
+/// The count and sum cover all calls either way, as summaries require.
+struct MethodSketch {
+    windows: VecDeque<Sketch>,
+    /// When the newest window started.
+    window_started: Instant,
+    count: u64,
+    sum: f64,
+}
+
+impl MethodSketch {
+    fn new(relative_accuracy: f64, now: Instant) -> Self {
+        Self {
+            windows: VecDeque::from([Sketch::new(relative_accuracy)]),
+            window_started: now,
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        if let Some(sketch) = self.windows.back_mut() {
+            sketch.add(value);
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// Start a new window for each sub-window elapsed, dropping the oldest.
+    fn rotate(&mut self, now: Instant, window: Option<&SketchWindow>) {
+        let Some(window) = window else {
+            return;
+        };
+        let windows = window.windows.max(1);
+        let slot = (window.duration.as_nanos() / windows as u128).max(1);
+        let elapsed = now
+            .saturating_duration_since(self.window_started)
+            .as_nanos();
+        let steps = elapsed / slot;
+        if steps == 0 {
+            return;
+        }
+
+        let empty = self.windows[0].empty();
+        for _ in 0..steps.min(windows as u128) {
+            self.windows.push_back(empty.clone());
+        }
+        while self.windows.len() > windows {
+            self.windows.pop_front();
+        }
+        self.window_started = now - Duration::from_nanos((elapsed % slot) as u64);
+    }
+
+    /// The sketch of the calls in all windows.
+    fn merged(&self) -> Cow<'_, Sketch> {
+        if self.windows.len() == 1 {
+            return Cow::Borrowed(&self.windows[0]);
+        }
+        let mut merged = self.windows[0].empty();
+        self.windows.iter().for_each(|sketch| merged.merge(sketch));
+        Cow::Owned(merged)
+    }
+}
+
+/// The sketches with their expired windows dropped.
+fn current_sketches() -> MutexGuard<'static, HashMap<(String, String), MethodSketch>> {
+    let mut sketches = SKETCHES.lock().unwrap();
+    let window = get_settings()
+        .quantile_sketches
+        .as_ref()
+        .and_then(|settings| settings.window.as_ref());
+    if window.is_some() {
+        let now = Instant::now();
+        sketches
+            .values_mut()
+            .for_each(|sketch| sketch.rotate(now, window));
+    }
+    sketches
+}
+
+/// Add a server call duration to the sketch of its method, if enabled.
+pub(crate) fn record(service: &str, method: &str, seconds: f64) {
+    let Some(settings) = get_settings().quantile_sketches.as_ref() else {
+        return;
+    };
+    if !settings.export_quantiles.is_empty() {
+        Lazy::force(&EXPORT);
+    }
+
+    let now = Instant::now();
+    let mut sketches = SKETCHES.lock().unwrap();
+    let sketch = sketches
+        .entry((service.to_owned(), method.to_owned()))
+        .or_insert_with(|| MethodSketch::new(settings.relative_accuracy, now));
+    sketch.rotate(now, settings.window.as_ref());
+    sketch.add(seconds);
+}
+
+/// Estimate the `q` quantile, e.g. 0.999, of the durations of server calls to
+/// `service`/`method` in seconds, if `GlobalSettings::quantile_sketches` is
+/// set and the method has been called.
+pub fn quantile(service: &str, method: &str, q: f64) -> Option<f64> {
+    let sketches = current_sketches();
+    sketches
+        .get(&(service.to_owned(), method.to_owned()))?
+        .merged()
+        .quantile(q)
+}
+
+/// Quantiles that suggested bucket boundaries are placed at.
+const REPORT_QUANTILES: [f64; 8] = [0.1, 0.25, 0.5, 0.75, 0.9, 0.95, 0.99, 0.999];
+
+/// Histogram buckets suggested for a method by [`bucket_report`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct BucketReport {
+    pub service: String,
+    pub method: String,
+    /// Number of calls the suggestion is based on.
+    pub count: u64,
+    /// Suggested upper bounds in seconds, ascending.
+    pub buckets: Vec<f64>,
+}
+
+/// Suggest histogram buckets for each method from the observed durations,
+/// placing boundaries at quantiles from p10 to p99.9 so that each
+/// bucket holds a meaningful share of calls.
+///
+/// Requires `GlobalSettings::quantile_sketches`; the returned boundaries can
+/// be merged into `GlobalSettings::histogram_buckets`.
+pub fn bucket_report() -> Vec<BucketReport> {
+    let sketches = current_sketches();
+    let mut report: Vec<_> = sketches
+        .iter()
+        .map(|((service, method), sketch)| {
+            let sketch = sketch.merged();
+            let mut buckets: Vec<f64> = REPORT_QUANTILES
+                .iter()
+                .filter_map(|&q| sketch.quantile(q))
+                .filter(|&v| v > 0.0)
+                .map(round_bound)
+                .collect();
+            buckets.dedup();
+            BucketReport {
+                service: service.clone(),
+                method: method.clone(),
+                count: sketch.count,
+                buckets,
+            }
+        })
+        .collect();
+    report.sort_by(|a, b| (&a.service, &a.method).cmp(&(&b.service, &b.method)));
+    report
+}
+
+/// Round `value` to two significant digits, for readable bucket boundaries.
+fn round_bound(value: f64) -> f64 {
+    let scale = 10f64.powi(1 - value.log10().floor() as i32);
+    (value * scale).round() / scale
+}
+
+/// Exports the sketches as a summary with the configured quantiles.
+#[derive(Clone)]
+struct SketchSummary {
+    desc: Desc,
+}
+
+impl Collector for SketchSummary {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let Some(settings) = get_settings().quantile_sketches.as_ref() else {
+            return Vec::new();
+        };
+
+        let sketches = current_sketches();
+        let metrics: Vec<Metric> = sketches
+            .iter()
+            .map(|((service, method), sketch)| {
+                let merged = sketch.merged();
+                let quantiles: Vec<Quantile> = settings
+                    .export_quantiles
+                    .iter()
+                    .filter_map(|&q| {
+                        let mut quantile = Quantile::default();
+                        quantile.set_quantile(q);
+                        quantile.set_value(merged.quantile(q)?);
+                        Some(quantile)
+                    })
+                    .collect();
+                let mut summary = Summary::default();
+                summary.set_sample_count(sketch.count);
+                summary.set_sample_sum(sketch.sum);
+                summary.set_quantile(quantiles.into());
+
+                let mut metric = Metric::default();
+                metric.set_label(
+                    vec![
+                        label_pair("grpc_service", service),
+                        label_pair("grpc_method", method),
+                    ]
+                    .into(),
+                );
+                metric.set_summary(summary);
+                metric
+            })
+            .collect();
+
+        let mut family = MetricFamily::default();
+        family.set_name(SUMMARY_NAME.to_owned());
+        family.set_help(SUMMARY_DESCRIPTION.to_owned());
+        family.set_field_type(MetricType::SUMMARY);
+        family.set_metric(metrics.into());
+        vec![family]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantiles_within_accuracy() {
+        let mut sketch = Sketch::new(0.01);
+        for ms in 1..=1000 {
+            sketch.add(ms as f64 / 1000.0);
+        }
+
+        for (q, want) in [(0.5, 0.5), (0.99, 0.99), (0.999, 0.999)] {
+            let got = sketch.quantile(q).unwrap();
+            assert!((got - want).abs() / want <= 0.011, "{q}: {got}");
+        }
+    }
+
+    #[test]
+    fn rotates_windows() {
+        let window = SketchWindow {
+            duration: Duration::from_secs(10),
+            windows: 2,
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut sketch = MethodSketch::new(0.01, start);
+        let min = |sketch: &MethodSketch| sketch.merged().quantile(0.0);
+
+        sketch.add(1.0);
+        sketch.rotate(at(6), Some(&window));
+        sketch.add(2.0);
+        assert!((min(&sketch).unwrap() - 1.0).abs() < 0.02);
+
+        // The window of the first call ends at 10s, the one of the second at 15s.
+        sketch.rotate(at(11), Some(&window));
+        assert!((min(&sketch).unwrap() - 2.0).abs() < 0.04);
+        sketch.rotate(at(30), Some(&window));
+        assert_eq!(min(&sketch), None);
+        assert_eq!((sketch.count, sketch.sum), (2, 3.0));
+    }
+
+    #[test]
+    fn rounds_bounds() {
+        assert_eq!(round_bound(0.012345), 0.012);
+        assert_eq!(round_bound(2.56), 2.6);
+        assert_eq!(round_bound(345.0), 350.0);
+    }
+}
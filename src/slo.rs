@@ -0,0 +1,285 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Counter, Gauge, Metric, MetricFamily, MetricType};
+use tonic::Code;
+
+use crate::metrics::{get_settings, label_pair, register};
+
+/// Good and bad events of each SLO, by service and method.
+static EVENTS: Lazy<Mutex<HashMap<(String, String), Events>>> = Lazy::new(Default::default);
+
+/// Start of the minutes events are counted in.
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+static EXPORT: Lazy<()> = Lazy::new(|| {
+    let burn_rate = Desc::new(
+        BURN_RATE_NAME.to_owned(),
+        BURN_RATE_DESCRIPTION.to_owned(),
+        vec![
+            "grpc_service".to_owned(),
+            "grpc_method".to_owned(),
+            "window".to_owned(),
+        ],
+        HashMap::new(),
+    )
+    .expect("failed to init slo_burn_rate");
+    let events = Desc::new(
+        EVENTS_NAME.to_owned(),
+        EVENTS_DESCRIPTION.to_owned(),
+        vec![
+            "grpc_service".to_owned(),
+            "grpc_method".to_owned(),
+            "result".to_owned(),
+        ],
+        HashMap::new(),
+    )
+    .expect("failed to init slo_events");
+    register(SloCollector { burn_rate, events });
+});
+
+const BURN_RATE_NAME: &str = "grpc_server_slo_burn_rate";
+const BURN_RATE_DESCRIPTION: &str =
+    "Rate at which server RPCs consume the error budget of their SLO over the window; 1 uses it up exactly";
+const EVENTS_NAME: &str = "grpc_server_slo_events_total";
+const EVENTS_DESCRIPTION: &str = "Total number of server RPCs with an SLO, by whether they met it";
+
+/// Windows burn rates are exported over, with their label values.
+const WINDOWS: [(&str, Duration); 3] = [
+    ("5m", Duration::from_secs(5 * 60)),
+    ("1h", Duration::from_secs(60 * 60)),
+    ("6h", Duration::from_secs(6 * 60 * 60)),
+];
+
+/// Codes of calls that failed on the server side, which count against an SLO.
+const SERVER_ERROR_CODES: [Code; 6] = [
+    Code::Unknown,
+    Code::DeadlineExceeded,
+    Code::Unimplemented,
+    Code::Internal,
+    Code::Unavailable,
+    Code::DataLoss,
+];
+
+/// Service level objective of a method, see `GlobalSettings::slos`.
+///
+/// A call is good if it does not fail with a server error (`Unknown`,
+/// `DeadlineExceeded`, `Unimplemented`, `Internal`, `Unavailable` or
+/// `DataLoss`) and, with a latency objective, completes within it.
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Slo {
+    /// Fraction of calls that must be good, e.g. 0.999.
+    pub target: f64,
+    /// Duration within which good calls complete, if any.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, deserialize_with = "crate::config::option_duration")
+    )]
+    pub latency: Option<Duration>,
+}
+
+impl Slo {
+    /// Objective of `target` calls, e.g. 0.999, not failing with a server error.
+    pub fn availability(target: f64) -> Self {
+        Slo {
+            target,
+            latency: None,
+        }
+    }
+
+    /// Also require good calls to complete within `latency`.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    fn is_good(&self, code: Code, duration: Duration) -> bool {
+        !SERVER_ERROR_CODES.contains(&code) && self.latency.is_none_or(|l| duration <= l)
+    }
+}
+
+/// Events of an SLO, in total and per minute over the longest window.
+#[derive(Default)]
+struct Events {
+    good: u64,
+    bad: u64,
+    minutes: VecDeque<Minute>,
+}
+
+struct Minute {
+    minute: u64,
+    good: u64,
+    bad: u64,
+}
+
+impl Events {
+    fn add(&mut self, minute: u64, good: bool) {
+        if self.minutes.back().is_none_or(|m| m.minute != minute) {
+            self.minutes.push_back(Minute {
+                minute,
+                good: 0,
+                bad: 0,
+            });
+        }
+        let longest = WINDOWS[WINDOWS.len() - 1].1.as_secs() / 60;
+        while self
+            .minutes
+            .front()
+            .is_some_and(|m| m.minute + longest <= minute)
+        {
+            self.minutes.pop_front();
+        }
+
+        let last = self.minutes.back_mut().expect("pushed above");
+        if good {
+            self.good += 1;
+            last.good += 1;
+        } else {
+            self.bad += 1;
+            last.bad += 1;
+        }
+    }
+
+    /// Error budget burn rate over the minutes of `window` up to `minute`.
+    fn burn_rate(&self, slo: &Slo, minute: u64, window: Duration) -> f64 {
+        let minutes = window.as_secs() / 60;
+        let (good, bad) = self
+            .minutes
+            .iter()
+            .filter(|m| m.minute + minutes > minute)
+            .fold((0, 0), |(good, bad), m| (good + m.good, bad + m.bad));
+        let budget = 1.0 - slo.target;
+        if good + bad == 0 || budget <= 0.0 {
+            return 0.0;
+        }
+        bad as f64 / (good + bad) as f64 / budget
+    }
+}
+
+fn current_minute() -> u64 {
+    EPOCH.elapsed().as_secs() / 60
+}
+
+/// Count a completed server call against the SLO of its method, if any.
+pub(crate) fn record(service: &str, method: &str, code: Code, duration: Duration) {
+    let slos = &get_settings().slos;
+    if slos.is_empty() {
+        return;
+    }
+    let key = (service.to_owned(), method.to_owned());
+    let Some(slo) = slos.get(&key) else {
+        return;
+    };
+    Lazy::force(&EXPORT);
+
+    let minute = current_minute();
+    EVENTS
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_default()
+        .add(minute, slo.is_good(code, duration));
+}
+
+/// Exports the event counters and burn rates of the SLOs.
+#[derive(Clone)]
+struct SloCollector {
+    burn_rate: Desc,
+    events: Desc,
+}
+
+impl Collector for SloCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.burn_rate, &self.events]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let slos = &get_settings().slos;
+        let minute = current_minute();
+        let events = EVENTS.lock().unwrap();
+
+        let mut burn_rates = Vec::new();
+        let mut counters = Vec::new();
+        for ((service, method), events) in events.iter() {
+            let Some(slo) = slos.get(&(service.clone(), method.clone())) else {
+                continue;
+            };
+            for (window, duration) in WINDOWS {
+                let mut gauge = Gauge::default();
+                gauge.set_value(events.burn_rate(slo, minute, duration));
+                let mut metric = Metric::default();
+                metric.set_label(
+                    vec![
+                        label_pair("grpc_service", service),
+                        label_pair("grpc_method", method),
+                        label_pair("window", window),
+                    ]
+                    .into(),
+                );
+                metric.set_gauge(gauge);
+                burn_rates.push(metric);
+            }
+            for (result, count) in [("good", events.good), ("bad", events.bad)] {
+                let mut counter = Counter::default();
+                counter.set_value(count as f64);
+                let mut metric = Metric::default();
+                metric.set_label(
+                    vec![
+                        label_pair("grpc_service", service),
+                        label_pair("grpc_method", method),
+                        label_pair("result", result),
+                    ]
+                    .into(),
+                );
+                metric.set_counter(counter);
+                counters.push(metric);
+            }
+        }
+
+        let mut burn_rate = MetricFamily::default();
+        burn_rate.set_name(BURN_RATE_NAME.to_owned());
+        burn_rate.set_help(BURN_RATE_DESCRIPTION.to_owned());
+        burn_rate.set_field_type(MetricType::GAUGE);
+        burn_rate.set_metric(burn_rates.into());
+        let mut events = MetricFamily::default();
+        events.set_name(EVENTS_NAME.to_owned());
+        events.set_help(EVENTS_DESCRIPTION.to_owned());
+        events.set_field_type(MetricType::COUNTER);
+        events.set_metric(counters.into());
+        vec![burn_rate, events]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burn_rates() {
+        let slo = Slo::availability(0.99).latency(Duration::from_millis(100));
+        assert!(slo.is_good(Code::NotFound, Duration::from_millis(10)));
+        assert!(!slo.is_good(Code::Ok, Duration::from_millis(200)));
+        assert!(!slo.is_good(Code::Unavailable, Duration::from_millis(10)));
+
+        let mut events = Events::default();
+        for _ in 0..9 {
+            events.add(0, true);
+        }
+        events.add(0, false);
+        // 1 bad call in 10 burns a 1% budget ten times too fast.
+        let rate = events.burn_rate(&slo, 0, WINDOWS[0].1);
+        assert!((rate - 10.0).abs() < 1e-9, "{rate}");
+
+        for _ in 0..10 {
+            events.add(30, true);
+        }
+        assert_eq!(events.burn_rate(&slo, 30, WINDOWS[0].1), 0.0);
+        let rate = events.burn_rate(&slo, 30, WINDOWS[1].1);
+        assert!((rate - 5.0).abs() < 1e-9, "{rate}");
+        assert_eq!((events.good, events.bad), (19, 1));
+    }
+}
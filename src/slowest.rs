@@ -0,0 +1,231 @@
+//! The slowest recent server calls of each method.
+//!
+//! Histograms show that a method's tail latency is bad, but not which calls
+//! caused it. Enable this with
+//! [`GlobalSettings::slowest_calls`](crate::metrics::GlobalSettings::slowest_calls)
+//! to keep the `k` slowest calls per method over roughly the last window.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::metrics::get_settings;
+use crate::observer::CallInfo;
+use crate::rpcz::{json_string, unix_seconds, CallRecord};
+
+static SLOWEST: Lazy<Mutex<HashMap<(String, String), Slowest>>> = Lazy::new(Default::default);
+
+/// Slowest calls of one method in the current and previous window.
+///
+/// Windows are tumbling, so queries cover between one and two windows.
+struct Slowest {
+    window_started: Instant,
+    current: Vec<CallRecord>,
+    previous: Vec<CallRecord>,
+}
+
+impl Slowest {
+    fn rotate(&mut self, now: Instant, window: Duration) {
+        let elapsed = now.duration_since(self.window_started);
+        if elapsed < window {
+            return;
+        }
+
+        self.previous = if elapsed < window * 2 {
+            std::mem::take(&mut self.current)
+        } else {
+            self.current.clear();
+            Vec::new()
+        };
+        self.window_started = now;
+    }
+}
+
+pub(crate) fn record(call: &CallInfo<'_>) {
+    let Some(settings) = get_settings().slowest_calls.as_ref() else {
+        return;
+    };
+    if settings.k == 0 {
+        return;
+    }
+
+    let now = Instant::now();
+    let mut slowest = SLOWEST.lock().unwrap();
+    let entry = slowest
+        .entry((call.service.to_owned(), call.method.to_owned()))
+        .or_insert_with(|| Slowest {
+            window_started: now,
+            current: Vec::new(),
+            previous: Vec::new(),
+        });
+    entry.rotate(now, settings.window);
+
+    let calls = &mut entry.current;
+    if calls.len() >= settings.k && calls.last().is_some_and(|c| c.duration >= call.duration) {
+        return;
+    }
+    let at = calls.partition_point(|c| c.duration >= call.duration);
+    calls.insert(at, CallRecord::from(call));
+    calls.truncate(settings.k);
+}
+
+/// The slowest recent calls of a method, slowest first.
+pub fn slowest_calls(service: &str, method: &str) -> Vec<CallRecord> {
+    let Some(settings) = get_settings().slowest_calls.as_ref() else {
+        return Vec::new();
+    };
+
+    let mut slowest = SLOWEST.lock().unwrap();
+    slowest
+        .get_mut(&(service.to_owned(), method.to_owned()))
+        .map(|entry| merge(entry, settings.k, settings.window))
+        .unwrap_or_default()
+}
+
+/// The slowest recent calls of every method, keyed by `(service, method)`.
+pub fn all_slowest_calls() -> BTreeMap<(String, String), Vec<CallRecord>> {
+    let Some(settings) = get_settings().slowest_calls.as_ref() else {
+        return BTreeMap::new();
+    };
+
+    let mut slowest = SLOWEST.lock().unwrap();
+    slowest
+        .iter_mut()
+        .map(|(key, entry)| (key.clone(), merge(entry, settings.k, settings.window)))
+        .filter(|(_, calls)| !calls.is_empty())
+        .collect()
+}
+
+/// Render the slowest recent calls of every method as a JSON object keyed by
+/// `service/method`.
+pub fn render_json() -> String {
+    let mut out = String::from("{");
+    for (i, ((service, method), calls)) in all_slowest_calls().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{}:[", json_string(&format!("{service}/{method}")));
+        for (j, call) in calls.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            let peer = call
+                .peer
+                .map_or_else(|| "null".to_owned(), |p| json_string(&p.to_string()));
+            let _ = write!(
+                out,
+                r#"{{"code":"{:?}","duration_seconds":{},"peer":{},"timestamp":{}}}"#,
+                call.code,
+                call.duration.as_secs_f64(),
+                peer,
+                unix_seconds(call.started_at),
+            );
+        }
+        out.push(']');
+    }
+    out.push('}');
+    out
+}
+
+fn merge(entry: &mut Slowest, k: usize, window: Duration) -> Vec<CallRecord> {
+    entry.rotate(Instant::now(), window);
+
+    let mut calls: Vec<_> = entry
+        .current
+        .iter()
+        .chain(&entry.previous)
+        .cloned()
+        .collect();
+    calls.sort_by_key(|c| std::cmp::Reverse(c.duration));
+    calls.truncate(k);
+    calls
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+    use crate::metrics::{self, SlowestCallsSettings};
+
+    fn call(method: &str, millis: u64) -> CallInfo<'_> {
+        CallInfo {
+            service: "test.Slowest",
+            method,
+            code: Code::Ok,
+            duration: Duration::from_millis(millis),
+            request_bytes: None,
+            response_bytes: 0,
+            peer: None,
+        }
+    }
+
+    fn durations(calls: &[CallRecord]) -> Vec<u64> {
+        calls
+            .iter()
+            .map(|c| c.duration.as_millis() as u64)
+            .collect()
+    }
+
+    #[test]
+    fn keeps_slowest_per_method() {
+        let _settings = metrics::test_settings(|settings| {
+            settings.slowest_calls = Some(SlowestCallsSettings {
+                k: 3,
+                ..Default::default()
+            });
+        });
+        for millis in [5, 40, 10, 30, 20, 40] {
+            record(&call("Get", millis));
+        }
+        record(&call("Put", 1));
+
+        let get = slowest_calls("test.Slowest", "Get");
+        assert_eq!(durations(&get), [40, 40, 30]);
+        assert_eq!(durations(&slowest_calls("test.Slowest", "Put")), [1]);
+        assert!(slowest_calls("test.Slowest", "Delete").is_empty());
+    }
+
+    #[test]
+    fn merges_windows() {
+        let record = |millis| CallRecord::from(&call("Merge", millis));
+        let mut slowest = Slowest {
+            window_started: Instant::now(),
+            current: vec![record(20), record(5)],
+            previous: vec![record(30), record(10)],
+        };
+
+        let merged = merge(&mut slowest, 3, Duration::from_secs(60));
+        assert_eq!(durations(&merged), [30, 20, 10]);
+    }
+
+    #[test]
+    fn forgets_expired_windows() {
+        let window = Duration::from_secs(60);
+        let started = Instant::now();
+        let record = |millis| CallRecord::from(&call("Expiry", millis));
+        let mut slowest = Slowest {
+            window_started: started,
+            current: vec![record(20)],
+            previous: vec![record(10)],
+        };
+
+        slowest.rotate(started + window / 2, window);
+        assert_eq!(durations(&slowest.current), [20]);
+        assert_eq!(durations(&slowest.previous), [10]);
+
+        // The current window becomes the previous one.
+        slowest.rotate(started + window, window);
+        assert!(slowest.current.is_empty());
+        assert_eq!(durations(&slowest.previous), [20]);
+
+        // After a whole idle window, nothing is left.
+        slowest.current.push(record(30));
+        slowest.rotate(started + window * 3, window);
+        assert!(slowest.current.is_empty());
+        assert!(slowest.previous.is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::{BTreeMap, HashMap};
+
+use prometheus::proto::{Metric, MetricFamily};
+use tonic::Code;
+
+use crate::metrics::get_settings;
+use crate::metrics::HISTOGRAM_SMC_NAME;
+use crate::metrics::{CLIENT_COUNTER_HANDLED_NAME, CLIENT_COUNTER_STARTED_NAME};
+use crate::metrics::{CLIENT_HISTOGRAM_NAME, COUNTER_SMC_NAME, COUNTER_SM_NAME};
+
+/// Point-in-time copy of the gRPC server and client metrics, broken out by
+/// service and method.
+///
+/// Series that differ only in optional labels (such as `protocol`) are
+/// summed up.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    server: BTreeMap<(String, String), MethodSnapshot>,
+    client: BTreeMap<(String, String), MethodSnapshot>,
+}
+
+impl Snapshot {
+    /// Server metrics for a method, if it has been called.
+    pub fn server(&self, service: &str, method: &str) -> Option<&MethodSnapshot> {
+        self.server.get(&(service.to_owned(), method.to_owned()))
+    }
+
+    /// Client metrics for a method, if it has been called.
+    pub fn client(&self, service: &str, method: &str) -> Option<&MethodSnapshot> {
+        self.client.get(&(service.to_owned(), method.to_owned()))
+    }
+
+    /// All server methods as `(service, method, metrics)`.
+    pub fn server_methods(&self) -> impl Iterator<Item = (&str, &str, &MethodSnapshot)> {
+        iter_methods(&self.server)
+    }
+
+    /// All client methods as `(service, method, metrics)`.
+    pub fn client_methods(&self) -> impl Iterator<Item = (&str, &str, &MethodSnapshot)> {
+        iter_methods(&self.client)
+    }
+}
+
+/// Metrics of a single gRPC method.
+#[derive(Clone, Debug, Default)]
+pub struct MethodSnapshot {
+    pub started: u64,
+    pub handled: HashMap<Code, u64>,
+    pub duration: HashMap<Code, HistogramSnapshot>,
+}
+
+impl MethodSnapshot {
+    /// Number of calls completed with `code`.
+    pub fn handled(&self, code: Code) -> u64 {
+        self.handled.get(&code).copied().unwrap_or_default()
+    }
+
+    /// Number of calls completed with any code.
+    pub fn handled_total(&self) -> u64 {
+        self.handled.values().sum()
+    }
+}
+
+/// Sample count and sum of a histogram.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum: f64,
+}
+
+/// Take a [`Snapshot`] of the collected gRPC metrics.
+pub fn snapshot() -> Snapshot {
+    Snapshot::from_registry(&get_settings().registry)
+}
+
+impl Snapshot {
+    /// Take a snapshot of the gRPC metrics in `registry`.
+    pub fn from_registry(registry: &prometheus::Registry) -> Self {
+        Self::from_families(&registry.gather())
+    }
+
+    /// Changes between `earlier` and this snapshot.
+    pub fn delta(&self, earlier: &Snapshot) -> Snapshot {
+        Snapshot {
+            server: delta_methods(&self.server, &earlier.server),
+            client: delta_methods(&self.client, &earlier.client),
+        }
+    }
+
+    pub(crate) fn from_families(families: &[MetricFamily]) -> Self {
+        let mut snapshot = Snapshot::default();
+
+        for family in families {
+            let (methods, kind) = match family.get_name() {
+                COUNTER_SM_NAME => (&mut snapshot.server, Kind::Started),
+                COUNTER_SMC_NAME => (&mut snapshot.server, Kind::Handled),
+                HISTOGRAM_SMC_NAME => (&mut snapshot.server, Kind::Duration),
+                CLIENT_COUNTER_STARTED_NAME => (&mut snapshot.client, Kind::Started),
+                CLIENT_COUNTER_HANDLED_NAME => (&mut snapshot.client, Kind::Handled),
+                CLIENT_HISTOGRAM_NAME => (&mut snapshot.client, Kind::Duration),
+                _ => continue,
+            };
+
+            for metric in family.get_metric() {
+                let key = (
+                    label(metric, "grpc_service").to_owned(),
+                    label(metric, "grpc_method").to_owned(),
+                );
+                let entry = methods.entry(key).or_default();
+                let code = code_from_name(label(metric, "grpc_code"));
+
+                match kind {
+                    Kind::Started => entry.started += metric.get_counter().get_value() as u64,
+                    Kind::Handled => {
+                        *entry.handled.entry(code).or_default() +=
+                            metric.get_counter().get_value() as u64
+                    }
+                    Kind::Duration => {
+                        let histogram = metric.get_histogram();
+                        let duration = entry.duration.entry(code).or_default();
+                        duration.count += histogram.get_sample_count();
+                        duration.sum += histogram.get_sample_sum();
+                    }
+                }
+            }
+        }
+
+        snapshot
+    }
+}
+
+enum Kind {
+    Started,
+    Handled,
+    Duration,
+}
+
+fn delta_methods(
+    later: &BTreeMap<(String, String), MethodSnapshot>,
+    earlier: &BTreeMap<(String, String), MethodSnapshot>,
+) -> BTreeMap<(String, String), MethodSnapshot> {
+    later
+        .iter()
+        .map(|(key, later)| {
+            let Some(earlier) = earlier.get(key) else {
+                return (key.clone(), later.clone());
+            };
+            let delta = MethodSnapshot {
+                started: later.started - earlier.started,
+                handled: later
+                    .handled
+                    .iter()
+                    .map(|(code, n)| (*code, n - earlier.handled(*code)))
+                    .collect(),
+                duration: later
+                    .duration
+                    .iter()
+                    .map(|(code, h)| {
+                        let e = earlier.duration.get(code).copied().unwrap_or_default();
+                        let delta = HistogramSnapshot {
+                            count: h.count - e.count,
+                            sum: h.sum - e.sum,
+                        };
+                        (*code, delta)
+                    })
+                    .collect(),
+            };
+            (key.clone(), delta)
+        })
+        .collect()
+}
+
+fn iter_methods(
+    methods: &BTreeMap<(String, String), MethodSnapshot>,
+) -> impl Iterator<Item = (&str, &str, &MethodSnapshot)> {
+    methods
+        .iter()
+        .map(|((service, method), snapshot)| (service.as_str(), method.as_str(), snapshot))
+}
+
+fn label<'a>(metric: &'a Metric, name: &str) -> &'a str {
+    metric
+        .get_label()
+        .iter()
+        .find(|l| l.get_name() == name)
+        .map_or("", |l| l.get_value())
+}
+
+/// Parse a `grpc_code` label value back into a [`Code`].
+pub(crate) fn code_from_name(name: &str) -> Code {
+    (0..=16)
+        .map(Code::from_i32)
+        .find(|code| format!("{:?}", code) == name)
+        .unwrap_or(Code::Unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_names() {
+        assert_eq!(code_from_name("NotFound"), Code::NotFound);
+        assert_eq!(code_from_name("Ok"), Code::Ok);
+        assert_eq!(code_from_name("nonsense"), Code::Unknown);
+    }
+}
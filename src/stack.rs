@@ -0,0 +1,195 @@
+use tower::layer::util::{Identity, Stack};
+use tower::{Layer, ServiceBuilder};
+
+use crate::{MetricsLayer, MetricsService};
+
+/// Preset order of [`MetricsLayer`] and the layers commonly around it, see
+/// [`stack`].
+///
+/// From the outside in:
+///
+/// 1. the [`ScrapeLayer`](crate::ScrapeLayer), so scrapes are not recorded as
+///    calls,
+/// 2. HTTP response compression, e.g. tower-http's `CompressionLayer`, so the
+///    metrics layer sees the bodies as sent by the service, and can read the
+///    status from gRPC-Web trailers,
+/// 3. the [`MetricsLayer`],
+/// 4. tonic-web's `GrpcWebLayer`, so gRPC-Web calls are recorded with their
+///    protocol and their responses are in the format the metrics layer reads,
+/// 5. authentication, so rejected calls are recorded, with the layer's status
+///    translated for gRPC-Web clients.
+///
+/// Layers that are not set are left out.
+#[derive(Clone, Debug)]
+pub struct MetricsStack<P = Identity, C = Identity, W = Identity, A = Identity> {
+    scrape: P,
+    compression: C,
+    metrics: MetricsLayer,
+    grpc_web: W,
+    auth: A,
+}
+
+/// Layers of a [`MetricsStack`] in a [`ServiceBuilder`], from the innermost.
+pub type StackLayers<P, C, W, A> =
+    Stack<A, Stack<W, Stack<MetricsLayer, Stack<C, Stack<P, Identity>>>>>;
+
+/// Start a [`MetricsStack`] with a default [`MetricsLayer`], ordering it and
+/// the other layers added to the stack so calls are recorded with the right
+/// status, protocol and duration.
+///
+/// ```no_run
+/// # async fn run() {
+/// use tonic_prometheus_layer::ScrapeLayer;
+///
+/// let (_, health) = tonic_health::server::health_reporter();
+///
+/// tonic::transport::Server::builder()
+///     .accept_http1(true)
+///     .layer(tonic_prometheus_layer::stack().scrape(ScrapeLayer::new()))
+///     .add_service(health)
+///     .serve("127.0.0.1:9090".parse().unwrap())
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+///
+/// The stack is also a layer itself, or can be turned into a
+/// [`ServiceBuilder`] to add further layers inside of it. A metrics layer
+/// nested inside of the stack's, e.g. around a single service, passes calls
+/// through rather than recording them twice.
+pub fn stack() -> MetricsStack {
+    MetricsStack {
+        scrape: Identity::new(),
+        compression: Identity::new(),
+        metrics: MetricsLayer::new(),
+        grpc_web: Identity::new(),
+        auth: Identity::new(),
+    }
+}
+
+impl<P, C, W, A> MetricsStack<P, C, W, A> {
+    /// Use `metrics` instead of the default [`MetricsLayer`].
+    pub fn metrics(mut self, metrics: MetricsLayer) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Serve scrapes with `scrape`, usually a [`ScrapeLayer`](crate::ScrapeLayer).
+    pub fn scrape<L>(self, scrape: L) -> MetricsStack<L, C, W, A> {
+        MetricsStack {
+            scrape,
+            compression: self.compression,
+            metrics: self.metrics,
+            grpc_web: self.grpc_web,
+            auth: self.auth,
+        }
+    }
+
+    /// Compress responses with `compression`, e.g. tower-http's
+    /// `CompressionLayer`.
+    pub fn compression<L>(self, compression: L) -> MetricsStack<P, L, W, A> {
+        MetricsStack {
+            scrape: self.scrape,
+            compression,
+            metrics: self.metrics,
+            grpc_web: self.grpc_web,
+            auth: self.auth,
+        }
+    }
+
+    /// Translate gRPC-Web with `grpc_web`, usually tonic-web's `GrpcWebLayer`.
+    pub fn grpc_web<L>(self, grpc_web: L) -> MetricsStack<P, C, L, A> {
+        MetricsStack {
+            scrape: self.scrape,
+            compression: self.compression,
+            metrics: self.metrics,
+            grpc_web,
+            auth: self.auth,
+        }
+    }
+
+    /// Authenticate calls with `auth`, e.g. a tonic interceptor layer.
+    /// Mark its rejections with [`Rejected`](crate::Rejected) to count them in
+    /// `grpc_server_rejected_total`.
+    pub fn auth<L>(self, auth: L) -> MetricsStack<P, C, W, L> {
+        MetricsStack {
+            scrape: self.scrape,
+            compression: self.compression,
+            metrics: self.metrics,
+            grpc_web: self.grpc_web,
+            auth,
+        }
+    }
+
+    /// The stack as a [`ServiceBuilder`], to add layers inside of it.
+    pub fn into_builder(self) -> ServiceBuilder<StackLayers<P, C, W, A>> {
+        ServiceBuilder::new()
+            .layer(self.scrape)
+            .layer(self.compression)
+            .layer(self.metrics)
+            .layer(self.grpc_web)
+            .layer(self.auth)
+    }
+}
+
+impl<S, P, C, W, A> Layer<S> for MetricsStack<P, C, W, A>
+where
+    P: Layer<C::Service>,
+    C: Layer<MetricsService<W::Service>>,
+    W: Layer<A::Service>,
+    A: Layer<S>,
+{
+    type Service = P::Service;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        let inner = self.auth.layer(inner);
+        let inner = self.grpc_web.layer(inner);
+        let inner = self.metrics.layer(inner);
+        let inner = self.compression.layer(inner);
+        self.scrape.layer(inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Full};
+    use tonic::codegen::http::{request, response, HeaderMap, HeaderValue};
+    use tonic::codegen::Bytes;
+    use tonic::Code;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+    use crate::metrics;
+
+    #[tokio::test]
+    async fn records_nested_calls_once() {
+        let service = service_fn(|_req: request::Request<()>| async {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            let body = Full::new(Bytes::from_static(b"data"))
+                .with_trailers(async move { Some(Ok(trailers)) });
+
+            Ok::<_, std::convert::Infallible>(response::Response::new(body))
+        });
+        let req = request::Request::builder()
+            .uri("/test.Stack/Nested")
+            .body(())
+            .unwrap();
+
+        let resp = stack()
+            .into_builder()
+            .layer(MetricsLayer::new())
+            .service(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+        resp.into_body().collect().await.unwrap();
+        let got = metrics::snapshot();
+        assert_eq!(
+            got.server("test.Stack", "Nested")
+                .unwrap()
+                .handled(Code::Ok),
+            1
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Gauge, Metric, MetricFamily, MetricType};
+
+use crate::metrics::{get_settings, label_pair, register};
+
+/// Server calls currently open, by id.
+static OPEN: Lazy<Mutex<HashMap<u64, OpenCall>>> = Lazy::new(Default::default);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Series computed by the latest sample.
+static SAMPLE: Mutex<Vec<Metric>> = Mutex::new(Vec::new());
+
+/// Registers the gauge and starts the sampler thread on first use.
+static SAMPLER: Lazy<()> = Lazy::new(|| {
+    let desc = Desc::new(
+        NAME.to_owned(),
+        DESCRIPTION.to_owned(),
+        vec![
+            "grpc_service".to_owned(),
+            "grpc_method".to_owned(),
+            "le".to_owned(),
+        ],
+        HashMap::new(),
+    )
+    .expect("failed to init open_stream_age");
+    register(OpenStreamAge { desc });
+
+    let result = thread::Builder::new()
+        .name("metrics-stream-age".to_owned())
+        .spawn(|| {
+            let settings = get_settings()
+                .open_stream_age
+                .as_ref()
+                .expect("sampler started without settings");
+            loop {
+                thread::sleep(settings.interval);
+                let metrics = sample(&settings.buckets);
+                *SAMPLE.lock().unwrap() = metrics;
+            }
+        });
+    if let Err(e) = result {
+        tracing::warn!("failed to start the open stream age sampler: {e}");
+    }
+});
+
+const NAME: &str = "grpc_server_open_stream_age_seconds";
+const DESCRIPTION: &str =
+    "Number of open server RPCs at most `le` seconds old, as of the latest sample";
+
+struct OpenCall {
+    service: String,
+    method: String,
+    started_at: Instant,
+}
+
+/// Tracks a server call as open until dropped.
+pub(crate) struct OpenCallGuard(u64);
+
+impl Drop for OpenCallGuard {
+    fn drop(&mut self) {
+        OPEN.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Track a server call of `service`/`method` as open, if
+/// `GlobalSettings::open_stream_age` is set.
+pub(crate) fn open(service: &str, method: &str) -> Option<OpenCallGuard> {
+    get_settings().open_stream_age.as_ref()?;
+    Lazy::force(&SAMPLER);
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let call = OpenCall {
+        service: service.to_owned(),
+        method: method.to_owned(),
+        started_at: Instant::now(),
+    };
+    OPEN.lock().unwrap().insert(id, call);
+    Some(OpenCallGuard(id))
+}
+
+/// Count the open calls of each method by age, cumulatively per bucket.
+fn sample(buckets: &[f64]) -> Vec<Metric> {
+    let now = Instant::now();
+    let mut counts: BTreeMap<(String, String), Vec<u64>> = BTreeMap::new();
+    for call in OPEN.lock().unwrap().values() {
+        let age = now.duration_since(call.started_at).as_secs_f64();
+        let counts = counts
+            .entry((call.service.clone(), call.method.clone()))
+            .or_insert_with(|| vec![0; buckets.len() + 1]);
+        for (count, _) in counts
+            .iter_mut()
+            .zip(buckets.iter().chain([&f64::INFINITY]))
+            .filter(|(_, &bound)| age <= bound)
+        {
+            *count += 1;
+        }
+    }
+
+    let bounds: Vec<String> = buckets
+        .iter()
+        .map(f64::to_string)
+        .chain(["+Inf".to_owned()])
+        .collect();
+    let mut metrics = Vec::new();
+    for ((service, method), counts) in counts {
+        for (bound, count) in bounds.iter().zip(counts) {
+            let mut gauge = Gauge::default();
+            gauge.set_value(count as f64);
+            let mut metric = Metric::default();
+            metric.set_label(
+                vec![
+                    label_pair("grpc_service", &service),
+                    label_pair("grpc_method", &method),
+                    label_pair("le", bound),
+                ]
+                .into(),
+            );
+            metric.set_gauge(gauge);
+            metrics.push(metric);
+        }
+    }
+    metrics
+}
+
+/// Exports the latest sample.
+#[derive(Clone)]
+struct OpenStreamAge {
+    desc: Desc,
+}
+
+impl Collector for OpenStreamAge {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut family = MetricFamily::default();
+        family.set_name(NAME.to_owned());
+        family.set_help(DESCRIPTION.to_owned());
+        family.set_field_type(MetricType::GAUGE);
+        family.set_metric(SAMPLE.lock().unwrap().clone().into());
+        vec![family]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_ages() {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let call = OpenCall {
+            service: "test.StreamAge".to_owned(),
+            method: "Watch".to_owned(),
+            started_at: Instant::now(),
+        };
+        OPEN.lock().unwrap().insert(id, call);
+        let guard = OpenCallGuard(id);
+
+        let metrics: Vec<_> = sample(&[0.0, 60.0])
+            .into_iter()
+            .filter(|m| m.get_label()[0].get_value() == "test.StreamAge")
+            .map(|m| {
+                (
+                    m.get_label()[2].get_value().to_owned(),
+                    m.get_gauge().get_value(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            metrics,
+            [
+                ("0".to_owned(), 0.0),
+                ("60".to_owned(), 1.0),
+                ("+Inf".to_owned(), 1.0)
+            ]
+        );
+
+        drop(guard);
+        assert!(!OPEN.lock().unwrap().contains_key(&id));
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use prometheus::{Registry, TextEncoder};
+use tonic::codegen::http::request;
+
+use crate::merge;
+use crate::metrics::{get_settings, override_help, Error, ServerMetrics};
+use crate::relabel::relabel;
+
+/// Registry and server metrics of each configured tenant.
+static TENANTS: Lazy<HashMap<String, TenantMetrics>> = Lazy::new(|| {
+    let Some(settings) = get_settings().tenants.as_ref() else {
+        return HashMap::new();
+    };
+    settings
+        .tenants
+        .iter()
+        .map(|tenant| {
+            let registry = Registry::new();
+            let metrics = ServerMetrics::register(&registry);
+            (tenant.clone(), TenantMetrics { registry, metrics })
+        })
+        .collect()
+});
+
+pub(crate) struct TenantMetrics {
+    registry: Registry,
+    pub(crate) metrics: ServerMetrics,
+}
+
+/// Request extension naming the tenant a server call belongs to, see
+/// [`TenantSettings`](crate::metrics::TenantSettings).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tenant(String);
+
+impl Tenant {
+    pub fn new(tenant: impl Into<String>) -> Self {
+        Self(tenant.into())
+    }
+}
+
+/// The metrics of the configured tenant that `req` belongs to, if any.
+pub(crate) fn tenant_of<B>(req: &request::Request<B>) -> Option<&'static TenantMetrics> {
+    let settings = get_settings().tenants.as_ref()?;
+    let tenant = match req.extensions().get::<Tenant>() {
+        Some(tenant) => tenant.0.as_str(),
+        None => req
+            .headers()
+            .get(settings.header.as_deref()?)?
+            .to_str()
+            .ok()?,
+    };
+    TENANTS.get(tenant)
+}
+
+/// The registry holding the server metrics of `tenant`, if it is configured.
+///
+/// The tenant registries are created from the settings in place at the first
+/// lookup or recorded call, so look them up after
+/// [`try_init_settings`](crate::metrics::try_init_settings): before it, the
+/// default settings are installed, without tenants.
+pub fn tenant_registry(tenant: &str) -> Option<&'static Registry> {
+    TENANTS.get(tenant).map(|t| &t.registry)
+}
+
+/// Export the server metrics of `tenant` to the Prometheus format.
+pub fn encode_tenant_to_string(tenant: &str) -> Result<String, Error> {
+    let registry =
+        tenant_registry(tenant).ok_or_else(|| Error::UnknownTenant(tenant.to_owned()))?;
+
+    let mut families = registry.gather();
+    override_help(&mut families);
+    relabel(&mut families);
+    merge::timestamp(&mut families);
+    merge::namespace(&mut families);
+
+    let mut output = String::new();
+    TextEncoder::new()
+        .encode_utf8(&families, &mut output)
+        .map_err(Error::PrometheusEncoding)?;
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+    use tonic::codegen::http::HeaderValue;
+    use tower::{Layer, ServiceExt};
+
+    use super::*;
+    use crate::metrics::{self, TenantSettings};
+    use crate::tests::{grpc_ok_service, grpc_request};
+    use crate::MetricsLayer;
+
+    #[tokio::test]
+    async fn records_in_tenant_registry() {
+        let _settings = metrics::test_settings(|settings| {
+            settings.tenants = Some(TenantSettings {
+                header: Some("x-tenant".to_owned()),
+                tenants: vec!["acme".to_owned()],
+            });
+        });
+        let mut req = grpc_request("/test.Tenants/Get");
+        req.headers_mut()
+            .insert("x-tenant", HeaderValue::from_static("acme"));
+
+        let resp = MetricsLayer::new()
+            .layer(grpc_ok_service())
+            .oneshot(req)
+            .await
+            .unwrap();
+        resp.into_body().collect().await.unwrap();
+
+        let encoded = encode_tenant_to_string("acme").unwrap();
+        assert!(encoded.contains(r#"grpc_server_handled_total{grpc_code="Ok",grpc_method="Get",grpc_service="test.Tenants"} 1"#));
+        assert!(matches!(
+            encode_tenant_to_string("globex"),
+            Err(Error::UnknownTenant(_))
+        ));
+    }
+}
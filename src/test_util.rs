@@ -0,0 +1,208 @@
+//! Helpers for asserting on recorded metrics in tests.
+//!
+//! ```
+//! use tonic::Code;
+//! use tonic_prometheus_layer::assert_handled;
+//! use tonic_prometheus_layer::test_util::MetricsDiff;
+//!
+//! let diff = MetricsDiff::new();
+//! // ... make some calls ...
+//! assert_handled!(diff, "pkg.Service", "Method", Code::Ok, 0);
+//! ```
+
+use std::io;
+
+use hyper_util::rt::TokioIo;
+use prometheus::Registry;
+use tokio::task::JoinHandle;
+use tonic::codegen::http::Uri;
+use tonic::service::Routes;
+use tonic::transport::{Channel, Endpoint, Server};
+
+use crate::metrics::{get_settings, Snapshot};
+use crate::{MetricsChannel, MetricsLayer};
+
+/// Captures the metrics at creation, so that only the changes made since then
+/// are asserted on.
+pub struct MetricsDiff {
+    registry: Registry,
+    before: Snapshot,
+}
+
+impl MetricsDiff {
+    /// Start capturing changes to the global registry.
+    pub fn new() -> Self {
+        Self::with_registry(&get_settings().registry)
+    }
+
+    /// Start capturing changes to `registry`.
+    pub fn with_registry(registry: &Registry) -> Self {
+        Self {
+            registry: registry.clone(),
+            before: Snapshot::from_registry(registry),
+        }
+    }
+
+    /// Changes made to the metrics since this diff was created.
+    pub fn delta(&self) -> Snapshot {
+        Snapshot::from_registry(&self.registry).delta(&self.before)
+    }
+}
+
+impl Default for MetricsDiff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sources of a [`Snapshot`] accepted by the assertion macros.
+pub trait ToSnapshot {
+    fn to_snapshot(&self) -> Snapshot;
+}
+
+impl ToSnapshot for Registry {
+    fn to_snapshot(&self) -> Snapshot {
+        Snapshot::from_registry(self)
+    }
+}
+
+impl ToSnapshot for MetricsDiff {
+    fn to_snapshot(&self) -> Snapshot {
+        self.delta()
+    }
+}
+
+impl ToSnapshot for Snapshot {
+    fn to_snapshot(&self) -> Snapshot {
+        self.clone()
+    }
+}
+
+/// Assert the number of server calls to a method completed with a code.
+///
+/// The first argument is a [`prometheus::Registry`], a [`MetricsDiff`] or a
+/// [`Snapshot`].
+#[macro_export]
+macro_rules! assert_handled {
+    ($source:expr, $service:expr, $method:expr, $code:expr, $expected:expr $(,)?) => {{
+        let snapshot = $crate::test_util::ToSnapshot::to_snapshot(&$source);
+        let got = snapshot
+            .server($service, $method)
+            .map_or(0, |m| m.handled($code));
+        assert_eq!(
+            got, $expected,
+            "handled {}/{} calls with {:?}",
+            $service, $method, $code
+        );
+    }};
+}
+
+/// Assert the number of server calls started for a method.
+///
+/// The first argument is a [`prometheus::Registry`], a [`MetricsDiff`] or a
+/// [`Snapshot`].
+#[macro_export]
+macro_rules! assert_started {
+    ($source:expr, $service:expr, $method:expr, $expected:expr $(,)?) => {{
+        let snapshot = $crate::test_util::ToSnapshot::to_snapshot(&$source);
+        let got = snapshot.server($service, $method).map_or(0, |m| m.started);
+        assert_eq!(got, $expected, "started {}/{} calls", $service, $method);
+    }};
+}
+
+/// An instrumented tonic server running in-process, connected to a client
+/// channel through an in-memory transport.
+pub struct TestHarness {
+    channel: MetricsChannel<Channel>,
+    registry: Registry,
+    server: JoinHandle<Result<(), tonic::transport::Error>>,
+}
+
+impl TestHarness {
+    /// Serve `routes` behind a [`MetricsLayer`] and connect a client to it.
+    ///
+    /// ```ignore
+    /// let harness = TestHarness::spawn(Routes::new(HealthServer::new(service))).await;
+    /// let mut client = HealthClient::new(harness.channel());
+    /// client.check(HealthCheckRequest::default()).await.unwrap();
+    ///
+    /// assert_handled!(harness.registry(), "grpc.health.v1.Health", "Check", Code::Ok, 1);
+    /// ```
+    pub async fn spawn(routes: impl Into<Routes>) -> Self {
+        let (client_io, server_io) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+
+        let incoming = tokio_stream::once(Ok::<_, io::Error>(server_io));
+        let server = tokio::spawn(
+            Server::builder()
+                .layer(MetricsLayer::new())
+                .add_routes(routes.into())
+                .serve_with_incoming(incoming),
+        );
+
+        // The endpoint only connects once, so the single client half is enough.
+        let mut client_io = Some(client_io);
+        let channel = Endpoint::from_static("http://[::]:50051")
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                let io = client_io
+                    .take()
+                    .ok_or_else(|| io::Error::other("test client already connected"));
+                async move { io.map(TokioIo::new) }
+            }))
+            .await
+            .expect("failed to connect to the test server");
+
+        Self {
+            channel: MetricsChannel::new(channel),
+            registry: get_settings().registry.clone(),
+            server,
+        }
+    }
+
+    /// Instrumented channel connected to the server, to build clients with.
+    pub fn channel(&self) -> MetricsChannel<Channel> {
+        self.channel.clone()
+    }
+
+    /// Registry the metrics are recorded in.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+}
+
+impl Drop for TestHarness {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tonic::codec::ProstCodec;
+    use tonic::codegen::http::uri::PathAndQuery;
+    use tonic::{Code, Request};
+
+    #[tokio::test]
+    async fn harness_records_calls() {
+        let harness = TestHarness::spawn(Routes::default()).await;
+        let diff = MetricsDiff::with_registry(harness.registry());
+
+        let mut grpc = tonic::client::Grpc::new(harness.channel());
+        grpc.ready().await.unwrap();
+        let status = grpc
+            .unary::<(), (), _>(
+                Request::new(()),
+                PathAndQuery::from_static("/test.Harness/Call"),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unimplemented);
+
+        crate::assert_started!(diff, "test.Harness", "Call", 1);
+        crate::assert_handled!(diff, "test.Harness", "Call", Code::Unimplemented, 1);
+    }
+}
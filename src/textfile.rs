@@ -0,0 +1,103 @@
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::metrics::encode_to_string;
+
+/// Periodically write the exposition to `path` for node_exporter's textfile
+/// collector, for hosts where opening another listening port is prohibited.
+///
+/// The file is written once before returning, so that an unwritable path is
+/// reported right away, then every `interval` from a background thread.
+/// Each write goes to a temporary file next to `path` that is then renamed
+/// over it, so the collector never reads a partial file. Later write errors
+/// are logged.
+///
+/// The writer keeps running when the returned handle is dropped; call
+/// [`TextfileWriter::stop`] to stop it.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// let writer = tonic_prometheus_layer::metrics::start_textfile_writer(
+///     "/var/lib/node_exporter/textfile/grpc.prom",
+///     Duration::from_secs(15),
+/// )
+/// .unwrap();
+/// ```
+pub fn start_textfile_writer(
+    path: impl Into<PathBuf>,
+    interval: Duration,
+) -> io::Result<TextfileWriter> {
+    let path = path.into();
+    write(&path)?;
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let thread = thread::Builder::new()
+        .name("metrics-textfile".to_owned())
+        .spawn({
+            let stopped = stopped.clone();
+            move || {
+                let mut next = Instant::now() + interval;
+                while !stopped.load(Ordering::Relaxed) {
+                    let now = Instant::now();
+                    if now < next {
+                        thread::park_timeout(next - now);
+                        continue;
+                    }
+                    next += interval;
+                    if let Err(e) = write(&path) {
+                        tracing::warn!("failed to write metrics to {}: {e}", path.display());
+                    }
+                }
+            }
+        })?;
+
+    Ok(TextfileWriter { stopped, thread })
+}
+
+/// Handle of a writer started by [`start_textfile_writer`].
+pub struct TextfileWriter {
+    stopped: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl TextfileWriter {
+    /// Stop writing, waiting for an ongoing write to finish.
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.thread.thread().unpark();
+        let _ = self.thread.join();
+    }
+}
+
+fn write(path: &Path) -> io::Result<()> {
+    let encoded = encode_to_string().map_err(io::Error::other)?;
+
+    // The collector only reads `*.prom` files, so it skips the temporary one.
+    let mut temporary = OsString::from(path.as_os_str());
+    temporary.push(".tmp");
+    std::fs::write(&temporary, encoded)?;
+    std::fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_file() {
+        let path = std::env::temp_dir().join(format!("textfile-{}.prom", std::process::id()));
+
+        let writer = start_textfile_writer(&path, Duration::from_millis(10)).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        writer.stop();
+
+        assert!(written.contains("# TYPE"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
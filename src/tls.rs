@@ -0,0 +1,174 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+
+use pin_project::{pin_project, pinned_drop};
+
+use crate::metrics::{TLS_HANDSHAKE_FAILURES, TLS_HANDSHAKE_HISTOGRAM};
+
+/// Wrapper for instrumenting a server-side TLS handshake with its duration
+/// and failures.
+///
+/// Wrap the accept future of the TLS acceptor used to build the stream passed
+/// to `serve_with_incoming`:
+///
+/// ```ignore
+/// let incoming = tcp_incoming.then(|conn| async {
+///     let conn = conn?;
+///     tonic_prometheus_layer::MetricsHandshake::new(acceptor.accept(conn)).await
+/// });
+/// ```
+///
+/// A handshake dropped before it completes, e.g. by a timeout around it, is
+/// counted as failed with `class="dropped"`.
+#[pin_project(PinnedDrop)]
+pub struct MetricsHandshake<F> {
+    started_at: Option<Instant>,
+    done: bool,
+    #[pin]
+    inner: F,
+}
+
+impl<F> MetricsHandshake<F> {
+    pub fn new(inner: F) -> Self {
+        Self {
+            started_at: None,
+            done: false,
+            inner,
+        }
+    }
+}
+
+impl<F, T, E> Future for MetricsHandshake<F>
+where
+    F: Future<Output = Result<T, E>>,
+    E: HandshakeError,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        let started_at = *this.started_at.get_or_insert_with(Instant::now);
+        let v = ready!(this.inner.poll(cx));
+
+        *this.done = true;
+        match &v {
+            Ok(_) => record(started_at, None),
+            Err(e) => record(started_at, Some(e.class())),
+        }
+
+        Poll::Ready(v)
+    }
+}
+
+#[pinned_drop]
+impl<F> PinnedDrop for MetricsHandshake<F> {
+    fn drop(self: Pin<&mut Self>) {
+        if let (Some(started_at), false) = (self.started_at, self.done) {
+            record(started_at, Some("dropped"));
+        }
+    }
+}
+
+/// Record a handshake, failed with `class` if any.
+fn record(started_at: Instant, class: Option<&str>) {
+    let result = match class {
+        None => "ok",
+        Some(class) => {
+            TLS_HANDSHAKE_FAILURES.with_label_values(&[class]).inc();
+            "error"
+        }
+    };
+    TLS_HANDSHAKE_HISTOGRAM
+        .with_label_values(&[result])
+        .observe(started_at.elapsed().as_secs_f64());
+}
+
+/// Classification of handshake errors for the `class` label of
+/// `grpc_server_tls_handshake_failures_total`.
+///
+/// The set of classes should be small and fixed, since each one creates a
+/// separate series.
+pub trait HandshakeError {
+    fn class(&self) -> &'static str;
+}
+
+/// TLS acceptors such as `tokio-rustls` and `tokio-native-tls` report
+/// failures as I/O errors; protocol and certificate problems surface as
+/// `InvalidData`.
+impl HandshakeError for io::Error {
+    fn class(&self) -> &'static str {
+        match self.kind() {
+            io::ErrorKind::InvalidData => "protocol",
+            io::ErrorKind::UnexpectedEof => "eof",
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => "reset",
+            io::ErrorKind::TimedOut => "timeout",
+            _ => "io",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn records_handshake() {
+        let histogram = TLS_HANDSHAKE_HISTOGRAM.with_label_values(&["ok"]);
+        let observed = histogram.get_sample_count();
+
+        let handshake = MetricsHandshake::new(async {
+            tokio::task::yield_now().await;
+            Ok::<_, io::Error>(())
+        });
+        handshake.await.unwrap();
+
+        assert_eq!(histogram.get_sample_count(), observed + 1);
+    }
+
+    #[tokio::test]
+    async fn records_failed_handshake() {
+        let histogram = TLS_HANDSHAKE_HISTOGRAM.with_label_values(&["error"]);
+        let observed = histogram.get_sample_count();
+
+        let handshake = MetricsHandshake::new(async {
+            Err::<(), _>(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad certificate",
+            ))
+        });
+        handshake.await.unwrap_err();
+
+        let failures = TLS_HANDSHAKE_FAILURES.with_label_values(&["protocol"]);
+        assert_eq!(failures.get(), 1);
+        // Other tests record failed handshakes concurrently.
+        assert!(histogram.get_sample_count() > observed);
+    }
+
+    #[tokio::test]
+    async fn records_dropped_handshake() {
+        let histogram = TLS_HANDSHAKE_HISTOGRAM.with_label_values(&["error"]);
+        let observed = histogram.get_sample_count();
+        let failures = TLS_HANDSHAKE_FAILURES.with_label_values(&["dropped"]);
+
+        // Never polled, so it never started.
+        drop(MetricsHandshake::new(
+            std::future::pending::<io::Result<()>>(),
+        ));
+        assert_eq!(failures.get(), 0);
+
+        // Like a timeout around it, poll once and give up.
+        let mut handshake = Box::pin(MetricsHandshake::new(
+            std::future::pending::<io::Result<()>>(),
+        ));
+        let polled = std::future::poll_fn(|cx| Poll::Ready(handshake.as_mut().poll(cx)));
+        assert!(polled.await.is_pending());
+        drop(handshake);
+
+        assert_eq!(failures.get(), 1);
+        assert!(histogram.get_sample_count() > observed);
+    }
+}
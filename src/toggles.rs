@@ -0,0 +1,161 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::metrics::get_settings;
+
+/// Recording features that can be switched on and off while the server runs
+/// with [`set_feature_enabled`], e.g. to record more detail during an
+/// incident. Each starts as set in its `GlobalSettings` field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RecordingFeature {
+    /// `GlobalSettings::metadata_size`
+    MetadataSize,
+    /// `GlobalSettings::cpu_time`
+    CpuTime,
+    /// `GlobalSettings::interarrival_time`
+    InterarrivalTime,
+    /// `GlobalSettings::send_blocked_time`
+    SendBlockedTime,
+    /// `GlobalSettings::message_interval`
+    MessageInterval,
+    /// `GlobalSettings::legacy_metrics`
+    LegacyMetrics,
+}
+
+impl RecordingFeature {
+    pub const ALL: [RecordingFeature; 6] = [
+        RecordingFeature::MetadataSize,
+        RecordingFeature::CpuTime,
+        RecordingFeature::InterarrivalTime,
+        RecordingFeature::SendBlockedTime,
+        RecordingFeature::MessageInterval,
+        RecordingFeature::LegacyMetrics,
+    ];
+
+    /// The name of the feature's `GlobalSettings` field, e.g. `cpu_time`.
+    pub fn name(self) -> &'static str {
+        match self {
+            RecordingFeature::MetadataSize => "metadata_size",
+            RecordingFeature::CpuTime => "cpu_time",
+            RecordingFeature::InterarrivalTime => "interarrival_time",
+            RecordingFeature::SendBlockedTime => "send_blocked_time",
+            RecordingFeature::MessageInterval => "message_interval",
+            RecordingFeature::LegacyMetrics => "legacy_metrics",
+        }
+    }
+
+    /// The feature named `name`, see [`RecordingFeature::name`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|feature| feature.name() == name)
+    }
+
+    fn configured(self) -> bool {
+        let settings = get_settings();
+        match self {
+            RecordingFeature::MetadataSize => settings.metadata_size,
+            RecordingFeature::CpuTime => settings.cpu_time,
+            RecordingFeature::InterarrivalTime => settings.interarrival_time,
+            RecordingFeature::SendBlockedTime => settings.send_blocked_time,
+            RecordingFeature::MessageInterval => settings.message_interval,
+            RecordingFeature::LegacyMetrics => settings.legacy_metrics,
+        }
+    }
+}
+
+const CONFIGURED: u8 = 0;
+const ON: u8 = 1;
+const OFF: u8 = 2;
+
+/// Runtime state of each feature, by its index in [`RecordingFeature::ALL`].
+static OVERRIDES: [AtomicU8; RecordingFeature::ALL.len()] =
+    [const { AtomicU8::new(CONFIGURED) }; RecordingFeature::ALL.len()];
+
+/// Switch `feature` on or off for the calls that start from now on, until the
+/// process exits or it is switched again.
+///
+/// ```
+/// use tonic_prometheus_layer::metrics::{set_feature_enabled, RecordingFeature};
+///
+/// set_feature_enabled(RecordingFeature::MetadataSize, true);
+/// ```
+pub fn set_feature_enabled(feature: RecordingFeature, enabled: bool) {
+    let state = if enabled { ON } else { OFF };
+    OVERRIDES[feature as usize].store(state, Ordering::Relaxed);
+}
+
+/// Whether `feature` is currently recorded.
+pub fn feature_enabled(feature: RecordingFeature) -> bool {
+    match OVERRIDES[feature as usize].load(Ordering::Relaxed) {
+        ON => true,
+        OFF => false,
+        _ => feature.configured(),
+    }
+}
+
+/// Apply the `feature=on` or `feature=off` pairs of an `&`-separated query,
+/// all or none of them.
+pub(crate) fn apply_query(query: &str) -> Result<(), String> {
+    let mut changes = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let feature =
+            RecordingFeature::from_name(name).ok_or_else(|| format!("unknown feature {name:?}"))?;
+        let enabled = match value {
+            "on" | "true" | "1" => true,
+            "off" | "false" | "0" => false,
+            _ => {
+                return Err(format!(
+                    "invalid value {value:?} of {name}, expected on or off"
+                ))
+            }
+        };
+        changes.push((feature, enabled));
+    }
+
+    for (feature, enabled) in changes {
+        tracing::info!(
+            feature = feature.name(),
+            enabled,
+            "recording feature switched"
+        );
+        set_feature_enabled(feature, enabled);
+    }
+    Ok(())
+}
+
+/// The state of each feature, one `name on|off` line each.
+pub(crate) fn render() -> String {
+    let mut out = String::new();
+    for feature in RecordingFeature::ALL {
+        let state = if feature_enabled(feature) {
+            "on"
+        } else {
+            "off"
+        };
+        let _ = writeln!(out, "{} {state}", feature.name());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switches_features() {
+        for feature in RecordingFeature::ALL {
+            assert_eq!(RecordingFeature::from_name(feature.name()), Some(feature));
+        }
+
+        let feature = RecordingFeature::InterarrivalTime;
+        assert!(apply_query("interarrival_time=on&cpu_time=maybe").is_err());
+        assert!(apply_query("interarrival=on").is_err());
+        assert_eq!(feature_enabled(feature), get_settings().interarrival_time);
+
+        apply_query("interarrival_time=on").unwrap();
+        assert!(feature_enabled(feature));
+        assert!(render().contains("interarrival_time on\n"));
+        set_feature_enabled(feature, false);
+        assert!(!feature_enabled(feature));
+    }
+}
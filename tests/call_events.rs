@@ -0,0 +1,44 @@
+//! Runs in its own process, so that the settings are installed before the
+//! first subscription.
+
+use http_body_util::{BodyExt, Full};
+use tokio::sync::broadcast::error::RecvError;
+use tonic::codegen::http::{request, response, HeaderValue};
+use tonic::codegen::Bytes;
+use tonic_prometheus_layer::metrics::{self, CallEventsSettings, GlobalSettings};
+use tonic_prometheus_layer::MetricsLayer;
+use tower::{service_fn, Layer, ServiceExt};
+
+#[tokio::test]
+async fn subscribes_with_configured_capacity() {
+    metrics::try_init_settings(GlobalSettings {
+        call_events: Some(CallEventsSettings { capacity: 2 }),
+        ..Default::default()
+    })
+    .unwrap();
+    let mut events = metrics::subscribe();
+
+    for _ in 0..2 {
+        let service = service_fn(|_req: request::Request<()>| async {
+            let mut resp = response::Response::new(Full::new(Bytes::from_static(b"data")));
+            resp.headers_mut()
+                .insert("grpc-status", HeaderValue::from_static("0"));
+            Ok::<_, std::convert::Infallible>(resp)
+        });
+        let req = request::Request::builder()
+            .uri("/test.Events/Get")
+            .header("content-type", "application/grpc")
+            .body(())
+            .unwrap();
+
+        let resp = MetricsLayer::new()
+            .layer(service)
+            .oneshot(req)
+            .await
+            .unwrap();
+        resp.into_body().collect().await.unwrap();
+    }
+
+    // Two calls send four events, of which the channel keeps the last two.
+    assert_eq!(events.recv().await, Err(RecvError::Lagged(2)));
+}
@@ -0,0 +1,64 @@
+//! Runs in its own process, so that no other scrape resets the peak gauges.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::task::Poll;
+
+use http_body_util::{BodyExt, Empty, Full};
+use tonic::codegen::http::{header, request, response, Method, StatusCode};
+use tonic::codegen::Bytes;
+use tonic_prometheus_layer::{MetricsLayer, ScrapeBody, ScrapeLayer};
+use tower::{service_fn, Layer, Service, ServiceExt};
+
+const PEAK: &str = r#"grpc_server_in_flight_max{grpc_service="test.Peaks",grpc_method="Get"}"#;
+
+#[tokio::test]
+async fn head_and_not_modified_keep_peaks() {
+    let handler = service_fn(|_req: request::Request<()>| {
+        std::future::pending::<Result<response::Response<Full<Bytes>>, Infallible>>()
+    });
+    let mut server = MetricsLayer::new().layer(handler);
+    let call = || {
+        request::Request::builder()
+            .uri("/test.Peaks/Get")
+            .header("content-type", "application/grpc")
+            .body(())
+            .unwrap()
+    };
+    // Calls start being recorded when first polled.
+    let mut first = Box::pin(server.ready().await.unwrap().call(call()));
+    let mut second = Box::pin(server.ready().await.unwrap().call(call()));
+    std::future::poll_fn(|cx| {
+        assert!(first.as_mut().poll(cx).is_pending());
+        assert!(second.as_mut().poll(cx).is_pending());
+        Poll::Ready(())
+    })
+    .await;
+    drop(first);
+
+    let scraper = ScrapeLayer::new().layer(service_fn(|_: request::Request<()>| async {
+        Ok::<_, Infallible>(response::Response::new(Empty::<Bytes>::new()))
+    }));
+    let scrape = |method, if_none_match: Option<&'static str>| {
+        let mut req = request::Request::builder().method(method).uri("/metrics");
+        if let Some(etag) = if_none_match {
+            req = req.header(header::IF_NONE_MATCH, etag);
+        }
+        scraper.clone().oneshot(req.body(()).unwrap())
+    };
+
+    let resp = scrape(Method::HEAD, None).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = scrape(Method::GET, Some("*")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+    let text = body_text(scrape(Method::GET, None).await.unwrap()).await;
+    assert!(text.contains(&format!("{PEAK} 2\n")), "{text}");
+    let text = body_text(scrape(Method::GET, None).await.unwrap()).await;
+    assert!(text.contains(&format!("{PEAK} 1\n")), "{text}");
+}
+
+async fn body_text(resp: response::Response<ScrapeBody<Empty<Bytes>>>) -> String {
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8(body.to_vec()).unwrap()
+}
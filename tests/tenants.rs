@@ -0,0 +1,19 @@
+//! Runs in its own process, so that the settings are installed before the
+//! first tenant lookup.
+
+use tonic_prometheus_layer::metrics::{self, GlobalSettings, TenantSettings};
+
+#[test]
+fn creates_configured_tenants() {
+    metrics::try_init_settings(GlobalSettings {
+        tenants: Some(TenantSettings {
+            header: Some("x-tenant".to_owned()),
+            tenants: vec!["acme".to_owned()],
+        }),
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert!(metrics::tenant_registry("acme").is_some());
+    assert!(metrics::tenant_registry("globex").is_none());
+}